@@ -0,0 +1,753 @@
+//! Options controlling how rust values are represented in lua.
+//!
+//! The same `Config` is accepted by both `LuaSerializer::with_config` and
+//! `LuaDeserializer::with_config`, so that a value serialized with a given
+//! configuration can always be deserialized with that same configuration.
+//!
+//! ```rust
+//! extern crate hlua;
+//! extern crate serde;
+//! #[macro_use] extern crate serde_derive;
+//! extern crate serde_hlua;
+//!
+//! use serde_hlua::config::{Config, EnumRepr};
+//!
+//! #[derive(Debug, Deserialize, Serialize, PartialEq)]
+//! enum Shape {
+//!     Circle { radius: f32 },
+//!     Square { side: f32 }
+//! }
+//!
+//! fn main() {
+//!     let mut lua = hlua::Lua::new();
+//!     let config = Config::new().enum_repr(EnumRepr::Internal { tag: "type" });
+//!
+//!     let circle = Shape::Circle { radius: 2.0 };
+//!     lua.set("circle", serde_hlua::to_lua_with_config(&circle, config).unwrap());
+//!     assert!(lua.execute::<bool>("
+//!         return circle.type == 'Circle' and circle.radius == 2
+//!     ").unwrap());
+//!
+//!     let square: Shape = serde_hlua::from_lua_with_config(
+//!         lua.execute::<hlua::AnyLuaValue>("
+//!             return { type = 'Square', side = 3 }
+//!         ").unwrap(),
+//!         config
+//!     ).unwrap();
+//!     assert_eq!(square, Shape::Square { side: 3.0 });
+//! }
+//! ```
+
+/// How enum variants are represented in lua.
+///
+/// Unit variants are always accepted by the deserializer as the bare name of
+/// the variant, regardless of the representation chosen here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnumRepr {
+    /// `{ Variant = payload }`, with unit variants encoded as `"Variant"`.
+    ///
+    /// This is the default representation.
+    #[default]
+    External,
+    /// `{ [tag] = "Variant", ...payload }`, where the payload's entries are
+    /// stored alongside the tag. Unit variants are encoded as
+    /// `{ [tag] = "Variant" }`.
+    ///
+    /// Unlike serde's `#[serde(tag = ...)]`, tuple variants are supported;
+    /// their fields are stored at the indices `1..N` next to the tag. Newtype
+    /// variants must contain a value that is encoded as a table.
+    Internal {
+        /// The name of the field holding the variant name.
+        tag: &'static str
+    },
+    /// `{ [tag] = "Variant", [content] = payload }`. Unit variants are encoded
+    /// as `{ [tag] = "Variant" }`.
+    Adjacent {
+        /// The name of the field holding the variant name.
+        tag: &'static str,
+        /// The name of the field holding the variant payload.
+        content: &'static str
+    },
+    /// `{ "Variant", payload }`. Unit variants are encoded as `{ "Variant" }`.
+    Array
+}
+
+/// How byte strings and byte buffers are represented in lua.
+///
+/// Whichever encoding is chosen, the deserializer also accepts non-UTF-8 lua
+/// strings (`AnyLuaValue::LuaAnyString`) as raw bytes, since none of the
+/// textual encodings can produce them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ByteEncoding {
+    /// A lua string holding the bytes as-is, which `string.byte` can read.
+    ///
+    /// This is the default when the `base64-bytes` feature is disabled.
+    #[cfg_attr(not(feature = "base64-bytes"), default)]
+    Raw,
+    /// A string holding the standard base64 encoding of the bytes.
+    ///
+    /// This is the default when the `base64-bytes` feature is enabled.
+    #[cfg(feature = "base64-bytes")]
+    #[cfg_attr(feature = "base64-bytes", default)]
+    Base64,
+    /// A string holding the URL-safe base64 encoding of the bytes.
+    #[cfg(feature = "base64-bytes")]
+    Base64UrlSafe,
+    /// A string holding the lowercase hexadecimal encoding of the bytes.
+    /// Uppercase digits are also accepted when deserializing.
+    Hex,
+    /// A sequence of integers from 0 to 255.
+    Array
+}
+
+/// How the deserializer treats non-UTF-8 lua strings
+/// (`AnyLuaValue::LuaAnyString`) when a rust string is expected.
+///
+/// Byte targets always accept non-UTF-8 strings as raw bytes, regardless of
+/// this setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Utf8Mode {
+    /// Fail with `ErrorKind::InvalidUtf8`, reporting the byte offset of the
+    /// first invalid sequence.
+    #[default]
+    Strict,
+    /// Replace invalid sequences with `U+FFFD REPLACEMENT CHARACTER`.
+    Lossy
+}
+
+/// Which lua tables are read as sequences.
+///
+/// Lua can't store `nil` in a table, so a sequence containing `None` or unit
+/// values has holes, such as `{1, nil, 3}`, which is the table `{[1] = 1,
+/// [3] = 3}`. How such tables are read is chosen here.
+///
+/// Under `Fill` and `Packed`, a table such as `{ [2^40] = 1 }` would be read
+/// as a huge sequence of nils. `Limits` count every hole as an entry and a
+/// value of the table; when neither `Limits::max_entries` nor
+/// `Limits::max_nodes` is set, a sequence with more than `MAX_HOLES` holes
+/// is rejected with `ErrorKind::InvalidLength` instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SparseArrays {
+    /// Only tables with keys exactly from 1 to N are sequences.
+    #[default]
+    Strict,
+    /// Tables whose keys are all positive integers are sequences, with any
+    /// holes up to the largest key read as nil.
+    Fill,
+    /// As `Strict`, except that tables with a numeric `n` field, as produced
+    /// by `table.pack`, are sequences of length `n` with any holes read as
+    /// nil.
+    ///
+    /// When serializing, an `n` field is written for sequences containing
+    /// nil values, so that trailing `None`s keep their place.
+    Packed
+}
+
+/// Whether the deserializer converts between lua strings, numbers and
+/// booleans the way lua itself does.
+///
+/// Serialization is not affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Coercion {
+    /// Every rust type only accepts the matching lua type.
+    #[default]
+    Strict,
+    /// Numeric types also accept strings that lua would convert to numbers,
+    /// such as `" 42 "` or `"0x1F"`, and string types also accept numbers,
+    /// formatted as lua's `tostring` would format them.
+    Lenient {
+        /// Whether `bool` also accepts any value, following lua's truthiness:
+        /// `nil` and `false` are false, and everything else is true.
+        truthiness: bool
+    }
+}
+
+/// What `LuaStackDeserializer` does with a table that contains itself, such
+/// as `t` after `t.self = t`.
+///
+/// Only a table found inside itself is a cycle. A table that is merely
+/// referred to from several places is read once for each of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cycles {
+    /// Fail with `ErrorKind::CyclicTable`, at the path where the table is
+    /// found inside itself.
+    #[default]
+    Error,
+    /// Read the table as the given string where it is found inside itself.
+    Marker(&'static str)
+}
+
+/// Limits on the lua data that the deserializers accept, for reading the
+/// output of untrusted scripts.
+///
+/// The whole value is checked against the limits before any of it is
+/// deserialized, so data exceeding one fails with `ErrorKind::LimitExceeded`
+/// instead of exhausting the stack or memory. By default, there are no
+/// limits.
+///
+/// The nils yielded for the holes of a sequence under `SparseArrays::Fill`
+/// and `SparseArrays::Packed` count as entries and values of the table, as
+/// if it held them.
+///
+/// Reading a value into an `AnyLuaValue` happens before any limit is
+/// checked, so untrusted data is best read straight from the stack with
+/// `from_lua_stack_with_config`.
+///
+/// ```rust
+/// extern crate hlua;
+/// extern crate serde_hlua;
+///
+/// use serde_hlua::ErrorKind;
+/// use serde_hlua::config::{Config, Limits};
+/// use serde_hlua::error::Limit;
+///
+/// fn main() {
+///     let mut lua = hlua::Lua::new();
+///     let value = lua.execute::<hlua::AnyLuaValue>("
+///         local value = {}
+///         for _ = 1, 8 do
+///             value = { value }
+///         end
+///         return value
+///     ").unwrap();
+///
+///     let config = Config::new().limits(Limits::new().max_depth(4));
+///     let error = serde_hlua::from_lua_with_config::<serde_hlua::Value>(value, config)
+///         .unwrap_err();
+///     assert_eq!(&ErrorKind::LimitExceeded { limit: Limit::Depth, max: 4 }, error.kind());
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    pub(crate) max_depth: Option<usize>,
+    pub(crate) max_entries: Option<usize>,
+    pub(crate) max_string_bytes: Option<usize>,
+    pub(crate) max_nodes: Option<usize>
+}
+
+impl Limits {
+    /// Return limits that accept everything.
+    pub fn new() -> Limits {
+        Limits::default()
+    }
+
+    /// Set how deeply tables can be nested. A table that isn't inside any
+    /// other is at depth 1.
+    pub fn max_depth(mut self, max_depth: usize) -> Limits {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// How deeply tables can be nested, if that is limited.
+    pub fn get_max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// Set how many entries a single table can have.
+    pub fn max_entries(mut self, max_entries: usize) -> Limits {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// How many entries a single table can have, if that is limited.
+    pub fn get_max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    /// Set how many bytes all the strings, keys included, can hold in total.
+    pub fn max_string_bytes(mut self, max_string_bytes: usize) -> Limits {
+        self.max_string_bytes = Some(max_string_bytes);
+        self
+    }
+
+    /// How many bytes all the strings can hold in total, if that is limited.
+    pub fn get_max_string_bytes(&self) -> Option<usize> {
+        self.max_string_bytes
+    }
+
+    /// Set how many values, counting tables, table keys and everything in
+    /// them, there can be in total.
+    pub fn max_nodes(mut self, max_nodes: usize) -> Limits {
+        self.max_nodes = Some(max_nodes);
+        self
+    }
+
+    /// How many values there can be in total, if that is limited.
+    pub fn get_max_nodes(&self) -> Option<usize> {
+        self.max_nodes
+    }
+}
+
+/// Options shared by `LuaSerializer` and `LuaDeserializer`.
+///
+/// All options are set through builder methods, starting from
+/// `Config::new()`, which is equivalent to the behaviour of
+/// `LuaSerializer::new()` and `LuaDeserializer::new()`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Config {
+    pub(crate) enum_repr: EnumRepr,
+    pub(crate) null_sentinel: Option<&'static str>,
+    pub(crate) byte_encoding: ByteEncoding,
+    pub(crate) utf8_mode: Utf8Mode,
+    pub(crate) sparse_arrays: SparseArrays,
+    pub(crate) coercion: Coercion,
+    pub(crate) limits: Limits,
+    pub(crate) cycles: Cycles,
+    pub(crate) share_tables: bool,
+    pub(crate) read_all_fields: bool
+}
+
+impl Config {
+    /// Return the default configuration.
+    pub fn new() -> Config {
+        Config::default()
+    }
+
+    /// Set the representation used for enum variants.
+    pub fn enum_repr(mut self, enum_repr: EnumRepr) -> Config {
+        self.enum_repr = enum_repr;
+        self
+    }
+
+    /// The representation used for enum variants.
+    pub fn get_enum_repr(&self) -> EnumRepr {
+        self.enum_repr
+    }
+
+    /// Encode unit values, `None` and unit structs as the string `sentinel`
+    /// instead of `nil`, and decode that string back to them.
+    ///
+    /// Lua erases table entries that are set to `nil`, so by default
+    /// `vec![(), ()]` and `vec![None, Some(1)]` don't survive a round trip.
+    /// With a sentinel, sequences and maps keep their length and keys.
+    /// Serializing a string equal to the sentinel is an error, as it could
+    /// not be told apart from a unit value.
+    ///
+    /// ```rust
+    /// extern crate hlua;
+    /// extern crate serde_hlua;
+    ///
+    /// use serde_hlua::config::{Config, NULL_SENTINEL};
+    ///
+    /// fn main() {
+    ///     let config = Config::new().null_sentinel(NULL_SENTINEL);
+    ///     let value = vec![None, Some(1), None];
+    ///     let lua = serde_hlua::to_lua_with_config(&value, config).unwrap();
+    ///     let round_trip: Vec<Option<u32>> =
+    ///         serde_hlua::from_lua_with_config(lua, config).unwrap();
+    ///     assert_eq!(value, round_trip);
+    /// }
+    /// ```
+    pub fn null_sentinel(mut self, sentinel: &'static str) -> Config {
+        self.null_sentinel = Some(sentinel);
+        self
+    }
+
+    /// The string used to encode unit values, if any.
+    pub fn get_null_sentinel(&self) -> Option<&'static str> {
+        self.null_sentinel
+    }
+
+    /// Set the representation used for byte strings and byte buffers.
+    pub fn byte_encoding(mut self, byte_encoding: ByteEncoding) -> Config {
+        self.byte_encoding = byte_encoding;
+        self
+    }
+
+    /// The representation used for byte strings and byte buffers.
+    pub fn get_byte_encoding(&self) -> ByteEncoding {
+        self.byte_encoding
+    }
+
+    /// Set how non-UTF-8 lua strings are deserialized into rust strings.
+    pub fn utf8_mode(mut self, utf8_mode: Utf8Mode) -> Config {
+        self.utf8_mode = utf8_mode;
+        self
+    }
+
+    /// How non-UTF-8 lua strings are deserialized into rust strings.
+    pub fn get_utf8_mode(&self) -> Utf8Mode {
+        self.utf8_mode
+    }
+
+    /// Set which lua tables are read as sequences.
+    pub fn sparse_arrays(mut self, sparse_arrays: SparseArrays) -> Config {
+        self.sparse_arrays = sparse_arrays;
+        self
+    }
+
+    /// Which lua tables are read as sequences.
+    pub fn get_sparse_arrays(&self) -> SparseArrays {
+        self.sparse_arrays
+    }
+
+    /// Set whether the deserializer converts between strings, numbers and
+    /// booleans as lua does.
+    ///
+    /// ```rust
+    /// extern crate hlua;
+    /// extern crate serde;
+    /// #[macro_use] extern crate serde_derive;
+    /// extern crate serde_hlua;
+    ///
+    /// use serde_hlua::config::{Coercion, Config};
+    ///
+    /// #[derive(Debug, Deserialize, PartialEq)]
+    /// struct Options {
+    ///     port: u16,
+    ///     name: String,
+    ///     verbose: bool
+    /// }
+    ///
+    /// fn main() {
+    ///     let mut lua = hlua::Lua::new();
+    ///     let value = lua.execute::<hlua::AnyLuaValue>("
+    ///         return { port = '0x1F90', name = 1.5, verbose = 'yes' }
+    ///     ").unwrap();
+    ///     let config = Config::new().coercion(Coercion::Lenient { truthiness: true });
+    ///     let options: Options = serde_hlua::from_lua_with_config(value, config).unwrap();
+    ///     assert_eq!(options, Options { port: 8080, name: "1.5".to_owned(), verbose: true });
+    /// }
+    /// ```
+    pub fn coercion(mut self, coercion: Coercion) -> Config {
+        self.coercion = coercion;
+        self
+    }
+
+    /// Whether the deserializer converts between strings, numbers and
+    /// booleans as lua does.
+    pub fn get_coercion(&self) -> Coercion {
+        self.coercion
+    }
+
+    /// Set the limits on the lua data that the deserializer accepts.
+    pub fn limits(mut self, limits: Limits) -> Config {
+        self.limits = limits;
+        self
+    }
+
+    /// The limits on the lua data that the deserializer accepts.
+    pub fn get_limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Set what the stack deserializer does with tables that contain
+    /// themselves.
+    ///
+    /// Lua values can only be cyclic on the lua stack: an `AnyLuaValue`
+    /// can't hold a cycle, and reading a cyclic table into one never ends.
+    ///
+    /// ```rust
+    /// extern crate hlua;
+    /// extern crate serde_hlua;
+    ///
+    /// use serde_hlua::Value;
+    /// use serde_hlua::config::{Config, Cycles};
+    ///
+    /// fn main() {
+    ///     let mut lua = hlua::Lua::new();
+    ///     lua.execute::<()>("
+    ///         node = { name = 'root' }
+    ///         node.parent = node
+    ///     ").unwrap();
+    ///     let mut node: hlua::LuaTable<_> = lua.get("node").unwrap();
+    ///
+    ///     let error = serde_hlua::from_lua_stack::<_, Value>(&mut node, -1).unwrap_err();
+    ///     assert_eq!("cyclic table at parent", error.to_string());
+    ///
+    ///     let config = Config::new().cycles(Cycles::Marker("<cycle>"));
+    ///     let root: Value = serde_hlua::from_lua_stack_with_config(&mut node, -1, config)
+    ///         .unwrap();
+    ///     assert_eq!(root["name"], Value::from("root"));
+    ///     assert_eq!(root["parent"], Value::from("<cycle>"));
+    /// }
+    /// ```
+    pub fn cycles(mut self, cycles: Cycles) -> Config {
+        self.cycles = cycles;
+        self
+    }
+
+    /// What the stack deserializer does with tables that contain themselves.
+    pub fn get_cycles(&self) -> Cycles {
+        self.cycles
+    }
+
+    /// Set whether pointers marked with the `shared` module are pushed as a
+    /// single lua table referred to from every place holding them, and
+    /// whether every place holding the same lua table is read into a single
+    /// pointer.
+    ///
+    /// This only affects `push_to_lua_with_config` and
+    /// `from_lua_stack_with_config`.
+    pub fn share_tables(mut self, share_tables: bool) -> Config {
+        self.share_tables = share_tables;
+        self
+    }
+
+    /// Whether shared pointers and lua tables are kept shared.
+    pub fn get_share_tables(&self) -> bool {
+        self.share_tables
+    }
+
+    /// Set whether the stack deserializer reads every entry of a table that
+    /// is deserialized as a struct, rather than looking up the fields of the
+    /// struct by name.
+    ///
+    /// Looking fields up skips the entries the struct doesn't have, so
+    /// `#[serde(deny_unknown_fields)]` only rejects them when every entry
+    /// is read. This only affects `from_lua_stack_with_config` and the other
+    /// readers of the lua stack; `LuaDeserializer` always reads every entry.
+    /// `from_lua_stack` and `SerdeLuaRead` look fields up by name.
+    ///
+    /// ```rust
+    /// extern crate hlua;
+    /// extern crate serde;
+    /// #[macro_use] extern crate serde_derive;
+    /// extern crate serde_hlua;
+    ///
+    /// use serde_hlua::Config;
+    ///
+    /// #[derive(Debug, Deserialize)]
+    /// #[serde(deny_unknown_fields)]
+    /// struct Point {
+    ///     x: f32,
+    ///     y: f32
+    /// }
+    ///
+    /// fn main() {
+    ///     let mut lua = hlua::Lua::new();
+    ///     lua.execute::<()>("point = { x = 1, y = 2, z = 3 }").unwrap();
+    ///     let mut point: hlua::LuaTable<_> = lua.get("point").unwrap();
+    ///
+    ///     assert!(serde_hlua::from_lua_stack::<_, Point>(&mut point, -1).is_ok());
+    ///     let config = Config::new().read_all_fields(true);
+    ///     let error = serde_hlua::from_lua_stack_with_config::<_, Point>(&mut point, -1, config)
+    ///         .unwrap_err();
+    ///     assert_eq!("unknown field `z`, expected one of `x`, `y`", error.to_string());
+    /// }
+    /// ```
+    pub fn read_all_fields(mut self, read_all_fields: bool) -> Config {
+        self.read_all_fields = read_all_fields;
+        self
+    }
+
+    /// Whether the stack deserializer reads every entry of a table that is
+    /// deserialized as a struct.
+    pub fn get_read_all_fields(&self) -> bool {
+        self.read_all_fields
+    }
+}
+
+/// How many holes a sequence read under `SparseArrays::Fill` or
+/// `SparseArrays::Packed` can have when the limits don't bound them.
+pub const MAX_HOLES: usize = 1 << 16;
+
+/// A sentinel suitable for `Config::null_sentinel`.
+pub const NULL_SENTINEL: &str = "serde_hlua.null";
+
+/// How the keys of a table are ordered in lua source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyOrder {
+    /// The order in which the entries were serialized.
+    #[default]
+    Serialized,
+    /// Numbers in ascending order, then strings in byte order, then booleans
+    /// and other keys in the order in which they were serialized. Sequences
+    /// therefore come first.
+    Sorted
+}
+
+/// How strings containing newlines are written in lua source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MultilineStrings {
+    /// As quoted strings, with newlines escaped as `\n`.
+    #[default]
+    Quoted,
+    /// As long brackets such as `[[...]]`, with the newlines kept as they
+    /// are. Strings that long brackets can't hold exactly, such as those
+    /// containing carriage returns or other control characters, are still
+    /// quoted, as are table keys.
+    LongBrackets
+}
+
+/// What precedes the value in lua source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Wrapper {
+    /// `return value`, so that running the chunk returns the value.
+    #[default]
+    Return,
+    /// `local name = value`. The name must be a lua identifier.
+    Local(&'static str),
+    /// Just the value, as an expression.
+    None
+}
+
+/// Options controlling how lua source text is laid out.
+///
+/// These only affect the text written by `to_lua_string_with_format` and
+/// `to_lua_writer_with_format`; which lua values are written is still
+/// controlled by `Config`. All options are set through builder methods,
+/// starting from either `SourceFormat::new()`, which writes everything on one
+/// line, or `SourceFormat::pretty()`.
+///
+/// ```rust
+/// extern crate serde;
+/// #[macro_use] extern crate serde_derive;
+/// extern crate serde_hlua;
+///
+/// use serde_hlua::config::{Config, KeyOrder, SourceFormat, Wrapper};
+///
+/// #[derive(Serialize)]
+/// struct Mod {
+///     name: &'static str,
+///     version: (u32, u32),
+///     authors: Vec<&'static str>
+/// }
+///
+/// fn main() {
+///     let manifest = Mod {
+///         name: "example",
+///         version: (1, 2),
+///         authors: vec!["first author", "second author", "third author"]
+///     };
+///     let format = SourceFormat::pretty()
+///         .indent(2)
+///         .max_width(40)
+///         .key_order(KeyOrder::Sorted)
+///         .wrapper(Wrapper::Local("manifest"));
+///     assert_eq!(
+///         serde_hlua::to_lua_string_with_format(&manifest, Config::new(), format).unwrap(),
+///         "local manifest = {
+///   authors = {
+///     \"first author\",
+///     \"second author\",
+///     \"third author\",
+///   },
+///   name = \"example\",
+///   version = { 1, 2 },
+/// }"
+///     );
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceFormat {
+    pub(crate) indent: usize,
+    pub(crate) multiline: bool,
+    pub(crate) trailing_commas: bool,
+    pub(crate) key_order: KeyOrder,
+    pub(crate) multiline_strings: MultilineStrings,
+    pub(crate) max_width: usize,
+    pub(crate) wrapper: Wrapper
+}
+
+impl Default for SourceFormat {
+    fn default() -> SourceFormat {
+        SourceFormat {
+            indent: 4,
+            multiline: false,
+            trailing_commas: false,
+            key_order: KeyOrder::Serialized,
+            multiline_strings: MultilineStrings::Quoted,
+            max_width: 80,
+            wrapper: Wrapper::Return
+        }
+    }
+}
+
+impl SourceFormat {
+    /// Return the default format, which writes everything on one line, as
+    /// `to_lua_string` does.
+    pub fn new() -> SourceFormat {
+        SourceFormat::default()
+    }
+
+    /// Return a format that writes one entry per line, with trailing commas,
+    /// for tables that don't fit within the maximum width.
+    pub fn pretty() -> SourceFormat {
+        SourceFormat::new().multiline(true).trailing_commas(true)
+    }
+
+    /// Set the number of spaces that each level of nesting is indented by.
+    pub fn indent(mut self, indent: usize) -> SourceFormat {
+        self.indent = indent;
+        self
+    }
+
+    /// The number of spaces that each level of nesting is indented by.
+    pub fn get_indent(&self) -> usize {
+        self.indent
+    }
+
+    /// Set whether tables that don't fit within the maximum width are written
+    /// one entry per line. Otherwise, everything is written on one line.
+    pub fn multiline(mut self, multiline: bool) -> SourceFormat {
+        self.multiline = multiline;
+        self
+    }
+
+    /// Whether tables that don't fit within the maximum width are written one
+    /// entry per line.
+    pub fn get_multiline(&self) -> bool {
+        self.multiline
+    }
+
+    /// Set whether the last entry of a table written one entry per line is
+    /// followed by a comma.
+    pub fn trailing_commas(mut self, trailing_commas: bool) -> SourceFormat {
+        self.trailing_commas = trailing_commas;
+        self
+    }
+
+    /// Whether the last entry of a table written one entry per line is
+    /// followed by a comma.
+    pub fn get_trailing_commas(&self) -> bool {
+        self.trailing_commas
+    }
+
+    /// Set the order in which table keys are written.
+    pub fn key_order(mut self, key_order: KeyOrder) -> SourceFormat {
+        self.key_order = key_order;
+        self
+    }
+
+    /// The order in which table keys are written.
+    pub fn get_key_order(&self) -> KeyOrder {
+        self.key_order
+    }
+
+    /// Set how strings containing newlines are written.
+    pub fn multiline_strings(mut self, multiline_strings: MultilineStrings) -> SourceFormat {
+        self.multiline_strings = multiline_strings;
+        self
+    }
+
+    /// How strings containing newlines are written.
+    pub fn get_multiline_strings(&self) -> MultilineStrings {
+        self.multiline_strings
+    }
+
+    /// Set the number of characters that a line can hold before tables on it
+    /// are written one entry per line. Has no effect unless `multiline` is
+    /// set.
+    pub fn max_width(mut self, max_width: usize) -> SourceFormat {
+        self.max_width = max_width;
+        self
+    }
+
+    /// The number of characters that a line can hold before tables on it are
+    /// written one entry per line.
+    pub fn get_max_width(&self) -> usize {
+        self.max_width
+    }
+
+    /// Set what precedes the value.
+    pub fn wrapper(mut self, wrapper: Wrapper) -> SourceFormat {
+        self.wrapper = wrapper;
+        self
+    }
+
+    /// What precedes the value.
+    pub fn get_wrapper(&self) -> Wrapper {
+        self.wrapper
+    }
+}
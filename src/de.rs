@@ -1,9 +1,11 @@
-
 //! Deserialization from lua values to rust values.
 
+use std::borrow::Cow;
 use std::error;
 use std::fmt;
 use std::iter::ExactSizeIterator;
+use std::iter::Peekable;
+use std::marker::PhantomData;
 use std::vec::IntoIter;
 
 #[cfg(feature = "base64-bytes")]
@@ -12,277 +14,835 @@ use hlua::AnyLuaValue;
 use serde;
 use serde::de::{Deserializer, Visitor};
 
+use config::{ByteEncoding, Coercion, Config, EnumRepr, Limits, SparseArrays, Utf8Mode};
+use config::MAX_HOLES;
+use error::{ErrorKind, Limit, Position};
+
 /// A deserializer over an `AnyLuaValue` that can deserialize it to a provided
 /// format.
 #[derive(Debug, Clone)]
-pub struct LuaDeserializer(AnyLuaValue);
+pub struct LuaDeserializer {
+    value: AnyLuaValue,
+    config: Config
+}
 
 impl LuaDeserializer {
     /// Return a deserializer that can deserialize a value from the provided
     /// lua data.
     pub fn new(value: AnyLuaValue) -> LuaDeserializer {
-        LuaDeserializer(value)
+        LuaDeserializer::with_config(value, Config::new())
+    }
+
+    /// Return a deserializer that deserializes a value from the provided lua
+    /// data using the provided configuration.
+    pub fn with_config(value: AnyLuaValue, config: Config) -> LuaDeserializer {
+        LuaDeserializer { value, config }
+    }
+
+    fn inner(self) -> ValueDeserializer<'static, Owned> {
+        ValueDeserializer::new(self.value, self.config)
+    }
+}
+
+/// A deserializer over a borrowed `AnyLuaValue`.
+///
+/// Unlike `LuaDeserializer`, this never copies any part of the lua data, and
+/// types such as `&'a str`, `&'a [u8]` and `Cow<'a, str>` can borrow from it
+/// directly.
+///
+/// ```rust
+/// extern crate hlua;
+/// extern crate serde;
+/// #[macro_use] extern crate serde_derive;
+/// extern crate serde_hlua;
+///
+/// #[derive(Deserialize)]
+/// struct Message<'a> {
+///     author: &'a str,
+///     text: &'a str
+/// }
+///
+/// fn main() {
+///     let mut lua = hlua::Lua::new();
+///     let value = lua.execute::<hlua::AnyLuaValue>("
+///         return { author = 'moth', text = 'lamp' }
+///     ").unwrap();
+///
+///     let message: Message = serde_hlua::from_lua_ref(&value).unwrap();
+///     assert_eq!(message.author, "moth");
+///     assert_eq!(message.text, "lamp");
+/// }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct LuaRefDeserializer<'a> {
+    value: &'a AnyLuaValue,
+    config: Config
+}
+
+impl<'a> LuaRefDeserializer<'a> {
+    /// Return a deserializer that can deserialize a value from the provided
+    /// lua data.
+    pub fn new(value: &'a AnyLuaValue) -> LuaRefDeserializer<'a> {
+        LuaRefDeserializer::with_config(value, Config::new())
+    }
+
+    /// Return a deserializer that deserializes a value from the provided lua
+    /// data using the provided configuration.
+    pub fn with_config(value: &'a AnyLuaValue, config: Config) -> LuaRefDeserializer<'a> {
+        LuaRefDeserializer { value, config }
+    }
+
+    fn inner(self) -> ValueDeserializer<'a, Borrowed> {
+        ValueDeserializer::new(self.value, self.config)
+    }
+}
+
+// Both public deserializers are thin wrappers around `ValueDeserializer`,
+// and differ only in whether strings are lent to the visitor. The limits are
+// checked here, once for the whole value.
+macro_rules! forward_to_inner {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method<V>(self, $($arg: $ty,)* visitor: V) -> DeResult<V::Value>
+                where V: Visitor<'de>
+            {
+                check_limits(&self.value, self.config)?;
+                self.inner().$method($($arg,)* visitor)
+            }
+        )*
+    }
+}
+
+macro_rules! forward_deserializer {
+    () => {
+        forward_to_inner! {
+            deserialize_any();
+            deserialize_bool();
+            deserialize_i8();
+            deserialize_i16();
+            deserialize_i32();
+            deserialize_i64();
+            deserialize_u8();
+            deserialize_u16();
+            deserialize_u32();
+            deserialize_u64();
+            deserialize_f32();
+            deserialize_f64();
+            deserialize_char();
+            deserialize_str();
+            deserialize_string();
+            deserialize_bytes();
+            deserialize_byte_buf();
+            deserialize_option();
+            deserialize_unit();
+            deserialize_unit_struct(name: &'static str);
+            deserialize_newtype_struct(name: &'static str);
+            deserialize_seq();
+            deserialize_tuple(len: usize);
+            deserialize_tuple_struct(name: &'static str, len: usize);
+            deserialize_map();
+            deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+            deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+            deserialize_identifier();
+            deserialize_ignored_any();
+        }
     }
 }
 
 impl<'de> Deserializer<'de> for LuaDeserializer {
     type Error = LuaDeserializeError;
 
+    forward_deserializer!();
+}
+
+impl<'de> Deserializer<'de> for LuaRefDeserializer<'de> {
+    type Error = LuaDeserializeError;
+
+    forward_deserializer!();
+}
+
+/// A value of the lua data being deserialized, either borrowed from it or
+/// taken out of it.
+trait Node: Sized {
+    /// Look at the value.
+    fn get(&self) -> &AnyLuaValue;
+
+    /// Return a nil value, which stands in for missing values.
+    fn nil() -> Self;
+
+    /// Return the entries of the value if it is a table, or give the value
+    /// back.
+    fn into_entries(self) -> Result<Vec<Entry<Self>>, Self>;
+
+    /// Return the value of the entry of a table whose key is the string
+    /// `field`, if any, along with the rest of the table.
+    fn take_field(self, field: &str) -> (Option<Self>, Self);
+}
+
+impl<'a> Node for &'a AnyLuaValue {
+    fn get(&self) -> &AnyLuaValue {
+        self
+    }
+
+    fn nil() -> &'a AnyLuaValue {
+        &NIL
+    }
+
+    fn into_entries(self) -> Result<Vec<Entry<&'a AnyLuaValue>>, &'a AnyLuaValue> {
+        match self {
+            AnyLuaValue::LuaArray(entries) => {
+                Ok(entries.iter().map(|(key, value)| (key, value)).collect())
+            },
+            value => Err(value)
+        }
+    }
+
+    fn take_field(self, field: &str) -> (Option<&'a AnyLuaValue>, &'a AnyLuaValue) {
+        match self {
+            AnyLuaValue::LuaArray(entries) => {
+                let field = entries.iter().find(|(key, _)| is_field(key, field));
+                (field.map(|(_, value)| value), self)
+            },
+            value => (None, value)
+        }
+    }
+}
+
+impl Node for AnyLuaValue {
+    fn get(&self) -> &AnyLuaValue {
+        self
+    }
+
+    fn nil() -> AnyLuaValue {
+        AnyLuaValue::LuaNil
+    }
+
+    fn into_entries(self) -> Result<Vec<Entry<AnyLuaValue>>, AnyLuaValue> {
+        match self {
+            AnyLuaValue::LuaArray(entries) => Ok(entries),
+            value => Err(value)
+        }
+    }
+
+    fn take_field(self, field: &str) -> (Option<AnyLuaValue>, AnyLuaValue) {
+        match self {
+            AnyLuaValue::LuaArray(mut entries) => {
+                let field = entries.iter()
+                    .position(|(key, _)| is_field(key, field))
+                    .map(|position| entries.remove(position).1);
+                (field, AnyLuaValue::LuaArray(entries))
+            },
+            value => (None, value)
+        }
+    }
+}
+
+/// The contents of a lua string.
+enum Text<'a> {
+    Str(Cow<'a, str>),
+    Bytes(Cow<'a, [u8]>)
+}
+
+/// The values a mode deserializes from.
+trait Nodes<'a> {
+    type Node: Node;
+
+    /// Return the contents of the value if it is a string, or give the
+    /// value back.
+    fn into_text(value: Self::Node) -> Result<Text<'a>, Self::Node>;
+}
+
+/// Whether strings and byte strings are lent to visitors for the lifetime of
+/// the lua data, only for the duration of the call, or given to them.
+trait Mode<'a, 'de>: Nodes<'a> + Sized {
+    fn visit_str<V>(string: &'a str, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>;
+
+    fn visit_bytes<V>(bytes: &'a [u8], visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>;
+
+    /// Deserialize the key of a table entry, which is kept afterwards to
+    /// locate errors in the entry's value.
+    fn deserialize_key<K>(key: &Self::Node, seed: K, config: Config) -> DeResult<K::Value>
+        where K: serde::de::DeserializeSeed<'de>;
+
+    fn visit_cow_str<V>(string: Cow<'a, str>, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        match string {
+            Cow::Borrowed(string) => Self::visit_str(string, visitor),
+            Cow::Owned(string) => visitor.visit_string(string)
+        }
+    }
+
+    fn visit_cow_bytes<V>(bytes: Cow<'a, [u8]>, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        match bytes {
+            Cow::Borrowed(bytes) => Self::visit_bytes(bytes, visitor),
+            Cow::Owned(bytes) => visitor.visit_byte_buf(bytes)
+        }
+    }
+}
+
+/// Return the contents of a borrowed value if it is a string.
+fn borrow_text(value: &AnyLuaValue) -> Result<Text<'_>, &AnyLuaValue> {
+    match value {
+        AnyLuaValue::LuaString(string) => Ok(Text::Str(Cow::Borrowed(string))),
+        AnyLuaValue::LuaAnyString(bytes) => Ok(Text::Bytes(Cow::Borrowed(&bytes.0))),
+        value => Err(value)
+    }
+}
+
+/// Lend strings for the lifetime of the lua data.
+#[derive(Debug)]
+struct Borrowed;
+
+impl<'a> Nodes<'a> for Borrowed {
+    type Node = &'a AnyLuaValue;
+
+    fn into_text(value: &'a AnyLuaValue) -> Result<Text<'a>, &'a AnyLuaValue> {
+        borrow_text(value)
+    }
+}
+
+impl<'a> Mode<'a, 'a> for Borrowed {
+    fn visit_str<V>(string: &'a str, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'a>
+    {
+        visitor.visit_borrowed_str(string)
+    }
+
+    fn visit_bytes<V>(bytes: &'a [u8], visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'a>
+    {
+        visitor.visit_borrowed_bytes(bytes)
+    }
+
+    fn deserialize_key<K>(key: &&'a AnyLuaValue, seed: K, config: Config) -> DeResult<K::Value>
+        where K: serde::de::DeserializeSeed<'a>
+    {
+        seed.deserialize(ValueDeserializer::<Borrowed>::new(key, config))
+    }
+}
+
+/// Lend strings only for the duration of the call to the visitor.
+#[derive(Debug)]
+struct Copied;
+
+impl<'a> Nodes<'a> for Copied {
+    type Node = &'a AnyLuaValue;
+
+    fn into_text(value: &'a AnyLuaValue) -> Result<Text<'a>, &'a AnyLuaValue> {
+        borrow_text(value)
+    }
+}
+
+impl<'a, 'de> Mode<'a, 'de> for Copied {
+    fn visit_str<V>(string: &'a str, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        visitor.visit_str(string)
+    }
+
+    fn visit_bytes<V>(bytes: &'a [u8], visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        visitor.visit_bytes(bytes)
+    }
+
+    fn deserialize_key<K>(key: &&'a AnyLuaValue, seed: K, config: Config) -> DeResult<K::Value>
+        where K: serde::de::DeserializeSeed<'de>
+    {
+        seed.deserialize(ValueDeserializer::<Copied>::new(key, config))
+    }
+}
+
+/// Take values out of the lua data, and give their strings to the visitor.
+/// Keys are only lent, as they are kept to locate errors.
+#[derive(Debug)]
+struct Owned;
+
+impl<'a> Nodes<'a> for Owned {
+    type Node = AnyLuaValue;
+
+    fn into_text(value: AnyLuaValue) -> Result<Text<'a>, AnyLuaValue> {
+        match value {
+            AnyLuaValue::LuaString(string) => Ok(Text::Str(Cow::Owned(string))),
+            AnyLuaValue::LuaAnyString(bytes) => Ok(Text::Bytes(Cow::Owned(bytes.0))),
+            value => Err(value)
+        }
+    }
+}
+
+impl<'a, 'de> Mode<'a, 'de> for Owned {
+    fn visit_str<V>(string: &'a str, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        visitor.visit_str(string)
+    }
+
+    fn visit_bytes<V>(bytes: &'a [u8], visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        visitor.visit_bytes(bytes)
+    }
+
+    fn deserialize_key<K>(key: &AnyLuaValue, seed: K, config: Config) -> DeResult<K::Value>
+        where K: serde::de::DeserializeSeed<'de>
+    {
+        seed.deserialize(ValueDeserializer::<Copied>::new(key, config))
+    }
+}
+
+/// Stands in for values that are decoded as nil, such as the null sentinel.
+static NIL: AnyLuaValue = AnyLuaValue::LuaNil;
+
+/// A table entry.
+type Entry<N> = (N, N);
+
+/// The deserializer behind both `LuaDeserializer` and `LuaRefDeserializer`.
+struct ValueDeserializer<'a, M: Nodes<'a>> {
+    value: M::Node,
+    config: Config,
+    // The tag field of an internally tagged enum, which is hidden when the
+    // rest of the table is deserialized as the variant's payload.
+    skip: Option<&'static str>,
+    mode: PhantomData<M>
+}
+
+impl<'a, M: Nodes<'a>> ValueDeserializer<'a, M> {
+    fn new(value: M::Node, config: Config) -> ValueDeserializer<'a, M> {
+        // The null sentinel is decoded here, so that every deserialization
+        // path sees it as a plain nil.
+        let sentinel = match (value.get(), config.null_sentinel) {
+            (AnyLuaValue::LuaString(string), Some(sentinel)) => string == sentinel,
+            _ => false
+        };
+        let value = if sentinel { M::Node::nil() } else { value };
+        ValueDeserializer { value, config, skip: None, mode: PhantomData }
+    }
+
+    /// Return the entries of the value if it is a table, or give the value
+    /// back.
+    fn table(self) -> Result<Vec<Entry<M::Node>>, M::Node> {
+        let skip = self.skip;
+        self.value.into_entries().map(|entries| entries.into_iter().filter(|(key, _)| {
+            skip.is_none_or(|skip| !is_field(key.get(), skip))
+        }).collect())
+    }
+
+    /// Return the value as a string, decoding non-UTF-8 strings according to
+    /// the configured UTF-8 mode.
+    fn string<E>(self, expected: &E) -> DeResult<Cow<'a, str>>
+        where E: serde::de::Expected
+    {
+        if let AnyLuaValue::LuaNumber(number) = *self.value.get() {
+            if self.config.coercion != Coercion::Strict {
+                return Ok(Cow::Owned(format_number(number)));
+            }
+        }
+        match M::into_text(self.value) {
+            Ok(Text::Str(string)) => Ok(string),
+            Ok(Text::Bytes(bytes)) => match self.config.utf8_mode {
+                Utf8Mode::Strict => utf8(bytes),
+                Utf8Mode::Lossy => Ok(lossy(bytes))
+            },
+            Err(value) => Err(error(value.get(), expected))
+        }
+    }
+
+    /// Return the value as a number, converting strings if coercion is
+    /// enabled.
+    fn number(&self) -> Option<f64> {
+        match (self.value.get(), self.config.coercion) {
+            (&AnyLuaValue::LuaNumber(number), _) => Some(number),
+            (AnyLuaValue::LuaString(string), Coercion::Lenient { .. }) => coerce_number(string),
+            _ => None
+        }
+    }
+
+    /// Decode a byte buffer according to the configured byte encoding.
+    fn bytes<E>(self, expected: &E) -> DeResult<Cow<'a, [u8]>>
+        where E: serde::de::Expected
+    {
+        let encoding = self.config.byte_encoding;
+        let value = match (encoding, self.value.get()) {
+            (ByteEncoding::Array, AnyLuaValue::LuaArray(_)) => {
+                let config = self.config;
+                let entries = self.table().unwrap_or_default();
+                return match sequence(entries, config)? {
+                    Ok(array) => array.map(|byte| match *byte.get() {
+                        AnyLuaValue::LuaNumber(number) if number as u8 as f64 == number => {
+                            Ok(number as u8)
+                        },
+                        _ => Err(error(byte.get(), &"an integer from 0 to 255"))
+                    }).collect::<DeResult<Vec<u8>>>().map(Cow::Owned),
+                    Err(_) => Err(serde::de::Error::invalid_type(
+                        serde::de::Unexpected::Map,
+                        expected
+                    ))
+                };
+            },
+            _ => self.value
+        };
+        match M::into_text(value) {
+            Ok(Text::Bytes(bytes)) => Ok(bytes),
+            Ok(Text::Str(Cow::Borrowed(string))) => decode_bytes(string, encoding, expected),
+            Ok(Text::Str(Cow::Owned(string))) => match encoding {
+                ByteEncoding::Raw | ByteEncoding::Array => Ok(Cow::Owned(string.into_bytes())),
+                _ => decode_bytes(&string, encoding, expected)
+                    .map(|bytes| Cow::Owned(bytes.into_owned()))
+            },
+            Err(value) => Err(error(value.get(), expected))
+        }
+    }
+
+    /// Return the value as a sequence, which must be a table with keys from
+    /// 1 to N, subject to the configured sparse array policy.
+    fn sequence<E>(self, expected: &E) -> DeResult<Sequence<M::Node>>
+        where E: serde::de::Expected
+    {
+        let config = self.config;
+        match self.table() {
+            Ok(entries) => sequence(entries, config)?.map_err(|_| {
+                serde::de::Error::invalid_type(serde::de::Unexpected::Map, expected)
+            }),
+            Err(value) => Err(error(value.get(), expected))
+        }
+    }
+
+    /// Split a table holding an enum variant into the variant name and its
+    /// payload, according to the configured enum representation.
+    fn untag_variant<E>(self, expected: &E) -> DeResult<(M::Node, LuaVariantAccess<'a, M>)>
+        where E: serde::de::Expected
+    {
+        let config = self.config;
+        if let EnumRepr::Internal { tag } = config.enum_repr {
+            // A tag that is already hidden is missing.
+            let (variant, rest) = match self.skip {
+                Some(skip) if skip == tag => (None, self.value),
+                _ => self.value.take_field(tag)
+            };
+            return match variant {
+                Some(variant) => Ok((variant, LuaVariantAccess(
+                    ValueDeserializer { value: rest, config, skip: Some(tag), mode: PhantomData },
+                    None
+                ))),
+                None => Err(serde::de::Error::missing_field(tag))
+            };
+        }
+        let entries = match self.table() {
+            Ok(entries) => entries,
+            Err(value) => return Err(error(value.get(), expected))
+        };
+        match config.enum_repr {
+            EnumRepr::External => {
+                if entries.len() != 1 {
+                    return Err(serde::de::Error::invalid_length(entries.len(), expected));
+                }
+                let (key, value) = entries.into_iter().next().unwrap();
+                let segment = PathSegment::from_key(key.get());
+                Ok((key, LuaVariantAccess(ValueDeserializer::new(value, config), Some(segment))))
+            },
+            EnumRepr::Internal { .. } => unreachable!(),
+            EnumRepr::Adjacent { tag, content } => {
+                let mut variant = None;
+                let mut payload = None;
+                for (key, value) in entries {
+                    if is_field(key.get(), tag) {
+                        variant = Some(value);
+                    } else if is_field(key.get(), content) {
+                        payload = Some(value);
+                    } else {
+                        return Err(serde::de::Error::custom(format!(
+                            "unexpected field in adjacently tagged variant, \
+                             expected '{}' or '{}'",
+                            tag,
+                            content
+                        )));
+                    }
+                }
+                match variant {
+                    Some(variant) => Ok((variant, LuaVariantAccess(
+                        ValueDeserializer::new(payload.unwrap_or_else(M::Node::nil), config),
+                        Some(PathSegment::Field(content.to_owned()))
+                    ))),
+                    None => Err(serde::de::Error::missing_field(tag))
+                }
+            },
+            EnumRepr::Array => match sequence(entries, config)? {
+                Ok(mut array) => {
+                    if array.len() == 0 || array.len() > 2 {
+                        return Err(serde::de::Error::invalid_length(array.len(), expected));
+                    }
+                    let variant = array.next().unwrap();
+                    let payload = array.next().unwrap_or_else(M::Node::nil);
+                    Ok((variant, LuaVariantAccess(
+                        ValueDeserializer::new(payload, config),
+                        Some(PathSegment::Index(2))
+                    )))
+                },
+                Err(_) => Err(serde::de::Error::invalid_type(
+                    serde::de::Unexpected::Map,
+                    expected
+                ))
+            }
+        }
+    }
+}
+
+/// Decode a string that may not be valid UTF-8, failing if it isn't.
+fn utf8(bytes: Cow<'_, [u8]>) -> DeResult<Cow<'_, str>> {
+    let offset = match bytes {
+        Cow::Borrowed(bytes) => match ::std::str::from_utf8(bytes) {
+            Ok(string) => return Ok(Cow::Borrowed(string)),
+            Err(error) => error.valid_up_to()
+        },
+        Cow::Owned(bytes) => match String::from_utf8(bytes) {
+            Ok(string) => return Ok(Cow::Owned(string)),
+            Err(error) => error.utf8_error().valid_up_to()
+        }
+    };
+    Err(ErrorKind::InvalidUtf8 { offset }.into())
+}
+
+/// Decode a string that may not be valid UTF-8, replacing invalid sequences.
+fn lossy(bytes: Cow<'_, [u8]>) -> Cow<'_, str> {
+    match bytes {
+        Cow::Borrowed(bytes) => String::from_utf8_lossy(bytes),
+        Cow::Owned(bytes) => match String::from_utf8(bytes) {
+            Ok(string) => Cow::Owned(string),
+            Err(error) => Cow::Owned(String::from_utf8_lossy(error.as_bytes()).into_owned())
+        }
+    }
+}
+
+impl<'a, 'de, M> Deserializer<'de> for ValueDeserializer<'a, M>
+    where M: Mode<'a, 'de>
+{
+    type Error = LuaDeserializeError;
+
     fn deserialize_any<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match self.0 {
-            AnyLuaValue::LuaString(string) => visitor.visit_string(string),
-            AnyLuaValue::LuaAnyString(_) => Err(serde::de::Error::invalid_type(
-                serde::de::Unexpected::Other("non-utf-8 string"),
-                &visitor
-            )),
-            AnyLuaValue::LuaNumber(number) => visitor.visit_f64(number),
-            AnyLuaValue::LuaBoolean(boolean) => visitor.visit_bool(boolean),
-            AnyLuaValue::LuaArray(array) => match is_vec(array) {
-                Ok(array) => visitor.visit_seq(LuaSeqAccess(array.into_iter())),
-                Err(map) => visitor.visit_map(LuaMapAccess(map.into_iter(), None))
+        let config = self.config;
+        match *self.value.get() {
+            AnyLuaValue::LuaNumber(number) => return visit_number(number, visitor),
+            AnyLuaValue::LuaBoolean(boolean) => return visitor.visit_bool(boolean),
+            AnyLuaValue::LuaNil => return visitor.visit_unit(),
+            _ => ()
+        }
+        let value = match self.table() {
+            Ok(entries) => return match sequence(entries, config)? {
+                Ok(array) => visitor.visit_seq(LuaSeqAccess::<M>::new(array, config)),
+                Err(entries) => visitor.visit_map(LuaMapAccess::<M>::new(entries, config))
             },
-            AnyLuaValue::LuaNil => visitor.visit_unit(),
-            _=> Err(error(&self.0, &visitor))
+            Err(value) => value
+        };
+        match M::into_text(value) {
+            Ok(Text::Str(string)) => M::visit_cow_str(string, visitor),
+            Ok(Text::Bytes(bytes)) => match config.utf8_mode {
+                Utf8Mode::Strict => M::visit_cow_bytes(bytes, visitor),
+                Utf8Mode::Lossy => M::visit_cow_str(lossy(bytes), visitor)
+            },
+            Err(value) => Err(error(value.get(), &visitor))
         }
     }
 
     fn deserialize_bool<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaBoolean(boolean) => visitor.visit_bool(boolean),
-            _ => Err(error(&self.0, &visitor))
+        let truthiness = self.config.coercion == Coercion::Lenient { truthiness: true };
+        match *self.value.get() {
+            AnyLuaValue::LuaBoolean(boolean) => visitor.visit_bool(boolean),
+            AnyLuaValue::LuaNil if truthiness => visitor.visit_bool(false),
+            _ if truthiness => visitor.visit_bool(true),
+            _ => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_i8<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) if (
+        match self.number() {
+            Some(number) if (
                 number as i8 as f64 == number
             ) => visitor.visit_i8(number as i8),
-            _ => Err(error(&self.0, &visitor))
+            Some(number) => Err(lossy_number(number, "i8")),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_i16<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) if (
+        match self.number() {
+            Some(number) if (
                 number as i16 as f64 == number
             ) => visitor.visit_i16(number as i16),
-            _ => Err(error(&self.0, &visitor))
+            Some(number) => Err(lossy_number(number, "i16")),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_i32<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) if (
+        match self.number() {
+            Some(number) if (
                 number as i32 as f64 == number
             ) => visitor.visit_i32(number as i32),
-            _ => Err(error(&self.0, &visitor))
+            Some(number) => Err(lossy_number(number, "i32")),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_i64<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) if (
-                number as i64 as f64 == number
+        match self.number() {
+            Some(number) if (
+                number as i64 as f64 == number && number < i64::MAX as f64
             ) => visitor.visit_i64(number as i64),
-            _ => Err(error(&self.0, &visitor))
+            Some(number) => Err(lossy_number(number, "i64")),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_u8<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) if (
+        match self.number() {
+            Some(number) if (
                 number as u8 as f64 == number
             ) => visitor.visit_u8(number as u8),
-            _ => Err(error(&self.0, &visitor))
+            Some(number) => Err(lossy_number(number, "u8")),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_u16<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) if (
+        match self.number() {
+            Some(number) if (
                 number as u16 as f64 == number
             ) => visitor.visit_u16(number as u16),
-            _ => Err(error(&self.0, &visitor))
+            Some(number) => Err(lossy_number(number, "u16")),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_u32<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) if (
+        match self.number() {
+            Some(number) if (
                 number as u32 as f64 == number
             ) => visitor.visit_u32(number as u32),
-            _ => Err(error(&self.0, &visitor))
+            Some(number) => Err(lossy_number(number, "u32")),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_u64<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) if (
-                number as u64 as f64 == number
+        match self.number() {
+            Some(number) if (
+                number as u64 as f64 == number && number < u64::MAX as f64
             ) => visitor.visit_u64(number as u64),
-            _ => Err(error(&self.0, &visitor))
+            Some(number) => Err(lossy_number(number, "u64")),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_f32<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) => visitor.visit_f32(number as f32),
-            _ => Err(error(&self.0, &visitor))
+        match self.number() {
+            Some(number) => visitor.visit_f32(number as f32),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_f64<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNumber(number) => visitor.visit_f64(number),
-            _ => Err(error(&self.0, &visitor))
+        match self.number() {
+            Some(number) => visitor.visit_f64(number),
+            None => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_char<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaString(ref string) => {
-                let mut char_iterator = string.chars();
-                if let Some(character) = char_iterator.next() {
-                    if char_iterator.next().is_some() {
-                        Err(serde::de::Error::invalid_length(
-                            2 + char_iterator.count(),
-                            &visitor
-                        ))
-                    } else {
-                        visitor.visit_char(character)
-                    }
-                } else {
-                    Err(serde::de::Error::invalid_length(0, &visitor))
-                }
+        let string = self.string(&visitor)?;
+        let mut char_iterator = string.chars();
+        if let Some(character) = char_iterator.next() {
+            if char_iterator.next().is_some() {
+                Err(serde::de::Error::invalid_length(
+                    2 + char_iterator.count(),
+                    &visitor
+                ))
+            } else {
+                visitor.visit_char(character)
             }
-            _ => Err(error(&self.0, &visitor))
+        } else {
+            Err(serde::de::Error::invalid_length(0, &visitor))
         }
     }
 
     fn deserialize_str<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaString(ref string) => visitor.visit_str(string.as_ref()),
-            _ => Err(error(&self.0, &visitor))
-        }
+        let string = self.string(&visitor)?;
+        M::visit_cow_str(string, visitor)
     }
 
     fn deserialize_string<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match self.0 {
-            AnyLuaValue::LuaString(string) => visitor.visit_string(string),
-            _ => Err(error(&self.0, &visitor))
-        }
+        self.deserialize_str(visitor)
     }
 
-    #[cfg(not(feature = "base64-bytes"))]
     fn deserialize_bytes<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        Err(serde::de::Error::custom(
-            "cannot deserialize bytes; compile with 'base64-bytes'"
-        ))
+        let bytes = self.bytes(&visitor)?;
+        M::visit_cow_bytes(bytes, visitor)
     }
 
-    #[cfg(feature = "base64-bytes")]
-    fn deserialize_bytes<V>(self, visitor: V) -> DeResult<V::Value>
-        where V: Visitor<'de>
-    {
-        match &self.0 {
-            &AnyLuaValue::LuaString(ref string) => {
-                match base64::decode(string) {
-                    Ok(bytes) => visitor.visit_bytes(bytes.as_ref()),
-                    Err(_) => Err(serde::de::Error::invalid_value(
-                        serde::de::Unexpected::Other("non-base64 data"),
-                        &visitor
-                    ))
-                }
-            },
-            _ => Err(error(&self.0, &visitor))
-        }
-    }
-
-    #[cfg(not(feature = "base64-bytes"))]
-    fn deserialize_byte_buf<V>(self, visitor: V) -> DeResult<V::Value>
-        where V: Visitor<'de>
-    {
-        Err(serde::de::Error::custom(
-            "cannot deserialize byte_buf; compile with 'base64-bytes'"
-        ))
-    }
-
-    #[cfg(feature = "base64-bytes")]
     fn deserialize_byte_buf<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaString(ref string) => {
-                match base64::decode(string) {
-                    Ok(bytes) => visitor.visit_byte_buf(bytes),
-                    Err(_) => Err(serde::de::Error::invalid_value(
-                        serde::de::Unexpected::Other("non-base64 data"),
-                        &visitor
-                    ))
-                }
-            },
-            _ => Err(error(&self.0, &visitor))
-        }
+        self.deserialize_bytes(visitor)
     }
 
     fn deserialize_option<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match self.0 {
+        match *self.value.get() {
             AnyLuaValue::LuaNil => visitor.visit_none(),
-            _ => visitor.visit_some(LuaDeserializer(self.0))
+            _ => visitor.visit_some(self)
         }
     }
 
     fn deserialize_unit<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNil => visitor.visit_unit(),
-            _ => Err(error(&self.0, &visitor))
+        match *self.value.get() {
+            AnyLuaValue::LuaNil => visitor.visit_unit(),
+            _ => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match &self.0 {
-            &AnyLuaValue::LuaNil => visitor.visit_unit(),
-            _ => Err(error(&self.0, &visitor))
-        }
+        self.deserialize_unit(visitor)
     }
 
     fn deserialize_newtype_struct<V>(
@@ -298,38 +858,20 @@ impl<'de> Deserializer<'de> for LuaDeserializer {
     fn deserialize_seq<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match self.0 {
-            AnyLuaValue::LuaArray(array) => {
-                match is_vec(array) {
-                    Ok(array) => visitor.visit_seq(LuaSeqAccess(array.into_iter())),
-                    Err(_) => Err(serde::de::Error::invalid_type(
-                        serde::de::Unexpected::Map,
-                        &visitor
-                    ))
-                }
-            },
-            _=> Err(error(&self.0, &visitor))
-        }
+        let config = self.config;
+        let array = self.sequence(&visitor)?;
+        visitor.visit_seq(LuaSeqAccess::<M>::new(array, config))
     }
 
     fn deserialize_tuple<V>(self, len: usize, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match self.0 {
-            AnyLuaValue::LuaArray(array) => {
-                if array.len() != len {
-                    return Err(serde::de::Error::invalid_length(array.len(), &visitor));
-                }
-                match is_vec(array) {
-                    Ok(array) => visitor.visit_seq(LuaSeqAccess(array.into_iter())),
-                    Err(_) => Err(serde::de::Error::invalid_type(
-                        serde::de::Unexpected::Map,
-                        &visitor
-                    ))
-                }
-            },
-            _=> Err(error(&self.0, &visitor))
+        let config = self.config;
+        let array = self.sequence(&visitor)?;
+        if array.len() != len {
+            return Err(serde::de::Error::invalid_length(array.len(), &visitor));
         }
+        visitor.visit_seq(LuaSeqAccess::<M>::new(array, config))
     }
 
     fn deserialize_tuple_struct<V>(
@@ -346,11 +888,10 @@ impl<'de> Deserializer<'de> for LuaDeserializer {
     fn deserialize_map<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match self.0 {
-            AnyLuaValue::LuaArray(array) => {
-                visitor.visit_map(LuaMapAccess(array.into_iter(), None))
-            },
-            _=> Err(error(&self.0, &visitor))
+        let config = self.config;
+        match self.table() {
+            Ok(entries) => visitor.visit_map(LuaMapAccess::<M>::new(entries, config)),
+            Err(value) => Err(error(value.get(), &visitor))
         }
     }
 
@@ -373,28 +914,23 @@ impl<'de> Deserializer<'de> for LuaDeserializer {
     ) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        match self.0 {
-            AnyLuaValue::LuaString(identifier) => {
-                visitor.visit_enum(LuaEnumAccess(
-                    AnyLuaValue::LuaString(identifier),
-                    AnyLuaValue::LuaNil
-                ))
+        match *self.value.get() {
+            AnyLuaValue::LuaString(_) => {
+                let payload = ValueDeserializer::<M>::new(M::Node::nil(), self.config);
+                visitor.visit_enum(LuaEnumAccess(self.value, LuaVariantAccess(payload, None)))
             },
-            AnyLuaValue::LuaArray(array) => {
-                if array.len() != 1 {
-                    return Err(serde::de::Error::invalid_length(array.len(), &visitor));
-                }
-                let (key, value) = array.into_iter().next().unwrap();
-                visitor.visit_enum(LuaEnumAccess(key, value))
+            AnyLuaValue::LuaArray(_) => {
+                let (variant, payload) = self.untag_variant(&visitor)?;
+                visitor.visit_enum(LuaEnumAccess(variant, payload))
             },
-            _=> Err(error(&self.0, &visitor))
+            _ => Err(error(self.value.get(), &visitor))
         }
     }
 
     fn deserialize_identifier<V>(self, visitor: V) -> DeResult<V::Value>
         where V: Visitor<'de>
     {
-        self.deserialize_string(visitor)
+        self.deserialize_str(visitor)
     }
 
     fn deserialize_ignored_any<V>(self, visitor: V) -> DeResult<V::Value>
@@ -404,53 +940,187 @@ impl<'de> Deserializer<'de> for LuaDeserializer {
     }
 }
 
-/// Return `Ok(sorted)` if the input array is an actual array (keys from
-/// 1..N) and `Err(original array)` otherwise.
-fn is_vec(array: Vec<(AnyLuaValue, AnyLuaValue)>) -> Result<
-    Vec<(AnyLuaValue, AnyLuaValue)>,
-    Vec<(AnyLuaValue, AnyLuaValue)>
-> {
-    if array.iter().any(|&(ref key, _)| match key {
-        &AnyLuaValue::LuaNumber(_) => false,
-        _ => true
-    }) {
-        return Err(array);
-    }
-
-    let mut sorted = array.clone();
-    sorted.sort_by_key(|&(ref index, _)| match index {
-        &AnyLuaValue::LuaNumber(number) => number as usize,
-        _ => unreachable!()
-    });
-
-    let mut is_array = true;
-    for (index, &(ref key, _)) in array.iter().enumerate() {
-        if !match key {
-            &AnyLuaValue::LuaNumber(number) if (
-                number as usize as f64 == number &&
-                number == (index + 1) as f64
-            ) => true,
-            _ => false
-        } {
-            is_array = false;
-            break;
+/// Read a table as a sequence according to the sparse array policy, or give
+/// its entries back if it isn't one.
+fn sequence<N: Node>(
+    entries: Vec<Entry<N>>,
+    config: Config
+) -> DeResult<Result<Sequence<N>, Vec<Entry<N>>>> {
+    let policy = config.sparse_arrays;
+    let mut indices = Vec::with_capacity(entries.len());
+    let mut declared = None;
+    for (key, value) in &entries {
+        match (key.get(), policy) {
+            (&AnyLuaValue::LuaNumber(number), _) => match index(number) {
+                Some(index) if index >= 1 => indices.push(index),
+                _ => return Ok(Err(entries))
+            },
+            (AnyLuaValue::LuaString(key), SparseArrays::Packed) if key == "n" => {
+                match *value.get() {
+                    AnyLuaValue::LuaNumber(number) => match index(number) {
+                        Some(index) => declared = Some(index),
+                        None => return Ok(Err(entries))
+                    },
+                    _ => return Ok(Err(entries))
+                }
+            },
+            _ => return Ok(Err(entries))
         }
     }
 
-    if is_array {
-        Ok(sorted)
+    indices.sort_unstable();
+    if indices.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Ok(Err(entries));
+    }
+
+    let max = indices.last().map_or(0, |&index| index);
+    let len = match sequence_len(policy, max, indices.len(), declared) {
+        Some(len) => sparse_len(len, indices.len(), config.limits)?,
+        None => return Ok(Err(entries))
+    };
+    let mut values: Vec<_> = entries.into_iter().filter_map(|(key, value)| match *key.get() {
+        AnyLuaValue::LuaNumber(number) => index(number).map(|index| (index, value)),
+        _ => None
+    }).collect();
+    values.sort_by_key(|&(index, _)| index);
+    Ok(Ok(Sequence { values: values.into_iter().peekable(), next: 1, len }))
+}
+
+/// Return the length of a table read as a sequence according to the sparse
+/// array policy, given its largest index, the number of its indices and its
+/// `n` field, or `None` if it isn't one. The table must have no other keys.
+pub(crate) fn sequence_len(
+    policy: SparseArrays,
+    max: usize,
+    count: usize,
+    declared: Option<usize>
+) -> Option<usize> {
+    match (policy, declared) {
+        (SparseArrays::Packed, Some(declared)) if max <= declared => Some(declared),
+        (SparseArrays::Packed, Some(_)) => None,
+        (SparseArrays::Fill, _) => Some(max),
+        _ if max == count => Some(max),
+        _ => None
+    }
+}
+
+/// Check the length of a sequence, which under the `Fill` and `Packed`
+/// policies comes from its largest index or its `n` field, against the
+/// number of values it actually holds.
+///
+/// Every missing value is yielded as nil, so a single entry such as
+/// `[2^40] = 1` could make a sequence of any length. Unless the limits
+/// bound the entries or values, which count the holes, a sequence can have
+/// at most `MAX_HOLES` of them.
+pub(crate) fn sparse_len(len: usize, count: usize, limits: Limits) -> DeResult<usize> {
+    if limits.max_entries.is_none() && limits.max_nodes.is_none() && len - count > MAX_HOLES {
+        return Err(serde::de::Error::invalid_length(
+            len,
+            &format!("a sparse array of at most {} elements", count + MAX_HOLES).as_str()
+        ));
+    }
+    Ok(len)
+}
+
+/// Decode a string holding a byte buffer according to a byte encoding.
+pub(crate) fn decode_bytes<'s, E>(
+    string: &'s str,
+    encoding: ByteEncoding,
+    expected: &E
+) -> DeResult<Cow<'s, [u8]>>
+    where E: serde::de::Expected
+{
+    let invalid = |what| serde::de::Error::invalid_value(
+        serde::de::Unexpected::Other(what),
+        expected
+    );
+    match encoding {
+        ByteEncoding::Raw | ByteEncoding::Array => Ok(Cow::Borrowed(string.as_bytes())),
+        #[cfg(feature = "base64-bytes")]
+        ByteEncoding::Base64 => {
+            base64::decode(string).map(Cow::Owned).map_err(|_| invalid("non-base64 data"))
+        },
+        #[cfg(feature = "base64-bytes")]
+        ByteEncoding::Base64UrlSafe => {
+            base64::decode_config(string, base64::URL_SAFE)
+                .map(Cow::Owned)
+                .map_err(|_| invalid("non-base64 data"))
+        },
+        ByteEncoding::Hex => {
+            if !string.len().is_multiple_of(2) {
+                return Err(invalid("odd-length hex data"));
+            }
+            (0..string.len()).step_by(2).map(|index| {
+                string.get(index..index + 2)
+                    .filter(|digits| digits.bytes().all(|digit| digit.is_ascii_hexdigit()))
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    .ok_or_else(|| invalid("non-hex data"))
+            }).collect::<DeResult<Vec<u8>>>().map(Cow::Owned)
+        }
+    }
+}
+
+/// Convert a number to a table index, if it is a non-negative integer.
+pub(crate) fn index(number: f64) -> Option<usize> {
+    if number >= 0.0 && number < usize::MAX as f64 && number.fract() == 0.0 {
+        Some(number as usize)
     } else {
-        Err(array)
+        None
     }
 }
 
-/// Sequential access over a `LuaArray`.
-// The vector used to create this must be a table with keys from 1 to N, and
-// must be sorted by key. The iterator given is the remaining key-values in
-// the array to be yielded, where the keys are ignored.
-pub struct LuaSeqAccess(IntoIter<(AnyLuaValue, AnyLuaValue)>);
+/// The values of a table read as a sequence, in order, with any holes
+/// yielded as nil.
+struct Sequence<N> {
+    values: Peekable<IntoIter<(usize, N)>>,
+    next: usize,
+    len: usize
+}
+
+impl<N: Node> Iterator for Sequence<N> {
+    type Item = N;
 
-impl<'de> serde::de::SeqAccess<'de> for LuaSeqAccess {
+    fn next(&mut self) -> Option<N> {
+        if self.next > self.len {
+            return None;
+        }
+        let index = self.next;
+        self.next += 1;
+        match self.values.peek() {
+            Some(&(next, _)) if next == index => self.values.next().map(|(_, value)| value),
+            _ => Some(N::nil())
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len + 1 - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<N: Node> ExactSizeIterator for Sequence<N> {
+}
+
+/// Return whether a table key is the string `field`.
+fn is_field(key: &AnyLuaValue, field: &str) -> bool {
+    match key {
+        AnyLuaValue::LuaString(key) => key == field,
+        _ => false
+    }
+}
+
+/// Sequential access over the values of a table read as a sequence.
+struct LuaSeqAccess<'a, M: Nodes<'a>>(Sequence<M::Node>, Config, PhantomData<M>);
+
+impl<'a, M: Nodes<'a>> LuaSeqAccess<'a, M> {
+    fn new(array: Sequence<M::Node>, config: Config) -> LuaSeqAccess<'a, M> {
+        LuaSeqAccess(array, config, PhantomData)
+    }
+}
+
+impl<'a, 'de, M> serde::de::SeqAccess<'de> for LuaSeqAccess<'a, M>
+    where M: Mode<'a, 'de>
+{
     type Error = LuaDeserializeError;
 
     fn next_element_seed<T>(
@@ -459,8 +1129,12 @@ impl<'de> serde::de::SeqAccess<'de> for LuaSeqAccess {
     ) -> DeResult<Option<T::Value>>
         where T: serde::de::DeserializeSeed<'de>
     {
+        let index = self.0.next;
         Ok(match self.0.next() {
-            Some((_, value)) => Some(seed.deserialize(LuaDeserializer(value))?),
+            Some(value) => Some(
+                seed.deserialize(ValueDeserializer::<M>::new(value, self.1))
+                    .map_err(|error| error.at(PathSegment::Index(index)))?
+            ),
             None => None
         })
     }
@@ -470,13 +1144,26 @@ impl<'de> serde::de::SeqAccess<'de> for LuaSeqAccess {
     }
 }
 
-/// Map access over a `LuaArray`.
+/// Map access over the entries of a table.
 // The first element is the remaining key-value pairs of the map to yield,
-// and the second element is the value in the case where a key has been
+// and the second element is the entry in the case where a key has been
 // yielded but not its value.
-pub struct LuaMapAccess(IntoIter<(AnyLuaValue, AnyLuaValue)>, Option<AnyLuaValue>);
+struct LuaMapAccess<'a, M: Nodes<'a>>(
+    IntoIter<Entry<M::Node>>,
+    Option<Entry<M::Node>>,
+    Config,
+    PhantomData<M>
+);
+
+impl<'a, M: Nodes<'a>> LuaMapAccess<'a, M> {
+    fn new(entries: Vec<Entry<M::Node>>, config: Config) -> LuaMapAccess<'a, M> {
+        LuaMapAccess(entries.into_iter(), None, config, PhantomData)
+    }
+}
 
-impl<'de> serde::de::MapAccess<'de> for LuaMapAccess {
+impl<'a, 'de, M> serde::de::MapAccess<'de> for LuaMapAccess<'a, M>
+    where M: Mode<'a, 'de>
+{
     type Error = LuaDeserializeError;
 
     fn next_key_seed<K>(
@@ -486,9 +1173,10 @@ impl<'de> serde::de::MapAccess<'de> for LuaMapAccess {
         where K: serde::de::DeserializeSeed<'de>
     {
         Ok(match self.0.next() {
-            Some((key, value)) => {
-                self.1 = Some(value);
-                Some(seed.deserialize(LuaDeserializer(key))?)
+            Some(entry) => {
+                let key = M::deserialize_key(&entry.0, seed, self.2)?;
+                self.1 = Some(entry);
+                Some(key)
             },
             None => None
         })
@@ -500,7 +1188,9 @@ impl<'de> serde::de::MapAccess<'de> for LuaMapAccess {
     ) -> DeResult<V::Value>
         where V: serde::de::DeserializeSeed<'de>
     {
-        seed.deserialize(LuaDeserializer(self.1.take().unwrap()))
+        let (key, value) = self.1.take().unwrap();
+        seed.deserialize(ValueDeserializer::<M>::new(value, self.2))
+            .map_err(|error| error.at(PathSegment::from_key(key.get())))
     }
 
     fn next_entry_seed<K, V>(
@@ -514,8 +1204,9 @@ impl<'de> serde::de::MapAccess<'de> for LuaMapAccess {
         Ok(match self.0.next() {
             Some((key, value)) => {
                 Some((
-                    kseed.deserialize(LuaDeserializer(key))?,
-                    vseed.deserialize(LuaDeserializer(value))?
+                    M::deserialize_key(&key, kseed, self.2)?,
+                    vseed.deserialize(ValueDeserializer::<M>::new(value, self.2))
+                        .map_err(|error| error.at(PathSegment::from_key(key.get())))?
                 ))
             },
             None => None
@@ -527,12 +1218,14 @@ impl<'de> serde::de::MapAccess<'de> for LuaMapAccess {
     }
 }
 
-/// Variant access over a `LuaArray` of one item.
-pub struct LuaEnumAccess(AnyLuaValue, AnyLuaValue);
+/// Enum access over a variant name and its payload.
+struct LuaEnumAccess<'a, M: Nodes<'a>>(M::Node, LuaVariantAccess<'a, M>);
 
-impl<'de> serde::de::EnumAccess<'de> for LuaEnumAccess {
+impl<'a, 'de, M> serde::de::EnumAccess<'de> for LuaEnumAccess<'a, M>
+    where M: Mode<'a, 'de>
+{
     type Error = LuaDeserializeError;
-    type Variant = LuaVariantAccess;
+    type Variant = LuaVariantAccess<'a, M>;
 
     fn variant_seed<V>(
         self,
@@ -540,84 +1233,469 @@ impl<'de> serde::de::EnumAccess<'de> for LuaEnumAccess {
     ) -> DeResult<(V::Value, Self::Variant)>
         where V: serde::de::DeserializeSeed<'de>
     {
-        Ok((seed.deserialize(LuaDeserializer(self.0))?, LuaVariantAccess(self.1)))
+        let variant = ValueDeserializer::<M>::new(self.0, self.1 .0.config);
+        Ok((seed.deserialize(variant)?, self.1))
+    }
+}
+
+/// Variant access over the payload of a variant, and where the payload is
+/// found relative to the enum.
+struct LuaVariantAccess<'a, M: Nodes<'a>>(ValueDeserializer<'a, M>, Option<PathSegment>);
+
+impl<'a, M: Nodes<'a>> LuaVariantAccess<'a, M> {
+    /// Attribute an error in the payload to the payload's location.
+    fn locate(segment: Option<PathSegment>, error: LuaDeserializeError) -> LuaDeserializeError {
+        match segment {
+            Some(segment) => error.at(segment),
+            None => error
+        }
     }
 }
 
-/// Variant access over a `LuaArray` of one item.
-pub struct LuaVariantAccess(AnyLuaValue);
+impl<'a, 'de, M> serde::de::VariantAccess<'de> for LuaVariantAccess<'a, M>
+    where M: Mode<'a, 'de>
+{
+    type Error = LuaDeserializeError;
+
+    fn unit_variant(self) -> DeResult<()> {
+        match (self.0.value.get(), self.0.skip) {
+            (AnyLuaValue::LuaNil, _) => Ok(()),
+            // Internally tagged unit variants leave behind an empty table
+            // once their tag is hidden.
+            (AnyLuaValue::LuaArray(entries), Some(skip))
+                if entries.iter().all(|(key, _)| is_field(key, skip)) => Ok(()),
+            (value, _) => Err(Self::locate(self.1, error(value, &"unit variant")))
+        }
+    }
+
+    fn newtype_variant_seed<T>(
+        self,
+        seed: T
+    ) -> DeResult<T::Value>
+        where T: serde::de::DeserializeSeed<'de>
+    {
+        let segment = self.1;
+        seed.deserialize(self.0).map_err(|error| Self::locate(segment, error))
+    }
+
+    fn tuple_variant<V>(
+        self,
+        len: usize,
+        visitor: V
+    ) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        let segment = self.1;
+        self.0.deserialize_tuple(len, visitor).map_err(|error| Self::locate(segment, error))
+    }
+
+    fn struct_variant<V>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V
+    ) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        let segment = self.1;
+        self.0.deserialize_struct("", fields, visitor)
+            .map_err(|error| Self::locate(segment, error))
+    }
+}
+
+fn error<E>(value: &AnyLuaValue, expected: &E) -> LuaDeserializeError
+    where E: serde::de::Expected
+{
+    serde::de::Error::invalid_type(
+        match value {
+            AnyLuaValue::LuaString(string) => serde::de::Unexpected::Str(
+                string.as_ref()
+            ),
+            AnyLuaValue::LuaAnyString(bytes) => serde::de::Unexpected::Bytes(
+                bytes.0.as_ref()
+            ),
+            AnyLuaValue::LuaNumber(number) => serde::de::Unexpected::Float(*number),
+            AnyLuaValue::LuaBoolean(boolean) => serde::de::Unexpected::Bool(*boolean),
+            AnyLuaValue::LuaArray(_) => serde::de::Unexpected::Map,
+            AnyLuaValue::LuaNil => serde::de::Unexpected::Unit,
+            AnyLuaValue::LuaOther => serde::de::Unexpected::Other("unserializable")
+        },
+        expected
+    )
+}
+
+/// Visit a number as an integer if it is one that an `i64` or a `u64` can
+/// hold, so that serde's buffered content, used by untagged, internally
+/// tagged and flattened types, keeps integers as integers.
+pub(crate) fn visit_number<'de, V>(number: f64, visitor: V) -> DeResult<V::Value>
+    where V: Visitor<'de>
+{
+    if number.fract() != 0.0 || (number == 0.0 && number.is_sign_negative()) {
+        visitor.visit_f64(number)
+    } else if number < 0.0 && number >= i64::MIN as f64 {
+        visitor.visit_i64(number as i64)
+    } else if number >= 0.0 && number < u64::MAX as f64 {
+        visitor.visit_u64(number as u64)
+    } else {
+        visitor.visit_f64(number)
+    }
+}
+
+/// Check lua data against the configured limits, so that none of it is
+/// deserialized if it exceeds one.
+fn check_limits(value: &AnyLuaValue, config: Config) -> DeResult<()> {
+    if config.limits == Limits::new() {
+        return Ok(());
+    }
+    Usage::new(config).value(value, 0)
+}
+
+/// How much of each limit the lua data checked so far uses.
+pub(crate) struct Usage {
+    limits: Limits,
+    sparse_arrays: SparseArrays,
+    nodes: usize,
+    string_bytes: usize
+}
+
+impl Usage {
+    pub(crate) fn new(config: Config) -> Usage {
+        Usage {
+            limits: config.limits,
+            sparse_arrays: config.sparse_arrays,
+            nodes: 0,
+            string_bytes: 0
+        }
+    }
+
+    /// Count one more value.
+    pub(crate) fn node(&mut self) -> DeResult<()> {
+        self.nodes += 1;
+        within(Limit::Nodes, self.nodes, self.limits.max_nodes)
+    }
+
+    /// Count one more string of `len` bytes.
+    pub(crate) fn string(&mut self, len: usize) -> DeResult<()> {
+        self.string_bytes = self.string_bytes.saturating_add(len);
+        within(Limit::StringBytes, self.string_bytes, self.limits.max_string_bytes)
+    }
+
+    /// Check a table at the given depth.
+    pub(crate) fn depth(&self, depth: usize) -> DeResult<()> {
+        within(Limit::Depth, depth, self.limits.max_depth)
+    }
+
+    /// Check the number of entries of a table, which may be counted one at a
+    /// time.
+    pub(crate) fn entries(&self, entries: usize) -> DeResult<()> {
+        within(Limit::Entries, entries, self.limits.max_entries)
+    }
+
+    /// Check the nils yielded for the holes of a table that is read as a
+    /// sparse sequence, given its largest index, the number of its indices
+    /// and its `n` field. The table must have no other keys.
+    ///
+    /// Each hole counts as an entry and a value, as if the table held it.
+    pub(crate) fn holes(
+        &mut self,
+        max: usize,
+        count: usize,
+        declared: Option<usize>
+    ) -> DeResult<()> {
+        match sequence_len(self.sparse_arrays, max, count, declared) {
+            Some(len) if len > count => {
+                self.entries(len)?;
+                self.nodes = self.nodes.saturating_add(len - count);
+                within(Limit::Nodes, self.nodes, self.limits.max_nodes)
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Check a value inside `depth` tables, and everything in it.
+    fn value(&mut self, value: &AnyLuaValue, depth: usize) -> DeResult<()> {
+        self.node()?;
+        match *value {
+            AnyLuaValue::LuaString(ref string) => self.string(string.len()),
+            AnyLuaValue::LuaAnyString(ref bytes) => self.string(bytes.0.len()),
+            AnyLuaValue::LuaArray(ref entries) => {
+                self.depth(depth + 1)?;
+                self.entries(entries.len())?;
+                let (mut max, mut count, mut declared, mut sequence) = (0, 0, None, true);
+                for (key, value) in entries {
+                    self.value(key, depth + 1)
+                        .and_then(|()| self.value(value, depth + 1))
+                        .map_err(|error| error.at(PathSegment::from_key(key)))?;
+                    match (key, value) {
+                        (&AnyLuaValue::LuaNumber(number), _) => match index(number) {
+                            Some(index) if index >= 1 => {
+                                count += 1;
+                                max = max.max(index);
+                            },
+                            _ => sequence = false
+                        },
+                        (AnyLuaValue::LuaString(key), &AnyLuaValue::LuaNumber(number))
+                            if key == "n" && self.sparse_arrays == SparseArrays::Packed => {
+                            declared = index(number);
+                            sequence &= declared.is_some();
+                        },
+                        _ => sequence = false
+                    }
+                }
+                if sequence {
+                    self.holes(max, count, declared)?;
+                }
+                Ok(())
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+fn within(limit: Limit, used: usize, max: Option<usize>) -> DeResult<()> {
+    match max {
+        Some(max) if used > max => Err(ErrorKind::LimitExceeded { limit, max }.into()),
+        _ => Ok(())
+    }
+}
+
+/// Format a number as lua's `tostring` does, with up to 14 significant
+/// digits.
+pub(crate) fn format_number(number: f64) -> String {
+    if number.is_nan() {
+        return if number.is_sign_negative() { "-nan" } else { "nan" }.to_owned();
+    } else if number.is_infinite() {
+        return if number < 0.0 { "-inf" } else { "inf" }.to_owned();
+    } else if number == 0.0 {
+        return if number.is_sign_negative() { "-0" } else { "0" }.to_owned();
+    }
+    // Like C's `%.14g`: round to 14 significant digits, then choose between
+    // fixed and exponent notation from the exponent of the rounded number.
+    let scientific = format!("{:.13e}", number);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    let trim = |digits: &str| -> String {
+        if digits.contains('.') {
+            digits.trim_end_matches('0').trim_end_matches('.').to_owned()
+        } else {
+            digits.to_owned()
+        }
+    };
+    if (-4..14).contains(&exponent) {
+        trim(&format!("{:.*}", (13 - exponent) as usize, number))
+    } else {
+        format!(
+            "{}e{}{:02}",
+            trim(mantissa),
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    }
+}
+
+/// Parse the digits of a hexadecimal number, which may have a fraction and
+/// a binary exponent.
+pub(crate) fn hex_number(text: &str) -> Option<f64> {
+    let (mantissa, exponent) = match text.find(['p', 'P']) {
+        Some(split) => (&text[..split], Some(&text[split + 1..])),
+        None => (text, None)
+    };
+    let mut value = 0.0;
+    let mut scale = 0;
+    let mut point = false;
+    let mut digits = 0;
+    for character in mantissa.chars() {
+        if character == '.' && !point {
+            point = true;
+            continue;
+        }
+        value = value * 16.0 + character.to_digit(16)? as f64;
+        digits += 1;
+        if point {
+            scale -= 4;
+        }
+    }
+    if digits == 0 {
+        return None;
+    }
+    if let Some(exponent) = exponent {
+        scale += exponent.parse::<i32>().ok()?;
+    }
+    Some(value * 2f64.powi(scale))
+}
+
+/// Convert a string to a number the way lua does for arithmetic and
+/// `tonumber`: surrounding whitespace and a sign are allowed, as are
+/// hexadecimal numbers, but `inf` and `nan` are not.
+pub(crate) fn coerce_number(text: &str) -> Option<f64> {
+    let text = text.trim_matches(|c: char| c.is_ascii_whitespace() || c == '\x0b');
+    let (negative, digits) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text)
+    };
+    let number = if digits.starts_with("0x") || digits.starts_with("0X") {
+        hex_number(&digits[2..])?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') && digits.bytes().all(
+        |byte| byte.is_ascii_digit() || matches!(byte, b'.' | b'e' | b'E' | b'+' | b'-')
+    ) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -number } else { number })
+}
+
+/// Report a number that the target integer type can't hold exactly.
+pub(crate) fn lossy_number(number: f64, target: &'static str) -> LuaDeserializeError {
+    ErrorKind::LossyNumber { value: number.to_string(), target }.into()
+}
+
+/// A result returned by lua deserialization.
+pub type DeResult<T> = Result<T, LuaDeserializeError>;
+
+/// One step of a `Path`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    /// A struct field, a map entry with a string key, or the name of an
+    /// externally tagged variant.
+    Field(String),
+    /// A position in a sequence, or a map entry with a non-negative integer
+    /// key. Like lua's, indices start at 1.
+    Index(usize),
+    /// A map entry with any other key, written as a lua expression.
+    Key(String)
+}
+
+impl PathSegment {
+    /// Return the segment leading to the table entry with the given key.
+    pub(crate) fn from_key(key: &AnyLuaValue) -> PathSegment {
+        match *key {
+            AnyLuaValue::LuaString(ref key) => PathSegment::Field(key.clone()),
+            AnyLuaValue::LuaNumber(number) => match index(number) {
+                Some(index) => PathSegment::Index(index),
+                None => PathSegment::Key(number.to_string())
+            },
+            AnyLuaValue::LuaBoolean(boolean) => PathSegment::Key(boolean.to_string()),
+            AnyLuaValue::LuaAnyString(ref bytes) => PathSegment::Key(format!(
+                "{:?}",
+                String::from_utf8_lossy(&bytes.0)
+            )),
+            _ => PathSegment::Key("?".to_owned())
+        }
+    }
+}
+
+/// The location of a value within the lua data being deserialized, such as
+/// `data.contents.vector[2]`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Path(Vec<PathSegment>);
+
+impl Path {
+    /// The steps leading from the root of the lua data to the value.
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.0
+    }
+
+    /// Whether the path refers to the root of the lua data.
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (position, segment) in self.0.iter().enumerate() {
+            match *segment {
+                PathSegment::Field(ref field) if is_identifier(field) => {
+                    if position > 0 {
+                        f.write_str(".")?;
+                    }
+                    f.write_str(field)?;
+                },
+                PathSegment::Field(ref field) => write!(f, "[{:?}]", field)?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+                PathSegment::Key(ref key) => write!(f, "[{}]", key)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Return whether a string can be used as a field name in lua without
+/// brackets.
+pub(crate) fn is_identifier(string: &str) -> bool {
+    const KEYWORDS: &[&str] = &[
+        "and", "break", "do", "else", "elseif", "end", "false", "for",
+        "function", "goto", "if", "in", "local", "nil", "not", "or", "repeat",
+        "return", "then", "true", "until", "while"
+    ];
+    let mut chars = string.chars();
+    match chars.next() {
+        Some(first) if first == '_' || first.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric()) &&
+                !KEYWORDS.contains(&string)
+        },
+        _ => false
+    }
+}
+
+/// An error returned by lua deserialization.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaDeserializeError {
+    kind: ErrorKind,
+    path: Path,
+    position: Option<Position>
+}
 
-impl<'de> serde::de::VariantAccess<'de> for LuaVariantAccess {
-    type Error = LuaDeserializeError;
+impl LuaDeserializeError {
+    /// What went wrong.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
 
-    fn unit_variant(self) -> DeResult<()> {
-        match &self.0 {
-            &AnyLuaValue::LuaNil => Ok(()),
-            _ => Err(error(&self.0, &"unit variant"))
-        }
+    /// Where in the lua data the error occurred.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
-    fn newtype_variant_seed<T>(
-        self,
-        seed: T
-    ) -> DeResult<T::Value>
-        where T: serde::de::DeserializeSeed<'de>
-    {
-        seed.deserialize(LuaDeserializer(self.0))
+    /// Where in the lua source text the error occurred, for errors from
+    /// `LuaSourceDeserializer`.
+    ///
+    /// For syntax errors, this is the position of the offending text. For
+    /// other errors, it's the position of the value at the error's path.
+    pub fn position(&self) -> Option<Position> {
+        self.position
     }
 
-    fn tuple_variant<V>(
-        self,
-        len: usize,
-        visitor: V
-    ) -> DeResult<V::Value>
-        where V: Visitor<'de>
-    {
-        LuaDeserializer(self.0).deserialize_tuple(len, visitor)
+    /// Prefix the error's path with the given segment, as the error is
+    /// passed up to the enclosing value.
+    pub(crate) fn at(mut self, segment: PathSegment) -> LuaDeserializeError {
+        self.path.0.insert(0, segment);
+        self
     }
 
-    fn struct_variant<V>(
-        self,
-        fields: &'static [&'static str],
-        visitor: V
-    ) -> DeResult<V::Value>
-        where V: Visitor<'de>
-    {
-        LuaDeserializer(self.0).deserialize_struct("", fields, visitor)
+    /// Set where in the lua source text the error occurred.
+    pub(crate) fn with_position(mut self, position: Position) -> LuaDeserializeError {
+        self.position = Some(position);
+        self
     }
 }
 
-fn error<E>(value: &AnyLuaValue, expected: &E) -> LuaDeserializeError
-    where E: serde::de::Expected
-{
-    serde::de::Error::invalid_type(
-        match value {
-            &AnyLuaValue::LuaString(ref string) => serde::de::Unexpected::Str(
-                string.as_ref()
-            ),
-            &AnyLuaValue::LuaAnyString(ref bytes) => serde::de::Unexpected::Bytes(
-                bytes.0.as_ref()
-            ),
-            &AnyLuaValue::LuaNumber(number) => serde::de::Unexpected::Float(number),
-            &AnyLuaValue::LuaBoolean(boolean) => serde::de::Unexpected::Bool(boolean),
-            &AnyLuaValue::LuaArray(_) => serde::de::Unexpected::Map,
-            &AnyLuaValue::LuaNil => serde::de::Unexpected::Unit,
-            &AnyLuaValue::LuaOther => serde::de::Unexpected::Other("unserializable")
-        },
-        expected
-    )
+impl From<ErrorKind> for LuaDeserializeError {
+    fn from(kind: ErrorKind) -> LuaDeserializeError {
+        LuaDeserializeError { kind, path: Path::default(), position: None }
+    }
 }
 
-/// A result returned by lua deserialization.
-pub type DeResult<T> = Result<T, LuaDeserializeError>;
-
-/// An error returned by lua deserialization.
-#[derive(Debug, Clone)]
-pub struct LuaDeserializeError(String);
-
 impl fmt::Display for LuaDeserializeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        fmt::Display::fmt(&self.kind, f)?;
+        match (self.path.is_root(), self.position) {
+            (true, None) => Ok(()),
+            (true, Some(position)) => write!(f, " at {}", position),
+            (false, None) => write!(f, " at {}", self.path),
+            (false, Some(position)) => write!(f, " at {}, {}", self.path, position)
+        }
     }
 }
 
@@ -628,7 +1706,43 @@ impl serde::de::Error for LuaDeserializeError {
     fn custom<T>(msg: T) -> Self
         where T: fmt::Display
     {
-        LuaDeserializeError(format!("{}", msg))
+        ErrorKind::Custom(format!("{}", msg)).into()
+    }
+
+    fn invalid_type(
+        unexpected: serde::de::Unexpected,
+        expected: &dyn serde::de::Expected
+    ) -> Self {
+        ErrorKind::InvalidType {
+            expected: expected.to_string(),
+            got: unexpected.to_string()
+        }.into()
+    }
+
+    fn invalid_value(
+        unexpected: serde::de::Unexpected,
+        expected: &dyn serde::de::Expected
+    ) -> Self {
+        ErrorKind::InvalidValue {
+            expected: expected.to_string(),
+            got: unexpected.to_string()
+        }.into()
+    }
+
+    fn invalid_length(len: usize, expected: &dyn serde::de::Expected) -> Self {
+        ErrorKind::InvalidLength { expected: expected.to_string(), len }.into()
+    }
+
+    fn unknown_variant(variant: &str, expected: &'static [&'static str]) -> Self {
+        ErrorKind::UnknownVariant { variant: variant.to_owned(), expected }.into()
+    }
+
+    fn unknown_field(field: &str, expected: &'static [&'static str]) -> Self {
+        ErrorKind::UnknownField { field: field.to_owned(), expected }.into()
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        ErrorKind::MissingField(field).into()
     }
 }
 
@@ -639,7 +1753,8 @@ mod tests {
 
     use std::collections::{BTreeMap, BTreeSet};
 
-    use ::from_lua;
+    use ::{from_lua, from_lua_ref, from_lua_ref_with_config, from_lua_with_config, Config};
+    use config::EnumRepr;
 
     fn procure(value: &str) -> hlua::AnyLuaValue {
         let mut lua = hlua::Lua::new();
@@ -679,6 +1794,78 @@ mod tests {
         assert!(from_lua::<String>(procure("false")).is_err());
     }
 
+    #[test]
+    fn bytes() {
+        use serde_bytes::ByteBuf;
+        use config::ByteEncoding;
+
+        let decode = |value: &str, encoding| from_lua_with_config::<ByteBuf>(
+            procure(value),
+            Config::new().byte_encoding(encoding)
+        ).map(|bytes| bytes.into_vec());
+
+        assert_eq!(vec![0, 171, 255], decode("'00abFF'", ByteEncoding::Hex).unwrap());
+        assert!(decode("'0ab'", ByteEncoding::Hex).is_err());
+        assert!(decode("'+f'", ByteEncoding::Hex).is_err());
+        assert_eq!(vec![104, 105], decode("'hi'", ByteEncoding::Raw).unwrap());
+        assert_eq!(vec![1, 255], decode("{ 1, 255 }", ByteEncoding::Array).unwrap());
+        assert!(decode("{ 1, 256 }", ByteEncoding::Array).is_err());
+        assert!(decode("{ 1.5 }", ByteEncoding::Array).is_err());
+
+        // Non-UTF-8 strings are raw bytes under every encoding.
+        for &encoding in &[ByteEncoding::Raw, ByteEncoding::Hex, ByteEncoding::Array] {
+            assert_eq!(vec![0, 200, 255], decode(r"'\0\200\255'", encoding).unwrap());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "base64-bytes")]
+    fn base64_bytes() {
+        use serde_bytes::ByteBuf;
+        use config::ByteEncoding;
+
+        let decode = |value: &str, encoding| from_lua_with_config::<ByteBuf>(
+            procure(value),
+            Config::new().byte_encoding(encoding)
+        ).map(|bytes| bytes.into_vec());
+
+        assert_eq!(vec![1, 2, 3, 4], decode("'AQIDBA=='", ByteEncoding::Base64).unwrap());
+        assert_eq!(vec![251, 255], decode("'-_8='", ByteEncoding::Base64UrlSafe).unwrap());
+        assert!(decode("'-_8='", ByteEncoding::Base64).is_err());
+        assert_eq!(vec![0, 200, 255], decode(r"'\0\200\255'", ByteEncoding::Base64).unwrap());
+    }
+
+    #[test]
+    fn non_utf8_strings() {
+        use serde_bytes::ByteBuf;
+        use config::Utf8Mode;
+
+        let value = procure(r"'ab\255c'");
+        assert_eq!(
+            "invalid utf-8 sequence at byte offset 2",
+            from_lua::<String>(value.clone()).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "ab\u{fffd}c",
+            from_lua_with_config::<String>(
+                value.clone(),
+                Config::new().utf8_mode(Utf8Mode::Lossy)
+            ).unwrap()
+        );
+        assert_eq!(
+            vec![97, 98, 255, 99],
+            from_lua::<ByteBuf>(value.clone()).unwrap().into_vec()
+        );
+        assert!(from_lua::<char>(procure(r"'\255'")).is_err());
+        assert_eq!(
+            '\u{fffd}',
+            from_lua_with_config::<char>(
+                procure(r"'\255'"),
+                Config::new().utf8_mode(Utf8Mode::Lossy)
+            ).unwrap()
+        );
+    }
+
     #[test]
     fn sequence() {
         use std::iter::FromIterator;
@@ -720,6 +1907,60 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sparse_arrays() {
+        use config::{SparseArrays, MAX_HOLES};
+        use ::ErrorKind;
+
+        let sparse = |value: &str, policy| from_lua_with_config::<Vec<Option<u32>>>(
+            procure(value),
+            Config::new().sparse_arrays(policy)
+        );
+
+        assert!(sparse("{ 1, nil, 3 }", SparseArrays::Strict).is_err());
+        assert_eq!(
+            vec![Some(1), None, Some(3)],
+            sparse("{ 1, nil, 3 }", SparseArrays::Fill).unwrap()
+        );
+        assert_eq!(
+            vec![None, None, Some(3)],
+            sparse("{ [3] = 3 }", SparseArrays::Fill).unwrap()
+        );
+        assert!(sparse("{ 1, n = 3 }", SparseArrays::Fill).is_err());
+        assert!(sparse("{ [1.5] = 1 }", SparseArrays::Fill).is_err());
+
+        assert_eq!(
+            vec![None, Some(2), None],
+            sparse("{ [2] = 2, n = 3 }", SparseArrays::Packed).unwrap()
+        );
+        assert_eq!(vec![None, None], sparse("{ n = 2 }", SparseArrays::Packed).unwrap());
+        assert_eq!(vec![Some(1)], sparse("{ 1 }", SparseArrays::Packed).unwrap());
+        assert!(sparse("{ 1, 2, n = 1 }", SparseArrays::Packed).is_err());
+        assert!(sparse("{ 1, nil, 3 }", SparseArrays::Packed).is_err());
+
+        // Lengths far beyond the number of values are rejected.
+        for &(value, policy) in &[
+            ("{ [2^40] = 1 }", SparseArrays::Fill),
+            ("{ 1, n = 2^40 }", SparseArrays::Packed)
+        ] {
+            match *sparse(value, policy).unwrap_err().kind() {
+                ErrorKind::InvalidLength { len, .. } => assert_eq!(1 << 40, len),
+                ref other => panic!("{}: {:?}", value, other)
+            }
+        }
+        let holes = |holes| format!("{{ 1, [{}] = 2 }}", holes + 2);
+        assert_eq!(MAX_HOLES + 2, sparse(&holes(MAX_HOLES), SparseArrays::Fill).unwrap().len());
+        assert!(sparse(&holes(MAX_HOLES + 1), SparseArrays::Fill).is_err());
+
+        assert_eq!(
+            (Some(1), None::<u32>),
+            from_lua_with_config(
+                procure("{ 1, n = 2 }"),
+                Config::new().sparse_arrays(SparseArrays::Packed)
+            ).unwrap()
+        );
+    }
+
     #[derive(Deserialize, PartialEq, Debug)]
     #[serde(rename_all = "snake_case")]
     enum UnitEnum {
@@ -903,6 +2144,135 @@ mod tests {
         );
     }
 
+    #[test]
+    fn null_sentinel() {
+        use std::iter::FromIterator;
+
+        let config = Config::new().null_sentinel("null");
+        assert_eq!(
+            vec![(), ()],
+            from_lua_with_config::<Vec<()>>(procure("{ 'null', 'null' }"), config).unwrap()
+        );
+        assert_eq!(
+            vec![None, Some(2), None],
+            from_lua_with_config::<Vec<Option<u32>>>(
+                procure("{ 'null', 2, 'null' }"),
+                config
+            ).unwrap()
+        );
+        assert_eq!(
+            BTreeMap::from_iter(vec![("a".to_owned(), ())]),
+            from_lua_with_config::<BTreeMap<String, ()>>(
+                procure("{ a = 'null' }"),
+                config
+            ).unwrap()
+        );
+        assert!(from_lua_with_config::<String>(procure("'null'"), config).is_err());
+        assert!(from_lua::<()>(procure("'null'")).is_err());
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct BorrowedStruct<'a> {
+        name: &'a str,
+        #[serde(borrow)]
+        alias: std::borrow::Cow<'a, str>,
+        #[serde(borrow)]
+        tags: Vec<&'a str>,
+        data: &'a [u8]
+    }
+
+    #[test]
+    fn borrowed() {
+        use config::ByteEncoding;
+
+        let value = procure(r"{ name = 'moth', alias = 'lamp', tags = { 'a', 'b' }, data = '\1\2' }");
+        let borrowed = from_lua_ref_with_config::<BorrowedStruct>(
+            &value,
+            Config::new().byte_encoding(ByteEncoding::Raw)
+        ).unwrap();
+        assert_eq!(borrowed.name, "moth");
+        assert!(matches!(borrowed.alias, std::borrow::Cow::Borrowed("lamp")));
+        assert_eq!(borrowed.tags, vec!["a", "b"]);
+        assert_eq!(borrowed.data, &[1, 2]);
+
+        // The value is only borrowed, so it can be read again.
+        assert_eq!(
+            SimpleStruct { scalar: 1.0, string: "Hi!".to_owned(), vector: vec![3, 2] },
+            from_lua_ref::<SimpleStruct>(&procure(
+                "{ scalar = 1, string = 'Hi!', vector = { 3, 2 } }"
+            )).unwrap()
+        );
+        assert!(from_lua_ref::<&str>(&value).is_err());
+        assert!(from_lua::<BorrowedStruct>(value).is_err());
+    }
+
+    /// Whether a string was lent to or given to the visitor.
+    #[derive(PartialEq, Debug)]
+    enum Received {
+        Lent,
+        Given
+    }
+
+    impl<'de> ::serde::Deserialize<'de> for Received {
+        fn deserialize<D>(deserializer: D) -> Result<Received, D::Error>
+            where D: ::serde::Deserializer<'de>
+        {
+            struct ReceivedVisitor;
+
+            impl<'de> ::serde::de::Visitor<'de> for ReceivedVisitor {
+                type Value = Received;
+
+                fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    formatter.write_str("a string")
+                }
+
+                fn visit_str<E>(self, _: &str) -> Result<Received, E> {
+                    Ok(Received::Lent)
+                }
+
+                fn visit_string<E>(self, _: String) -> Result<Received, E> {
+                    Ok(Received::Given)
+                }
+
+                fn visit_bytes<E>(self, _: &[u8]) -> Result<Received, E> {
+                    Ok(Received::Lent)
+                }
+
+                fn visit_byte_buf<E>(self, _: Vec<u8>) -> Result<Received, E> {
+                    Ok(Received::Given)
+                }
+            }
+
+            deserializer.deserialize_any(ReceivedVisitor)
+        }
+    }
+
+    #[test]
+    fn owned() {
+        use self::Received::{Given, Lent};
+
+        // Strings are moved out of owned lua data rather than copied.
+        assert_eq!(from_lua::<Received>(procure("'moth'")).unwrap(), Given);
+        assert_eq!(
+            from_lua::<Vec<Received>>(procure(r"{ 'moth', '\255' }")).unwrap(),
+            vec![Given, Given]
+        );
+        assert_eq!(
+            from_lua::<BTreeMap<String, Received>>(procure("{ moth = 'lamp' }")).unwrap(),
+            vec![("moth".to_owned(), Given)].into_iter().collect()
+        );
+        assert_eq!(
+            from_lua_with_config::<BTreeMap<String, Received>>(
+                procure("{ moth = 'lamp', none = 'null' }"),
+                Config::new().null_sentinel("null")
+            ).unwrap_err().path().to_string(),
+            "none"
+        );
+
+        // Borrowed lua data can only lend them.
+        assert_eq!(from_lua_ref::<Received>(&procure("'moth'")).unwrap(), Lent);
+    }
+
     #[test]
     fn unit_limitations() {
         assert!(from_lua::<FailUnitStruct>(procure("{}")).is_err());
@@ -910,5 +2280,411 @@ mod tests {
         assert!(from_lua::<FailUnitStruct>(procure("{ value = nil }")).is_err());
         assert!(from_lua::<SuccessUnitStruct>(procure("{ value = nil }")).is_ok());
     }
-}
 
+    #[test]
+    fn enum_reprs() {
+        let internal = Config::new().enum_repr(EnumRepr::Internal { tag: "type" });
+        assert_eq!(
+            vec![
+                ComplexEnum::Scalar,
+                ComplexEnum::Scalar,
+                ComplexEnum::Tuple(1.0, 2.0),
+                ComplexEnum::Struct {
+                    name: String::new(),
+                    contents: SimpleStruct {
+                        scalar: 1.0,
+                        string: "Hi!".to_owned(),
+                        vector: vec![3]
+                    }
+                }
+            ],
+            from_lua_with_config::<Vec<ComplexEnum>>(procure(
+                "{ 'scalar',
+                   { type = 'scalar' },
+                   { type = 'tuple', 1, 2 },
+                   { type = 'struct',
+                     contents = { scalar = 1, string = 'Hi!', vector = { 3 } } } }"
+            ), internal).unwrap()
+        );
+        assert!(from_lua_with_config::<ComplexEnum>(
+            procure("{ tuple = { 1, 2 } }"),
+            internal
+        ).is_err());
+        // Only hiding the tag of an internally tagged variant leaves an
+        // empty table behind.
+        assert!(from_lua::<ComplexEnum>(procure("{ scalar = {} }")).is_err());
+
+        let adjacent = Config::new().enum_repr(EnumRepr::Adjacent {
+            tag: "t",
+            content: "c"
+        });
+        assert_eq!(
+            vec![ComplexEnum::Scalar, ComplexEnum::Tuple(1.0, 2.0)],
+            from_lua_with_config::<Vec<ComplexEnum>>(procure(
+                "{ { t = 'scalar' }, { t = 'tuple', c = { 1, 2 } } }"
+            ), adjacent).unwrap()
+        );
+        assert!(from_lua_with_config::<ComplexEnum>(
+            procure("{ t = 'tuple', c = { 1, 2 }, extra = true }"),
+            adjacent
+        ).is_err());
+
+        let array = Config::new().enum_repr(EnumRepr::Array);
+        assert_eq!(
+            vec![ComplexEnum::Scalar, ComplexEnum::Scalar, ComplexEnum::Tuple(1.0, 2.0)],
+            from_lua_with_config::<Vec<ComplexEnum>>(procure(
+                "{ 'scalar', { 'scalar' }, { 'tuple', { 1, 2 } } }"
+            ), array).unwrap()
+        );
+        assert!(from_lua_with_config::<ComplexEnum>(
+            procure("{ 'tuple', { 1, 2 }, 3 }"),
+            array
+        ).is_err());
+    }
+
+    #[test]
+    fn paths() {
+        use de::PathSegment;
+
+        let error = from_lua::<NestedStruct>(procure("{
+            title = 'Dr', first_name = 'A', last_name = 'B',
+            data = { struct = { contents = {
+                scalar = 1, string = 'Hi!', vector = { 1, 'two', 3 }
+            } } }
+        }")).unwrap_err();
+        assert_eq!(
+            &[
+                PathSegment::Field("data".to_owned()),
+                PathSegment::Field("struct".to_owned()),
+                PathSegment::Field("contents".to_owned()),
+                PathSegment::Field("vector".to_owned()),
+                PathSegment::Index(2)
+            ],
+            error.path().segments()
+        );
+        assert_eq!("data.struct.contents.vector[2]", error.path().to_string());
+        assert_eq!(
+            format!("{} at data.struct.contents.vector[2]", error.kind()),
+            error.to_string()
+        );
+
+        let error = from_lua::<BTreeMap<String, Vec<u32>>>(
+            procure("{ ['not an identifier'] = { 1, 2, -3 } }")
+        ).unwrap_err();
+        assert_eq!(r#"["not an identifier"][3]"#, error.path().to_string());
+
+        let error = from_lua::<Vec<ComplexEnum>>(
+            procure("{ 'scalar', { tuple = { 1, false } } }")
+        ).unwrap_err();
+        assert_eq!("[2].tuple[2]", error.path().to_string());
+
+        let adjacent = Config::new().enum_repr(EnumRepr::Adjacent { tag: "t", content: "c" });
+        let error = from_lua_with_config::<ComplexEnum>(
+            procure("{ t = 'struct', c = { contents = { scalar = 'x' } } }"),
+            adjacent
+        ).unwrap_err();
+        assert_eq!("c.contents.scalar", error.path().to_string());
+
+        let error = from_lua::<u32>(procure("'a'")).unwrap_err();
+        assert!(error.path().is_root());
+        assert_eq!(error.kind().to_string(), error.to_string());
+    }
+
+    #[test]
+    fn error_kinds() {
+        use ErrorKind;
+
+        let error = from_lua::<u32>(procure("'a'")).unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidType {
+                expected: "u32".to_owned(),
+                got: "string \"a\"".to_owned()
+            },
+            error.kind()
+        );
+        assert_eq!(r#"invalid type: string "a", expected u32"#, error.to_string());
+
+        let error = from_lua::<u32>(procure("1.5")).unwrap_err();
+        assert_eq!(
+            &ErrorKind::LossyNumber { value: "1.5".to_owned(), target: "u32" },
+            error.kind()
+        );
+        assert_eq!("1.5 cannot be losslessly represented as u32", error.to_string());
+        assert!(matches!(
+            *from_lua::<i64>(procure("2^63")).unwrap_err().kind(),
+            ErrorKind::LossyNumber { target: "i64", .. }
+        ));
+        assert!(matches!(
+            *from_lua::<u64>(procure("2^64")).unwrap_err().kind(),
+            ErrorKind::LossyNumber { target: "u64", .. }
+        ));
+
+        let error = from_lua::<(u32, u32)>(procure("{ 1, 2, 3 }")).unwrap_err();
+        assert!(matches!(*error.kind(), ErrorKind::InvalidLength { len: 3, .. }));
+
+        let error = from_lua::<String>(procure(r"'ab\255c'")).unwrap_err();
+        assert_eq!(&ErrorKind::InvalidUtf8 { offset: 2 }, error.kind());
+        let error = from_lua_ref::<Vec<String>>(&procure(r"{ 'a', '\255' }")).unwrap_err();
+        assert_eq!(&ErrorKind::InvalidUtf8 { offset: 0 }, error.kind());
+
+        let error = from_lua::<UnitEnum>(procure("'fourth'")).unwrap_err();
+        assert_eq!(
+            &ErrorKind::UnknownVariant {
+                variant: "fourth".to_owned(),
+                expected: &["first", "second", "and_the_third"]
+            },
+            error.kind()
+        );
+        assert_eq!(
+            "unknown variant `fourth`, expected one of `first`, `second`, `and_the_third`",
+            error.to_string()
+        );
+
+        let error = from_lua::<SimpleStruct>(
+            procure("{ scalar = 1, vector = {} }")
+        ).unwrap_err();
+        assert_eq!(&ErrorKind::MissingField("string"), error.kind());
+        assert_eq!("missing field `string`", error.to_string());
+
+        #[derive(Deserialize, Debug)]
+        #[serde(deny_unknown_fields)]
+        struct Strict {
+            #[allow(dead_code)]
+            field: u32
+        }
+        let error = from_lua::<Strict>(procure("{ field = 1, other = 2 }")).unwrap_err();
+        assert_eq!(
+            &ErrorKind::UnknownField { field: "other".to_owned(), expected: &["field"] },
+            error.kind()
+        );
+        assert_eq!("unknown field `other`, expected `field`", error.to_string());
+    }
+
+    #[test]
+    fn buffered_integers() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(tag = "type")]
+        enum Tagged {
+            Sized { width: u32, height: i8 }
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(untagged)]
+        enum Untagged {
+            Count(u64),
+            Offset(i64),
+            Ratio(f64),
+            Name(String)
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Inner {
+            id: u16,
+            delta: i32
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Flattened {
+            name: String,
+            #[serde(flatten)]
+            inner: Inner
+        }
+
+        assert_eq!(
+            Tagged::Sized { width: 3, height: -2 },
+            from_lua(procure("{ type = 'Sized', width = 3, height = -2 }")).unwrap()
+        );
+        assert!(from_lua::<Tagged>(procure("{ type = 'Sized', width = 1.5, height = 0 }")).is_err());
+
+        assert_eq!(
+            vec![
+                Untagged::Count(3),
+                Untagged::Offset(-3),
+                Untagged::Ratio(0.5),
+                Untagged::Ratio(-0.0),
+                Untagged::Count(1 << 53),
+                Untagged::Offset(i64::MIN),
+                Untagged::Ratio(1e300),
+                Untagged::Name("x".to_owned())
+            ],
+            from_lua::<Vec<Untagged>>(procure("{ 3, -3, 0.5, -0.0, 2^53, -2^63, 1e300, 'x' }"))
+                .unwrap()
+        );
+
+        assert_eq!(
+            Flattened { name: "n".to_owned(), inner: Inner { id: 7, delta: -1 } },
+            from_lua(procure("{ name = 'n', id = 7, delta = -1 }")).unwrap()
+        );
+        assert_eq!(
+            Flattened { name: "n".to_owned(), inner: Inner { id: 7, delta: -1 } },
+            ::from_lua_str("return { name = 'n', id = 7, delta = -1 }").unwrap()
+        );
+    }
+
+    #[test]
+    fn coercion() {
+        use config::Coercion;
+
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        let lenient = Config::new().coercion(Coercion::Lenient { truthiness: false });
+        let truthy = Config::new().coercion(Coercion::Lenient { truthiness: true });
+
+        // Strings convert to numbers exactly when lua's `tonumber` would
+        // convert them.
+        for text in &[
+            "42", " 42 ", "\t-7\n", "+3", "1e3", "1E-2", ".5", "5.", "-.5", "0x1F", "-0X10",
+            "0x.8", "0x1p4", "0xA.8P-1", "1.5e+3", "", " ", ".", "-", "1e", "0x", "0xg", "1 2",
+            "--1", "+-1", "inf", "nan", "1e400", "abc", "0b1", "1_000"
+        ] {
+            let expected = lua.execute::<hlua::AnyLuaValue>(
+                &format!("return tonumber({:?})", text)
+            ).unwrap();
+            let actual = from_lua_with_config::<f64>(procure(&format!("{:?}", text)), lenient);
+            match expected {
+                hlua::AnyLuaValue::LuaNumber(number) => {
+                    assert_eq!(number, actual.unwrap(), "{:?}", text)
+                },
+                _ => assert!(actual.is_err(), "{:?}", text)
+            }
+        }
+
+        // Numbers convert to strings exactly as lua's `tostring` would
+        // convert them.
+        for expression in &[
+            "0", "-0.0", "1", "-1", "0.1", "1/3", "-2/3", "100", "1e14", "1e15", "123456789012345",
+            "2^53", "2^63", "1e100", "1.5e-5", "1e-4", "0.0001234", "12345.678901234567",
+            "99999999999999.5", "1/0", "-1/0", "0/0", "-(0/0)", "5e-324"
+        ] {
+            let expected = lua.execute::<String>(&format!("return tostring({})", expression))
+                .unwrap();
+            let actual = from_lua_with_config::<String>(procure(expression), lenient).unwrap();
+            assert_eq!(expected, actual, "{}", expression);
+        }
+
+        assert_eq!(8080u16, from_lua_with_config(procure("'0x1F90'"), lenient).unwrap());
+        assert_eq!(-3i8, from_lua_with_config(procure("' -3 '"), lenient).unwrap());
+        assert!(from_lua_with_config::<u8>(procure("'1.5'"), lenient).is_err());
+        assert!(from_lua_with_config::<u8>(procure("'300'"), lenient).is_err());
+        assert_eq!('7', from_lua_with_config(procure("7"), lenient).unwrap());
+        assert_eq!(
+            BTreeMap::from([("1".to_owned(), 2.0)]),
+            from_lua_with_config(procure("{ [1] = '2' }"), lenient).unwrap()
+        );
+
+        // Booleans only follow truthiness when asked to.
+        assert!(from_lua_with_config::<bool>(procure("0"), lenient).is_err());
+        assert!(from_lua_with_config::<bool>(procure("0"), truthy).unwrap());
+        assert!(from_lua_with_config::<bool>(procure("''"), truthy).unwrap());
+        assert!(from_lua_with_config::<bool>(procure("{}"), truthy).unwrap());
+        assert!(!from_lua_with_config::<bool>(procure("nil"), truthy).unwrap());
+        assert!(!from_lua_with_config::<bool>(procure("false"), truthy).unwrap());
+
+        // None of this happens by default.
+        assert!(from_lua::<u16>(procure("'42'")).is_err());
+        assert!(from_lua::<String>(procure("42")).is_err());
+        assert!(from_lua::<bool>(procure("nil")).is_err());
+    }
+
+    #[test]
+    fn limits() {
+        use config::{Limits, SparseArrays};
+        use error::Limit;
+        use ErrorKind;
+        use Value;
+
+        let limited = |limits: Limits, value: &str| -> Result<(), (Limit, usize, String)> {
+            let config = Config::new().limits(limits);
+            from_lua_with_config::<Value>(procure(value), config).map(|_| ()).map_err(|error| {
+                match *error.kind() {
+                    ErrorKind::LimitExceeded { limit, max } => {
+                        (limit, max, error.path().to_string())
+                    },
+                    ref kind => panic!("{}: {}", value, kind)
+                }
+            })
+        };
+
+        let nested = "{ a = { b = { c = {} } } }";
+        assert_eq!(Ok(()), limited(Limits::new(), nested));
+        assert_eq!(Ok(()), limited(Limits::new().max_depth(4), nested));
+        assert_eq!(
+            Err((Limit::Depth, 3, "a.b.c".to_owned())),
+            limited(Limits::new().max_depth(3), nested)
+        );
+        assert_eq!(
+            Err((Limit::Depth, 0, "".to_owned())),
+            limited(Limits::new().max_depth(0), "{}")
+        );
+        assert_eq!(Ok(()), limited(Limits::new().max_depth(0), "'scalar'"));
+
+        let wide = "{ 1, 2, { 3, 4, 5 } }";
+        assert_eq!(Ok(()), limited(Limits::new().max_entries(3), wide));
+        assert_eq!(
+            Err((Limit::Entries, 2, "".to_owned())),
+            limited(Limits::new().max_entries(2), wide)
+        );
+
+        let strings = "{ key = 'value', [2] = 'ab' }";
+        assert_eq!(Ok(()), limited(Limits::new().max_string_bytes(10), strings));
+        assert_eq!(
+            Err((Limit::StringBytes, 9, "[3]".to_owned())),
+            limited(Limits::new().max_string_bytes(9), "{ 'abcd', 'efgh', 'ij' }")
+        );
+        assert_eq!(
+            Err((Limit::StringBytes, 3, "".to_owned())),
+            limited(Limits::new().max_string_bytes(3), "'long'")
+        );
+
+        // The table, two keys, and two values.
+        assert_eq!(Ok(()), limited(Limits::new().max_nodes(5), "{ 1, 2 }"));
+        assert_eq!(
+            Err((Limit::Nodes, 4, "[2]".to_owned())),
+            limited(Limits::new().max_nodes(4), "{ 1, 2 }")
+        );
+
+        // The nils filling the holes of sparse sequences count as entries
+        // and values too.
+        let sparse = |policy, limits: Limits, value: &str| {
+            let config = Config::new().sparse_arrays(policy).limits(limits);
+            from_lua_with_config::<Vec<Option<u8>>>(procure(value), config)
+                .map(|_| ())
+                .map_err(|error| error.kind().clone())
+        };
+        let limits = Limits::new().max_entries(10).max_nodes(10);
+        for &(value, policy) in &[
+            ("{ [2^40] = 1 }", SparseArrays::Fill),
+            ("{ 1, n = 2^40 }", SparseArrays::Packed)
+        ] {
+            assert_eq!(
+                Err(ErrorKind::LimitExceeded { limit: Limit::Entries, max: 10 }),
+                sparse(policy, limits, value)
+            );
+        }
+        assert_eq!(Ok(()), sparse(SparseArrays::Fill, limits, "{ [8] = 1 }"));
+        assert_eq!(
+            Err(ErrorKind::LimitExceeded { limit: Limit::Nodes, max: 10 }),
+            sparse(SparseArrays::Fill, limits, "{ [9] = 1 }")
+        );
+        // Tables that aren't read as sequences have no holes.
+        assert!(matches!(
+            sparse(SparseArrays::Strict, limits, "{ [2^40] = 1 }"),
+            Err(ErrorKind::InvalidType { .. })
+        ));
+
+        // Limits are checked before anything is deserialized, even parts that
+        // the target type ignores.
+        #[derive(Deserialize, Debug)]
+        struct Small {
+            #[allow(dead_code)]
+            name: String
+        }
+        let config = Config::new().limits(Limits::new().max_depth(1));
+        let error = from_lua_with_config::<Small>(procure("{ name = 'n', extra = {} }"), config)
+            .unwrap_err();
+        assert_eq!("table nesting depth exceeds the limit of 1 at extra", error.to_string());
+        let value = procure("{ name = 'n', extra = {} }");
+        assert!(from_lua_ref_with_config::<Small>(&value, config).is_err());
+        assert!(from_lua_ref_with_config::<Small>(&procure("{ name = 'n' }"), config).is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+//! The kinds of failure shared by serialization and deserialization.
+
+use std::fmt;
+use std::io;
+
+/// What went wrong during serialization or deserialization.
+///
+/// Both `LuaSerializeError` and `LuaDeserializeError` expose their kind
+/// through a `kind` method, so that callers can react to specific failures.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// A value of one type was found where another was expected.
+    InvalidType {
+        /// A description of the expected value, such as `"a string"`.
+        expected: String,
+        /// A description of the value found, such as `"map"`.
+        got: String
+    },
+    /// A value of the right type was found, but its contents were invalid.
+    InvalidValue {
+        /// A description of the expected value.
+        expected: String,
+        /// A description of the value found.
+        got: String
+    },
+    /// A sequence, tuple or string had the wrong number of elements.
+    InvalidLength {
+        /// A description of the expected length.
+        expected: String,
+        /// The number of elements found.
+        len: usize
+    },
+    /// An enum variant name wasn't one of the enum's variants.
+    UnknownVariant {
+        /// The variant name found.
+        variant: String,
+        /// The names of the enum's variants.
+        expected: &'static [&'static str]
+    },
+    /// A struct field wasn't one of the struct's fields.
+    UnknownField {
+        /// The field name found.
+        field: String,
+        /// The names of the struct's fields.
+        expected: &'static [&'static str]
+    },
+    /// A struct field was missing.
+    MissingField(&'static str),
+    /// A number couldn't be converted without losing precision.
+    LossyNumber {
+        /// The number, as written by rust.
+        value: String,
+        /// The type it would have been converted to.
+        target: &'static str
+    },
+    /// A lua string read into a rust string wasn't valid UTF-8, under
+    /// `Utf8Mode::Strict`.
+    InvalidUtf8 {
+        /// The byte offset of the first invalid sequence.
+        offset: usize
+    },
+    /// A map key can't be used as a lua table key, such as `nil` or NaN.
+    InvalidKey(String),
+    /// Lua source text couldn't be parsed.
+    Syntax(String),
+    /// Reading or writing lua source text failed.
+    Io {
+        /// The kind of the underlying `io::Error`.
+        kind: io::ErrorKind,
+        /// The message of the underlying `io::Error`.
+        message: String
+    },
+    /// Lua data exceeded one of the limits set with `Config::limits`.
+    LimitExceeded {
+        /// The limit that was exceeded.
+        limit: Limit,
+        /// The value of that limit.
+        max: usize
+    },
+    /// A table read from the lua stack contained itself.
+    CyclicTable,
+    /// The lua stack couldn't grow to hold the values being read or
+    /// written.
+    StackOverflow,
+    /// Any other failure, including those reported by `Serialize` and
+    /// `Deserialize` implementations.
+    Custom(String)
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::InvalidType { ref expected, ref got } => {
+                write!(f, "invalid type: {}, expected {}", got, expected)
+            },
+            ErrorKind::InvalidValue { ref expected, ref got } => {
+                write!(f, "invalid value: {}, expected {}", got, expected)
+            },
+            ErrorKind::InvalidLength { ref expected, len } => {
+                write!(f, "invalid length {}, expected {}", len, expected)
+            },
+            ErrorKind::UnknownVariant { ref variant, expected } => {
+                write!(f, "unknown variant `{}`, {}", variant, OneOf(expected))
+            },
+            ErrorKind::UnknownField { ref field, expected } => {
+                write!(f, "unknown field `{}`, {}", field, OneOf(expected))
+            },
+            ErrorKind::MissingField(field) => write!(f, "missing field `{}`", field),
+            ErrorKind::LossyNumber { ref value, target } => write!(
+                f,
+                "{} cannot be losslessly represented as {}",
+                value,
+                target
+            ),
+            ErrorKind::InvalidUtf8 { offset } => {
+                write!(f, "invalid utf-8 sequence at byte offset {}", offset)
+            },
+            ErrorKind::InvalidKey(ref key) => write!(f, "unserializable key {}", key),
+            ErrorKind::Syntax(ref message) => write!(f, "syntax error: {}", message),
+            ErrorKind::Io { ref message, .. } => write!(f, "io error: {}", message),
+            ErrorKind::CyclicTable => f.write_str("cyclic table"),
+            ErrorKind::StackOverflow => f.write_str("lua stack overflow"),
+            ErrorKind::LimitExceeded { limit, max } => {
+                write!(f, "{} exceeds the limit of {}", limit, max)
+            },
+            ErrorKind::Custom(ref message) => f.write_str(message)
+        }
+    }
+}
+
+impl From<io::Error> for ErrorKind {
+    fn from(error: io::Error) -> ErrorKind {
+        ErrorKind::Io { kind: error.kind(), message: error.to_string() }
+    }
+}
+
+/// One of the limits on lua data, as set by `config::Limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// How deeply tables can be nested.
+    Depth,
+    /// How many entries a single table can have.
+    Entries,
+    /// How many bytes all the strings can hold in total.
+    StringBytes,
+    /// How many values there can be in total.
+    Nodes
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Limit::Depth => "table nesting depth",
+            Limit::Entries => "number of table entries",
+            Limit::StringBytes => "total string length",
+            Limit::Nodes => "number of values"
+        })
+    }
+}
+
+/// A position in lua source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// The line, counting from 1.
+    pub line: usize,
+    /// The column, counting characters from 1.
+    pub column: usize
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Lists the names that were expected in place of an unknown one.
+struct OneOf(&'static [&'static str]);
+
+impl fmt::Display for OneOf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.len() {
+            0 => f.write_str("there are none"),
+            1 => write!(f, "expected `{}`", self.0[0]),
+            _ => {
+                f.write_str("expected one of ")?;
+                for (position, name) in self.0.iter().enumerate() {
+                    if position > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "`{}`", name)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
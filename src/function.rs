@@ -0,0 +1,954 @@
+//! Calling lua functions with serde arguments and results, and exposing
+//! rust closures to lua as functions of the same kind.
+//!
+//! ```rust
+//! extern crate hlua;
+//! extern crate serde;
+//! #[macro_use] extern crate serde_derive;
+//! extern crate serde_hlua;
+//!
+//! use serde_hlua::{CallError, SerdeLuaCall};
+//!
+//! #[derive(Serialize, Deserialize, Debug, PartialEq)]
+//! struct Point {
+//!     x: f64,
+//!     y: f64
+//! }
+//!
+//! fn main() {
+//!     let mut lua = hlua::Lua::new();
+//!     lua.execute::<()>("
+//!         function scale(point, factor)
+//!             return { x = point.x * factor, y = point.y * factor }, factor > 1
+//!         end
+//!     ").unwrap();
+//!
+//!     let mut scale: hlua::LuaFunction<_> = lua.get("scale").unwrap();
+//!     let (point, grown): (Point, bool) =
+//!         scale.call_serde((Point { x: 1.0, y: 2.0 }, 3.0)).unwrap();
+//!     assert_eq!(Point { x: 3.0, y: 6.0 }, point);
+//!     assert!(grown);
+//!
+//!     match scale.call_serde::<_, Point>((Point { x: 1.0, y: 2.0 }, "twice")) {
+//!         Err(CallError::Lua(message)) => assert!(message.contains("arithmetic")),
+//!         other => panic!("{:?}", other)
+//!     }
+//! }
+//! ```
+
+use std::any::TypeId;
+use std::cell::Cell;
+use std::error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::c_int;
+use std::ptr;
+use std::slice;
+
+use hlua;
+use hlua::{AsMutLua, LuaFunction, PushGuard, Void};
+use hlua::ffi;
+use serde;
+use serde::Serialize;
+use serde::de::{DeserializeOwned, Deserializer, Visitor};
+use serde::ser::{Impossible, Serializer};
+
+use config::Config;
+use de::{DeResult, LuaDeserializeError, PathSegment};
+use ser::{LuaSerializeError, SerResult};
+use shared;
+use stack::check_stack;
+use stack::de::LuaStackDeserializer;
+use stack::ser::LuaStackSerializer;
+
+// Missing from the bindings.
+const LUA_MULTRET: c_int = -1;
+
+/// Calls lua functions with arguments and results converted through serde.
+pub trait SerdeLuaCall {
+    /// Call the function with `args` and read its results.
+    ///
+    /// A tuple of arguments is passed as separate arguments to the function,
+    /// and `()` as none at all; any other value is passed as a single
+    /// argument. Likewise, the results of the function are read into a
+    /// tuple, with missing results read as `nil` and extra ones ignored,
+    /// and anything else is read from the first result.
+    fn call_serde<A, R>(&mut self, args: A) -> Result<R, CallError>
+        where A: Serialize,
+              R: DeserializeOwned
+    {
+        self.call_serde_with_config(args, Config::new())
+    }
+
+    /// Call the function with `args` and read its results, using the
+    /// provided configuration in both directions.
+    fn call_serde_with_config<A, R>(&mut self, args: A, config: Config) -> Result<R, CallError>
+        where A: Serialize,
+              R: DeserializeOwned;
+}
+
+impl<'lua, L> SerdeLuaCall for LuaFunction<L>
+    where L: AsMutLua<'lua>
+{
+    fn call_serde_with_config<A, R>(&mut self, args: A, config: Config) -> Result<R, CallError>
+        where A: Serialize,
+              R: DeserializeOwned
+    {
+        let lua = self.as_mut_lua().state_ptr();
+        unsafe {
+            let top = ffi::lua_gettop(lua);
+            check_stack(lua, 1).map_err(|kind| CallError::Serialize(LuaSerializeError(kind)))?;
+            // `lua_pcall` pops the function, so call a copy of it.
+            ffi::lua_pushvalue(lua, -1);
+            if let Err(error) = push_arguments(lua, &args, config) {
+                ffi::lua_settop(lua, top);
+                return Err(CallError::Serialize(error));
+            }
+            let arguments = ffi::lua_gettop(lua) - top - 1;
+            if ffi::lua_pcall(lua, arguments, LUA_MULTRET, 0) != 0 {
+                let message = error_message(lua);
+                ffi::lua_settop(lua, top);
+                return Err(CallError::Lua(message));
+            }
+            let count = ffi::lua_gettop(lua) - top;
+            let result = check_stack(lua, 1).map_err(LuaDeserializeError::from).and_then(|()| {
+                ffi::lua_pushnil(lua);
+                let _scope = if config.share_tables { Some(shared::Scope::enter()) } else { None };
+                R::deserialize(Results { lua, first: top + 1, count, config, failed: None })
+            });
+            ffi::lua_settop(lua, top);
+            result.map_err(CallError::Deserialize)
+        }
+    }
+}
+
+/// An error returned when calling a lua function through `SerdeLuaCall`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallError {
+    /// The function raised an error, with the given message.
+    Lua(String),
+    /// The arguments couldn't be pushed onto the lua stack.
+    Serialize(LuaSerializeError),
+    /// The results couldn't be read from the lua stack.
+    Deserialize(LuaDeserializeError)
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CallError::Lua(ref message) => write!(f, "error in lua function: {}", message),
+            CallError::Serialize(ref error) => write!(f, "invalid arguments: {}", error),
+            CallError::Deserialize(ref error) => write!(f, "invalid results: {}", error)
+        }
+    }
+}
+
+impl error::Error for CallError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            CallError::Lua(_) => None,
+            CallError::Serialize(ref error) => Some(error),
+            CallError::Deserialize(ref error) => Some(error)
+        }
+    }
+}
+
+impl From<LuaSerializeError> for CallError {
+    fn from(error: LuaSerializeError) -> CallError {
+        CallError::Serialize(error)
+    }
+}
+
+impl From<LuaDeserializeError> for CallError {
+    fn from(error: LuaDeserializeError) -> CallError {
+        CallError::Deserialize(error)
+    }
+}
+
+/// Wrap a rust closure as a lua function whose arguments and return value
+/// are converted through serde.
+///
+/// The arguments of the lua function are read into a tuple, with missing
+/// arguments read as `nil` and extra ones ignored, or, if the closure takes
+/// anything else, from the first argument. A tuple returned by the closure
+/// is returned as separate values, and `()` as none at all.
+///
+/// When the arguments can't be read, the closure returns an error, or its
+/// return value can't be pushed, the function raises a lua error saying
+/// why. For arguments, it names the position of the argument and the path
+/// of the error within it.
+///
+/// ```rust
+/// extern crate hlua;
+/// extern crate serde;
+/// #[macro_use] extern crate serde_derive;
+/// extern crate serde_hlua;
+///
+/// #[derive(Deserialize)]
+/// struct Point {
+///     x: f64,
+///     y: f64
+/// }
+///
+/// fn main() {
+///     let mut lua = hlua::Lua::new();
+///     lua.openlibs();
+///     lua.set("split", serde_hlua::serde_function(|(point, factor): (Point, f64)| {
+///         if factor == 0.0 {
+///             return Err("factor can't be zero");
+///         }
+///         Ok((point.x / factor, point.y / factor))
+///     }));
+///
+///     let halves: String = lua.execute("
+///         return table.concat({ split({ x = 4, y = 6 }, 2) }, ' ')
+///     ").unwrap();
+///     assert_eq!("2 3", halves);
+///
+///     let error: String = lua.execute("
+///         return select(2, pcall(split, { x = 4, y = 'six' }, 2))
+///     ").unwrap();
+///     assert_eq!(
+///         "bad argument #1 (invalid type: string \"six\", expected f64 at y)",
+///         error
+///     );
+///     let error: String = lua.execute("return select(2, pcall(split, { x = 4, y = 6 }, 0))")
+///         .unwrap();
+///     assert_eq!("factor can't be zero", error);
+/// }
+/// ```
+pub fn serde_function<F, A, R, E>(function: F) -> SerdeLuaFunction<F, A>
+    where F: FnMut(A) -> Result<R, E>,
+          A: DeserializeOwned,
+          R: Serialize,
+          E: fmt::Display
+{
+    serde_function_with_config(function, Config::new())
+}
+
+/// Wrap a rust closure as a lua function whose arguments and return value
+/// are converted through serde using the provided configuration.
+pub fn serde_function_with_config<F, A, R, E>(function: F, config: Config) -> SerdeLuaFunction<F, A>
+    where F: FnMut(A) -> Result<R, E>,
+          A: DeserializeOwned,
+          R: Serialize,
+          E: fmt::Display
+{
+    SerdeLuaFunction { function, config, arguments: PhantomData }
+}
+
+/// A rust closure that can be pushed onto the lua stack as a function, as
+/// returned by `serde_function`.
+pub struct SerdeLuaFunction<F, A> {
+    function: F,
+    config: Config,
+    arguments: PhantomData<fn(A)>
+}
+
+impl<F, A, R, E> SerdeLuaFunction<F, A>
+    where F: FnMut(A) -> Result<R, E>,
+          A: DeserializeOwned,
+          R: Serialize,
+          E: fmt::Display
+{
+    /// Call the closure with the arguments on the stack, replacing them
+    /// with its results, or return the message of the error to raise.
+    unsafe fn call(&mut self, lua: *mut ffi::lua_State) -> Result<c_int, String> {
+        let count = ffi::lua_gettop(lua);
+        check_stack(lua, 1).map_err(|kind| kind.to_string())?;
+        ffi::lua_pushnil(lua);
+        let failed = Cell::new(None);
+        let arguments = {
+            let _scope = if self.config.share_tables { Some(shared::Scope::enter()) } else { None };
+            let config = self.config;
+            A::deserialize(Results { lua, first: 1, count, config, failed: Some(&failed) })
+        };
+        let arguments = arguments.map_err(|error| {
+            format!("bad argument #{} ({})", failed.get().unwrap_or(1), error)
+        })?;
+        ffi::lua_settop(lua, 0);
+        let results = (self.function)(arguments).map_err(|error| error.to_string())?;
+        push_arguments(lua, &results, self.config)
+            .map_err(|error| format!("invalid return value ({})", error))?;
+        Ok(ffi::lua_gettop(lua))
+    }
+}
+
+impl<'lua, L, F, A, R, E> hlua::Push<L> for SerdeLuaFunction<F, A>
+    where L: AsMutLua<'lua>,
+          F: FnMut(A) -> Result<R, E> + 'static,
+          A: DeserializeOwned + 'static,
+          R: Serialize,
+          E: fmt::Display
+{
+    type Err = Void;
+
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (Void, L)> {
+        let state = lua.as_mut_lua().state_ptr();
+        unsafe {
+            // The closure lives in a userdata, which the lua function keeps
+            // as an upvalue. Lua only aligns userdata for its own types, so
+            // the userdata holds a box rather than the closure itself. The
+            // box is taken out when the userdata is collected, since the
+            // debug library lets scripts call `__gc` themselves.
+            let size = mem::size_of::<Option<Box<Self>>>();
+            let data = ffi::lua_newuserdata(state, size as _) as *mut Option<Box<Self>>;
+            ptr::write(data, Some(Box::new(self)));
+            push_metatable::<Box<Self>>(state);
+            ffi::lua_setmetatable(state, -2);
+            ffi::lua_pushcclosure(state, call::<F, A, R, E>, 1);
+            Ok(PushGuard::new(lua, 1))
+        }
+    }
+}
+
+impl<'lua, L, F, A, R, E> hlua::PushOne<L> for SerdeLuaFunction<F, A>
+    where L: AsMutLua<'lua>,
+          F: FnMut(A) -> Result<R, E> + 'static,
+          A: DeserializeOwned + 'static,
+          R: Serialize,
+          E: fmt::Display
+{
+}
+
+/// Push the metatable of the userdata that hold an `Option<T>`, creating it
+/// the first time.
+///
+/// There is one metatable for each type, kept in the lua registry, so that
+/// a userdata can be checked to hold a `T` before it is used.
+unsafe fn push_metatable<T: 'static>(lua: *mut ffi::lua_State) {
+    let key = metatable_key::<T>();
+    ffi::lua_pushlstring(lua, key.as_ptr() as *const _, key.len() as _);
+    ffi::lua_rawget(lua, ffi::LUA_REGISTRYINDEX);
+    if ffi::lua_type(lua, -1) != ffi::LUA_TTABLE {
+        ffi::lua_pop(lua, 1);
+        ffi::lua_createtable(lua, 0, 2);
+        ffi::lua_pushcfunction(lua, collect::<T>);
+        ffi::lua_setfield(lua, -2, b"__gc\0".as_ptr() as *const _);
+        ffi::lua_pushboolean(lua, 0);
+        ffi::lua_setfield(lua, -2, b"__metatable\0".as_ptr() as *const _);
+        ffi::lua_pushlstring(lua, key.as_ptr() as *const _, key.len() as _);
+        ffi::lua_pushvalue(lua, -2);
+        ffi::lua_rawset(lua, ffi::LUA_REGISTRYINDEX);
+    }
+}
+
+fn metatable_key<T: 'static>() -> String {
+    format!("serde_hlua::function {:?}", TypeId::of::<T>())
+}
+
+/// Return the contents of the userdata at `index`, if it has the metatable
+/// of the userdata that hold an `Option<T>`.
+///
+/// Scripts can reach `__gc` and the upvalues of functions through the debug
+/// library, and pass anything to them.
+unsafe fn data<T: 'static>(lua: *mut ffi::lua_State, index: c_int) -> Option<*mut Option<T>> {
+    let data = ffi::lua_touserdata(lua, index) as *mut Option<T>;
+    if data.is_null() || ffi::lua_getmetatable(lua, index) == 0 {
+        return None;
+    }
+    let key = metatable_key::<T>();
+    ffi::lua_pushlstring(lua, key.as_ptr() as *const _, key.len() as _);
+    ffi::lua_rawget(lua, ffi::LUA_REGISTRYINDEX);
+    let same = ffi::lua_rawequal(lua, -1, -2) != 0;
+    ffi::lua_pop(lua, 2);
+    if same { Some(data) } else { None }
+}
+
+/// Raise a lua error with `message`.
+unsafe fn raise(lua: *mut ffi::lua_State, message: String) -> c_int {
+    ffi::lua_pushlstring(lua, message.as_ptr() as *const _, message.len() as _);
+    mem::drop(message);
+    // Nothing that needs dropping is left in this frame.
+    ffi::lua_error(lua)
+}
+
+extern "C" fn call<F, A, R, E>(lua: *mut ffi::lua_State) -> c_int
+    where F: FnMut(A) -> Result<R, E> + 'static,
+          A: DeserializeOwned + 'static,
+          R: Serialize,
+          E: fmt::Display
+{
+    let result = unsafe {
+        match data::<Box<SerdeLuaFunction<F, A>>>(lua, ffi::lua_upvalueindex(1)) {
+            // The closure is taken out while it runs, so that neither
+            // calling the function again nor collecting it from within can
+            // reach it.
+            Some(data) => match (*data).take() {
+                Some(mut function) => {
+                    let result = function.call(lua);
+                    *data = Some(function);
+                    result
+                },
+                None => Err("attempt to call a collected or running function".to_owned())
+            },
+            None => Err("attempt to call a function whose closure was replaced".to_owned())
+        }
+    };
+    match result {
+        Ok(results) => results,
+        Err(message) => unsafe { raise(lua, message) }
+    }
+}
+
+extern "C" fn collect<T: 'static>(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        match data::<T>(lua, 1) {
+            Some(data) => {
+                mem::drop((*data).take());
+                0
+            },
+            None => raise(lua, "bad argument #1 to '__gc' (rust function expected)".to_owned())
+        }
+    }
+}
+
+/// Return the message of the error on top of the stack.
+unsafe fn error_message(lua: *mut ffi::lua_State) -> String {
+    let mut len = 0;
+    let message = ffi::lua_tolstring(lua, -1, &mut len);
+    if message.is_null() {
+        "(error object is not a string)".to_owned()
+    } else {
+        let bytes = slice::from_raw_parts(message as *const u8, len);
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+/// Push the arguments of a function call.
+fn push_arguments<A>(lua: *mut ffi::lua_State, args: &A, config: Config) -> SerResult<()>
+    where A: Serialize
+{
+    match args.serialize(Spread { lua, config }) {
+        Ok(()) => Ok(()),
+        Err(SpreadError::NotTuple) => LuaStackSerializer::from_state(lua, config).push_root(args),
+        Err(SpreadError::Failed(error)) => Err(error)
+    }
+}
+
+#[derive(Debug)]
+enum SpreadError {
+    /// The arguments are a single value rather than a tuple.
+    NotTuple,
+    Failed(LuaSerializeError)
+}
+
+impl fmt::Display for SpreadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpreadError::NotTuple => f.write_str("not a tuple"),
+            SpreadError::Failed(ref error) => error.fmt(f)
+        }
+    }
+}
+
+impl error::Error for SpreadError {}
+
+impl serde::ser::Error for SpreadError {
+    fn custom<T>(msg: T) -> Self
+        where T: fmt::Display
+    {
+        SpreadError::Failed(serde::ser::Error::custom(msg))
+    }
+}
+
+/// A serializer that pushes the elements of a tuple as separate values,
+/// and rejects anything else.
+#[derive(Clone, Copy)]
+struct Spread {
+    lua: *mut ffi::lua_State,
+    config: Config
+}
+
+macro_rules! not_tuple {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method(self, $(_: $ty),*) -> Result<(), SpreadError> {
+                Err(SpreadError::NotTuple)
+            }
+        )*
+    }
+}
+
+impl Serializer for Spread {
+    type Ok = ();
+    type Error = SpreadError;
+    type SerializeSeq = Impossible<(), SpreadError>;
+    type SerializeTuple = Spread;
+    type SerializeTupleStruct = Impossible<(), SpreadError>;
+    type SerializeTupleVariant = Impossible<(), SpreadError>;
+    type SerializeMap = Impossible<(), SpreadError>;
+    type SerializeStruct = Impossible<(), SpreadError>;
+    type SerializeStructVariant = Impossible<(), SpreadError>;
+
+    not_tuple! {
+        serialize_bool(v: bool);
+        serialize_i8(v: i8);
+        serialize_i16(v: i16);
+        serialize_i32(v: i32);
+        serialize_i64(v: i64);
+        serialize_u8(v: u8);
+        serialize_u16(v: u16);
+        serialize_u32(v: u32);
+        serialize_u64(v: u64);
+        serialize_f32(v: f32);
+        serialize_f64(v: f64);
+        serialize_char(v: char);
+        serialize_str(v: &str);
+        serialize_bytes(v: &[u8]);
+        serialize_none();
+        serialize_unit_struct(name: &'static str);
+        serialize_unit_variant(name: &'static str, index: u32, variant: &'static str);
+    }
+
+    fn serialize_unit(self) -> Result<(), SpreadError> {
+        Ok(())
+    }
+
+    fn serialize_some<T>(self, _value: &T) -> Result<(), SpreadError>
+        where T: ?Sized + Serialize
+    {
+        Err(SpreadError::NotTuple)
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        _value: &T
+    ) -> Result<(), SpreadError>
+        where T: ?Sized + Serialize
+    {
+        Err(SpreadError::NotTuple)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T
+    ) -> Result<(), SpreadError>
+        where T: ?Sized + Serialize
+    {
+        Err(SpreadError::NotTuple)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, SpreadError> {
+        Err(SpreadError::NotTuple)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Spread, SpreadError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeTupleStruct, SpreadError> {
+        Err(SpreadError::NotTuple)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeTupleVariant, SpreadError> {
+        Err(SpreadError::NotTuple)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, SpreadError> {
+        Err(SpreadError::NotTuple)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeStruct, SpreadError> {
+        Err(SpreadError::NotTuple)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeStructVariant, SpreadError> {
+        Err(SpreadError::NotTuple)
+    }
+}
+
+impl serde::ser::SerializeTuple for Spread {
+    type Ok = ();
+    type Error = SpreadError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), SpreadError>
+        where T: ?Sized + Serialize
+    {
+        LuaStackSerializer::from_state(self.lua, self.config)
+            .push_root(value)
+            .map_err(SpreadError::Failed)
+    }
+
+    fn end(self) -> Result<(), SpreadError> {
+        Ok(())
+    }
+}
+
+/// A deserializer over the results of a function call, or the arguments
+/// of one, followed on the stack by a `nil` that stands in for missing
+/// values.
+#[derive(Clone, Copy)]
+struct Results<'a> {
+    lua: *mut ffi::lua_State,
+    first: c_int,
+    count: c_int,
+    config: Config,
+    // Where to record the position of the argument that couldn't be read,
+    // when reading arguments rather than results.
+    failed: Option<&'a Cell<Option<usize>>>
+}
+
+impl<'a> Results<'a> {
+    /// Return a deserializer over the value at `position`, counting from 0.
+    fn result<'b>(self, position: c_int) -> LuaStackDeserializer<'b> {
+        let index = self.first + position.min(self.count);
+        LuaStackDeserializer::from_state(self.lua, index, self.config)
+    }
+}
+
+macro_rules! forward_to_first {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method<V>(self, $($arg: $ty,)* visitor: V) -> DeResult<V::Value>
+                where V: Visitor<'de>
+            {
+                self.result(0).$method($($arg,)* visitor)
+            }
+        )*
+    }
+}
+
+impl<'a, 'de> Deserializer<'de> for Results<'a> {
+    type Error = LuaDeserializeError;
+
+    forward_to_first! {
+        deserialize_any();
+        deserialize_bool();
+        deserialize_i8();
+        deserialize_i16();
+        deserialize_i32();
+        deserialize_i64();
+        deserialize_u8();
+        deserialize_u16();
+        deserialize_u32();
+        deserialize_u64();
+        deserialize_f32();
+        deserialize_f64();
+        deserialize_char();
+        deserialize_str();
+        deserialize_string();
+        deserialize_bytes();
+        deserialize_byte_buf();
+        deserialize_option();
+        deserialize_unit_struct(name: &'static str);
+        deserialize_newtype_struct(name: &'static str);
+        deserialize_seq();
+        deserialize_tuple_struct(name: &'static str, len: usize);
+        deserialize_map();
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+        deserialize_identifier();
+        deserialize_ignored_any();
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        visitor.visit_seq(ResultsAccess { results: self, next: 0, len })
+    }
+}
+
+/// Sequence access over the results of a function call, as a tuple.
+struct ResultsAccess<'a> {
+    results: Results<'a>,
+    next: usize,
+    len: usize
+}
+
+impl<'a, 'de> serde::de::SeqAccess<'de> for ResultsAccess<'a> {
+    type Error = LuaDeserializeError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> DeResult<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'de>
+    {
+        if self.next == self.len {
+            return Ok(None);
+        }
+        let position = self.next;
+        self.next += 1;
+        let lua = self.results.lua;
+        let top = unsafe { ffi::lua_gettop(lua) };
+        let result = seed.deserialize(self.results.result(position as c_int));
+        unsafe { ffi::lua_settop(lua, top) };
+        result.map(Some).map_err(|error| match self.results.failed {
+            Some(failed) => {
+                failed.set(Some(position + 1));
+                error
+            },
+            None => error.at(PathSegment::Index(position + 1))
+        })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use hlua::AsLua;
+    use hlua::ffi;
+
+    use ::{Config, ErrorKind};
+    use config::EnumRepr;
+    use super::{CallError, SerdeLuaCall};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Circle(f64),
+        Rect { width: f64, height: f64 }
+    }
+
+    fn lua() -> hlua::Lua<'static> {
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        lua.execute::<()>("
+            function describe(...)
+                local parts = { select('#', ...) }
+                for i = 1, select('#', ...) do
+                    parts[#parts + 1] = type((select(i, ...)))
+                end
+                return table.concat(parts, ' ')
+            end
+            function identity(...) return ... end
+            function fail(message) error(message, 0) end
+        ").unwrap();
+        lua
+    }
+
+    #[test]
+    fn spreads_arguments() {
+        let mut lua = lua();
+        let mut describe: hlua::LuaFunction<_> = lua.get("describe").unwrap();
+        let check = |expected: &str, described: Result<String, CallError>| {
+            assert_eq!(expected, described.unwrap());
+        };
+
+        check("0", describe.call_serde(()));
+        check("3 number string nil", describe.call_serde((1, "two", None::<u32>)));
+        check("1 table", describe.call_serde(((1, 2),)));
+        // Anything but a tuple is a single argument.
+        check("1 table", describe.call_serde(vec![1, 2]));
+        check("1 table", describe.call_serde(Shape::Circle(1.0)));
+        check("1 nil", describe.call_serde(None::<u32>));
+        check("1 number", describe.call_serde(1));
+    }
+
+    #[test]
+    fn collects_results() {
+        let mut lua = lua();
+        let state = lua.as_lua().state_ptr();
+        let top = unsafe { ffi::lua_gettop(state) };
+        {
+            let mut identity: hlua::LuaFunction<_> = lua.get("identity").unwrap();
+            let shape = Shape::Rect { width: 1.0, height: 2.0 };
+            let result: (Shape, Option<u32>, Option<u32>) =
+                identity.call_serde((&shape, 5)).unwrap();
+            assert_eq!((Shape::Rect { width: 1.0, height: 2.0 }, Some(5), None), result);
+            let first: Shape = identity.call_serde((Shape::Circle(1.0), 2)).unwrap();
+            assert_eq!(Shape::Circle(1.0), first);
+            let none: Option<u32> = identity.call_serde(()).unwrap();
+            assert_eq!(None, none);
+            identity.call_serde::<_, ()>((1, 2)).unwrap();
+
+            let internal = Config::new().enum_repr(EnumRepr::Internal { tag: "type" });
+            let shape: Shape = identity.call_serde_with_config(&shape, internal).unwrap();
+            assert_eq!(Shape::Rect { width: 1.0, height: 2.0 }, shape);
+        }
+        assert_eq!(top, unsafe { ffi::lua_gettop(state) });
+    }
+
+    #[test]
+    fn distinguishes_errors() {
+        let mut lua = lua();
+        let state = lua.as_lua().state_ptr();
+        let top = unsafe { ffi::lua_gettop(state) };
+        {
+            let mut fail: hlua::LuaFunction<_> = lua.get("fail").unwrap();
+            assert_eq!(
+                Err(CallError::Lua("broken".to_owned())),
+                fail.call_serde::<_, ()>("broken")
+            );
+            assert_eq!(
+                Err(CallError::Lua("(error object is not a string)".to_owned())),
+                fail.call_serde::<_, ()>(vec![1])
+            );
+            match fail.call_serde::<_, ()>((1, u64::MAX)) {
+                Err(CallError::Serialize(error)) => match *error.kind() {
+                    ErrorKind::LossyNumber { .. } => (),
+                    ref kind => panic!("{:?}", kind)
+                },
+                other => panic!("{:?}", other)
+            }
+        }
+        {
+            let mut identity: hlua::LuaFunction<_> = lua.get("identity").unwrap();
+            match identity.call_serde::<_, (u32, u32)>((1, "two")) {
+                Err(error @ CallError::Deserialize(_)) => assert_eq!(
+                    "invalid results: invalid type: string \"two\", expected u32 at [2]",
+                    error.to_string()
+                ),
+                other => panic!("{:?}", other)
+            }
+        }
+        assert_eq!(top, unsafe { ffi::lua_gettop(state) });
+    }
+
+    #[test]
+    fn serde_functions() {
+        use std::rc::Rc;
+        use super::serde_function;
+
+        #[derive(Serialize, Deserialize)]
+        struct Point {
+            x: f64,
+            y: f64
+        }
+
+        let mut lua = lua();
+        let mut count = 0;
+        lua.set("count", serde_function(move |step: Option<u32>| {
+            count += step.unwrap_or(1);
+            Ok::<_, String>(count)
+        }));
+        lua.set("split", serde_function(|(scale, point): (f64, Point)| {
+            Ok::<_, String>((point.x * scale, point.y * scale))
+        }));
+        lua.set("sum", serde_function(|numbers: Vec<u32>| {
+            Ok::<_, String>(numbers.iter().sum::<u32>())
+        }));
+        lua.set("nothing", serde_function(|()| Ok::<_, String>(())));
+        lua.set("huge", serde_function(|()| Ok::<_, String>(u64::MAX)));
+        lua.set("fail", serde_function(|message: String| Err::<(), _>(message)));
+        let error = |lua: &mut hlua::Lua, code: &str| {
+            lua.execute::<String>(&format!("return select(2, pcall(function() {} end))", code))
+                .unwrap()
+        };
+
+        assert_eq!(5, lua.execute::<u32>("count(); count(); return count(3)").unwrap());
+        assert_eq!("2 4", lua.execute::<String>("
+            local x, y = split(2, { x = 1, y = 2 })
+            return x .. ' ' .. y
+        ").unwrap());
+        assert_eq!(6, lua.execute::<u32>("return sum({ 1, 2, 3 }, 'ignored')").unwrap());
+        assert_eq!(0, lua.execute::<u32>("return select('#', nothing())").unwrap());
+
+        assert_eq!(
+            "bad argument #2 (invalid type: string \"two\", expected f64 at y)",
+            error(&mut lua, "split(2, { x = 1, y = 'two' })")
+        );
+        assert_eq!(
+            "bad argument #2 (invalid type: unit value, expected struct Point)",
+            error(&mut lua, "split(2)")
+        );
+        assert_eq!(
+            "bad argument #1 (invalid type: boolean `true`, expected u32 at [2])",
+            error(&mut lua, "sum({ 1, true })")
+        );
+        assert_eq!("broken", error(&mut lua, "fail('broken')"));
+        assert!(error(&mut lua, "huge()").starts_with("invalid return value ("));
+
+        // Closures capturing over-aligned values see them aligned.
+        #[repr(align(64))]
+        struct Aligned(u32);
+        for value in 0..8 {
+            let aligned = Aligned(value);
+            lua.set("aligned", serde_function(move |()| {
+                assert_eq!(0, &aligned as *const Aligned as usize % 64);
+                Ok::<_, String>(aligned.0)
+            }));
+            assert_eq!(value, lua.execute::<u32>("return aligned()").unwrap());
+        }
+
+        // Scripts can't free closures through the debug library.
+        assert_eq!(
+            "attempt to call a collected or running function",
+            error(&mut lua, "
+                local _, data = debug.getupvalue(count, 1)
+                debug.getmetatable(data).__gc(data)
+                debug.getmetatable(data).__gc(data)
+                count()
+            ")
+        );
+        assert_eq!(false, lua.execute::<bool>("
+            local _, data = debug.getupvalue(sum, 1)
+            return getmetatable(data)
+        ").unwrap());
+
+        // Nor hand them anything else in place of a closure.
+        for code in &[
+            "debug.getmetatable(data).__gc(io.stdout)",
+            "debug.getmetatable(data).__gc(other)",
+            "debug.getmetatable(data).__gc({})"
+        ] {
+            assert_eq!(
+                "bad argument #1 to '__gc' (rust function expected)",
+                error(&mut lua, &format!("
+                    local _, data = debug.getupvalue(sum, 1)
+                    local _, other = debug.getupvalue(split, 1)
+                    {}
+                ", code))
+            );
+        }
+        for replacement in &["io.stdout", "other", "nil"] {
+            lua.set("victim", serde_function(|()| Ok::<_, String>(())));
+            assert_eq!(
+                "attempt to call a function whose closure was replaced",
+                error(&mut lua, &format!("
+                    local _, other = debug.getupvalue(split, 1)
+                    debug.setupvalue(victim, 1, {})
+                    victim()
+                ", replacement))
+            );
+        }
+        assert_eq!(6, lua.execute::<u32>("return sum({ 1, 2, 3 })").unwrap());
+
+        // Closures are dropped along with their lua functions.
+        let captured = Rc::new(());
+        {
+            let mut lua = hlua::Lua::new();
+            let captured = captured.clone();
+            let keep = serde_function(move |()| Ok::<_, String>(Rc::strong_count(&captured)));
+            lua.set("keep", keep);
+            assert_eq!(2, lua.execute::<u32>("return keep()").unwrap());
+        }
+        assert_eq!(1, Rc::strong_count(&captured));
+
+        let mut split: hlua::LuaFunction<_> = lua.get("split").unwrap();
+        let result: (f64, f64) = split.call_serde((3, Point { x: 1.0, y: 2.0 })).unwrap();
+        assert_eq!((3.0, 6.0), result);
+    }
+}
@@ -35,7 +35,9 @@
 //! ```
 //! 
 //! The serialization implemented by this crate doesn't use lua's userdata
-//! system; all types are serialized to plain lua types.
+//! system; all types are serialized to plain lua types. The one exception is
+//! `SerdeLuaUserdata`, which lets lua look into large values without copying
+//! them.
 //! 
 //! ```rust
 //! # extern crate hlua;
@@ -101,6 +103,10 @@
 //!   Unit enum variants are also encoded losslessly, as they are encoded
 //!   as the name of the variant as a string.
 //!
+//!   When exact round trips are needed, `Config::null_sentinel` encodes
+//!   unit values, `None` and unit structs as a reserved string instead of
+//!   `nil`.
+//!
 //! * Integer values are only serialized and deserialized if they can do
 //!   so losslessly. `std::i64::MIN` can be losslessly encoded, but
 //!   `std::i64::MIN + 1` cannot, as it is rounded to a different value.
@@ -109,6 +115,11 @@
 //!   values with too many significant digits (such as `1/3`) would not
 //!   encode. They are cast using rust's `as` operator.
 
+// The crate is written for edition 2015 and keeps its older idioms, such as
+// `?Sized` bounds next to where clauses and `std::f32::NAN`.
+#![allow(clippy::multiple_bound_locations, clippy::legacy_numeric_constants)]
+#![cfg_attr(test, allow(clippy::bool_assert_comparison, clippy::enum_variant_names))]
+
 #[cfg(feature = "base64-bytes")]
 extern crate base64;
 extern crate hlua;
@@ -119,20 +130,162 @@ extern crate serde_bytes;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod config;
 pub mod de;
+pub mod error;
+pub mod function;
+pub mod metatable;
 pub mod ser;
+pub mod source;
+pub mod stack;
+pub mod shared;
+pub mod userdata;
+pub mod value;
 pub mod macros;
 
-pub use de::LuaDeserializer;
+pub use config::Config;
+pub use de::{LuaDeserializer, LuaRefDeserializer};
+pub use error::ErrorKind;
+pub use function::{serde_function, serde_function_with_config};
+pub use function::{CallError, SerdeLuaCall, SerdeLuaFunction};
 pub use ser::LuaSerializer;
+pub use source::de::LuaSourceDeserializer;
+pub use stack::de::LuaStackDeserializer;
+pub use stack::ser::LuaStackSerializer;
+pub use userdata::SerdeLuaUserdata;
+pub use value::Value;
 
 /// Convert a value to an `AnyLuaValue`.
-pub fn to_lua<T: ?Sized>(value: &T) -> ser::SerResult<hlua::AnyLuaValue>
-    where T: serde::Serialize
+pub fn to_lua<T>(value: &T) -> ser::SerResult<hlua::AnyLuaValue>
+    where T: ?Sized + serde::Serialize
 {
     value.serialize(LuaSerializer::new())
 }
 
+/// Convert a value to an `AnyLuaValue` using the provided configuration.
+pub fn to_lua_with_config<T>(
+    value: &T,
+    config: Config
+) -> ser::SerResult<hlua::AnyLuaValue>
+    where T: ?Sized + serde::Serialize
+{
+    value.serialize(LuaSerializer::with_config(config))
+}
+
+/// Convert a value to lua source text: a chunk that returns the value when
+/// run.
+///
+/// ```rust
+/// extern crate serde;
+/// #[macro_use] extern crate serde_derive;
+/// extern crate serde_hlua;
+///
+/// #[derive(Serialize)]
+/// struct Point {
+///     x: f32,
+///     y: f32
+/// }
+///
+/// fn main() {
+///     assert_eq!(
+///         serde_hlua::to_lua_string(&Point { x: 3.0, y: 4.0 }).unwrap(),
+///         "return { x = 3, y = 4 }"
+///     );
+/// }
+/// ```
+pub fn to_lua_string<T>(value: &T) -> ser::SerResult<String>
+    where T: ?Sized + serde::Serialize
+{
+    to_lua_string_with_config(value, Config::new())
+}
+
+/// Convert a value to lua source text using the provided configuration.
+pub fn to_lua_string_with_config<T>(value: &T, config: Config) -> ser::SerResult<String>
+    where T: ?Sized + serde::Serialize
+{
+    to_lua_string_with_format(value, config, config::SourceFormat::new())
+}
+
+/// Write a value as lua source text.
+pub fn to_lua_writer<W, T>(writer: W, value: &T) -> ser::SerResult<()>
+    where W: std::io::Write,
+          T: ?Sized + serde::Serialize
+{
+    to_lua_writer_with_config(writer, value, Config::new())
+}
+
+/// Write a value as lua source text using the provided configuration.
+pub fn to_lua_writer_with_config<W, T>(
+    writer: W,
+    value: &T,
+    config: Config
+) -> ser::SerResult<()>
+    where W: std::io::Write,
+          T: ?Sized + serde::Serialize
+{
+    to_lua_writer_with_format(writer, value, config, config::SourceFormat::new())
+}
+
+/// Convert a value to lua source text using the provided configuration,
+/// laid out according to `format`.
+pub fn to_lua_string_with_format<T>(
+    value: &T,
+    config: Config,
+    format: config::SourceFormat
+) -> ser::SerResult<String>
+    where T: ?Sized + serde::Serialize
+{
+    let mut source = Vec::new();
+    to_lua_writer_with_format(&mut source, value, config, format)?;
+    Ok(String::from_utf8(source).expect("lua source text is always UTF-8"))
+}
+
+/// Write a value as lua source text using the provided configuration, laid
+/// out according to `format`.
+pub fn to_lua_writer_with_format<W, T>(
+    writer: W,
+    value: &T,
+    config: Config,
+    format: config::SourceFormat
+) -> ser::SerResult<()>
+    where W: std::io::Write,
+          T: ?Sized + serde::Serialize
+{
+    source::ser::write_chunk(writer, &to_lua_with_config(value, config)?, format)
+}
+
+/// Push a value onto the lua stack, without building an `AnyLuaValue`.
+///
+/// On failure, the stack is left unchanged.
+pub fn push_to_lua<'lua, L, T>(
+    value: &T,
+    lua: L
+) -> Result<hlua::PushGuard<L>, (ser::LuaSerializeError, L)>
+    where L: hlua::AsMutLua<'lua>,
+          T: ?Sized + serde::Serialize
+{
+    push_to_lua_with_config(value, lua, Config::new())
+}
+
+/// Push a value onto the lua stack using the provided configuration,
+/// without building an `AnyLuaValue`.
+///
+/// On failure, the stack is left unchanged.
+pub fn push_to_lua_with_config<'lua, L, T>(
+    value: &T,
+    mut lua: L,
+    config: Config
+) -> Result<hlua::PushGuard<L>, (ser::LuaSerializeError, L)>
+    where L: hlua::AsMutLua<'lua>,
+          T: ?Sized + serde::Serialize
+{
+    let result = LuaStackSerializer::with_config(&mut lua, config).push_root(value);
+    match result {
+        Ok(()) => Ok(unsafe { hlua::PushGuard::new(lua, 1) }),
+        Err(error) => Err((error, lua))
+    }
+}
+
 /// Convert a value from an `AnyLuaValue`.
 pub fn from_lua<'de, T>(value: hlua::AnyLuaValue) -> de::DeResult<T>
     where T: serde::Deserialize<'de>
@@ -140,6 +293,116 @@ pub fn from_lua<'de, T>(value: hlua::AnyLuaValue) -> de::DeResult<T>
     T::deserialize(LuaDeserializer::new(value))
 }
 
+/// Convert a value from an `AnyLuaValue` using the provided configuration.
+pub fn from_lua_with_config<'de, T>(
+    value: hlua::AnyLuaValue,
+    config: Config
+) -> de::DeResult<T>
+    where T: serde::Deserialize<'de>
+{
+    T::deserialize(LuaDeserializer::with_config(value, config))
+}
+
+/// Convert a value from a borrowed `AnyLuaValue`, without copying it.
+///
+/// Strings in the result can borrow from the lua data.
+pub fn from_lua_ref<'a, T>(value: &'a hlua::AnyLuaValue) -> de::DeResult<T>
+    where T: serde::Deserialize<'a>
+{
+    T::deserialize(LuaRefDeserializer::new(value))
+}
+
+/// Convert a value from a borrowed `AnyLuaValue` using the provided
+/// configuration, without copying it.
+pub fn from_lua_ref_with_config<'a, T>(
+    value: &'a hlua::AnyLuaValue,
+    config: Config
+) -> de::DeResult<T>
+    where T: serde::Deserialize<'a>
+{
+    T::deserialize(LuaRefDeserializer::with_config(value, config))
+}
+
+/// Convert a value from lua source text, which is parsed without being run.
+///
+/// See `LuaSourceDeserializer` for the subset of lua that is accepted. The
+/// source is parsed into an `AnyLuaValue` first, so the target type can't
+/// borrow from it.
+pub fn from_lua_str<T>(source: &str) -> de::DeResult<T>
+    where T: serde::de::DeserializeOwned
+{
+    from_lua_str_with_config(source, Config::new())
+}
+
+/// Convert a value from lua source text using the provided configuration.
+pub fn from_lua_str_with_config<T>(source: &str, config: Config) -> de::DeResult<T>
+    where T: serde::de::DeserializeOwned
+{
+    T::deserialize(LuaSourceDeserializer::with_config(source, config)?)
+}
+
+/// Convert a value from lua source text read from `reader`, which is parsed
+/// without being run.
+pub fn from_lua_reader<R, T>(reader: R) -> de::DeResult<T>
+    where R: std::io::Read,
+          T: serde::de::DeserializeOwned
+{
+    from_lua_reader_with_config(reader, Config::new())
+}
+
+/// Convert a value from lua source text read from `reader` using the
+/// provided configuration.
+pub fn from_lua_reader_with_config<R, T>(mut reader: R, config: Config) -> de::DeResult<T>
+    where R: std::io::Read,
+          T: serde::de::DeserializeOwned
+{
+    let mut source = Vec::new();
+    reader.read_to_end(&mut source).map_err(ErrorKind::from)?;
+    T::deserialize(LuaSourceDeserializer::with_config(&source, config)?)
+}
+
+/// Convert a value from the value at `index` on the lua stack, without
+/// building an `AnyLuaValue`.
+///
+/// Only the parts of the lua value that the target type needs are read.
+/// Strings are copied out of lua, so the target type can't borrow from it.
+pub fn from_lua_stack<'lua, L, T>(lua: &mut L, index: i32) -> de::DeResult<T>
+    where L: hlua::AsMutLua<'lua>,
+          T: serde::de::DeserializeOwned
+{
+    from_lua_stack_with_config(lua, index, Config::new())
+}
+
+/// Convert a value from the value at `index` on the lua stack using the
+/// provided configuration, without building an `AnyLuaValue`.
+pub fn from_lua_stack_with_config<'lua, L, T>(
+    lua: &mut L,
+    index: i32,
+    config: Config
+) -> de::DeResult<T>
+    where L: hlua::AsMutLua<'lua>,
+          T: serde::de::DeserializeOwned
+{
+    let _scope = if config.share_tables { Some(shared::Scope::enter()) } else { None };
+    T::deserialize(LuaStackDeserializer::with_config(lua, index, config))
+}
+
+/// Convert a value to a `Value`.
+pub fn to_value<T>(value: &T) -> ser::SerResult<Value>
+    where T: ?Sized + serde::Serialize
+{
+    to_lua(value).map(Value::from)
+}
+
+/// Convert a value from a `Value`.
+///
+/// The target type can't borrow from the value, which is consumed.
+pub fn from_value<T>(value: Value) -> de::DeResult<T>
+    where T: serde::de::DeserializeOwned
+{
+    from_lua(value.into())
+}
+
 /// Implements `Push` for any type which is `Serialize`.
 ///
 /// This makes it easy to call lua functions with rust structures:
@@ -173,7 +436,7 @@ pub fn from_lua<'de, T>(value: hlua::AnyLuaValue) -> de::DeResult<T>
 /// ```
 ///
 /// This can be made even more ergonomic by implementing `Push` for your
-/// type in terms of `SerdeLuaPush`. The macro `serde_lua_impl_read!` does
+/// type in terms of `SerdeLuaPush`. The macro `serde_hlua_impl_push!` does
 /// this automatically.
 #[derive(Default)]
 pub struct SerdeLuaPush<T: serde::Serialize>(pub T);
@@ -186,11 +449,7 @@ impl<'lua, L, T> hlua::Push<L> for SerdeLuaPush<T>
 
     #[inline]
     fn push_to_lua(self, lua: L) -> Result<hlua::PushGuard<L>, (ser::LuaSerializeError, L)> {
-        match to_lua(&self.0) {
-            Ok(any) => hlua::Push::push_to_lua(any, lua)
-                .map_err(|_| unreachable!()),
-            Err(error) => Err((error, lua))
-        }
+        push_to_lua(&self.0, lua)
     }
 }
 
@@ -239,9 +498,19 @@ impl<'lua, L, T> hlua::PushOne<L> for SerdeLuaPush<T>
 /// }
 /// ```
 ///
-/// This can be made even more ergonomic by implementing `Push` for your
-/// type in terms of `SerdeLuaPush`. The macro `serde_lua_impl_read!` does
+/// This can be made even more ergonomic by implementing `LuaRead` for your
+/// type in terms of `SerdeLuaRead`. The macro `serde_hlua_impl_read!` does
 /// this automatically.
+///
+/// When an argument can't be read, hlua only reports that the arguments
+/// didn't match. Functions made with `serde_function` report what was wrong
+/// with which argument instead.
+///
+/// Values are read straight from the lua stack as `from_lua_stack` reads
+/// them, so struct fields are looked up by name and entries that aren't
+/// fields are never seen, even with `#[serde(deny_unknown_fields)]`. Types
+/// that need such entries rejected can implement `LuaRead` themselves with
+/// `from_lua_stack_with_config` and `Config::read_all_fields`.
 #[derive(Default)]
 pub struct SerdeLuaRead<T>(pub T)
     where T: for<'de> serde::Deserialize<'de>;
@@ -252,19 +521,9 @@ impl<'lua, L, T> hlua::LuaRead<L> for SerdeLuaRead<T>
 {
     #[inline]
     fn lua_read_at_position(mut lua: L, index: i32) -> Result<Self, L> {
-        {
-            let any_maybe = hlua::AnyLuaValue::lua_read_at_position(
-                &mut lua as &mut dyn hlua::AsMutLua<'lua>,
-                index
-            );
-            match any_maybe {
-                Ok(any) => match from_lua::<T>(any) {
-                    Ok(value) => return Ok(SerdeLuaRead(value)),
-                    Err(_) => {}
-                },
-                Err(_) => {}
-            };
+        match from_lua_stack::<L, T>(&mut lua, index) {
+            Ok(value) => Ok(SerdeLuaRead(value)),
+            Err(_) => Err(lua)
         }
-        Err(lua)
     }
 }
@@ -3,12 +3,47 @@
 /// access these traits through this module; access them through the
 /// `hlua` crate instead.
 pub mod hlua {
-    pub use hlua::{Push, PushOne, PushGuard, LuaRead, AsMutLua};
+    pub use hlua::{Push, PushOne, PushGuard, LuaRead, AsMutLua, AnyLuaValue};
+}
+
+/// Adds an entry to a table built by `lua!`, following the rules of lua's
+/// table constructors: positional entries, which have no key, take
+/// precedence over keyed ones with the same index, wherever they appear.
+/// `length` counts the positional entries so far. Not public API.
+#[doc(hidden)]
+pub fn insert(
+    entries: &mut Vec<(hlua::AnyLuaValue, hlua::AnyLuaValue)>,
+    key: Option<hlua::AnyLuaValue>,
+    value: hlua::AnyLuaValue,
+    length: &mut f64
+) {
+    let key = match key {
+        None => {
+            *length += 1.0;
+            hlua::AnyLuaValue::LuaNumber(*length)
+        },
+        Some(hlua::AnyLuaValue::LuaNil) => panic!("table index is nil"),
+        Some(hlua::AnyLuaValue::LuaNumber(number)) if number.is_nan() => {
+            panic!("table index is NaN")
+        },
+        Some(hlua::AnyLuaValue::LuaNumber(number))
+            if number >= 1.0 && number <= *length && number.fract() == 0.0 => return,
+        Some(key) => key
+    };
+    entries.retain(|entry| entry.0 != key);
+    if value != hlua::AnyLuaValue::LuaNil {
+        entries.push((key, value));
+    }
 }
 
 /// Writes a `Push` impl and a `PushOne` impl for any type which is
 /// `Serialize`.
 ///
+/// Generic types are written as `impl<...> for Type<...>`, optionally
+/// followed by a where clause, with the bounds that make them `Serialize`;
+/// see `serde_hlua_impl!`. The lifetime `'lua` and the type parameter `L`
+/// are used by the impls, so they can't be used here.
+///
 /// ```rust
 /// extern crate hlua;
 /// extern crate serde;
@@ -38,9 +73,10 @@ pub mod hlua {
 /// ```
 #[macro_export]
 macro_rules! serde_hlua_impl_push {
-    ($type: ty) => {
-        impl<'lua, L> $crate::macros::hlua::Push<L> for $type
-            where L: $crate::macros::hlua::AsMutLua<'lua>
+    (@impl [$($lifetimes:tt)*] [$($params:tt)*] $type:ty [$($bounds:tt)*]) => {
+        impl<$($lifetimes)* 'lua, L, $($params)*> $crate::macros::hlua::Push<L> for $type
+            where L: $crate::macros::hlua::AsMutLua<'lua>,
+                  $($bounds)*
         {
             type Err = $crate::ser::LuaSerializeError;
 
@@ -56,15 +92,25 @@ macro_rules! serde_hlua_impl_push {
             }
         }
 
-        impl<'lua, L> $crate::macros::hlua::PushOne<L> for $type
-            where L: $crate::macros::hlua::AsMutLua<'lua>
+        impl<$($lifetimes)* 'lua, L, $($params)*> $crate::macros::hlua::PushOne<L> for $type
+            where L: $crate::macros::hlua::AsMutLua<'lua>,
+                  $($bounds)*
         {
         }
-    }
+    };
+    (impl < $($generics:tt)+) => {
+        $crate::__serde_hlua_generics!(serde_hlua_impl_push [] $($generics)+);
+    };
+    ($type: ty) => {
+        $crate::serde_hlua_impl_push!(@impl [] [] $type []);
+    };
 }
 
 /// Writes a `LuaRead` impl for any type which is `Deserialize`.
 ///
+/// Generic types are written as for `serde_hlua_impl_push!`, with the
+/// bounds that make them `DeserializeOwned`.
+///
 /// ```rust
 /// extern crate hlua;
 /// extern crate serde;
@@ -99,15 +145,297 @@ macro_rules! serde_hlua_impl_push {
 /// ```
 #[macro_export]
 macro_rules! serde_hlua_impl_read {
-    ($type: ty) => {
-        impl<'lua, L> $crate::macros::hlua::LuaRead<L> for $type
-            where L: $crate::macros::hlua::AsMutLua<'lua>
+    (@impl [$($lifetimes:tt)*] [$($params:tt)*] $type:ty [$($bounds:tt)*]) => {
+        impl<$($lifetimes)* 'lua, L, $($params)*> $crate::macros::hlua::LuaRead<L> for $type
+            where L: $crate::macros::hlua::AsMutLua<'lua>,
+                  $($bounds)*
         {
             #[inline]
-            fn lua_read_at_position(mut lua: L, index: i32) -> Result<Self, L> {
+            fn lua_read_at_position(lua: L, index: i32) -> Result<Self, L> {
                 $crate::SerdeLuaRead::lua_read_at_position(lua, index)
                     .map(|wrapper| wrapper.0)
             }
         }
+    };
+    (impl < $($generics:tt)+) => {
+        $crate::__serde_hlua_generics!(serde_hlua_impl_read [] $($generics)+);
+    };
+    ($type: ty) => {
+        $crate::serde_hlua_impl_read!(@impl [] [] $type []);
+    };
+}
+
+/// Writes `Push`, `PushOne` and `LuaRead` impls for any type which is
+/// `Serialize` and `Deserialize`, as `serde_hlua_impl_push!` and
+/// `serde_hlua_impl_read!` do.
+///
+/// ```rust
+/// extern crate hlua;
+/// extern crate serde;
+/// #[macro_use] extern crate serde_derive;
+/// #[macro_use] extern crate serde_hlua;
+///
+/// use std::borrow::Cow;
+///
+/// use serde::Serialize;
+/// use serde::de::DeserializeOwned;
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Tagged<T> {
+///     tag: String,
+///     value: T
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Event<'a> {
+///     name: Cow<'a, str>
+/// }
+///
+/// serde_hlua_impl!(impl<T> for Tagged<T> where T: Serialize + DeserializeOwned);
+/// serde_hlua_impl!(impl<'a> for Event<'a>);
+///
+/// fn main() {
+///     let mut lua = hlua::Lua::new();
+///     lua.checked_set("tagged", Tagged { tag: "size".to_owned(), value: 3u32 }).unwrap();
+///     lua.checked_set("event", Event { name: Cow::Borrowed("click") }).unwrap();
+///
+///     let tagged: Tagged<u32> = lua.execute("return tagged").unwrap();
+///     assert_eq!(("size", 3), (&tagged.tag[..], tagged.value));
+///     let event: Event = lua.execute("return event").unwrap();
+///     assert_eq!("click", event.name);
+/// }
+/// ```
+#[macro_export]
+macro_rules! serde_hlua_impl {
+    ($($input:tt)+) => {
+        $crate::serde_hlua_impl_push!($($input)+);
+        $crate::serde_hlua_impl_read!($($input)+);
+    };
+}
+
+/// Splits the generic parameters given to the impl macros into lifetimes
+/// and other parameters, and passes them on to the `@impl` rule of the
+/// macro named by `$callback`. Not public API.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __serde_hlua_generics {
+    (@params $callback:ident [$($lifetimes:tt)*] [$($params:tt)*]
+        > for $type:ty $(where $($bounds:tt)*)?) => {
+        $crate::$callback!(@impl [$($lifetimes)*] [$($params)*] $type [$($($bounds)*)?]);
+    };
+    // `>>` is a single token, which closes a bound such as `Into<Vec<u8>>`
+    // as well as the parameters.
+    (@params $callback:ident [$($lifetimes:tt)*] [$($params:tt)*]
+        >> for $type:ty $(where $($bounds:tt)*)?) => {
+        $crate::$callback!(@impl [$($lifetimes)*] [$($params)* >] $type [$($($bounds)*)?]);
+    };
+    (@params $callback:ident [$($lifetimes:tt)*] [$($params:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::__serde_hlua_generics!(
+            @params $callback [$($lifetimes)*] [$($params)* $next] $($rest)*
+        );
+    };
+    ($callback:ident [$($lifetimes:tt)*]
+        $lifetime:lifetime $(: $first:lifetime $(+ $other:lifetime)*)? , $($rest:tt)*) => {
+        $crate::__serde_hlua_generics!(
+            $callback [$($lifetimes)* $lifetime $(: $first $(+ $other)*)? ,] $($rest)*
+        );
+    };
+    ($callback:ident [$($lifetimes:tt)*]
+        $lifetime:lifetime $(: $first:lifetime $(+ $other:lifetime)*)? > $($rest:tt)*) => {
+        $crate::__serde_hlua_generics!(
+            $callback [$($lifetimes)* $lifetime $(: $first $(+ $other)*)? ,] > $($rest)*
+        );
+    };
+    ($callback:ident [$($lifetimes:tt)*] $($rest:tt)*) => {
+        $crate::__serde_hlua_generics!(@params $callback [$($lifetimes)*] [] $($rest)*);
+    };
+}
+
+/// Builds an `AnyLuaValue` table using lua's table constructor syntax.
+///
+/// Entries can be written as `name = value`, `[key] = value` or just
+/// `value`, in which case they are numbered from 1. Values can be `nil`,
+/// nested tables in braces, or any rust expression that implements
+/// `Serialize`, which is converted with `to_lua`. Keys in brackets are also
+/// rust expressions. As in lua, `nil` values leave no entry, numbered
+/// entries take precedence over keyed ones with the same index, and
+/// otherwise later entries replace earlier ones with the same key.
+///
+/// ```rust
+/// extern crate hlua;
+/// extern crate serde;
+/// #[macro_use] extern crate serde_derive;
+/// #[macro_use] extern crate serde_hlua;
+///
+/// #[derive(Deserialize, PartialEq, Debug)]
+/// struct Point {
+///     x: f32,
+///     y: f32,
+///     tags: Vec<String>
+/// }
+///
+/// fn main() {
+///     let y = 2.5;
+///     let table = lua!{ x = 1, y = y, tags = { "a", "b" } };
+///     assert_eq!(
+///         serde_hlua::from_lua::<Point>(table).unwrap(),
+///         Point { x: 1.0, y: 2.5, tags: vec!["a".to_owned(), "b".to_owned()] }
+///     );
+///
+///     let table = lua!{ [10] = true, ["not an identifier"] = nil };
+///     assert_eq!(
+///         table,
+///         hlua::AnyLuaValue::LuaArray(vec![(
+///             hlua::AnyLuaValue::LuaNumber(10.0),
+///             hlua::AnyLuaValue::LuaBoolean(true)
+///         )])
+///     );
+/// }
+/// ```
+///
+/// Serializing an interpolated expression panics if it fails.
+#[macro_export]
+macro_rules! lua {
+    (@entries $entries:ident $index:ident) => {};
+    (@entries $entries:ident $index:ident $key:ident = $($rest:tt)+) => {
+        $crate::lua!(@value $entries $index (Some(
+            $crate::macros::hlua::AnyLuaValue::LuaString(stringify!($key).to_owned())
+        )) $($rest)+)
+    };
+    (@entries $entries:ident $index:ident [$($key:tt)+] = $($rest:tt)+) => {
+        $crate::lua!(@value $entries $index (Some(
+            $crate::to_lua(&($($key)+)).unwrap()
+        )) $($rest)+)
+    };
+    (@entries $entries:ident $index:ident $($rest:tt)+) => {
+        $crate::lua!(@value $entries $index (None) $($rest)+)
+    };
+    (@value $entries:ident $index:ident $key:tt nil $(, $($rest:tt)*)?) => {
+        $crate::macros::insert(
+            &mut $entries,
+            $key,
+            $crate::macros::hlua::AnyLuaValue::LuaNil,
+            &mut $index
+        );
+        $crate::lua!(@entries $entries $index $($($rest)*)?);
+    };
+    (@value $entries:ident $index:ident $key:tt { $($table:tt)* } $(, $($rest:tt)*)?) => {
+        $crate::macros::insert(&mut $entries, $key, $crate::lua!{ $($table)* }, &mut $index);
+        $crate::lua!(@entries $entries $index $($($rest)*)?);
+    };
+    (@value $entries:ident $index:ident $key:tt $value:expr $(, $($rest:tt)*)?) => {
+        $crate::macros::insert(
+            &mut $entries,
+            $key,
+            $crate::to_lua(&$value).unwrap(),
+            &mut $index
+        );
+        $crate::lua!(@entries $entries $index $($($rest)*)?);
+    };
+    ($($table:tt)*) => {{
+        #[allow(unused_mut)]
+        let mut entries = Vec::new();
+        #[allow(unused_mut, unused_variables)]
+        let mut index = 0.0;
+        $crate::lua!(@entries entries index $($table)*);
+        $crate::macros::hlua::AnyLuaValue::LuaArray(entries)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use hlua::AnyLuaValue;
+
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+
+    use std::borrow::Cow;
+    use std::collections::BTreeMap;
+
+    use ::{from_lua, Value};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Wrapper<T>(T);
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Event<'a, T> {
+        name: Cow<'a, str>,
+        data: Vec<T>
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Nested<T>(Wrapper<T>);
+
+    serde_hlua_impl_push!(impl<T: Serialize> for Wrapper<T>);
+    serde_hlua_impl_read!(impl<T: DeserializeOwned> for Wrapper<T>);
+    serde_hlua_impl!(impl<'a, T> for Event<'a, T> where T: Serialize + DeserializeOwned,);
+    serde_hlua_impl!(impl<T: Serialize + PartialEq<T>> for Nested<T> where T: DeserializeOwned);
+
+    fn procure(value: &str) -> Value {
+        let mut lua = hlua::Lua::new();
+        lua.execute::<AnyLuaValue>(&format!("return {}", value)).unwrap().into()
+    }
+
+    /// Compares tables without regard to the order of their entries.
+    fn assert_same(left: AnyLuaValue, right: Value) {
+        assert_eq!(Value::from(left).sorted(), right.sorted());
+    }
+
+    #[test]
+    fn constructors() {
+        assert_same(lua!{}, procure("{}"));
+        assert_same(
+            lua!{ x = 1, y = { "a", "b" }, [10] = true },
+            procure("{ x = 1, y = { 'a', 'b' }, [10] = true }")
+        );
+        assert_same(
+            lua!{ "a", nil, "c", ["key with spaces"] = -0.5, [1.5] = {}, },
+            procure("{ 'a', nil, 'c', ['key with spaces'] = -0.5, [1.5] = {} }")
+        );
+        assert_same(lua!{ a = 1, a = nil, b = 2, b = 3 }, procure("{ b = 3 }"));
+        assert_same(lua!{ "a", [1] = "x" }, procure("{ 'a', [1] = 'x' }"));
+        assert_same(
+            lua!{ [2] = "x", [3] = "y", "a", nil, [2] = "z", [4] = "w" },
+            procure("{ [2] = 'x', [3] = 'y', 'a', nil, [2] = 'z', [4] = 'w' }")
+        );
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut map = BTreeMap::new();
+        map.insert("inner", vec![1u8, 2]);
+        let key = "computed";
+        let table = lua!{ map = map, [key] = 1 + 2, none = None::<u32>, vec![true][0] };
+        assert_same(table.clone(), procure("{ map = { inner = { 1, 2 } }, computed = 3, true }"));
+
+        let value: Value = from_lua(table).unwrap();
+        assert_eq!(Some(2.0), value["map"]["inner"][2].as_f64());
+        assert_eq!(Some(3.0), value["computed"].as_f64());
+        assert_eq!(Some(true), value[1].as_bool());
+    }
+
+    #[test]
+    fn generic_impls() {
+        let mut lua = hlua::Lua::new();
+        lua.checked_set("wrapper", Wrapper("a".to_owned())).unwrap();
+        lua.checked_set("event", Event { name: Cow::Borrowed("click"), data: vec![1u8, 2] })
+            .unwrap();
+        lua.checked_set("nested", Nested(Wrapper(true))).unwrap();
+
+        assert_eq!(Wrapper("a".to_owned()), lua.get::<Wrapper<String>, _>("wrapper").unwrap());
+        assert_eq!(
+            Event { name: Cow::Borrowed("click"), data: vec![1u8, 2] },
+            lua.get::<Event<u8>, _>("event").unwrap()
+        );
+        assert_eq!(Nested(Wrapper(true)), lua.get::<Nested<bool>, _>("nested").unwrap());
+        assert_eq!(2, lua.execute::<u32>("return event.data[2]").unwrap());
+        assert!(lua.get::<Event<u8>, _>("wrapper").is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "table index is nil")]
+    fn nil_key() {
+        lua!{ [()] = 1 };
     }
 }
@@ -0,0 +1,234 @@
+//! Attaching lua metatables to the tables that rust types are pushed as.
+//!
+//! Lua code often gives its tables methods through a shared metatable, as
+//! in `setmetatable(t, Vec2)`. A metatable registered here under the serde
+//! name of a type, such as `Point` for a struct or `Shape::Circle` for an
+//! enum variant, is attached to every table that type is pushed as, so that
+//! values coming from rust have the same methods as those built in lua.
+//!
+//! ```rust
+//! extern crate hlua;
+//! extern crate serde;
+//! #[macro_use] extern crate serde_derive;
+//! extern crate serde_hlua;
+//!
+//! use serde_hlua::SerdeLuaPush;
+//!
+//! #[derive(Serialize)]
+//! struct Point {
+//!     x: f64,
+//!     y: f64
+//! }
+//!
+//! fn main() {
+//!     let mut lua = hlua::Lua::new();
+//!     lua.openlibs();
+//!     lua.execute::<()>("
+//!         Vec2 = {}
+//!         Vec2.__index = Vec2
+//!         function Vec2:length()
+//!             return math.sqrt(self.x * self.x + self.y * self.y)
+//!         end
+//!     ").unwrap();
+//!     {
+//!         let mut vec2: hlua::LuaTable<_> = lua.get("Vec2").unwrap();
+//!         serde_hlua::metatable::register(&mut vec2, "Point", -1).unwrap();
+//!     }
+//!
+//!     lua.checked_set("p", SerdeLuaPush(Point { x: 3.0, y: 4.0 })).unwrap();
+//!     assert_eq!(5.0, lua.execute::<f64>("return p:length()").unwrap());
+//! }
+//! ```
+//!
+//! Metatables are attached to the tables of structs, tuple structs, and
+//! struct and tuple variants. They are only attached by the stack
+//! serializer, as used by `push_to_lua`, `SerdeLuaPush` and their variants;
+//! an `AnyLuaValue` can't hold a metatable.
+
+use hlua::AsMutLua;
+use hlua::ffi;
+
+use error::ErrorKind;
+use ser::{LuaSerializeError, SerResult};
+use stack::check_stack;
+use stack::ser::type_name;
+
+// The address of this static is the key of the table of registered
+// metatables in the lua registry.
+static REGISTRY: u8 = 0;
+
+fn registry_key() -> *const ::std::os::raw::c_char {
+    &REGISTRY as *const u8 as *const _
+}
+
+/// Register the table at `index` on the stack of `lua` as the metatable of
+/// the type or enum variant called `name`, replacing any metatable already
+/// registered for it.
+///
+/// Registering `nil` removes the metatable registered for `name`.
+pub fn register<'lua, L>(lua: &mut L, name: &str, index: i32) -> SerResult<()>
+    where L: AsMutLua<'lua>
+{
+    let lua = lua.as_mut_lua().state_ptr();
+    unsafe {
+        let index = ffi::lua_absindex(lua, index);
+        match ffi::lua_type(lua, index) {
+            ffi::LUA_TTABLE | ffi::LUA_TNIL => (),
+            other => return Err(LuaSerializeError(ErrorKind::InvalidType {
+                expected: "a metatable".to_owned(),
+                got: type_name(other).to_owned()
+            }))
+        }
+        check_stack(lua, 3).map_err(LuaSerializeError)?;
+        ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX, registry_key());
+        if ffi::lua_type(lua, -1) != ffi::LUA_TTABLE {
+            ffi::lua_pop(lua, 1);
+            ffi::lua_createtable(lua, 0, 1);
+            ffi::lua_pushvalue(lua, -1);
+            ffi::lua_rawsetp(lua, ffi::LUA_REGISTRYINDEX, registry_key());
+        }
+        ffi::lua_pushlstring(lua, name.as_ptr() as *const _, name.len() as _);
+        ffi::lua_pushvalue(lua, index);
+        ffi::lua_rawset(lua, -3);
+        ffi::lua_pop(lua, 1);
+    }
+    Ok(())
+}
+
+/// Attach the metatable registered for the struct or variant `name`, if
+/// any, to the table on top of the stack.
+pub(crate) fn attach(
+    lua: *mut ffi::lua_State,
+    name: &'static str,
+    variant: Option<&'static str>
+) -> SerResult<()> {
+    unsafe {
+        check_stack(lua, 4).map_err(LuaSerializeError)?;
+        ffi::lua_rawgetp(lua, ffi::LUA_REGISTRYINDEX, registry_key());
+        if ffi::lua_type(lua, -1) != ffi::LUA_TTABLE {
+            ffi::lua_pop(lua, 1);
+            return Ok(());
+        }
+        ffi::lua_pushlstring(lua, name.as_ptr() as *const _, name.len() as _);
+        if let Some(variant) = variant {
+            ffi::lua_pushlstring(lua, b"::".as_ptr() as *const _, 2);
+            ffi::lua_pushlstring(lua, variant.as_ptr() as *const _, variant.len() as _);
+            ffi::lua_concat(lua, 3);
+        }
+        ffi::lua_rawget(lua, -2);
+        if ffi::lua_type(lua, -1) == ffi::LUA_TTABLE {
+            ffi::lua_setmetatable(lua, -3);
+        } else {
+            ffi::lua_pop(lua, 1);
+        }
+        ffi::lua_pop(lua, 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use hlua::{AsLua, LuaTable};
+
+    use std::collections::BTreeMap;
+
+    use ::{push_to_lua_with_config, Config, SerdeLuaPush};
+    use config::EnumRepr;
+    use super::register;
+
+    #[derive(Serialize)]
+    struct Point {
+        x: f64,
+        y: f64
+    }
+
+    #[derive(Serialize)]
+    struct Pair(u32, u32);
+
+    #[derive(Serialize)]
+    #[allow(dead_code)]
+    enum Shape {
+        Circle { radius: f64 },
+        Line(Point, Point),
+        Dot(Point)
+    }
+
+    fn lua() -> hlua::Lua<'static> {
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        lua.execute::<()>("
+            classes = {}
+            for _, name in ipairs({ 'Point', 'Pair', 'Shape::Circle', 'Shape::Line' }) do
+                classes[name] = { __index = { class = name } }
+            end
+        ").unwrap();
+        for name in &["Point", "Pair", "Shape::Circle", "Shape::Line"] {
+            let mut classes: LuaTable<_> = lua.get("classes").unwrap();
+            let mut class: LuaTable<_> = classes.get(*name).unwrap();
+            register(&mut class, name, -1).unwrap();
+        }
+        lua
+    }
+
+    #[test]
+    fn attaches_metatables() {
+        let mut lua = lua();
+        let class = |lua: &mut hlua::Lua, code: &str| lua.execute::<String>(code).unwrap();
+
+        lua.checked_set("point", SerdeLuaPush(Point { x: 1.0, y: 2.0 })).unwrap();
+        lua.checked_set("pair", SerdeLuaPush(Pair(1, 2))).unwrap();
+        lua.checked_set("circle", SerdeLuaPush(Shape::Circle { radius: 1.0 })).unwrap();
+        lua.checked_set("line", SerdeLuaPush(Shape::Line(
+            Point { x: 0.0, y: 0.0 },
+            Point { x: 1.0, y: 1.0 }
+        ))).unwrap();
+        lua.checked_set("dot", SerdeLuaPush(Shape::Dot(Point { x: 0.0, y: 0.0 }))).unwrap();
+        lua.checked_set("map", SerdeLuaPush(BTreeMap::<String, u32>::new())).unwrap();
+        assert_eq!("Point", class(&mut lua, "return point.class"));
+        assert_eq!("Pair", class(&mut lua, "return pair.class"));
+        assert_eq!("Shape::Circle", class(&mut lua, "return circle.Circle.class"));
+        assert_eq!("Shape::Line", class(&mut lua, "return line.Line.class"));
+        assert_eq!("Point", class(&mut lua, "return line.Line[2].class"));
+        assert_eq!("Point", class(&mut lua, "return dot.Dot.class"));
+        assert_eq!(
+            "nil nil",
+            class(&mut lua, "return tostring(getmetatable(dot)) .. ' ' .. tostring(map.class)")
+        );
+
+        // Internally tagged variants are a single table.
+        let internal = Config::new().enum_repr(EnumRepr::Internal { tag: "type" });
+        let state = lua.as_lua().state_ptr();
+        unsafe {
+            let guard = push_to_lua_with_config(&Shape::Circle { radius: 1.0 }, &mut lua, internal)
+                .map_err(|(error, _)| error)
+                .unwrap();
+            guard.forget();
+            hlua::ffi::lua_setglobal(state, b"circle\0".as_ptr() as *const _);
+        }
+        assert_eq!("Shape::Circle", class(&mut lua, "return circle.class"));
+    }
+
+    #[test]
+    fn replaces_and_removes_metatables() {
+        let mut lua = lua();
+        lua.execute::<()>("other = { __index = { class = 'other' } }").unwrap();
+        {
+            let mut other: LuaTable<_> = lua.get("other").unwrap();
+            register(&mut other, "Point", -1).unwrap();
+        }
+        lua.checked_set("point", SerdeLuaPush(Point { x: 1.0, y: 2.0 })).unwrap();
+        assert_eq!("other", lua.execute::<String>("return point.class").unwrap());
+
+        let state = lua.as_lua().state_ptr();
+        unsafe { hlua::ffi::lua_pushnil(state) };
+        register(&mut lua, "Point", -1).unwrap();
+        unsafe { hlua::ffi::lua_pushnumber(state, 1.0) };
+        let error = register(&mut lua, "Point", -1).unwrap_err();
+        assert_eq!("invalid type: number, expected a metatable", error.to_string());
+        unsafe { hlua::ffi::lua_settop(state, 0) };
+
+        lua.checked_set("point", SerdeLuaPush(Point { x: 1.0, y: 2.0 })).unwrap();
+        assert!(lua.execute::<bool>("return getmetatable(point) == nil").unwrap());
+    }
+}
@@ -1,28 +1,49 @@
-
 //! Serialization from rust values to lua values.
 
 use std::error;
 use std::fmt;
-use std::marker::PhantomData;
+use std::io;
 
 #[cfg(feature = "base64-bytes")]
 use base64;
-use hlua::AnyLuaValue;
+use hlua::{AnyLuaString, AnyLuaValue};
 use serde;
 use serde::Serialize;
 use serde::ser::Serializer;
 
-// The phantom data here is just to make the type unconstructable outside of
-// this crate, as we want to be able to potentially add fields in the future
-// without it being a breaking API change.
+use config::{ByteEncoding, Config, EnumRepr, SparseArrays};
+use error::ErrorKind;
+use value;
 
 /// A serializer that converts its input data to an `AnyLuaValue`.
-pub struct LuaSerializer(PhantomData<()>);
+pub struct LuaSerializer {
+    config: Config
+}
 
 impl LuaSerializer {
     /// Return a serializer that can serialize input data to an `AnyLuaValue`.
     pub fn new() -> LuaSerializer {
-        LuaSerializer(PhantomData)
+        LuaSerializer::with_config(Config::new())
+    }
+
+    /// Return a serializer that serializes input data to an `AnyLuaValue`
+    /// using the provided configuration.
+    pub fn with_config(config: Config) -> LuaSerializer {
+        LuaSerializer { config }
+    }
+
+    /// The value that unit values, `None` and unit structs serialize to.
+    fn nil(&self) -> AnyLuaValue {
+        match self.config.null_sentinel {
+            Some(sentinel) => AnyLuaValue::LuaString(sentinel.to_owned()),
+            None => AnyLuaValue::LuaNil
+        }
+    }
+}
+
+impl Default for LuaSerializer {
+    fn default() -> LuaSerializer {
+        LuaSerializer::new()
     }
 }
 
@@ -54,10 +75,10 @@ impl Serializer for LuaSerializer {
     }
 
     fn serialize_i64(self, v: i64) -> SerResult<AnyLuaValue> {
-        if v as f64 as i64 != v {
-            Err(serde::ser::Error::custom(
-                "value cannot be losslessly represented as lua number (f64)"
-            ))
+        // Float to integer casts saturate, so the round trip is checked in a
+        // wider type to catch values that round up past `i64::MAX`.
+        if v as f64 as i128 != v as i128 {
+            Err(LuaSerializeError::lossy_number(v))
         } else {
             Ok(AnyLuaValue::LuaNumber(v as f64))
         }
@@ -76,10 +97,8 @@ impl Serializer for LuaSerializer {
     }
 
     fn serialize_u64(self, v: u64) -> SerResult<AnyLuaValue> {
-        if v as f64 as u64 != v {
-            Err(serde::ser::Error::custom(
-                "value cannot be losslessly represented as lua number (f64)"
-            ))
+        if v as f64 as i128 != v as i128 {
+            Err(LuaSerializeError::lossy_number(v))
         } else {
             Ok(AnyLuaValue::LuaNumber(v as f64))
         }
@@ -96,27 +115,48 @@ impl Serializer for LuaSerializer {
     fn serialize_char(self, v: char) -> SerResult<AnyLuaValue> {
         let mut result = String::new();
         result.push(v);
-        Ok(AnyLuaValue::LuaString(result))
+        self.serialize_str(&result)
     }
 
     fn serialize_str(self, v: &str) -> SerResult<AnyLuaValue> {
+        if self.config.null_sentinel == Some(v) {
+            return Err(LuaSerializeError(ErrorKind::InvalidValue {
+                expected: "a string other than the null sentinel".to_owned(),
+                got: format!("string {:?}", v)
+            }));
+        }
         Ok(AnyLuaValue::LuaString(v.to_owned()))
     }
 
-    #[cfg(not(feature = "base64-bytes"))]
-    fn serialize_bytes(self, v: &[u8]) -> SerResult<AnyLuaValue> {
-        Err(LuaSerializeError::custom(
-            "cannot serialize bytes; compile with 'base64-bytes'"
-        ))
-    }
-
-    #[cfg(feature = "base64-bytes")]
     fn serialize_bytes(self, v: &[u8]) -> SerResult<AnyLuaValue> {
-        Ok(AnyLuaValue::LuaString(base64::encode(v)))
+        Ok(match self.config.byte_encoding {
+            ByteEncoding::Raw => AnyLuaValue::LuaAnyString(AnyLuaString(v.to_owned())),
+            #[cfg(feature = "base64-bytes")]
+            ByteEncoding::Base64 => AnyLuaValue::LuaString(base64::encode(v)),
+            #[cfg(feature = "base64-bytes")]
+            ByteEncoding::Base64UrlSafe => AnyLuaValue::LuaString(
+                base64::encode_config(v, base64::URL_SAFE)
+            ),
+            ByteEncoding::Hex => {
+                const DIGITS: &[u8; 16] = b"0123456789abcdef";
+                let mut hex = String::with_capacity(v.len() * 2);
+                for &byte in v {
+                    hex.push(DIGITS[(byte >> 4) as usize] as char);
+                    hex.push(DIGITS[(byte & 0xf) as usize] as char);
+                }
+                AnyLuaValue::LuaString(hex)
+            },
+            ByteEncoding::Array => AnyLuaValue::LuaArray(
+                v.iter().enumerate().map(|(index, &byte)| (
+                    AnyLuaValue::LuaNumber((index + 1) as f64),
+                    AnyLuaValue::LuaNumber(byte as f64)
+                )).collect()
+            )
+        })
     }
 
     fn serialize_none(self) -> SerResult<AnyLuaValue> {
-        Ok(AnyLuaValue::LuaNil)
+        Ok(self.nil())
     }
 
     fn serialize_some<T: ?Sized>(self, value: &T) -> SerResult<AnyLuaValue>
@@ -126,11 +166,11 @@ impl Serializer for LuaSerializer {
     }
 
     fn serialize_unit(self) -> SerResult<AnyLuaValue> {
-        Ok(AnyLuaValue::LuaNil)
+        Ok(self.nil())
     }
 
     fn serialize_unit_struct(self, _name: &'static str) -> SerResult<AnyLuaValue> {
-        Ok(AnyLuaValue::LuaNil)
+        Ok(self.nil())
     }
 
     fn serialize_unit_variant(
@@ -139,16 +179,31 @@ impl Serializer for LuaSerializer {
         _variant_index: u32,
         variant: &'static str
     ) -> SerResult<AnyLuaValue> {
-        Ok(AnyLuaValue::LuaString(variant.to_owned()))
+        let variant = AnyLuaValue::LuaString(variant.to_owned());
+        Ok(match self.config.enum_repr {
+            EnumRepr::External => variant,
+            EnumRepr::Internal { tag } | EnumRepr::Adjacent { tag, .. } => {
+                AnyLuaValue::LuaArray(vec![
+                    (AnyLuaValue::LuaString(tag.to_owned()), variant)
+                ])
+            },
+            EnumRepr::Array => AnyLuaValue::LuaArray(vec![
+                (AnyLuaValue::LuaNumber(1.0), variant)
+            ])
+        })
     }
 
     fn serialize_newtype_struct<T: ?Sized>(
         self,
-        _name: &'static str,
+        name: &'static str,
         value: &T
     ) -> SerResult<AnyLuaValue>
         where T: serde::Serialize
     {
+        if name == value::BYTES {
+            let config = self.config.byte_encoding(ByteEncoding::Raw);
+            return value.serialize(LuaSerializer::with_config(config));
+        }
         value.serialize(self)
     }
 
@@ -161,20 +216,19 @@ impl Serializer for LuaSerializer {
     ) -> SerResult<AnyLuaValue>
         where T: serde::Serialize
     {
-        Ok(AnyLuaValue::LuaArray(vec![
-            (AnyLuaValue::LuaString(variant.to_owned()), value.serialize(self)?)
-        ]))
+        let config = self.config;
+        tag_variant(&config, variant, value.serialize(self)?)
     }
 
     fn serialize_seq(self, len: Option<usize>) -> SerResult<LuaSerializeSeq> {
         Ok(LuaSerializeSeq(match len {
             Some(len) => Vec::with_capacity(len),
             None => Vec::new()
-        }))
+        }, self.config))
     }
 
     fn serialize_tuple(self, len: usize) -> SerResult<LuaSerializeSeq> {
-        Ok(LuaSerializeSeq(Vec::with_capacity(len)))
+        Ok(LuaSerializeSeq(Vec::with_capacity(len), self.config))
     }
 
     fn serialize_tuple_struct(
@@ -182,7 +236,7 @@ impl Serializer for LuaSerializer {
         _name: &'static str,
         len: usize
     ) -> SerResult<LuaSerializeSeq> {
-        Ok(LuaSerializeSeq(Vec::with_capacity(len)))
+        Ok(LuaSerializeSeq(Vec::with_capacity(len), self.config))
     }
 
     fn serialize_tuple_variant(
@@ -192,18 +246,21 @@ impl Serializer for LuaSerializer {
         variant: &'static str,
         len: usize
     ) -> SerResult<LuaSerializeTupleVariant> {
-        Ok(LuaSerializeTupleVariant(variant, LuaSerializeSeq(Vec::with_capacity(len))))
+        Ok(LuaSerializeTupleVariant(
+            variant,
+            LuaSerializeSeq(Vec::with_capacity(len), self.config)
+        ))
     }
 
     fn serialize_map(self, len: Option<usize>) -> SerResult<LuaSerializeMap> {
         Ok(LuaSerializeMap(match len {
             Some(len) => Vec::with_capacity(len),
             None => Vec::new()
-        }))
+        }, self.config))
     }
 
     fn serialize_struct(self, _name: &'static str, len: usize) -> SerResult<LuaSerializeMap> {
-        Ok(LuaSerializeMap(Vec::with_capacity(len)))
+        Ok(LuaSerializeMap(Vec::with_capacity(len), self.config))
     }
 
     fn serialize_struct_variant(
@@ -213,11 +270,54 @@ impl Serializer for LuaSerializer {
         variant: &'static str,
         len: usize
     ) -> SerResult<LuaSerializeStructVariant> {
-        Ok(LuaSerializeStructVariant(variant, LuaSerializeMap(Vec::with_capacity(len))))
+        Ok(LuaSerializeStructVariant(
+            variant,
+            LuaSerializeMap(Vec::with_capacity(len), self.config)
+        ))
     }
 }
 
-pub struct LuaSerializeSeq(Vec<(AnyLuaValue, AnyLuaValue)>);
+/// Wrap the payload of a non-unit enum variant according to the configured
+/// enum representation.
+fn tag_variant(
+    config: &Config,
+    variant: &'static str,
+    payload: AnyLuaValue
+) -> SerResult<AnyLuaValue> {
+    let variant = AnyLuaValue::LuaString(variant.to_owned());
+    Ok(match config.enum_repr {
+        EnumRepr::External => AnyLuaValue::LuaArray(vec![(variant, payload)]),
+        EnumRepr::Internal { tag } => match payload {
+            AnyLuaValue::LuaArray(mut entries) => {
+                if entries.iter().any(|(key, _)| match key {
+                    AnyLuaValue::LuaString(key) => key == tag,
+                    _ => false
+                }) {
+                    return Err(LuaSerializeError(ErrorKind::Custom(format!(
+                        "variant payload already contains the tag field '{}'",
+                        tag
+                    ))));
+                }
+                entries.insert(0, (AnyLuaValue::LuaString(tag.to_owned()), variant));
+                AnyLuaValue::LuaArray(entries)
+            },
+            _ => return Err(LuaSerializeError(ErrorKind::InvalidType {
+                expected: "a table for an internally tagged variant".to_owned(),
+                got: type_name(&payload).to_owned()
+            }))
+        },
+        EnumRepr::Adjacent { tag, content } => AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaString(tag.to_owned()), variant),
+            (AnyLuaValue::LuaString(content.to_owned()), payload)
+        ]),
+        EnumRepr::Array => AnyLuaValue::LuaArray(vec![
+            (AnyLuaValue::LuaNumber(1.0), variant),
+            (AnyLuaValue::LuaNumber(2.0), payload)
+        ])
+    })
+}
+
+pub struct LuaSerializeSeq(Vec<(AnyLuaValue, AnyLuaValue)>, Config);
 
 impl serde::ser::SerializeSeq for LuaSerializeSeq {
     type Ok = AnyLuaValue;
@@ -229,12 +329,21 @@ impl serde::ser::SerializeSeq for LuaSerializeSeq {
         let index = (self.0.len() + 1) as f64;
         self.0.push((
             AnyLuaValue::LuaNumber(index),
-            value.serialize(LuaSerializer::new())?
+            value.serialize(LuaSerializer::with_config(self.1))?
         ));
         Ok(())
     }
 
-    fn end(self) -> SerResult<AnyLuaValue> {
+    fn end(mut self) -> SerResult<AnyLuaValue> {
+        if self.1.sparse_arrays == SparseArrays::Packed &&
+            self.0.iter().any(|(_, value)| *value == AnyLuaValue::LuaNil)
+        {
+            let len = self.0.len() as f64;
+            self.0.push((
+                AnyLuaValue::LuaString("n".to_owned()),
+                AnyLuaValue::LuaNumber(len)
+            ));
+        }
         Ok(AnyLuaValue::LuaArray(self.0))
     }
 }
@@ -282,14 +391,33 @@ impl serde::ser::SerializeTupleVariant for LuaSerializeTupleVariant {
     }
 
     fn end(self) -> SerResult<AnyLuaValue> {
-        Ok(AnyLuaValue::LuaArray(vec![(
-            AnyLuaValue::LuaString(self.0.to_owned()),
-            serde::ser::SerializeSeq::end(self.1)?
-        )]))
+        let config = self.1 .1;
+        tag_variant(&config, self.0, serde::ser::SerializeSeq::end(self.1)?)
     }
 }
 
-pub struct LuaSerializeMap(Vec<(AnyLuaValue, AnyLuaValue)>);
+pub struct LuaSerializeMap(Vec<(AnyLuaValue, AnyLuaValue)>, Config);
+
+impl LuaSerializeMap {
+    /// Serialize a map key, rejecting keys that lua tables can't hold.
+    fn key<T: ?Sized>(&self, key: &T) -> SerResult<AnyLuaValue>
+        where T: Serialize
+    {
+        // Keys are always externally tagged, so that unit variants used as
+        // keys remain plain strings.
+        let config = self.1.enum_repr(EnumRepr::External);
+        let key = key.serialize(LuaSerializer::with_config(config))?;
+        match key {
+            AnyLuaValue::LuaNumber(number) if number.is_nan() => Err(
+                LuaSerializeError(ErrorKind::InvalidKey("NaN".to_owned()))
+            ),
+            AnyLuaValue::LuaNil => Err(
+                LuaSerializeError(ErrorKind::InvalidKey("nil".to_owned()))
+            ),
+            key => Ok(key)
+        }
+    }
+}
 
 impl serde::ser::SerializeMap for LuaSerializeMap {
     type Ok = AnyLuaValue;
@@ -298,17 +426,7 @@ impl serde::ser::SerializeMap for LuaSerializeMap {
     fn serialize_key<T: ?Sized>(&mut self, key: &T) -> SerResult<()>
         where T: Serialize
     {
-
-        let key = key.serialize(LuaSerializer::new())?;
-        match &key {
-            &AnyLuaValue::LuaNumber(number) if number != number => return Err(
-                serde::ser::Error::custom(&"unserializable key NaN")
-            ),
-            &AnyLuaValue::LuaNil => return Err(serde::ser::Error::custom(
-                &"unserializable key nil"
-            )),
-            _ => {}
-        }
+        let key = self.key(key)?;
         self.0.push((key, AnyLuaValue::LuaNil));
         Ok(())
     }
@@ -317,7 +435,7 @@ impl serde::ser::SerializeMap for LuaSerializeMap {
         where T: Serialize
     {
         let len = self.0.len();
-        self.0[len - 1].1 = value.serialize(LuaSerializer::new())?;
+        self.0[len - 1].1 = value.serialize(LuaSerializer::with_config(self.1))?;
         Ok(())
     }
 
@@ -329,19 +447,10 @@ impl serde::ser::SerializeMap for LuaSerializeMap {
         where K: Serialize,
               V: Serialize
     {
-        let key = key.serialize(LuaSerializer::new())?;
-        match &key {
-            &AnyLuaValue::LuaNumber(number) if number != number => return Err(
-                serde::ser::Error::custom(&"unserializable key NaN")
-            ),
-            &AnyLuaValue::LuaNil => return Err(serde::ser::Error::custom(
-                &"unserializable key nil"
-            )),
-            _ => {}
-        }
+        let key = self.key(key)?;
         self.0.push((
             key,
-            value.serialize(LuaSerializer::new())?
+            value.serialize(LuaSerializer::with_config(self.1))?
         ));
         Ok(())
     }
@@ -387,34 +496,67 @@ impl serde::ser::SerializeStructVariant for LuaSerializeStructVariant {
     }
 
     fn end(self) -> SerResult<AnyLuaValue> {
-        Ok(AnyLuaValue::LuaArray(vec![(
-            AnyLuaValue::LuaString(self.0.to_owned()),
-            serde::ser::SerializeMap::end(self.1)?
-        )]))
+        let config = self.1 .1;
+        tag_variant(&config, self.0, serde::ser::SerializeMap::end(self.1)?)
     }
 }
 
 /// A result returned by lua serialization.
 pub type SerResult<T> = Result<T, LuaSerializeError>;
 
+/// Return the name of the lua type of a value.
+fn type_name(value: &AnyLuaValue) -> &'static str {
+    match *value {
+        AnyLuaValue::LuaString(_) | AnyLuaValue::LuaAnyString(_) => "string",
+        AnyLuaValue::LuaNumber(_) => "number",
+        AnyLuaValue::LuaBoolean(_) => "boolean",
+        AnyLuaValue::LuaArray(_) => "table",
+        AnyLuaValue::LuaNil => "nil",
+        AnyLuaValue::LuaOther => "other"
+    }
+}
+
 /// An error returned by lua serialization.
-#[derive(Debug, Clone)]
-pub struct LuaSerializeError(String);
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaSerializeError(pub(crate) ErrorKind);
+
+impl LuaSerializeError {
+    /// What went wrong.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.0
+    }
+
+    /// Report an integer that lua numbers can't hold exactly.
+    pub(crate) fn lossy_number<T>(value: T) -> LuaSerializeError
+        where T: fmt::Display
+    {
+        LuaSerializeError(ErrorKind::LossyNumber {
+            value: value.to_string(),
+            target: "lua number (f64)"
+        })
+    }
+}
 
 impl fmt::Display for LuaSerializeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        fmt::Display::fmt(&self.0, f)
     }
 }
 
 impl error::Error for LuaSerializeError {
 }
 
+impl From<io::Error> for LuaSerializeError {
+    fn from(error: io::Error) -> LuaSerializeError {
+        LuaSerializeError(error.into())
+    }
+}
+
 impl serde::ser::Error for LuaSerializeError {
     fn custom<T>(msg: T) -> Self
         where T: fmt::Display
     {
-        LuaSerializeError(format!("{}", msg))
+        LuaSerializeError(ErrorKind::Custom(format!("{}", msg)))
     }
 }
 
@@ -426,15 +568,25 @@ mod tests {
 
     use std::collections::BTreeMap;
 
-    use ::to_lua;
+    use ::{to_lua_with_config, Config, ErrorKind};
+    use config::{ByteEncoding, EnumRepr};
 
     fn test<S: Serialize>(value: &S, test: &str, openlibs: bool) -> bool {
+        test_with_config(value, test, openlibs, Config::new())
+    }
+
+    fn test_with_config<S: Serialize>(
+        value: &S,
+        test: &str,
+        openlibs: bool,
+        config: Config
+    ) -> bool {
         let mut lua = hlua::Lua::new();
         if openlibs {
             lua.openlibs();
         }
         lua.execute::<()>("table = {}").unwrap();
-        lua.set("value", to_lua(value).unwrap());
+        lua.set("value", to_lua_with_config(value, config).unwrap());
         let result = lua.execute::<bool>(test).unwrap();
         if !result {
             if !openlibs {
@@ -454,7 +606,7 @@ mod tests {
         lua.execute::<()>("table = {}").unwrap();
         {
             let mut table = lua.get::<hlua::LuaTable<_>, _>("table").unwrap();
-            table.set("value", to_lua(value).map_err(|_|())?);
+            table.set("value", to_lua_with_config(value, Config::new()).map_err(|_|())?);
         }
         Ok(())
     }
@@ -464,6 +616,45 @@ mod tests {
         assert!(test_eq(&(), "nil"));
     }
 
+    #[test]
+    fn null_sentinel() {
+        let config = Config::new().null_sentinel("null");
+        assert!(test_with_config(&(), "return value == 'null'", false, config));
+        assert!(test_with_config(
+            &vec![None, Some(1), None],
+            "return #value == 3 and value[1] == 'null' and value[2] == 1",
+            false,
+            config
+        ));
+        assert_eq!(
+            &ErrorKind::InvalidValue {
+                expected: "a string other than the null sentinel".to_owned(),
+                got: "string \"null\"".to_owned()
+            },
+            to_lua_with_config(&"null", config).unwrap_err().kind()
+        );
+        assert!(to_lua_with_config(&"nil", config).is_ok());
+    }
+
+    #[test]
+    fn packed_sequences() {
+        use config::SparseArrays;
+
+        let config = Config::new().sparse_arrays(SparseArrays::Packed);
+        assert!(test_with_config(
+            &vec![None, Some(1), None],
+            "return value.n == 3 and value[1] == nil and value[2] == 1",
+            false,
+            config
+        ));
+        assert!(test_with_config(
+            &vec![Some(1), Some(2)],
+            "return value.n == nil and #value == 2",
+            false,
+            config
+        ));
+    }
+
     #[test]
     fn boolean() {
         assert!(test_eq(&true, "true"));
@@ -708,11 +899,40 @@ mod tests {
     #[test]
     fn bytes() {
         use serde_bytes::Bytes;
+
+        let bytes = Bytes::new(&[0, 200, 251, 255]);
+        let encoded = |encoding, test| test_with_config(
+            &bytes,
+            test,
+            true,
+            Config::new().byte_encoding(encoding)
+        );
+        assert!(encoded(
+            ByteEncoding::Raw,
+            "return value == string.char(0, 200, 251, 255)"
+        ));
+        assert!(encoded(ByteEncoding::Hex, "return value == '00c8fbff'"));
+        assert!(encoded(
+            ByteEncoding::Array,
+            "return #value == 4 and value[1] == 0 and value[2] == 200 and value[4] == 255"
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "base64-bytes")]
+    fn base64_bytes() {
+        use serde_bytes::Bytes;
         assert!(test_eq(&Bytes::new(&[1, 2, 3, 4]), "'AQIDBA=='"));
         assert!(test_eq(
             &Bytes::new(&[91, 144, 255, 193, 22, 11, 52, 9, 3]),
             "'W5D/wRYLNAkD'"
         ));
+        assert!(test_with_config(
+            &Bytes::new(&[0, 200, 251, 255]),
+            "return value == 'AMj7_w=='",
+            false,
+            Config::new().byte_encoding(ByteEncoding::Base64UrlSafe)
+        ));
     }
 
     #[derive(Serialize)]
@@ -752,5 +972,109 @@ mod tests {
         assert!(test_eq(&UntaggedEnum::TypeA(1.5), "1.5"));
         assert!(test_eq(&UntaggedEnum::TypeB("yeehaw!".to_string()), "'yeehaw!'"));
     }
-}
 
+    #[derive(Serialize)]
+    enum Shape {
+        Empty,
+        Circle(f32),
+        Line(f32, f32),
+        Rect {
+            w: f32,
+            h: f32
+        },
+        Point(Simple)
+    }
+
+    #[derive(Serialize, PartialEq, Eq, PartialOrd, Ord)]
+    enum Key {
+        Unit
+    }
+
+    #[test]
+    fn enum_reprs() {
+        use std::iter::FromIterator;
+
+        let internal = Config::new().enum_repr(EnumRepr::Internal { tag: "type" });
+        assert!(test_with_config(
+            &[
+                Shape::Empty,
+                Shape::Line(1.0, 2.0),
+                Shape::Rect { w: 3.0, h: 4.0 },
+                Shape::Point(Simple { x: 5.0, y: "five" })
+            ],
+            "return (
+                value[1].type == 'Empty' and
+                value[2].type == 'Line' and value[2][1] == 1 and value[2][2] == 2 and
+                value[3].type == 'Rect' and value[3].w == 3 and value[3].h == 4 and
+                value[4].type == 'Point' and value[4].x == 5 and value[4].y == 'five')",
+            false,
+            internal
+        ));
+        assert!(to_lua_with_config(&Shape::Circle(1.0), internal).is_err());
+
+        let adjacent = Config::new().enum_repr(EnumRepr::Adjacent {
+            tag: "t",
+            content: "c"
+        });
+        assert!(test_with_config(
+            &[Shape::Empty, Shape::Circle(1.5), Shape::Rect { w: 3.0, h: 4.0 }],
+            "return (
+                value[1].t == 'Empty' and value[1].c == nil and
+                value[2].t == 'Circle' and value[2].c == 1.5 and
+                value[3].t == 'Rect' and value[3].c.w == 3 and value[3].c.h == 4)",
+            false,
+            adjacent
+        ));
+
+        assert!(test_with_config(
+            &[Shape::Empty, Shape::Circle(1.5), Shape::Line(1.0, 2.0)],
+            "return (
+                #value[1] == 1 and value[1][1] == 'Empty' and
+                value[2][1] == 'Circle' and value[2][2] == 1.5 and
+                value[3][1] == 'Line' and value[3][2][1] == 1 and value[3][2][2] == 2)",
+            false,
+            Config::new().enum_repr(EnumRepr::Array)
+        ));
+
+        assert!(test_with_config(
+            &BTreeMap::from_iter(vec![(Key::Unit, 1)]),
+            "return value.Unit == 1",
+            false,
+            Config::new().enum_repr(EnumRepr::Array)
+        ));
+    }
+
+    #[test]
+    fn error_kinds() {
+        use std::f32;
+        use serde::ser::{SerializeMap, Serializer};
+        use ::{to_lua, ErrorKind};
+
+        let error = to_lua(&u64::MAX).unwrap_err();
+        assert_eq!(
+            &ErrorKind::LossyNumber {
+                value: "18446744073709551615".to_owned(),
+                target: "lua number (f64)"
+            },
+            error.kind()
+        );
+        assert_eq!(
+            "18446744073709551615 cannot be losslessly represented as lua number (f64)",
+            error.to_string()
+        );
+
+        let mut map = ::LuaSerializer::new().serialize_map(Some(2)).unwrap();
+        let error = map.serialize_entry(&f32::NAN, &"hello").unwrap_err();
+        assert_eq!(&ErrorKind::InvalidKey("NaN".to_owned()), error.kind());
+        let error = map.serialize_entry(&(), &"hello").unwrap_err();
+        assert_eq!(&ErrorKind::InvalidKey("nil".to_owned()), error.kind());
+        assert_eq!("unserializable key nil", error.to_string());
+
+        let internal = Config::new().enum_repr(EnumRepr::Internal { tag: "type" });
+        let error = to_lua_with_config(&Shape::Circle(1.0), internal).unwrap_err();
+        match *error.kind() {
+            ErrorKind::InvalidType { ref got, .. } => assert_eq!("number", got),
+            ref kind => panic!("unexpected error kind {:?}", kind)
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Sharing one lua table between every place that holds the same `Rc` or
+//! `Arc`.
+//!
+//! Serde serializes a pointer like any other value, so data that holds the
+//! same `Rc` many times becomes as many separate tables. When
+//! `Config::share_tables` is enabled, pointers marked with this module are
+//! pushed onto the lua stack once, and every other place holding the same
+//! pointer refers to that table. The other way around, every place holding
+//! the same lua table is read into one pointer.
+//!
+//! ```rust
+//! extern crate hlua;
+//! extern crate serde;
+//! #[macro_use] extern crate serde_derive;
+//! extern crate serde_hlua;
+//!
+//! use std::rc::Rc;
+//!
+//! use serde_hlua::config::Config;
+//!
+//! #[derive(Deserialize, Serialize)]
+//! struct Texture {
+//!     path: String
+//! }
+//!
+//! #[derive(Deserialize, Serialize)]
+//! struct Sprite {
+//!     #[serde(with = "serde_hlua::shared")]
+//!     texture: Rc<Texture>
+//! }
+//!
+//! fn main() {
+//!     let mut lua = hlua::Lua::new();
+//!     let config = Config::new().share_tables(true);
+//!
+//!     let texture = Rc::new(Texture { path: "moth.png".to_owned() });
+//!     let sprites = vec![
+//!         Sprite { texture: texture.clone() },
+//!         Sprite { texture: texture.clone() }
+//!     ];
+//!     lua.set("sprites", serde_hlua::to_lua(&sprites).unwrap());
+//!     lua.execute::<()>("
+//!         sprites[2].texture = sprites[1].texture
+//!     ").unwrap();
+//!
+//!     // Both sprites now hold the same table, which is read only once.
+//!     let sprites: Vec<Sprite> = {
+//!         let mut table: hlua::LuaTable<_> = lua.get("sprites").unwrap();
+//!         serde_hlua::from_lua_stack_with_config(&mut table, -1, config).unwrap()
+//!     };
+//!     assert!(Rc::ptr_eq(&sprites[0].texture, &sprites[1].texture));
+//!
+//!     // Pushing them again pushes that table once.
+//!     let mut pushed = serde_hlua::push_to_lua_with_config(&sprites, &mut lua, config)
+//!         .map_err(|(error, _)| error)
+//!         .unwrap();
+//!     let sprites: Vec<Sprite> =
+//!         serde_hlua::from_lua_stack_with_config(&mut pushed, -1, config).unwrap();
+//!     assert!(Rc::ptr_eq(&sprites[0].texture, &sprites[1].texture));
+//! }
+//! ```
+//!
+//! Only the stack serializer and deserializer, as used by `push_to_lua`,
+//! `from_lua_stack` and their variants, can share tables. Everywhere else,
+//! and whenever `Config::share_tables` is disabled, shared pointers are
+//! serialized and deserialized as the values they point to.
+
+use std::any::{Any, TypeId};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Visitor;
+
+/// The name of the newtype struct that shared pointers are serialized as,
+/// which the stack serializer and deserializer look out for.
+pub(crate) const NAME: &str = "$serde_hlua::Shared";
+
+// The pointers read for each lua table, by table address and pointer type.
+type Decoded = HashMap<(usize, TypeId), Box<dyn Any>>;
+
+thread_local! {
+    // The address of the pointee of the shared pointer being serialized.
+    static POINTEE: Cell<Option<usize>> = const { Cell::new(None) };
+    // The address of the lua table that a shared pointer is being read from.
+    static TABLE: Cell<Option<usize>> = const { Cell::new(None) };
+    // The pointers read so far during the outermost stack deserialization,
+    // by table and pointer type.
+    static DECODED: RefCell<Option<Decoded>> = const { RefCell::new(None) };
+}
+
+/// A reference-counted pointer that can be shared between lua tables.
+pub trait SharedPointer: Deref + Clone + 'static {
+    /// Return a pointer to a new value.
+    fn new(value: Self::Target) -> Self
+        where Self::Target: Sized;
+}
+
+impl<T: 'static> SharedPointer for Rc<T> {
+    fn new(value: T) -> Rc<T> {
+        Rc::new(value)
+    }
+}
+
+impl<T: 'static> SharedPointer for Arc<T> {
+    fn new(value: T) -> Arc<T> {
+        Arc::new(value)
+    }
+}
+
+/// Serialize a shared pointer, for use with `#[serde(with = ...)]` or
+/// `#[serde(serialize_with = ...)]`.
+pub fn serialize<P, S>(pointer: &P, serializer: S) -> Result<S::Ok, S::Error>
+    where P: SharedPointer,
+          P::Target: Serialize,
+          S: Serializer
+{
+    let pointee: &P::Target = pointer;
+    POINTEE.with(|cell| cell.set(Some(pointee as *const P::Target as *const () as usize)));
+    serializer.serialize_newtype_struct(NAME, pointee)
+}
+
+/// Deserialize a shared pointer, for use with `#[serde(with = ...)]` or
+/// `#[serde(deserialize_with = ...)]`.
+pub fn deserialize<'de, P, D>(deserializer: D) -> Result<P, D::Error>
+    where P: SharedPointer,
+          P::Target: Sized + Deserialize<'de>,
+          D: Deserializer<'de>
+{
+    deserializer.deserialize_newtype_struct(NAME, SharedVisitor(PhantomData))
+}
+
+/// A shared pointer that is serialized and deserialized through this
+/// module, for use where `#[serde(with = ...)]` can't reach, such as the
+/// elements of a `Vec`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Shared<P>(pub P);
+
+impl<P> Deref for Shared<P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        &self.0
+    }
+}
+
+impl<P> Serialize for Shared<P>
+    where P: SharedPointer,
+          P::Target: Serialize
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serialize(&self.0, serializer)
+    }
+}
+
+impl<'de, P> Deserialize<'de> for Shared<P>
+    where P: SharedPointer,
+          P::Target: Sized + Deserialize<'de>
+{
+    fn deserialize<D>(deserializer: D) -> Result<Shared<P>, D::Error>
+        where D: Deserializer<'de>
+    {
+        deserialize(deserializer).map(Shared)
+    }
+}
+
+struct SharedVisitor<P>(PhantomData<P>);
+
+impl<'de, P> Visitor<'de> for SharedVisitor<P>
+    where P: SharedPointer,
+          P::Target: Sized + Deserialize<'de>
+{
+    type Value = P;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a shared value")
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> Result<P, D::Error>
+        where D: Deserializer<'de>
+    {
+        let key = TABLE.with(Cell::take).map(|table| (table, TypeId::of::<P>()));
+        let found = key.and_then(|key| DECODED.with(|decoded| {
+            decoded.borrow().as_ref()
+                .and_then(|decoded| decoded.get(&key))
+                .and_then(|pointer| pointer.downcast_ref::<P>())
+                .cloned()
+        }));
+        if let Some(pointer) = found {
+            return Ok(pointer);
+        }
+        let pointer = P::new(P::Target::deserialize(deserializer)?);
+        if let Some(key) = key {
+            DECODED.with(|decoded| {
+                if let Some(decoded) = decoded.borrow_mut().as_mut() {
+                    decoded.insert(key, Box::new(pointer.clone()));
+                }
+            });
+        }
+        Ok(pointer)
+    }
+}
+
+/// Take the address of the pointee of the shared pointer being serialized.
+pub(crate) fn take_pointee() -> Option<usize> {
+    POINTEE.with(Cell::take)
+}
+
+/// Set the address of the lua table that a shared pointer is about to be
+/// read from.
+pub(crate) fn set_table(table: usize) {
+    TABLE.with(|cell| cell.set(Some(table)));
+}
+
+/// Keeps the pointers read during a stack deserialization, so that they can
+/// be reused, until it is dropped.
+pub(crate) struct Scope(Option<Decoded>);
+
+impl Scope {
+    pub(crate) fn enter() -> Scope {
+        Scope(DECODED.with(|decoded| decoded.replace(Some(HashMap::new()))))
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let outer = self.0.take();
+        DECODED.with(|decoded| *decoded.borrow_mut() = outer);
+    }
+}
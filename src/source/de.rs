@@ -0,0 +1,997 @@
+//! Parsing lua source text without running it.
+
+use std::collections::HashMap;
+
+use hlua::{AnyLuaString, AnyLuaValue};
+use serde::de::{Deserializer, Visitor};
+
+use config::Config;
+use de::{hex_number, is_identifier, DeResult, LuaDeserializeError, LuaDeserializer, PathSegment};
+use error::{ErrorKind, Limit};
+
+pub use error::Position;
+
+/// How deeply tables can be nested when `Limits::max_depth` isn't set, so
+/// that untrusted text can't exhaust the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A deserializer over lua source text, which is parsed without being run.
+///
+/// Only the data subset of lua is accepted: a single expression made of
+/// table constructors, strings, numbers, `true`, `false` and `nil`,
+/// optionally preceded by `return` or `local name =`. Numbers can be negated
+/// and divided by one another, so that `1/0` and `0/0` can express infinity
+/// and NaN. Anything else, such as a variable or a function call, is a
+/// syntax error, so parsing untrusted text is safe.
+///
+/// The text is parsed when the deserializer is created. Errors found then or
+/// during deserialization carry the position in the text that they refer to.
+/// Tables nested deeper than `Limits::max_depth`, or 128 levels when it isn't
+/// set, are rejected as soon as they are parsed.
+///
+/// ```rust
+/// extern crate serde;
+/// #[macro_use] extern crate serde_derive;
+/// extern crate serde_hlua;
+///
+/// #[derive(Debug, Deserialize)]
+/// struct Manifest {
+///     name: String,
+///     version: (u32, u32)
+/// }
+///
+/// fn main() {
+///     let manifest: Manifest = serde_hlua::from_lua_str("
+///         -- Generated by the mod tool.
+///         return { name = 'example', version = { 1, 2 } }
+///     ").unwrap();
+///     assert_eq!(manifest.name, "example");
+///
+///     let error = serde_hlua::from_lua_str::<Manifest>("
+///         return { name = 'example', version = { 1, -2 } }
+///     ").unwrap_err();
+///     assert_eq!(
+///         error.to_string(),
+///         "-2 cannot be losslessly represented as u32 at version[2], line 2, column 51"
+///     );
+///
+///     assert!(serde_hlua::from_lua_str::<Manifest>("return os.exit()").is_err());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LuaSourceDeserializer {
+    deserializer: LuaDeserializer,
+    positions: Positions
+}
+
+impl LuaSourceDeserializer {
+    /// Parse lua source text into a deserializer.
+    pub fn new<S>(source: &S) -> DeResult<LuaSourceDeserializer>
+        where S: ?Sized + AsRef<[u8]>
+    {
+        LuaSourceDeserializer::with_config(source, Config::new())
+    }
+
+    /// Parse lua source text into a deserializer that uses the provided
+    /// configuration.
+    pub fn with_config<S>(source: &S, config: Config) -> DeResult<LuaSourceDeserializer>
+        where S: ?Sized + AsRef<[u8]>
+    {
+        let max_depth = config.limits.max_depth.unwrap_or(MAX_DEPTH);
+        let (value, positions) = Parser::new(source.as_ref(), max_depth).chunk()?;
+        Ok(LuaSourceDeserializer {
+            deserializer: LuaDeserializer::with_config(value, config),
+            positions
+        })
+    }
+}
+
+// Deserialization is left to `LuaDeserializer`, and errors are given the
+// position of the value they refer to.
+macro_rules! forward_located {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method<V>(self, $($arg: $ty,)* visitor: V) -> DeResult<V::Value>
+                where V: Visitor<'de>
+            {
+                let positions = self.positions;
+                self.deserializer.$method($($arg,)* visitor)
+                    .map_err(|error| positions.locate(error))
+            }
+        )*
+    }
+}
+
+impl<'de> Deserializer<'de> for LuaSourceDeserializer {
+    type Error = LuaDeserializeError;
+
+    forward_located! {
+        deserialize_any();
+        deserialize_bool();
+        deserialize_i8();
+        deserialize_i16();
+        deserialize_i32();
+        deserialize_i64();
+        deserialize_u8();
+        deserialize_u16();
+        deserialize_u32();
+        deserialize_u64();
+        deserialize_f32();
+        deserialize_f64();
+        deserialize_char();
+        deserialize_str();
+        deserialize_string();
+        deserialize_bytes();
+        deserialize_byte_buf();
+        deserialize_option();
+        deserialize_unit();
+        deserialize_unit_struct(name: &'static str);
+        deserialize_newtype_struct(name: &'static str);
+        deserialize_seq();
+        deserialize_tuple(len: usize);
+        deserialize_tuple_struct(name: &'static str, len: usize);
+        deserialize_map();
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+        deserialize_identifier();
+        deserialize_ignored_any();
+    }
+}
+
+/// Where a value was written, and where the values in it were written.
+#[derive(Debug, Clone)]
+struct Positions {
+    position: Position,
+    entries: Vec<(AnyLuaValue, Positions)>
+}
+
+impl Positions {
+    fn new(position: Position) -> Positions {
+        Positions { position, entries: Vec::new() }
+    }
+
+    /// Give an error the position of the value it refers to, or of the
+    /// innermost enclosing value that was written.
+    fn locate(&self, error: LuaDeserializeError) -> LuaDeserializeError {
+        let mut positions = self;
+        for segment in error.path().segments() {
+            let entry = positions.entries.iter().find(|entry| {
+                PathSegment::from_key(&entry.0) == *segment
+            });
+            match entry {
+                Some(entry) => positions = &entry.1,
+                None => break
+            }
+        }
+        let position = positions.position;
+        error.with_position(position)
+    }
+}
+
+/// A value along with where it and the values in it were written.
+type Parsed = (AnyLuaValue, Positions);
+
+/// A recursive descent parser for the data subset of lua.
+#[derive(Clone, Copy)]
+struct Parser<'a> {
+    source: &'a [u8],
+    offset: usize,
+    position: Position,
+    /// How many tables the parser is inside of.
+    depth: usize,
+    /// How many tables the parser can be inside of.
+    max_depth: usize
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a [u8], max_depth: usize) -> Parser<'a> {
+        let position = Position { line: 1, column: 1 };
+        Parser { source, offset: 0, position, depth: 0, max_depth }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, ahead: usize) -> Option<u8> {
+        self.source.get(self.offset + ahead).cloned()
+    }
+
+    fn bump(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.offset += 1;
+        if byte == b'\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else if byte & 0xc0 != 0x80 {
+            self.position.column += 1;
+        }
+        Some(byte)
+    }
+
+    fn error<T, S>(&self, position: Position, message: S) -> DeResult<T>
+        where S: Into<String>
+    {
+        Err(LuaDeserializeError::from(ErrorKind::Syntax(message.into())).with_position(position))
+    }
+
+    /// Fail, describing what was found instead of what was expected.
+    fn unexpected<T>(&self, expected: &str) -> DeResult<T> {
+        let found = match self.peek() {
+            None => "end of input".to_owned(),
+            Some(_) => {
+                let end = (self.offset + 4).min(self.source.len());
+                let text = String::from_utf8_lossy(&self.source[self.offset..end]);
+                format!("`{}`", text.chars().next().unwrap())
+            }
+        };
+        self.error(self.position, format!("unexpected {}, expected {}", found, expected))
+    }
+
+    fn expect(&mut self, byte: u8) -> DeResult<()> {
+        self.skip()?;
+        if self.peek() == Some(byte) {
+            self.bump();
+            Ok(())
+        } else {
+            self.unexpected(&format!("`{}`", byte as char))
+        }
+    }
+
+    /// Skip whitespace and comments.
+    fn skip(&mut self) -> DeResult<()> {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') | Some(0x0b) | Some(0x0c) => {
+                    self.bump();
+                },
+                Some(b'-') if self.peek_at(1) == Some(b'-') => {
+                    self.bump();
+                    self.bump();
+                    match self.long_bracket() {
+                        Some(level) => {
+                            self.long_text(level, "comment")?;
+                        },
+                        None => while self.peek().is_some_and(|byte| byte != b'\n') {
+                            self.bump();
+                        }
+                    }
+                },
+                _ => return Ok(())
+            }
+        }
+    }
+
+    /// Consume a line break, treating `\r\n` and `\n\r` as one.
+    fn newline(&mut self) -> bool {
+        match self.peek() {
+            Some(first) if first == b'\n' || first == b'\r' => {
+                self.bump();
+                match self.peek() {
+                    Some(second) if (second == b'\n' || second == b'\r') && second != first => {
+                        self.bump();
+                    },
+                    _ => ()
+                }
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Read an identifier or keyword.
+    fn word(&mut self) -> Option<&'a str> {
+        let start = self.offset;
+        if !self.peek().is_some_and(|byte| byte == b'_' || byte.is_ascii_alphabetic()) {
+            return None;
+        }
+        while self.peek().is_some_and(|byte| byte == b'_' || byte.is_ascii_alphanumeric()) {
+            self.bump();
+        }
+        // Identifiers are ASCII.
+        Some(::std::str::from_utf8(&self.source[start..self.offset]).unwrap())
+    }
+
+    /// Parse a chunk holding a single value.
+    fn chunk(mut self) -> DeResult<Parsed> {
+        // Like lua, skip a first line starting with `#`, such as a shebang.
+        if self.peek() == Some(b'#') {
+            while self.peek().is_some_and(|byte| byte != b'\n') {
+                self.bump();
+            }
+        }
+        self.skip()?;
+        let start = self;
+        match self.word() {
+            Some("return") => (),
+            Some("local") => {
+                self.skip()?;
+                let position = self.position;
+                match self.word() {
+                    Some(name) if is_identifier(name) => (),
+                    _ => return self.error(position, "expected a name after `local`")
+                }
+                self.expect(b'=')?;
+            },
+            _ => self = start
+        }
+        let value = self.expression()?;
+        self.skip()?;
+        if self.peek() == Some(b';') {
+            self.bump();
+            self.skip()?;
+        }
+        match self.peek() {
+            Some(_) => self.unexpected("end of input"),
+            None => Ok(value)
+        }
+    }
+
+    /// Parse a value, which may be a division of numbers.
+    fn expression(&mut self) -> DeResult<Parsed> {
+        let (mut value, positions) = self.unary()?;
+        loop {
+            self.skip()?;
+            if self.peek() != Some(b'/') {
+                return Ok((value, positions));
+            }
+            let position = self.position;
+            self.bump();
+            let (divisor, _) = self.unary()?;
+            value = match (value, divisor) {
+                (AnyLuaValue::LuaNumber(a), AnyLuaValue::LuaNumber(b)) => {
+                    AnyLuaValue::LuaNumber(a / b)
+                },
+                _ => return self.error(position, "only numbers can be divided")
+            };
+        }
+    }
+
+    /// Parse a value, which may be a negated number.
+    fn unary(&mut self) -> DeResult<Parsed> {
+        self.skip()?;
+        let position = self.position;
+        let mut negate = false;
+        while self.peek() == Some(b'-') && self.peek_at(1) != Some(b'-') {
+            self.bump();
+            self.skip()?;
+            negate = !negate;
+        }
+        let (value, positions) = self.primary()?;
+        match value {
+            AnyLuaValue::LuaNumber(number) if negate => {
+                Ok((AnyLuaValue::LuaNumber(-number), Positions::new(position)))
+            },
+            _ if negate => self.error(position, "only numbers can be negated"),
+            value => Ok((value, positions))
+        }
+    }
+
+    fn primary(&mut self) -> DeResult<Parsed> {
+        let position = self.position;
+        let value = match self.peek() {
+            Some(b'{') => return self.table(),
+            Some(b'"') | Some(b'\'') => string(self.quoted()?),
+            Some(b'[') => match self.long_bracket() {
+                Some(level) => string(self.long_text(level, "string")?),
+                None => return self.unexpected("a value")
+            },
+            Some(byte) if byte.is_ascii_digit() => AnyLuaValue::LuaNumber(self.number()?),
+            Some(b'.') if self.peek_at(1).is_some_and(|byte| byte.is_ascii_digit()) => {
+                AnyLuaValue::LuaNumber(self.number()?)
+            },
+            _ => match self.word() {
+                Some("nil") => AnyLuaValue::LuaNil,
+                Some("true") => AnyLuaValue::LuaBoolean(true),
+                Some("false") => AnyLuaValue::LuaBoolean(false),
+                Some(word) => return self.error(
+                    position,
+                    format!("unexpected `{}`, expected a value", word)
+                ),
+                None => return self.unexpected("a value")
+            }
+        };
+        Ok((value, Positions::new(position)))
+    }
+
+    fn table(&mut self) -> DeResult<Parsed> {
+        let position = self.position;
+        if self.depth >= self.max_depth {
+            let error = ErrorKind::LimitExceeded { limit: Limit::Depth, max: self.max_depth };
+            return Err(LuaDeserializeError::from(error).with_position(position));
+        }
+        self.depth += 1;
+        self.bump();
+        let mut table = Table::default();
+        loop {
+            self.skip()?;
+            let key_position = self.position;
+            match self.peek() {
+                Some(b'}') => break,
+                Some(b'[') if self.long_bracket().is_none() => {
+                    self.bump();
+                    let (key, _) = self.expression()?;
+                    self.expect(b']')?;
+                    self.expect(b'=')?;
+                    let value = self.expression()?;
+                    table.insert(self, key_position, key, value)?;
+                },
+                _ => {
+                    let start = *self;
+                    let name = self.word().filter(|&name| is_identifier(name));
+                    self.skip()?;
+                    match name {
+                        Some(name) if self.peek() == Some(b'=') && self.peek_at(1) != Some(b'=') => {
+                            self.bump();
+                            let value = self.expression()?;
+                            let key = AnyLuaValue::LuaString(name.to_owned());
+                            table.insert(self, key_position, key, value)?;
+                        },
+                        _ => {
+                            *self = start;
+                            table.positional.push(self.expression()?);
+                        }
+                    }
+                }
+            }
+            self.skip()?;
+            match self.peek() {
+                Some(b',') | Some(b';') => {
+                    self.bump();
+                },
+                Some(b'}') => break,
+                _ => return self.unexpected("`,` or `}`")
+            }
+        }
+        self.bump();
+        self.depth -= 1;
+        Ok(table.finish(position))
+    }
+
+    /// Return the level of the long bracket that starts here, if any.
+    fn long_bracket(&self) -> Option<usize> {
+        if self.peek() != Some(b'[') {
+            return None;
+        }
+        let level = self.source[self.offset + 1..].iter().take_while(|&&byte| byte == b'=').count();
+        match self.peek_at(level + 1) {
+            Some(b'[') => Some(level),
+            _ => None
+        }
+    }
+
+    /// Whether a closing long bracket of the given level starts here.
+    fn closes(&self, level: usize) -> bool {
+        self.peek() == Some(b']') &&
+            (1..=level).all(|ahead| self.peek_at(ahead) == Some(b'=')) &&
+            self.peek_at(level + 1) == Some(b']')
+    }
+
+    /// Read the contents of a long string or comment, whose opening bracket
+    /// starts here.
+    fn long_text(&mut self, level: usize, what: &str) -> DeResult<Vec<u8>> {
+        let start = self.position;
+        for _ in 0..level + 2 {
+            self.bump();
+        }
+        // A line break straight after the opening bracket isn't part of the
+        // text.
+        self.newline();
+        let mut text = Vec::new();
+        loop {
+            match self.peek() {
+                None => return self.error(start, format!("unfinished long {}", what)),
+                Some(b']') if self.closes(level) => {
+                    for _ in 0..level + 2 {
+                        self.bump();
+                    }
+                    return Ok(text);
+                },
+                Some(b'\n') | Some(b'\r') => {
+                    self.newline();
+                    text.push(b'\n');
+                },
+                Some(byte) => {
+                    self.bump();
+                    text.push(byte);
+                }
+            }
+        }
+    }
+
+    /// Read a quoted string, decoding its escape sequences.
+    fn quoted(&mut self) -> DeResult<Vec<u8>> {
+        let start = self.position;
+        let quote = self.bump();
+        let mut text = Vec::new();
+        loop {
+            let position = self.position;
+            match self.peek() {
+                None | Some(b'\n') | Some(b'\r') => {
+                    return self.error(start, "unfinished string");
+                },
+                Some(b'\\') => {
+                    self.bump();
+                    self.escape(position, &mut text)?;
+                },
+                Some(byte) => {
+                    self.bump();
+                    if Some(byte) == quote {
+                        return Ok(text);
+                    }
+                    text.push(byte);
+                }
+            }
+        }
+    }
+
+    /// Decode the escape sequence following a backslash.
+    fn escape(&mut self, position: Position, text: &mut Vec<u8>) -> DeResult<()> {
+        let byte = match self.peek() {
+            Some(b'a') => 0x07,
+            Some(b'b') => 0x08,
+            Some(b'f') => 0x0c,
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'v') => 0x0b,
+            Some(byte @ b'\\') | Some(byte @ b'"') | Some(byte @ b'\'') => byte,
+            Some(b'\n') | Some(b'\r') => {
+                self.newline();
+                text.push(b'\n');
+                return Ok(());
+            },
+            Some(b'z') => {
+                self.bump();
+                while self.peek().is_some_and(|byte| byte.is_ascii_whitespace() || byte == 0x0b) {
+                    self.bump();
+                }
+                return Ok(());
+            },
+            Some(b'x') => {
+                self.bump();
+                let mut value = 0;
+                for _ in 0..2 {
+                    match self.peek().and_then(|byte| (byte as char).to_digit(16)) {
+                        Some(digit) => value = value * 16 + digit,
+                        None => return self.error(position, "expected two hexadecimal digits")
+                    }
+                    self.bump();
+                }
+                text.push(value as u8);
+                return Ok(());
+            },
+            Some(b'u') => {
+                self.bump();
+                if self.bump() != Some(b'{') {
+                    return self.error(position, "expected `{` in unicode escape");
+                }
+                let mut value: u32 = 0;
+                let mut digits = 0;
+                while let Some(digit) = self.peek().and_then(|byte| (byte as char).to_digit(16)) {
+                    self.bump();
+                    digits += 1;
+                    value = value.saturating_mul(16).saturating_add(digit);
+                }
+                let character = ::std::char::from_u32(value).filter(|_| digits > 0);
+                match (character, self.bump()) {
+                    (Some(character), Some(b'}')) => {
+                        let mut buffer = [0; 4];
+                        text.extend(character.encode_utf8(&mut buffer).as_bytes());
+                        return Ok(());
+                    },
+                    _ => return self.error(position, "invalid unicode escape")
+                }
+            },
+            Some(byte) if byte.is_ascii_digit() => {
+                let mut value = 0u32;
+                for _ in 0..3 {
+                    match self.peek() {
+                        Some(byte) if byte.is_ascii_digit() => {
+                            self.bump();
+                            value = value * 10 + (byte - b'0') as u32;
+                        },
+                        _ => break
+                    }
+                }
+                if value > 255 {
+                    return self.error(position, "decimal escape is too large");
+                }
+                text.push(value as u8);
+                return Ok(());
+            },
+            _ => return self.error(position, "invalid escape sequence")
+        };
+        self.bump();
+        text.push(byte);
+        Ok(())
+    }
+
+    /// Read a decimal or hexadecimal number.
+    fn number(&mut self) -> DeResult<f64> {
+        let position = self.position;
+        let start = self.offset;
+        let hex = self.peek() == Some(b'0') && matches!(self.peek_at(1), Some(b'x') | Some(b'X'));
+        let exponents: &[u8] = if hex { b"pP" } else { b"eE" };
+        // Like lua, read everything that could be part of a number, then
+        // check that it is one.
+        while let Some(byte) = self.peek() {
+            if byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'_' {
+                self.bump();
+                if exponents.contains(&byte) && matches!(self.peek(), Some(b'+') | Some(b'-')) {
+                    self.bump();
+                }
+            } else {
+                break;
+            }
+        }
+        // Numbers are ASCII.
+        let text = ::std::str::from_utf8(&self.source[start..self.offset]).unwrap();
+        let number = if hex {
+            hex_number(&text[2..])
+        } else {
+            text.parse().ok()
+        };
+        match number {
+            Some(number) => Ok(number),
+            None => self.error(position, format!("malformed number `{}`", text))
+        }
+    }
+}
+
+/// Make a lua string from the bytes of a string literal.
+fn string(bytes: Vec<u8>) -> AnyLuaValue {
+    match String::from_utf8(bytes) {
+        Ok(string) => AnyLuaValue::LuaString(string),
+        Err(error) => AnyLuaValue::LuaAnyString(AnyLuaString(error.into_bytes()))
+    }
+}
+
+/// The entries of a table constructor being parsed.
+#[derive(Default)]
+struct Table {
+    keyed: Vec<(AnyLuaValue, Parsed)>,
+    positional: Vec<Parsed>
+}
+
+impl Table {
+    fn insert(
+        &mut self,
+        parser: &Parser,
+        position: Position,
+        key: AnyLuaValue,
+        value: Parsed
+    ) -> DeResult<()> {
+        match key {
+            AnyLuaValue::LuaNil => parser.error(position, "table index is nil"),
+            AnyLuaValue::LuaNumber(number) if number.is_nan() => {
+                parser.error(position, "table index is NaN")
+            },
+            key => {
+                self.keyed.push((key, value));
+                Ok(())
+            }
+        }
+    }
+
+    /// Build the table as lua would: positional entries take precedence over
+    /// keyed ones, later keyed entries replace earlier ones, and `nil`
+    /// values leave no entry.
+    fn finish(self, position: Position) -> Parsed {
+        let length = self.positional.len();
+        let mut entries: Vec<_> = self.positional.into_iter().enumerate().map(|(index, value)| {
+            Some((AnyLuaValue::LuaNumber((index + 1) as f64), value))
+        }).collect();
+        let mut indices = HashMap::new();
+        for (key, value) in self.keyed {
+            if let AnyLuaValue::LuaNumber(number) = key {
+                if number >= 1.0 && number <= length as f64 && number.fract() == 0.0 {
+                    continue;
+                }
+            }
+            if let Some(hash) = HashKey::new(&key) {
+                if let Some(previous) = indices.insert(hash, entries.len()) {
+                    entries[previous] = None;
+                }
+            }
+            entries.push(Some((key, value)));
+        }
+        let (values, positions) = entries.into_iter()
+            .flatten()
+            .filter(|entry| (entry.1).0 != AnyLuaValue::LuaNil)
+            .map(|(key, (value, positions))| ((key.clone(), value), (key, positions)))
+            .unzip();
+        (AnyLuaValue::LuaArray(values), Positions { position, entries: positions })
+    }
+}
+
+/// Identifies the table keys that lua considers equal.
+#[derive(PartialEq, Eq, Hash)]
+enum HashKey {
+    Bytes(Vec<u8>),
+    Number(u64),
+    Boolean(bool)
+}
+
+impl HashKey {
+    /// Return the key's identity, or `None` for tables, which are only equal
+    /// to themselves.
+    fn new(key: &AnyLuaValue) -> Option<HashKey> {
+        match *key {
+            AnyLuaValue::LuaString(ref string) => Some(HashKey::Bytes(string.as_bytes().to_vec())),
+            AnyLuaValue::LuaAnyString(ref bytes) => Some(HashKey::Bytes(bytes.0.clone())),
+            // Zero and negative zero are the same key.
+            AnyLuaValue::LuaNumber(0.0) => Some(HashKey::Number(0)),
+            AnyLuaValue::LuaNumber(number) => Some(HashKey::Number(number.to_bits())),
+            AnyLuaValue::LuaBoolean(boolean) => Some(HashKey::Boolean(boolean)),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use hlua::AnyLuaValue;
+
+    use std::collections::BTreeMap;
+    use std::f64;
+
+    use super::Position;
+    use ::{from_lua_reader, from_lua_str, from_lua_str_with_config, to_lua_string_with_format};
+    use ::{Config, Value};
+    use config::{EnumRepr, KeyOrder, Limits, MultilineStrings, SourceFormat, Wrapper};
+    use de::DeResult;
+    use error::{ErrorKind, Limit};
+
+    /// Check that parsing a chunk gives what running it does.
+    fn check(source: &str) {
+        let mut lua = hlua::Lua::new();
+        let executed: AnyLuaValue = lua.execute(source).unwrap();
+        let parsed: Value = from_lua_str(source).unwrap();
+        assert_eq!(Value::from(executed).sorted(), parsed.sorted(), "parsing {}", source);
+    }
+
+    fn syntax_error(source: &str) -> (String, Position) {
+        let error = from_lua_str::<Value>(source).unwrap_err();
+        match *error.kind() {
+            ErrorKind::Syntax(ref message) => (message.clone(), error.position().unwrap()),
+            ref kind => panic!("expected a syntax error, got {:?}", kind)
+        }
+    }
+
+    #[test]
+    fn literals() {
+        check("return nil");
+        check("return true");
+        check("return false");
+        check("return 'single' ");
+        check(r#"return "double""#);
+        check("return 3");
+        check("return -3.25e2");
+        check("return .5");
+        check("return 5.");
+        check("return 1E+3");
+        check("return 0x10");
+        check("return 0xA.8p1");
+        check("return 0x.1P-2");
+        check("return - -2");
+        check("return 1/0");
+        check("return -1/0");
+        check("return 1/-2/4");
+        check("return 'a' ;");
+        check("  -- comment\nreturn --[[ long\ncomment ]] 1 --[==[ another ]==]");
+    }
+
+    #[test]
+    fn strings() {
+        check(r#"return "\a\b\f\n\r\t\v\\\"\'""#);
+        check(r"return '\x41\x7a\0651\2551\0'");
+        check("return 'line\\\nbreak'");
+        check("return 'skip \\z   \n   spaces'");
+        check("return [[long]]");
+        check("return [[\nleading newline dropped]]");
+        check("return [==[contains ]] and ]=] ]==]");
+        check("return [[crlf\r\nnormalized\rtoo]]");
+        check("return 'ünïcödé'");
+        check(r"return '\255\254'");
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        // Lua 5.2 predates these, so they are checked by hand.
+        let value: String = from_lua_str(r"return '\u{48}\u{e9}\u{1F600}'").unwrap();
+        assert_eq!("H\u{e9}\u{1F600}", value);
+        assert_eq!(
+            ("invalid unicode escape".to_owned(), Position { line: 1, column: 9 }),
+            syntax_error(r"return '\u{D800}'")
+        );
+        assert_eq!(
+            ("invalid unicode escape".to_owned(), Position { line: 1, column: 9 }),
+            syntax_error(r"return '\u{}'")
+        );
+    }
+
+    #[test]
+    fn tables() {
+        check("return {}");
+        check("return { 1, 2, 3, }");
+        check("return { x = 1; y = 2 }");
+        check("return { ['not an identifier'] = 1, [2.5] = 'a', [true] = false }");
+        check("return { 'a', nil, 'c' }");
+        check("return { x = nil, y = 1 }");
+        check("return { x = 1, x = 2 }");
+        check("return { [1] = 'explicit', 'positional' }");
+        check("return { 'positional', [1] = 'explicit' }");
+        check("return { [2] = 'explicit', 'a', nil }");
+        check("return { [0] = 'zero', [-0] = 'negative zero' }");
+        check("return { nested = { deeper = { 'value' } }, { {}, { {} } } }");
+        check("return { true, false, nil_value = 1, truth = true }");
+
+        let value: Value = from_lua_str("local data = { 1 }").unwrap();
+        assert_eq!(Some(1.0), value[1].as_f64());
+        let value: Value = from_lua_str("#!/usr/bin/lua\nreturn 2").unwrap();
+        assert_eq!(Some(2.0), value.as_f64());
+        let value: Value = from_lua_str("'no return'").unwrap();
+        assert_eq!(Some("no return"), value.as_str());
+    }
+
+    #[test]
+    fn nan() {
+        let value: f64 = from_lua_str("return 0/0").unwrap();
+        assert!(value.is_nan());
+        assert_eq!(f64::NEG_INFINITY, from_lua_str::<f64>("-1/0").unwrap());
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let at = |line, column| Position { line, column };
+        assert_eq!(
+            ("unexpected `os`, expected a value".to_owned(), at(1, 8)),
+            syntax_error("return os.exit()")
+        );
+        assert_eq!(
+            ("unexpected `(`, expected end of input".to_owned(), at(1, 11)),
+            syntax_error("return print('hello')".replace("print", "'a'").as_str())
+        );
+        assert_eq!(
+            ("unexpected `y`, expected a value".to_owned(), at(2, 12)),
+            syntax_error("return {\n    x = 1, y f }")
+        );
+        assert_eq!(
+            ("unexpected end of input, expected a value".to_owned(), at(1, 1)),
+            syntax_error("")
+        );
+        assert_eq!(("unfinished string".to_owned(), at(1, 10)), syntax_error("return { 'open\n' }"));
+        assert_eq!(("unfinished long string".to_owned(), at(1, 8)), syntax_error("return [==[ ]] "));
+        assert_eq!(("unfinished long comment".to_owned(), at(1, 3)), syntax_error("--[[ return 1"));
+        assert_eq!(("invalid escape sequence".to_owned(), at(1, 10)), syntax_error(r"return 'a\qb'"));
+        assert_eq!(("decimal escape is too large".to_owned(), at(1, 9)), syntax_error(r"return '\256'"));
+        assert_eq!(("malformed number `3x`".to_owned(), at(1, 8)), syntax_error("return 3x"));
+        assert_eq!(("malformed number `0x`".to_owned(), at(1, 8)), syntax_error("return 0x"));
+        assert_eq!(("table index is nil".to_owned(), at(1, 10)), syntax_error("return { [nil] = 1 }"));
+        assert_eq!(("table index is NaN".to_owned(), at(1, 10)), syntax_error("return { [0/0] = 1 }"));
+        assert_eq!(("only numbers can be negated".to_owned(), at(1, 8)), syntax_error("return -'1'"));
+        assert_eq!(("only numbers can be divided".to_owned(), at(1, 10)), syntax_error("return 1 / {}"));
+        assert_eq!(
+            ("unexpected `=`, expected `,` or `}`".to_owned(), at(1, 14)),
+            syntax_error("return { end = 1 }".replace("end", "nil").as_str())
+        );
+        assert_eq!(
+            ("expected a name after `local`".to_owned(), at(1, 7)),
+            syntax_error("local end = 1")
+        );
+
+        // Nesting is bounded while the text is parsed, by the depth limit or
+        // by a fallback when there is none.
+        let nested = |depth| "{".repeat(depth) + &"}".repeat(depth);
+        let depth_error = |source: &str, config| {
+            let error = from_lua_str_with_config::<Value>(source, config).unwrap_err();
+            (error.kind().clone(), error.position().unwrap())
+        };
+        assert_eq!(
+            (ErrorKind::LimitExceeded { limit: Limit::Depth, max: 128 }, at(1, 129)),
+            depth_error(&nested(200), Config::new())
+        );
+        assert!(from_lua_str::<Value>(&nested(128)).is_ok());
+        let limited = |max_depth| Config::new().limits(Limits::new().max_depth(max_depth));
+        assert!(from_lua_str_with_config::<Value>(&nested(150), limited(150)).is_ok());
+        assert_eq!(
+            (ErrorKind::LimitExceeded { limit: Limit::Depth, max: 4 }, at(1, 5)),
+            depth_error(&nested(200), limited(4))
+        );
+    }
+
+    #[test]
+    fn positions() {
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Manifest {
+            name: String,
+            dependencies: BTreeMap<String, (u32, u32)>,
+            kind: Kind
+        }
+
+        #[derive(Deserialize, Debug)]
+        #[allow(dead_code)]
+        enum Kind {
+            Library { exports: Vec<String> },
+            Binary(String)
+        }
+
+        let error = from_lua_str::<Manifest>("return {
+            name = 'example',
+            dependencies = {
+                core = { 1, 2 },
+                extra = { 1, 'two' },
+            },
+            kind = 'Binary',
+        }").unwrap_err();
+        assert_eq!(Some(Position { line: 5, column: 30 }), error.position());
+        assert_eq!("dependencies.extra[2]", error.path().to_string());
+
+        let source = "return {
+            name = 'example',
+            dependencies = {},
+            kind = { Library = { exports = { 'a', 'b', false } } }
+        }";
+        let error = from_lua_str::<Manifest>(source).unwrap_err();
+        assert_eq!(Some(Position { line: 4, column: 56 }), error.position());
+        assert_eq!(
+            "invalid type: boolean `false`, expected a string \
+             at kind.Library.exports[3], line 4, column 56",
+            error.to_string()
+        );
+
+        let config = Config::new().enum_repr(EnumRepr::Array);
+        let error = from_lua_str_with_config::<Manifest>(
+            "return { name = 'a', dependencies = {}, kind = { 'Binary', 3 } }",
+            config
+        ).unwrap_err();
+        assert_eq!(Some(Position { line: 1, column: 60 }), error.position());
+
+        // Errors without a path are at the start of the value.
+        let error = from_lua_str::<Manifest>("\n  return { name = 'a' }").unwrap_err();
+        assert_eq!(&ErrorKind::MissingField("dependencies"), error.kind());
+        assert_eq!(Some(Position { line: 2, column: 10 }), error.position());
+        assert_eq!("missing field `dependencies` at line 2, column 10", error.to_string());
+    }
+
+    #[test]
+    fn round_trip() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Save {
+            name: String,
+            notes: String,
+            scores: Vec<f64>,
+            flags: BTreeMap<String, bool>,
+            raw: Option<::serde_bytes::ByteBuf>
+        }
+
+        let mut flags = BTreeMap::new();
+        flags.insert("end".to_owned(), true);
+        flags.insert("two words".to_owned(), false);
+        let save = Save {
+            name: "quote \" and \\ and \u{7} and ü".to_owned(),
+            notes: "multiple\nlines ]] with brackets".to_owned(),
+            scores: vec![1.5, -0.0, 1e300, f64::INFINITY, f64::NEG_INFINITY, 0.1],
+            flags,
+            raw: Some(::serde_bytes::ByteBuf::from(vec![0, 255, 10]))
+        };
+        let formats = [
+            SourceFormat::new(),
+            SourceFormat::pretty().key_order(KeyOrder::Sorted),
+            SourceFormat::pretty()
+                .multiline_strings(MultilineStrings::LongBrackets)
+                .wrapper(Wrapper::Local("save"))
+                .max_width(0),
+            SourceFormat::new().wrapper(Wrapper::None)
+        ];
+        for &format in &formats {
+            let source = to_lua_string_with_format(&save, Config::new(), format).unwrap();
+            let parsed: DeResult<Save> = from_lua_str(&source);
+            assert_eq!(save, parsed.unwrap(), "parsing {}", source);
+            assert_eq!(save, from_lua_reader::<_, Save>(source.as_bytes()).unwrap());
+        }
+    }
+}
@@ -0,0 +1,10 @@
+//! Conversion between rust values and lua source text.
+//!
+//! The text follows the same data model as `LuaSerializer` and
+//! `LuaDeserializer`: a value is written exactly as the table constructor
+//! that would rebuild the `AnyLuaValue` that `to_lua` produces for it, and
+//! text is read into the `AnyLuaValue` that running it would produce,
+//! without running it.
+
+pub mod de;
+pub mod ser;
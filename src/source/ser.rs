@@ -0,0 +1,513 @@
+//! Writing lua values as lua source text.
+
+use std::cmp::Ordering;
+use std::io::Write;
+use std::iter;
+
+use hlua::AnyLuaValue;
+
+use config::{KeyOrder, MultilineStrings, SourceFormat, Wrapper};
+use de::is_identifier;
+use error::ErrorKind;
+use ser::{LuaSerializeError, SerResult};
+
+/// Write `value` as a lua chunk laid out according to `format`.
+pub(crate) fn write_chunk<W>(writer: W, value: &AnyLuaValue, format: SourceFormat) -> SerResult<()>
+    where W: Write
+{
+    let mut writer = SourceWriter { writer, format, depth: 0, column: 0 };
+    match format.wrapper {
+        Wrapper::Return => writer.raw("return ")?,
+        Wrapper::Local(name) if is_identifier(name) => {
+            writer.raw("local ")?;
+            writer.raw(name)?;
+            writer.raw(" = ")?;
+        },
+        Wrapper::Local(name) => return Err(LuaSerializeError(ErrorKind::InvalidValue {
+            expected: "a lua identifier".to_owned(),
+            got: format!("`{}`", name)
+        })),
+        Wrapper::None => ()
+    }
+    writer.value(value)
+}
+
+/// Writes lua values as expressions.
+struct SourceWriter<W> {
+    writer: W,
+    format: SourceFormat,
+    /// How many tables the writer is inside of.
+    depth: usize,
+    /// How many characters have been written on the current line.
+    column: usize
+}
+
+impl<W> SourceWriter<W>
+    where W: Write
+{
+    fn bytes(&mut self, bytes: &[u8]) -> SerResult<()> {
+        self.writer.write_all(bytes)?;
+        match bytes.iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => self.column = width(&bytes[newline + 1..]),
+            None => self.column += width(bytes)
+        }
+        Ok(())
+    }
+
+    fn raw(&mut self, text: &str) -> SerResult<()> {
+        self.bytes(text.as_bytes())
+    }
+
+    fn newline(&mut self) -> SerResult<()> {
+        let indent = " ".repeat(self.depth * self.format.indent);
+        self.raw("\n")?;
+        self.raw(&indent)
+    }
+
+    fn value(&mut self, value: &AnyLuaValue) -> SerResult<()> {
+        match *value {
+            AnyLuaValue::LuaString(ref string) => self.string(string),
+            AnyLuaValue::LuaAnyString(ref bytes) => self.quoted(&bytes.0, true),
+            AnyLuaValue::LuaNumber(number) => self.number(number),
+            AnyLuaValue::LuaBoolean(true) => self.raw("true"),
+            AnyLuaValue::LuaBoolean(false) => self.raw("false"),
+            AnyLuaValue::LuaArray(ref entries) => self.table(entries),
+            AnyLuaValue::LuaNil | AnyLuaValue::LuaOther => self.raw("nil")
+        }
+    }
+
+    /// Write a number so that lua reads it back exactly, using divisions for
+    /// the values that have no literal.
+    fn number(&mut self, number: f64) -> SerResult<()> {
+        if number.is_nan() {
+            self.raw("0/0")
+        } else if number.is_infinite() {
+            self.raw(if number > 0.0 { "1/0" } else { "-1/0" })
+        } else if number.fract() == 0.0 && number.abs() < 1e15 {
+            self.raw(&format!("{}", number))
+        } else {
+            self.raw(&format!("{:?}", number))
+        }
+    }
+
+    /// Write a UTF-8 string, in long brackets if the format asks for them
+    /// and they can hold it.
+    fn string(&mut self, string: &str) -> SerResult<()> {
+        let long = self.format.multiline_strings == MultilineStrings::LongBrackets &&
+            string.contains('\n');
+        match long_bracket_level(string.as_bytes()) {
+            Some(level) if long => {
+                let equals = "=".repeat(level);
+                self.raw(&format!("[{}[\n", equals))?;
+                self.raw(string)?;
+                self.raw(&format!("]{}]", equals))
+            },
+            _ => self.quoted(string.as_bytes(), false)
+        }
+    }
+
+    /// Write a quoted string. Bytes that aren't printable ASCII are written
+    /// as `\ddd` escapes, except for the non-ASCII characters of valid UTF-8
+    /// strings, which are kept as they are.
+    fn quoted(&mut self, bytes: &[u8], escape_non_ascii: bool) -> SerResult<()> {
+        self.raw("\"")?;
+        let mut start = 0;
+        for (position, &byte) in bytes.iter().enumerate() {
+            let escape = match byte {
+                b'"' => "\\\"",
+                b'\\' => "\\\\",
+                b'\n' => "\\n",
+                b'\r' => "\\r",
+                b'\t' => "\\t",
+                0x20..=0x7e => continue,
+                0x80..=0xff if !escape_non_ascii => continue,
+                _ => ""
+            };
+            self.bytes(&bytes[start..position])?;
+            start = position + 1;
+            if escape.is_empty() {
+                self.raw(&format!("\\{:03}", byte))?;
+            } else {
+                self.raw(escape)?;
+            }
+        }
+        self.bytes(&bytes[start..])?;
+        self.raw("\"")
+    }
+
+    /// Write a table constructor, on one line if it fits within the maximum
+    /// width and one entry per line otherwise.
+    fn table(&mut self, entries: &[(AnyLuaValue, AnyLuaValue)]) -> SerResult<()> {
+        let mut entries: Vec<_> = entries.iter().filter(|entry| !is_nil(&entry.1)).collect();
+        if self.format.key_order == KeyOrder::Sorted {
+            entries.sort_by(|a, b| compare_keys(&a.0, &b.0));
+        }
+        if entries.is_empty() {
+            return self.raw("{}");
+        }
+        if !self.format.multiline {
+            return self.entries(&entries, false);
+        }
+        let mut inline = SourceWriter {
+            writer: Vec::new(),
+            format: self.format.multiline(false),
+            depth: self.depth,
+            column: self.column
+        };
+        inline.entries(&entries, false)?;
+        if inline.column <= self.format.max_width && !inline.writer.contains(&b'\n') {
+            self.bytes(&inline.writer)
+        } else {
+            self.entries(&entries, true)
+        }
+    }
+
+    /// Write the entries of a table constructor. Entries whose keys continue
+    /// the sequence from 1 are written without keys.
+    fn entries(
+        &mut self,
+        entries: &[&(AnyLuaValue, AnyLuaValue)],
+        multiline: bool
+    ) -> SerResult<()> {
+        self.raw("{")?;
+        self.depth += 1;
+        let mut length = 0.0;
+        for (position, entry) in entries.iter().enumerate() {
+            if position > 0 {
+                self.raw(",")?;
+            }
+            if multiline {
+                self.newline()?;
+            } else {
+                self.raw(" ")?;
+            }
+            match entry.0 {
+                AnyLuaValue::LuaNumber(index) if index == length + 1.0 => length = index,
+                AnyLuaValue::LuaString(ref name) if is_identifier(name) => {
+                    self.raw(name)?;
+                    self.raw(" = ")?;
+                },
+                AnyLuaValue::LuaNumber(number) if number.is_nan() => {
+                    return Err(LuaSerializeError(ErrorKind::InvalidKey("NaN".to_owned())));
+                },
+                ref key if is_nil(key) => {
+                    return Err(LuaSerializeError(ErrorKind::InvalidKey("nil".to_owned())));
+                },
+                ref key => {
+                    self.raw("[")?;
+                    match *key {
+                        AnyLuaValue::LuaString(ref string) => {
+                            self.quoted(string.as_bytes(), false)?
+                        },
+                        ref key => self.value(key)?
+                    }
+                    self.raw("] = ")?;
+                }
+            }
+            self.value(&entry.1)?;
+        }
+        self.depth -= 1;
+        if multiline {
+            if self.format.trailing_commas {
+                self.raw(",")?;
+            }
+            self.newline()?;
+        } else {
+            self.raw(" ")?;
+        }
+        self.raw("}")
+    }
+}
+
+/// The number of characters in UTF-8 text.
+fn width(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&byte| byte & 0xc0 != 0x80).count()
+}
+
+/// Return the number of `=` signs needed for long brackets to hold `bytes`
+/// exactly, or `None` if they can't, because lua would change its line
+/// endings or it contains control characters.
+fn long_bracket_level(bytes: &[u8]) -> Option<usize> {
+    let printable = bytes.iter().all(|&byte| {
+        byte == b'\n' || byte == b'\t' || (byte >= 0x20 && byte != 0x7f)
+    });
+    if !printable {
+        return None;
+    }
+    // The string must not contain the closing bracket, including one formed
+    // with the first character of the closing bracket itself.
+    let mut text = bytes.to_vec();
+    text.push(b']');
+    (0..).find(|&level| {
+        let mut close = vec![b']'];
+        close.extend(iter::repeat_n(b'=', level));
+        close.push(b']');
+        !text.windows(close.len()).any(|window| window == &close[..])
+    })
+}
+
+/// Order keys for `KeyOrder::Sorted`.
+fn compare_keys(a: &AnyLuaValue, b: &AnyLuaValue) -> Ordering {
+    fn rank(key: &AnyLuaValue) -> u8 {
+        match *key {
+            AnyLuaValue::LuaNumber(_) => 0,
+            AnyLuaValue::LuaString(_) | AnyLuaValue::LuaAnyString(_) => 1,
+            _ => 2
+        }
+    }
+    fn string(key: &AnyLuaValue) -> Option<&[u8]> {
+        match *key {
+            AnyLuaValue::LuaString(ref string) => Some(string.as_bytes()),
+            AnyLuaValue::LuaAnyString(ref bytes) => Some(&bytes.0),
+            _ => None
+        }
+    }
+    rank(a).cmp(&rank(b)).then_with(|| match (a, b) {
+        (&AnyLuaValue::LuaNumber(a), &AnyLuaValue::LuaNumber(b)) => {
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        },
+        _ => match (string(a), string(b)) {
+            (Some(a), Some(b)) => a.cmp(b),
+            _ => Ordering::Equal
+        }
+    })
+}
+
+fn is_nil(value: &AnyLuaValue) -> bool {
+    matches!(*value, AnyLuaValue::LuaNil | AnyLuaValue::LuaOther)
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use hlua::AnyLuaValue;
+    use serde::Serialize;
+    use serde_bytes::Bytes;
+
+    use std::collections::BTreeMap;
+    use std::f64;
+
+    use ::{to_lua_string, to_lua_string_with_config, to_lua_string_with_format};
+    use ::{to_lua_with_config, Config, Value};
+    use config::{ByteEncoding, EnumRepr, KeyOrder, MultilineStrings, SourceFormat, Wrapper};
+    use error::ErrorKind;
+    use lua;
+
+    /// Check the source text, and that running it rebuilds what `to_lua`
+    /// produces.
+    fn check<S>(value: &S, config: Config, source: &str)
+        where S: Serialize
+    {
+        assert_eq!(source, to_lua_string_with_config(value, config).unwrap());
+        let mut lua = hlua::Lua::new();
+        let executed: AnyLuaValue = lua.execute(source).unwrap();
+        let expected = to_lua_with_config(value, config).unwrap();
+        match expected {
+            AnyLuaValue::LuaNumber(number) if number.is_nan() => match executed {
+                AnyLuaValue::LuaNumber(number) => assert!(number.is_nan()),
+                other => panic!("expected NaN, got {:?}", other)
+            },
+            expected => assert_eq!(Value::from(expected).sorted(), Value::from(executed).sorted())
+        }
+    }
+
+    #[derive(Serialize)]
+    struct Point {
+        x: f64,
+        y: f64
+    }
+
+    #[derive(Serialize)]
+    enum Shape {
+        Circle { radius: f64 },
+        Polygon(Vec<Point>)
+    }
+
+    #[test]
+    fn scalars() {
+        let config = Config::new();
+        check(&(), config, "return nil");
+        check(&true, config, "return true");
+        check(&3u8, config, "return 3");
+        check(&-0.5, config, "return -0.5");
+        check(&1e300, config, "return 1e300");
+        check(&0.1, config, "return 0.1");
+        check(&f64::INFINITY, config, "return 1/0");
+        check(&f64::NEG_INFINITY, config, "return -1/0");
+        check(&f64::NAN, config, "return 0/0");
+    }
+
+    #[test]
+    fn strings() {
+        let config = Config::new();
+        check(&"plain", config, r#"return "plain""#);
+        check(&"quote \" backslash \\ \n\r\t", config, r#"return "quote \" backslash \\ \n\r\t""#);
+        check(&"\u{0}1 bell \u{7}", config, r#"return "\0001 bell \007""#);
+        check(&"ünïcödé", config, r#"return "ünïcödé""#);
+        check(
+            &Bytes::new(b"\xff\x00a"),
+            config.byte_encoding(ByteEncoding::Raw),
+            r#"return "\255\000a""#
+        );
+    }
+
+    #[test]
+    fn tables() {
+        let config = Config::new();
+        check(&Point { x: 3.0, y: 4.0 }, config, "return { x = 3, y = 4 }");
+        check(&Vec::<u8>::new(), config, "return {}");
+        check(&vec!["a", "b"], config, r#"return { "a", "b" }"#);
+        check(&vec![Some(1), None, Some(3)], config, "return { 1, [3] = 3 }");
+
+        let mut map = BTreeMap::new();
+        map.insert("end", 1);
+        map.insert("two words", 2);
+        map.insert("_ok", 3);
+        check(&map, config, r#"return { _ok = 3, ["end"] = 1, ["two words"] = 2 }"#);
+
+        let mut map = BTreeMap::new();
+        map.insert(2, "two");
+        map.insert(-1, "minus one");
+        check(&map, config, r#"return { [-1] = "minus one", [2] = "two" }"#);
+
+        let shapes = vec![
+            Shape::Circle { radius: 1.0 },
+            Shape::Polygon(vec![Point { x: 0.0, y: 0.0 }, Point { x: 1.0, y: 0.5 }])
+        ];
+        check(
+            &shapes,
+            config,
+            "return { { Circle = { radius = 1 } }, \
+             { Polygon = { { x = 0, y = 0 }, { x = 1, y = 0.5 } } } }"
+        );
+        check(
+            &shapes,
+            config.enum_repr(EnumRepr::Internal { tag: "type" }),
+            concat!(
+                r#"return { { type = "Circle", radius = 1 }, "#,
+                r#"{ type = "Polygon", { x = 0, y = 0 }, { x = 1, y = 0.5 } } }"#
+            )
+        );
+        assert_eq!("return { true }", to_lua_string(&(true,)).unwrap());
+    }
+
+    /// Check formatted source text, and that running it rebuilds what
+    /// `to_lua` produces.
+    fn check_format<S>(value: &S, format: SourceFormat, source: &str)
+        where S: Serialize
+    {
+        assert_eq!(source, to_lua_string_with_format(value, Config::new(), format).unwrap());
+        let mut lua = hlua::Lua::new();
+        let executed: AnyLuaValue = lua.execute(source).unwrap();
+        let expected = to_lua_with_config(value, Config::new()).unwrap();
+        assert_eq!(Value::from(expected).sorted(), Value::from(executed).sorted());
+    }
+
+    #[test]
+    fn formats() {
+        let shapes = vec![
+            Shape::Circle { radius: 1.0 },
+            Shape::Polygon(vec![Point { x: 0.0, y: 0.0 }, Point { x: 1.0, y: 0.5 }])
+        ];
+        check_format(&shapes, SourceFormat::pretty(), concat!(
+            "return {\n",
+            "    { Circle = { radius = 1 } },\n",
+            "    { Polygon = { { x = 0, y = 0 }, { x = 1, y = 0.5 } } },\n",
+            "}"
+        ));
+        check_format(&shapes, SourceFormat::pretty().max_width(30).indent(1), concat!(
+            "return {\n",
+            " { Circle = { radius = 1 } },\n",
+            " {\n",
+            "  Polygon = {\n",
+            "   { x = 0, y = 0 },\n",
+            "   { x = 1, y = 0.5 },\n",
+            "  },\n",
+            " },\n",
+            "}"
+        ));
+        check_format(&shapes, SourceFormat::new().multiline(true).max_width(0), concat!(
+            "return {\n",
+            "    {\n",
+            "        Circle = {\n",
+            "            radius = 1\n",
+            "        }\n",
+            "    },\n",
+            "    {\n",
+            "        Polygon = {\n",
+            "            {\n",
+            "                x = 0,\n",
+            "                y = 0\n",
+            "            },\n",
+            "            {\n",
+            "                x = 1,\n",
+            "                y = 0.5\n",
+            "            }\n",
+            "        }\n",
+            "    }\n",
+            "}"
+        ));
+
+        let mut map = BTreeMap::new();
+        map.insert("b", vec![2]);
+        map.insert("a", vec![1, 2]);
+        map.insert("c", vec![]);
+        let format = SourceFormat::new().wrapper(Wrapper::None);
+        check_format(&map, format.wrapper(Wrapper::Return), "return { a = { 1, 2 }, b = { 2 }, c = {} }");
+        assert_eq!(
+            "{ a = { 1, 2 }, b = { 2 }, c = {} }",
+            to_lua_string_with_format(&map, Config::new(), format).unwrap()
+        );
+        assert_eq!(
+            "local map = { a = { 1, 2 }, b = { 2 }, c = {} }",
+            to_lua_string_with_format(&map, Config::new(), format.wrapper(Wrapper::Local("map")))
+                .unwrap()
+        );
+        assert_eq!(
+            &ErrorKind::InvalidValue {
+                expected: "a lua identifier".to_owned(),
+                got: "`end`".to_owned()
+            },
+            to_lua_string_with_format(&map, Config::new(), format.wrapper(Wrapper::Local("end")))
+                .unwrap_err()
+                .kind()
+        );
+    }
+
+    #[test]
+    fn key_order() {
+        let value = lua!{ z = 1, "first", ["a b"] = 2, [true] = 3, [-1] = 4, a = 5, "second" };
+        assert_eq!(
+            r#"return { z = 1, "first", ["a b"] = 2, [true] = 3, [-1] = 4, a = 5, "second" }"#,
+            to_lua_string(&::Value::from(value.clone())).unwrap()
+        );
+        check_format(
+            &::Value::from(value),
+            SourceFormat::new().key_order(KeyOrder::Sorted),
+            r#"return { [-1] = 4, "first", "second", a = 5, ["a b"] = 2, z = 1, [true] = 3 }"#
+        );
+    }
+
+    #[test]
+    fn long_strings() {
+        let format = SourceFormat::new().multiline_strings(MultilineStrings::LongBrackets);
+        check_format(&"one line", format, r#"return "one line""#);
+        check_format(&"two\nlines", format, "return [[\ntwo\nlines]]");
+        check_format(&"\nleading newline", format, "return [[\n\nleading newline]]");
+        check_format(&"a]]\nb]", format, "return [=[\na]]\nb]]=]");
+        check_format(&"a]=]\nb]", format, "return [==[\na]=]\nb]]==]");
+        check_format(&"crlf\r\n", format, r#"return "crlf\r\n""#);
+        check_format(&"bell\u{7}\n", format, r#"return "bell\007\n""#);
+
+        let mut map = BTreeMap::new();
+        map.insert("key\nwith newline", "value\nwith newline");
+        check_format(&map, format, "return { [\"key\\nwith newline\"] = [[\nvalue\nwith newline]] }");
+        check_format(&map, format.multiline(true).trailing_commas(true), concat!(
+            "return {\n",
+            "    [\"key\\nwith newline\"] = [[\n",
+            "value\n",
+            "with newline]],\n",
+            "}"
+        ));
+    }
+}
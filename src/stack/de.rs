@@ -0,0 +1,1584 @@
+//! Deserialization from values on the lua stack to rust values.
+
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::ptr;
+use std::slice;
+
+use hlua::AsMutLua;
+use hlua::ffi;
+use serde;
+use serde::de::{Deserializer, IntoDeserializer, Unexpected, Visitor};
+
+use config::{ByteEncoding, Coercion, Config, Cycles, EnumRepr, Limits, SparseArrays};
+use config::Utf8Mode;
+use de::{decode_bytes, format_number, index, lossy_number, visit_number, DeResult};
+use de::{sequence_len, sparse_len, LuaDeserializeError, Usage};
+use error::ErrorKind;
+use shared;
+use stack::check_stack;
+use de::PathSegment;
+
+/// A deserializer over a value on the lua stack.
+///
+/// Tables are walked in place with `lua_next` and `lua_rawget` rather than
+/// copied into an `AnyLuaValue` first. Struct fields are looked up by name,
+/// and values that the target type ignores are never read. As a result,
+/// entries that aren't fields of a struct go unnoticed, even with
+/// `#[serde(deny_unknown_fields)]`, unless `Config::read_all_fields` is set.
+///
+/// Strings are copied out of lua, so types that borrow from their input
+/// can't be deserialized. The stack is left as it was, whether or not
+/// deserialization succeeds.
+///
+/// Unlike an `AnyLuaValue`, a table on the stack can contain itself. Such
+/// tables are handled according to `Config::cycles`.
+#[derive(Clone, Copy)]
+pub struct LuaStackDeserializer<'a> {
+    lua: *mut ffi::lua_State,
+    index: c_int,
+    config: Config,
+    // The tag field of an internally tagged enum, which is hidden when the
+    // rest of the table is deserialized as the variant's payload.
+    skip: Option<&'static str>,
+    // Whether the value has been checked against the limits, which is done
+    // once, for the whole value, by the outermost deserializer.
+    checked: bool,
+    // The tables that the value is inside of.
+    ancestors: Option<&'a Ancestor<'a>>,
+    lifetime: PhantomData<&'a mut ()>
+}
+
+/// A table being read, in a list of the tables enclosing a value.
+struct Ancestor<'a> {
+    table: *const (),
+    parent: Option<&'a Ancestor<'a>>
+}
+
+impl<'a> LuaStackDeserializer<'a> {
+    /// Return a deserializer over the value at `index` on the stack of
+    /// `lua`.
+    pub fn new<'lua, L>(lua: &'a mut L, index: i32) -> LuaStackDeserializer<'a>
+        where L: AsMutLua<'lua>
+    {
+        LuaStackDeserializer::with_config(lua, index, Config::new())
+    }
+
+    /// Return a deserializer over the value at `index` on the stack of `lua`
+    /// using the provided configuration.
+    pub fn with_config<'lua, L>(
+        lua: &'a mut L,
+        index: i32,
+        config: Config
+    ) -> LuaStackDeserializer<'a>
+        where L: AsMutLua<'lua>
+    {
+        LuaStackDeserializer::from_state(lua.as_mut_lua().state_ptr(), index, config)
+    }
+
+    /// Return a deserializer over the value at `index` on the stack of a lua
+    /// state that is borrowed elsewhere, such as by a function call.
+    pub(crate) fn from_state(
+        lua: *mut ffi::lua_State,
+        index: c_int,
+        config: Config
+    ) -> LuaStackDeserializer<'a> {
+        LuaStackDeserializer {
+            lua,
+            index: unsafe { ffi::lua_absindex(lua, index) },
+            config,
+            skip: None,
+            checked: false,
+            ancestors: None,
+            lifetime: PhantomData
+        }
+    }
+
+    /// Return a deserializer over another value on the stack.
+    ///
+    /// A table found inside itself is replaced with the marker, if there is
+    /// one, which is pushed for the purpose.
+    fn at(self, index: c_int) -> LuaStackDeserializer<'a> {
+        let value = LuaStackDeserializer { index, skip: None, checked: true, ..self };
+        match self.config.cycles {
+            Cycles::Marker(marker) if value.is_cycle() => unsafe {
+                ffi::lua_pushlstring(self.lua, marker.as_ptr() as *const _, marker.len() as _);
+                LuaStackDeserializer { index: ffi::lua_gettop(self.lua), ..value }
+            },
+            _ => value
+        }
+    }
+
+    /// Return the table as the innermost of those being read.
+    fn ancestor(&self) -> Ancestor<'a> {
+        Ancestor {
+            table: unsafe { ffi::lua_topointer(self.lua, self.index) } as *const (),
+            parent: self.ancestors
+        }
+    }
+
+    /// Return a deserializer over the table whose values know that they are
+    /// inside it.
+    fn within<'b>(self, ancestor: &'b Ancestor<'b>) -> LuaStackDeserializer<'b>
+        where 'a: 'b
+    {
+        LuaStackDeserializer { ancestors: Some(ancestor), ..self }
+    }
+
+    /// Return whether the value is a table found inside itself.
+    fn is_cycle(&self) -> bool {
+        if unsafe { ffi::lua_type(self.lua, self.index) } != ffi::LUA_TTABLE {
+            return false;
+        }
+        let table = unsafe { ffi::lua_topointer(self.lua, self.index) } as *const ();
+        let mut ancestors = self.ancestors;
+        while let Some(ancestor) = ancestors {
+            if ancestor.table == table {
+                return true;
+            }
+            ancestors = ancestor.parent;
+        }
+        false
+    }
+
+    /// Check the value before reading it: that it isn't a table found inside
+    /// itself, and, once for the whole value, that it is within the limits.
+    fn check(&self) -> DeResult<()> {
+        if self.is_cycle() {
+            return Err(ErrorKind::CyclicTable.into());
+        }
+        if self.checked || self.config.limits == Limits::new() {
+            return Ok(());
+        }
+        self.walk(&mut Usage::new(self.config), 0)
+    }
+
+    /// Count the value, which is inside `depth` tables, and everything in it
+    /// against the limits. Tables found inside themselves aren't walked
+    /// again.
+    fn walk(&self, usage: &mut Usage, depth: usize) -> DeResult<()> {
+        let lua = self.lua;
+        usage.node()?;
+        match unsafe { ffi::lua_type(lua, self.index) } {
+            ffi::LUA_TSTRING => usage.string(unsafe { ffi::lua_rawlen(lua, self.index) }),
+            ffi::LUA_TTABLE if !self.is_cycle() => {
+                usage.depth(depth + 1)?;
+                let ancestor = self.ancestor();
+                let this = self.within(&ancestor);
+                let packed = self.config.sparse_arrays == SparseArrays::Packed;
+                this.scoped(|_| unsafe {
+                    let mut entries = 0;
+                    let (mut max, mut count, mut declared, mut sequence) = (0, 0, None, true);
+                    ffi::lua_pushnil(lua);
+                    while ffi::lua_next(lua, this.index) != 0 {
+                        entries += 1;
+                        usage.entries(entries)?;
+                        let value = ffi::lua_gettop(lua);
+                        if ffi::lua_type(lua, value - 1) == ffi::LUA_TNUMBER {
+                            match index(ffi::lua_tonumberx(lua, value - 1, ptr::null_mut())) {
+                                Some(index) if index >= 1 => {
+                                    count += 1;
+                                    max = max.max(index);
+                                },
+                                _ => sequence = false
+                            }
+                        } else if packed && raw_bytes(lua, value - 1) == Some(b"n") &&
+                            ffi::lua_type(lua, value) == ffi::LUA_TNUMBER
+                        {
+                            declared = index(ffi::lua_tonumberx(lua, value, ptr::null_mut()));
+                            sequence &= declared.is_some();
+                        } else {
+                            sequence = false;
+                        }
+                        this.at(value - 1).walk(usage, depth + 1)
+                            .and_then(|()| this.at(value).walk(usage, depth + 1))
+                            .map_err(|error| error.at(key_segment(lua, value - 1)))?;
+                        // Keep only the key, dropping any markers too.
+                        ffi::lua_settop(lua, value - 1);
+                    }
+                    if sequence {
+                        usage.holes(max, count, declared)?;
+                    }
+                    Ok(())
+                })
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Run `f`, then restore the stack to the height it had beforehand, so
+    /// that whatever `f` pushes is popped again even if it fails. `f` is
+    /// passed that height.
+    fn scoped<F, R>(&self, f: F) -> DeResult<R>
+        where F: FnOnce(c_int) -> DeResult<R>
+    {
+        let top = unsafe { ffi::lua_gettop(self.lua) };
+        // At most six values are pushed before the next nested value checks
+        // again: a key, a value, an enum variant and its payload, and the
+        // markers standing in for two of them.
+        check_stack(self.lua, 6)?;
+        let result = f(top);
+        unsafe { ffi::lua_settop(self.lua, top) };
+        result
+    }
+
+    /// The type of the value, with the null sentinel read as nil.
+    fn lua_type(&self) -> c_int {
+        let lua_type = unsafe { ffi::lua_type(self.lua, self.index) };
+        match self.config.null_sentinel {
+            Some(sentinel) if raw_bytes(self.lua, self.index) == Some(sentinel.as_bytes()) => {
+                ffi::LUA_TNIL
+            },
+            _ => lua_type
+        }
+    }
+
+    /// Return the value as a number, letting lua convert strings if
+    /// coercion is enabled.
+    fn number(&self) -> Option<f64> {
+        match (self.lua_type(), self.config.coercion) {
+            (ffi::LUA_TNUMBER, _) => Some(unsafe {
+                ffi::lua_tonumberx(self.lua, self.index, ptr::null_mut())
+            }),
+            (ffi::LUA_TSTRING, Coercion::Lenient { .. }) => {
+                let mut is_number = 0;
+                let number = unsafe { ffi::lua_tonumberx(self.lua, self.index, &mut is_number) };
+                if is_number != 0 { Some(number) } else { None }
+            },
+            _ => None
+        }
+    }
+
+    fn is_table(&self) -> bool {
+        self.lua_type() == ffi::LUA_TTABLE
+    }
+
+    /// Return whether the key at `index` is the hidden tag field.
+    fn is_skipped(&self, index: c_int) -> bool {
+        self.skip.is_some_and(|skip| raw_bytes(self.lua, index) == Some(skip.as_bytes()))
+    }
+
+    /// Push the value of the table's field `field`, returning its index.
+    fn get_field(&self, field: &str) -> c_int {
+        unsafe {
+            ffi::lua_pushlstring(self.lua, field.as_ptr() as *const _, field.len() as _);
+            ffi::lua_rawget(self.lua, self.index);
+            ffi::lua_gettop(self.lua)
+        }
+    }
+
+    /// Push the value of the table at index `index`, returning its index on
+    /// the stack.
+    fn get_index(&self, index: usize) -> c_int {
+        unsafe {
+            ffi::lua_pushnumber(self.lua, index as f64);
+            ffi::lua_rawget(self.lua, self.index);
+            ffi::lua_gettop(self.lua)
+        }
+    }
+
+    /// Return the value as a string, decoding non-UTF-8 strings according to
+    /// the configured UTF-8 mode.
+    fn string<E>(&self, expected: &E) -> DeResult<Cow<'_, str>>
+        where E: serde::de::Expected
+    {
+        match raw_bytes(self.lua, self.index) {
+            Some(bytes) if self.lua_type() == ffi::LUA_TSTRING => {
+                match ::std::str::from_utf8(bytes) {
+                    Ok(string) => Ok(Cow::Borrowed(string)),
+                    Err(error) => match self.config.utf8_mode {
+                        Utf8Mode::Strict => Err(ErrorKind::InvalidUtf8 {
+                            offset: error.valid_up_to()
+                        }.into()),
+                        Utf8Mode::Lossy => Ok(String::from_utf8_lossy(bytes))
+                    }
+                }
+            },
+            // Numbers are formatted here rather than by `lua_tolstring`,
+            // which would convert them in place.
+            None if self.config.coercion != Coercion::Strict => match self.number() {
+                Some(number) => Ok(Cow::Owned(format_number(number))),
+                None => Err(self.error(expected))
+            },
+            _ => Err(self.error(expected))
+        }
+    }
+
+    /// Decode a byte buffer according to the configured byte encoding.
+    fn bytes<E>(&self, expected: &E) -> DeResult<Cow<'_, [u8]>>
+        where E: serde::de::Expected
+    {
+        match (self.lua_type(), self.config.byte_encoding) {
+            (ffi::LUA_TSTRING, encoding) => {
+                let bytes = raw_bytes(self.lua, self.index).unwrap();
+                match ::std::str::from_utf8(bytes) {
+                    Ok(string) => decode_bytes(string, encoding, expected),
+                    // None of the textual encodings can produce non-UTF-8
+                    // strings, so they are always raw bytes.
+                    Err(_) => Ok(Cow::Borrowed(bytes))
+                }
+            },
+            (ffi::LUA_TTABLE, ByteEncoding::Array) => match self.sequence_len()? {
+                Some(len) => self.scoped(|top| (1..=len).map(|index| {
+                    unsafe { ffi::lua_settop(self.lua, top) };
+                    let byte = self.at(self.get_index(index));
+                    match byte.number() {
+                        Some(number) if number as u8 as f64 == number => Ok(number as u8),
+                        _ => Err(byte.error(&"an integer from 0 to 255"))
+                    }
+                }).collect::<DeResult<Vec<u8>>>()).map(Cow::Owned),
+                None => Err(serde::de::Error::invalid_type(Unexpected::Map, expected))
+            },
+            _ => Err(self.error(expected))
+        }
+    }
+
+    /// Return the length of the table if it is read as a sequence according
+    /// to the sparse array policy, by looking at its keys only.
+    fn sequence_len(&self) -> DeResult<Option<usize>> {
+        let lua = self.lua;
+        let policy = self.config.sparse_arrays;
+        self.scoped(|_| unsafe {
+            let mut count = 0;
+            let mut max = 0;
+            let mut declared = None;
+            ffi::lua_pushnil(lua);
+            while ffi::lua_next(lua, self.index) != 0 {
+                if ffi::lua_type(lua, -2) == ffi::LUA_TNUMBER {
+                    match index(ffi::lua_tonumberx(lua, -2, ptr::null_mut())) {
+                        Some(index) if index >= 1 => {
+                            count += 1;
+                            max = max.max(index);
+                        },
+                        _ => return Ok(None)
+                    }
+                } else if self.is_skipped(-2) {
+                } else if policy == SparseArrays::Packed && raw_bytes(lua, -2) == Some(b"n") {
+                    if ffi::lua_type(lua, -1) != ffi::LUA_TNUMBER {
+                        return Ok(None);
+                    }
+                    match index(ffi::lua_tonumberx(lua, -1, ptr::null_mut())) {
+                        Some(index) => declared = Some(index),
+                        None => return Ok(None)
+                    }
+                } else {
+                    return Ok(None);
+                }
+                ffi::lua_pop(lua, 1);
+            }
+            match sequence_len(policy, max, count, declared) {
+                Some(len) => sparse_len(len, count, self.config.limits).map(Some),
+                None => Ok(None)
+            }
+        })
+    }
+
+    /// Return the length of the value as a sequence, subject to the
+    /// configured sparse array policy.
+    fn sequence<E>(&self, expected: &E) -> DeResult<usize>
+        where E: serde::de::Expected
+    {
+        if !self.is_table() {
+            return Err(self.error(expected));
+        }
+        self.sequence_len()?.ok_or_else(|| {
+            serde::de::Error::invalid_type(Unexpected::Map, expected)
+        })
+    }
+
+    /// Count the entries of the table, leaving out the hidden tag field.
+    fn count_entries(&self) -> DeResult<usize> {
+        let lua = self.lua;
+        self.scoped(|_| unsafe {
+            let mut count = 0;
+            ffi::lua_pushnil(lua);
+            while ffi::lua_next(lua, self.index) != 0 {
+                if !self.is_skipped(-2) {
+                    count += 1;
+                }
+                ffi::lua_pop(lua, 1);
+            }
+            Ok(count)
+        })
+    }
+
+    /// Push the variant name and payload of the table holding an enum
+    /// variant, according to the configured enum representation.
+    ///
+    /// This must be called from within `scoped`.
+    fn untag_variant<E>(
+        &self,
+        expected: &E
+    ) -> DeResult<(LuaStackDeserializer<'a>, LuaStackVariantAccess<'a>)>
+        where E: serde::de::Expected
+    {
+        let lua = self.lua;
+        match self.config.enum_repr {
+            EnumRepr::External => {
+                let count = self.count_entries()?;
+                if count != 1 {
+                    return Err(serde::de::Error::invalid_length(count, expected));
+                }
+                unsafe {
+                    ffi::lua_pushnil(lua);
+                    while ffi::lua_next(lua, self.index) != 0 && self.is_skipped(-2) {
+                        ffi::lua_pop(lua, 1);
+                    }
+                    let value = ffi::lua_gettop(lua);
+                    Ok((self.at(value - 1), LuaStackVariantAccess(
+                        self.at(value),
+                        Some(key_segment(lua, value - 1))
+                    )))
+                }
+            },
+            EnumRepr::Internal { tag } => {
+                let variant = self.get_field(tag);
+                if unsafe { ffi::lua_type(lua, variant) } == ffi::LUA_TNIL {
+                    return Err(serde::de::Error::missing_field(tag));
+                }
+                // The payload is the table itself, which isn't inside
+                // itself.
+                let payload = LuaStackDeserializer {
+                    skip: Some(tag),
+                    checked: true,
+                    ancestors: self.ancestors.and_then(|ancestor| ancestor.parent),
+                    ..*self
+                };
+                Ok((self.at(variant), LuaStackVariantAccess(payload, None)))
+            },
+            EnumRepr::Adjacent { tag, content } => {
+                self.scoped(|_| unsafe {
+                    ffi::lua_pushnil(lua);
+                    while ffi::lua_next(lua, self.index) != 0 {
+                        let key = raw_bytes(lua, -2);
+                        if key != Some(tag.as_bytes()) && key != Some(content.as_bytes()) &&
+                            !self.is_skipped(-2)
+                        {
+                            return Err(serde::de::Error::custom(format!(
+                                "unexpected field in adjacently tagged variant, \
+                                 expected '{}' or '{}'",
+                                tag,
+                                content
+                            )));
+                        }
+                        ffi::lua_pop(lua, 1);
+                    }
+                    Ok(())
+                })?;
+                let variant = self.get_field(tag);
+                if unsafe { ffi::lua_type(lua, variant) } == ffi::LUA_TNIL {
+                    return Err(serde::de::Error::missing_field(tag));
+                }
+                let payload = self.get_field(content);
+                Ok((self.at(variant), LuaStackVariantAccess(
+                    self.at(payload),
+                    Some(PathSegment::Field(content.to_owned()))
+                )))
+            },
+            EnumRepr::Array => match self.sequence_len()? {
+                Some(len) => {
+                    if len == 0 || len > 2 {
+                        return Err(serde::de::Error::invalid_length(len, expected));
+                    }
+                    let variant = self.get_index(1);
+                    let payload = self.get_index(2);
+                    Ok((self.at(variant), LuaStackVariantAccess(
+                        self.at(payload),
+                        Some(PathSegment::Index(2))
+                    )))
+                },
+                None => Err(serde::de::Error::invalid_type(Unexpected::Map, expected))
+            }
+        }
+    }
+
+    fn visit_seq<'de, V>(self, len: usize, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        let ancestor = self.ancestor();
+        let this = self.within(&ancestor);
+        this.scoped(|top| visitor.visit_seq(LuaStackSeqAccess {
+            deserializer: this,
+            top,
+            next: 1,
+            len
+        }))
+    }
+
+    fn visit_map<'de, V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        let ancestor = self.ancestor();
+        let this = self.within(&ancestor);
+        this.scoped(|top| {
+            unsafe { ffi::lua_pushnil(this.lua) };
+            visitor.visit_map(LuaStackMapAccess {
+                deserializer: this,
+                key: top + 1,
+                done: false
+            })
+        })
+    }
+
+    /// Return an error for a value of the wrong type.
+    fn error<E>(&self, expected: &E) -> LuaDeserializeError
+        where E: serde::de::Expected
+    {
+        let unexpected = match self.lua_type() {
+            ffi::LUA_TNIL => Unexpected::Unit,
+            ffi::LUA_TBOOLEAN => Unexpected::Bool(unsafe {
+                ffi::lua_toboolean(self.lua, self.index) != 0
+            }),
+            ffi::LUA_TNUMBER => Unexpected::Float(self.number().unwrap()),
+            ffi::LUA_TSTRING => {
+                let bytes = raw_bytes(self.lua, self.index).unwrap();
+                match ::std::str::from_utf8(bytes) {
+                    Ok(string) => Unexpected::Str(string),
+                    Err(_) => Unexpected::Bytes(bytes)
+                }
+            },
+            ffi::LUA_TTABLE => Unexpected::Map,
+            lua_type => Unexpected::Other(type_name(lua_type))
+        };
+        serde::de::Error::invalid_type(unexpected, expected)
+    }
+}
+
+/// Return the contents of the value at `index` if it is a string.
+///
+/// Unlike `lua_tolstring` on its own, this never converts numbers to
+/// strings in place. The slice is only valid while the string stays on the
+/// stack.
+fn raw_bytes<'s>(lua: *mut ffi::lua_State, index: c_int) -> Option<&'s [u8]> {
+    unsafe {
+        if ffi::lua_type(lua, index) != ffi::LUA_TSTRING {
+            return None;
+        }
+        let mut len: usize = 0;
+        let data = ffi::lua_tolstring(lua, index, &mut len);
+        Some(slice::from_raw_parts(data as *const u8, len))
+    }
+}
+
+/// Return the name of a lua type.
+fn type_name(lua_type: c_int) -> &'static str {
+    match lua_type {
+        ffi::LUA_TNIL => "nil",
+        ffi::LUA_TBOOLEAN => "boolean",
+        ffi::LUA_TNUMBER => "number",
+        ffi::LUA_TSTRING => "string",
+        ffi::LUA_TTABLE => "table",
+        ffi::LUA_TFUNCTION => "function",
+        ffi::LUA_TUSERDATA | ffi::LUA_TLIGHTUSERDATA => "userdata",
+        ffi::LUA_TTHREAD => "thread",
+        _ => "no value"
+    }
+}
+
+/// Return the path segment leading to the table entry whose key is at
+/// `key`.
+fn key_segment(lua: *mut ffi::lua_State, key: c_int) -> PathSegment {
+    unsafe {
+        match ffi::lua_type(lua, key) {
+            ffi::LUA_TSTRING => {
+                let bytes = raw_bytes(lua, key).unwrap();
+                match ::std::str::from_utf8(bytes) {
+                    Ok(key) => PathSegment::Field(key.to_owned()),
+                    Err(_) => PathSegment::Key(format!("{:?}", String::from_utf8_lossy(bytes)))
+                }
+            },
+            ffi::LUA_TNUMBER => {
+                let number = ffi::lua_tonumberx(lua, key, ptr::null_mut());
+                match index(number) {
+                    Some(index) => PathSegment::Index(index),
+                    None => PathSegment::Key(number.to_string())
+                }
+            },
+            ffi::LUA_TBOOLEAN => {
+                PathSegment::Key((ffi::lua_toboolean(lua, key) != 0).to_string())
+            },
+            _ => PathSegment::Key("?".to_owned())
+        }
+    }
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident => $visit:ident: $ty:ident,)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> DeResult<V::Value>
+                where V: Visitor<'de>
+            {
+                self.check()?;
+                match self.number() {
+                    // Casts saturate, and the largest 64-bit integers round
+                    // up to the first float out of range, so that float is
+                    // ruled out explicitly.
+                    Some(number) if (
+                        number as $ty as f64 == number && number < $ty::MAX as f64 + 1.0
+                    ) => visitor.$visit(number as $ty),
+                    Some(number) => Err(lossy_number(number, stringify!($ty))),
+                    None => Err(self.error(&visitor))
+                }
+            }
+        )*
+    }
+}
+
+impl<'a, 'de> Deserializer<'de> for LuaStackDeserializer<'a> {
+    type Error = LuaDeserializeError;
+
+    fn deserialize_any<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        match self.lua_type() {
+            ffi::LUA_TSTRING => {
+                let bytes = raw_bytes(self.lua, self.index).unwrap();
+                match (::std::str::from_utf8(bytes), self.config.utf8_mode) {
+                    (Ok(string), _) => visitor.visit_str(string),
+                    (Err(_), Utf8Mode::Strict) => visitor.visit_bytes(bytes),
+                    (Err(_), Utf8Mode::Lossy) => visitor.visit_str(&String::from_utf8_lossy(bytes))
+                }
+            },
+            ffi::LUA_TNUMBER => visit_number(self.number().unwrap(), visitor),
+            ffi::LUA_TBOOLEAN => visitor.visit_bool(unsafe {
+                ffi::lua_toboolean(self.lua, self.index) != 0
+            }),
+            ffi::LUA_TTABLE => match self.sequence_len()? {
+                Some(len) => self.visit_seq(len, visitor),
+                None => self.visit_map(visitor)
+            },
+            ffi::LUA_TNIL => visitor.visit_unit(),
+            _ => Err(self.error(&visitor))
+        }
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        let truthiness = self.config.coercion == Coercion::Lenient { truthiness: true };
+        match self.lua_type() {
+            ffi::LUA_TBOOLEAN => visitor.visit_bool(unsafe {
+                ffi::lua_toboolean(self.lua, self.index) != 0
+            }),
+            ffi::LUA_TNIL if truthiness => visitor.visit_bool(false),
+            _ if truthiness => visitor.visit_bool(true),
+            _ => Err(self.error(&visitor))
+        }
+    }
+
+    deserialize_integer! {
+        deserialize_i8 => visit_i8: i8,
+        deserialize_i16 => visit_i16: i16,
+        deserialize_i32 => visit_i32: i32,
+        deserialize_i64 => visit_i64: i64,
+        deserialize_u8 => visit_u8: u8,
+        deserialize_u16 => visit_u16: u16,
+        deserialize_u32 => visit_u32: u32,
+        deserialize_u64 => visit_u64: u64,
+    }
+
+    fn deserialize_f32<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        match self.number() {
+            Some(number) => visitor.visit_f32(number as f32),
+            None => Err(self.error(&visitor))
+        }
+    }
+
+    fn deserialize_f64<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        match self.number() {
+            Some(number) => visitor.visit_f64(number),
+            None => Err(self.error(&visitor))
+        }
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        let string = self.string(&visitor)?;
+        let mut char_iterator = string.chars();
+        if let Some(character) = char_iterator.next() {
+            if char_iterator.next().is_some() {
+                Err(serde::de::Error::invalid_length(
+                    2 + char_iterator.count(),
+                    &visitor
+                ))
+            } else {
+                visitor.visit_char(character)
+            }
+        } else {
+            Err(serde::de::Error::invalid_length(0, &visitor))
+        }
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        match self.string(&visitor)? {
+            Cow::Borrowed(string) => visitor.visit_str(string),
+            Cow::Owned(string) => visitor.visit_string(string)
+        }
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        match self.bytes(&visitor)? {
+            Cow::Borrowed(bytes) => visitor.visit_bytes(bytes),
+            Cow::Owned(bytes) => visitor.visit_byte_buf(bytes)
+        }
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        match self.lua_type() {
+            ffi::LUA_TNIL => visitor.visit_none(),
+            _ => visitor.visit_some(LuaStackDeserializer { checked: true, ..self })
+        }
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        match self.lua_type() {
+            ffi::LUA_TNIL => visitor.visit_unit(),
+            _ => Err(self.error(&visitor))
+        }
+    }
+
+    fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        name: &'static str,
+        visitor: V
+    ) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        if name == shared::NAME && self.config.share_tables && self.is_table() {
+            shared::set_table(unsafe { ffi::lua_topointer(self.lua, self.index) } as usize);
+        }
+        visitor.visit_newtype_struct(LuaStackDeserializer { checked: true, ..self })
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        let len = self.sequence(&visitor)?;
+        self.visit_seq(len, visitor)
+    }
+
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        let actual = self.sequence(&visitor)?;
+        if actual != len {
+            return Err(serde::de::Error::invalid_length(actual, &visitor));
+        }
+        self.visit_seq(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V
+    ) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        if !self.is_table() {
+            return Err(self.error(&visitor));
+        }
+        self.visit_map(visitor)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V
+    ) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        if !self.is_table() {
+            return Err(self.error(&visitor));
+        }
+        if self.config.read_all_fields {
+            return self.visit_map(visitor);
+        }
+        let ancestor = self.ancestor();
+        let this = self.within(&ancestor);
+        this.scoped(|top| visitor.visit_map(LuaStackStructAccess {
+            deserializer: this,
+            top,
+            fields: fields.iter(),
+            value: None
+        }))
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V
+    ) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.check()?;
+        match self.lua_type() {
+            ffi::LUA_TSTRING => self.scoped(|_| {
+                unsafe { ffi::lua_pushnil(self.lua) };
+                let payload = self.at(unsafe { ffi::lua_gettop(self.lua) });
+                let variant = LuaStackDeserializer { checked: true, ..self };
+                let payload = LuaStackVariantAccess(payload, None);
+                visitor.visit_enum(LuaStackEnumAccess(variant, payload))
+            }),
+            ffi::LUA_TTABLE => {
+                let ancestor = self.ancestor();
+                let this = self.within(&ancestor);
+                this.scoped(|_| {
+                    let (variant, payload) = this.untag_variant(&visitor)?;
+                    visitor.visit_enum(LuaStackEnumAccess(variant, payload))
+                })
+            },
+            _ => Err(self.error(&visitor))
+        }
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        // Ignored values are skipped without being read at all.
+        visitor.visit_unit()
+    }
+}
+
+/// Sequential access over the values of a table read as a sequence.
+struct LuaStackSeqAccess<'a> {
+    deserializer: LuaStackDeserializer<'a>,
+    // The height of the stack above which elements are pushed.
+    top: c_int,
+    next: usize,
+    len: usize
+}
+
+impl<'a, 'de> serde::de::SeqAccess<'de> for LuaStackSeqAccess<'a> {
+    type Error = LuaDeserializeError;
+
+    fn next_element_seed<T>(
+        &mut self,
+        seed: T
+    ) -> DeResult<Option<T::Value>>
+        where T: serde::de::DeserializeSeed<'de>
+    {
+        if self.next > self.len {
+            return Ok(None);
+        }
+        let index = self.next;
+        self.next += 1;
+        unsafe { ffi::lua_settop(self.deserializer.lua, self.top) };
+        let value = self.deserializer.get_index(index);
+        seed.deserialize(self.deserializer.at(value))
+            .map(Some)
+            .map_err(|error| error.at(PathSegment::Index(index)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len + 1 - self.next)
+    }
+}
+
+/// Map access over the entries of a table, in the order of `lua_next`.
+struct LuaStackMapAccess<'a> {
+    deserializer: LuaStackDeserializer<'a>,
+    // The stack index of the current key, which the value follows.
+    key: c_int,
+    done: bool
+}
+
+impl<'a, 'de> serde::de::MapAccess<'de> for LuaStackMapAccess<'a> {
+    type Error = LuaDeserializeError;
+
+    fn next_key_seed<K>(
+        &mut self,
+        seed: K
+    ) -> DeResult<Option<K::Value>>
+        where K: serde::de::DeserializeSeed<'de>
+    {
+        let lua = self.deserializer.lua;
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+            // `lua_next` expects the previous key on top of the stack.
+            unsafe {
+                ffi::lua_settop(lua, self.key);
+                self.done = ffi::lua_next(lua, self.deserializer.index) == 0;
+            }
+            if !self.done && !self.deserializer.is_skipped(self.key) {
+                break;
+            }
+        }
+        seed.deserialize(self.deserializer.at(self.key)).map(Some)
+    }
+
+    fn next_value_seed<V>(
+        &mut self,
+        seed: V
+    ) -> DeResult<V::Value>
+        where V: serde::de::DeserializeSeed<'de>
+    {
+        seed.deserialize(self.deserializer.at(self.key + 1))
+            .map_err(|error| error.at(key_segment(self.deserializer.lua, self.key)))
+    }
+}
+
+/// Map access over the fields of a struct, looked up by name.
+struct LuaStackStructAccess<'a> {
+    deserializer: LuaStackDeserializer<'a>,
+    // The height of the stack above which field values are pushed.
+    top: c_int,
+    fields: slice::Iter<'static, &'static str>,
+    // The name and stack index of the field whose key has been yielded but
+    // not its value.
+    value: Option<(&'static str, c_int)>
+}
+
+impl<'a, 'de> serde::de::MapAccess<'de> for LuaStackStructAccess<'a> {
+    type Error = LuaDeserializeError;
+
+    fn next_key_seed<K>(
+        &mut self,
+        seed: K
+    ) -> DeResult<Option<K::Value>>
+        where K: serde::de::DeserializeSeed<'de>
+    {
+        let lua = self.deserializer.lua;
+        for &field in &mut self.fields {
+            unsafe { ffi::lua_settop(lua, self.top) };
+            let value = self.deserializer.get_field(field);
+            if unsafe { ffi::lua_type(lua, value) } != ffi::LUA_TNIL {
+                self.value = Some((field, value));
+                let key: serde::de::value::StrDeserializer<LuaDeserializeError> =
+                    field.into_deserializer();
+                return seed.deserialize(key).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V>(
+        &mut self,
+        seed: V
+    ) -> DeResult<V::Value>
+        where V: serde::de::DeserializeSeed<'de>
+    {
+        let (field, value) = self.value.take().unwrap();
+        seed.deserialize(self.deserializer.at(value))
+            .map_err(|error| error.at(PathSegment::Field(field.to_owned())))
+    }
+}
+
+/// Enum access over a variant name and its payload.
+struct LuaStackEnumAccess<'a>(LuaStackDeserializer<'a>, LuaStackVariantAccess<'a>);
+
+impl<'a, 'de> serde::de::EnumAccess<'de> for LuaStackEnumAccess<'a> {
+    type Error = LuaDeserializeError;
+    type Variant = LuaStackVariantAccess<'a>;
+
+    fn variant_seed<V>(
+        self,
+        seed: V
+    ) -> DeResult<(V::Value, Self::Variant)>
+        where V: serde::de::DeserializeSeed<'de>
+    {
+        Ok((seed.deserialize(self.0)?, self.1))
+    }
+}
+
+/// Variant access over the payload of a variant, and where the payload is
+/// found relative to the enum.
+struct LuaStackVariantAccess<'a>(LuaStackDeserializer<'a>, Option<PathSegment>);
+
+impl<'a> LuaStackVariantAccess<'a> {
+    /// Attribute an error in the payload to the payload's location.
+    fn locate(segment: Option<PathSegment>, error: LuaDeserializeError) -> LuaDeserializeError {
+        match segment {
+            Some(segment) => error.at(segment),
+            None => error
+        }
+    }
+}
+
+impl<'a, 'de> serde::de::VariantAccess<'de> for LuaStackVariantAccess<'a> {
+    type Error = LuaDeserializeError;
+
+    fn unit_variant(self) -> DeResult<()> {
+        match self.0.lua_type() {
+            ffi::LUA_TNIL => Ok(()),
+            // Internally tagged unit variants leave behind an empty table
+            // once their tag is hidden.
+            ffi::LUA_TTABLE if self.0.skip.is_some() && self.0.count_entries()? == 0 => Ok(()),
+            _ => Err(Self::locate(self.1, self.0.error(&"unit variant")))
+        }
+    }
+
+    fn newtype_variant_seed<T>(
+        self,
+        seed: T
+    ) -> DeResult<T::Value>
+        where T: serde::de::DeserializeSeed<'de>
+    {
+        let segment = self.1;
+        seed.deserialize(self.0).map_err(|error| Self::locate(segment, error))
+    }
+
+    fn tuple_variant<V>(
+        self,
+        len: usize,
+        visitor: V
+    ) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        let segment = self.1;
+        self.0.deserialize_tuple(len, visitor).map_err(|error| Self::locate(segment, error))
+    }
+
+    fn struct_variant<V>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V
+    ) -> DeResult<V::Value>
+        where V: Visitor<'de>
+    {
+        let segment = self.1;
+        self.0.deserialize_struct("", fields, visitor)
+            .map_err(|error| Self::locate(segment, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use hlua::{AsLua, ffi};
+    use serde::de::DeserializeOwned;
+
+    use std::collections::BTreeMap;
+    use std::fmt::Debug;
+
+    use ::{from_lua_stack_with_config, from_lua_with_config, Config};
+    use config::{ByteEncoding, EnumRepr, SparseArrays, NULL_SENTINEL};
+    use de::DeResult;
+    use ErrorKind;
+
+    /// Deserialize the result of a lua expression from the stack, checking
+    /// that the stack is left as it was.
+    fn read<T: DeserializeOwned>(value: &str, config: Config) -> DeResult<T> {
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        lua.execute::<()>(&format!("value = {}", value)).unwrap();
+        let state = lua.as_lua().state_ptr();
+        unsafe { ffi::lua_getglobal(state, b"value\0".as_ptr() as *const _) };
+        let result = from_lua_stack_with_config(&mut lua, -1, config);
+        assert_eq!(1, unsafe { ffi::lua_gettop(state) });
+        result
+    }
+
+    /// Check that reading from the stack agrees with reading through an
+    /// `AnyLuaValue`.
+    fn same<T: DeserializeOwned + PartialEq + Debug>(value: &str, config: Config) {
+        let mut lua = hlua::Lua::new();
+        let any = lua.execute::<hlua::AnyLuaValue>(&format!("return {}", value)).unwrap();
+        let expected = from_lua_with_config::<T>(any, config);
+        let actual = read::<T>(value, config);
+        match (expected, actual) {
+            (Ok(expected), Ok(actual)) => assert_eq!(expected, actual, "{}", value),
+            // Structs are read in a different order, so the first error
+            // found may differ when there are several.
+            (Err(_), Err(_)) => (),
+            (expected, actual) => panic!("{}: {:?} != {:?}", value, expected, actual)
+        }
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Line(f64, f64),
+        Rect { width: f64, height: Option<f64> }
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Scene {
+        name: String,
+        shapes: Vec<Shape>,
+        layers: BTreeMap<String, Vec<Option<u32>>>,
+        #[serde(with = "::serde_bytes")]
+        data: Vec<u8>,
+        #[serde(default)]
+        hidden: bool
+    }
+
+    #[test]
+    fn matches_value_deserializer() {
+        let configs = [
+            Config::new(),
+            Config::new().enum_repr(EnumRepr::Internal { tag: "type" }),
+            Config::new().enum_repr(EnumRepr::Adjacent { tag: "t", content: "c" }),
+            Config::new().enum_repr(EnumRepr::Array),
+            Config::new().null_sentinel(NULL_SENTINEL),
+            Config::new().sparse_arrays(SparseArrays::Fill),
+            Config::new().sparse_arrays(SparseArrays::Packed),
+            Config::new().byte_encoding(ByteEncoding::Hex),
+            Config::new().byte_encoding(ByteEncoding::Array),
+            Config::new()
+                .enum_repr(EnumRepr::Internal { tag: "type" })
+                .sparse_arrays(SparseArrays::Fill)
+                .byte_encoding(ByteEncoding::Hex),
+            Config::new()
+                .enum_repr(EnumRepr::Adjacent { tag: "t", content: "c" })
+                .sparse_arrays(SparseArrays::Packed)
+                .byte_encoding(ByteEncoding::Array),
+            Config::new().enum_repr(EnumRepr::Array).null_sentinel(NULL_SENTINEL)
+        ];
+        let scenes = [
+            "{ name = 'a', shapes = {}, layers = {}, data = '' }",
+            "{ name = 'a', shapes = { 'Point', { Circle = 1 }, { Line = { 1, 2 } } },
+               layers = { top = { 1, 2 } }, data = 'AAE=' }",
+            "{ name = 'a', shapes = { { type = 'Rect', width = 1 }, { type = 'Point' } },
+               layers = { top = { 1, nil, 3 } }, data = '00ff' }",
+            "{ name = 'a', shapes = { { t = 'Circle', c = 2 }, { t = 'Rect', c = { width = 1 } } },
+               layers = { top = { n = 3, 1 } }, data = { 0, 255 } }",
+            "{ name = 'a', shapes = { { 'Line', { 1, 2 } }, { 'Point' } },
+               layers = { top = { 1, 'serde_hlua.null' } }, data = '', hidden = true }",
+            "{ name = 'a', shapes = { { Circle = 'x' } }, layers = {}, data = '' }",
+            "{ name = 'a', shapes = { { Point = {} } }, layers = {}, data = '' }",
+            "{ name = 1, shapes = {}, layers = {}, data = '' }",
+            "{ shapes = {}, layers = {}, data = '' }"
+        ];
+        for &config in &configs {
+            for scene in &scenes {
+                same::<Scene>(scene, config);
+            }
+            same::<Vec<u64>>("{ 1, 2^53, 2^64 }", config);
+            same::<(i8, String)>("{ -1, 'x' }", config);
+            same::<Option<bool>>("nil", config);
+            same::<char>("'ab'", config);
+            same::<Vec<Option<u8>>>("{ [2^40] = 1 }", config);
+            same::<Vec<Option<u8>>>("{ 1, n = 2^40 }", config);
+        }
+        let error = read::<Vec<Option<u8>>>("{ [2^40] = 1 }", configs[5]).unwrap_err();
+        assert!(matches!(*error.kind(), ErrorKind::InvalidLength { len, .. } if len == 1 << 40));
+        // Each of the valid scenes is read successfully with the
+        // configuration it was written for.
+        for (scene, &config) in scenes[1..5].iter().zip(&[
+            configs[0],
+            configs[configs.len() - 3],
+            configs[configs.len() - 2],
+            configs[configs.len() - 1]
+        ]) {
+            assert!(read::<Scene>(scene, config).is_ok(), "{}", scene);
+        }
+    }
+
+    #[test]
+    fn lua_type_names() {
+        let error = read::<u32>("print", Config::new()).unwrap_err();
+        assert_eq!("invalid type: function, expected u32", error.to_string());
+        let error = read::<Vec<u32>>("{ coroutine.create(print) }", Config::new());
+        assert!(error.is_err());
+        let error = read::<BTreeMap<String, String>>(
+            "{ thread = coroutine.create(print) }",
+            Config::new()
+        ).unwrap_err();
+        assert_eq!(
+            &ErrorKind::InvalidType {
+                expected: "a string".to_owned(),
+                got: "thread".to_owned()
+            },
+            error.kind()
+        );
+        assert_eq!("thread", error.path().to_string());
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let error = read::<String>(r"'ab\255c'", Config::new()).unwrap_err();
+        assert_eq!(&ErrorKind::InvalidUtf8 { offset: 2 }, error.kind());
+        assert_eq!("invalid utf-8 sequence at byte offset 2", error.to_string());
+        let error = read::<Vec<String>>(r"{ 'a', '\255' }", Config::new()).unwrap_err();
+        assert_eq!(&ErrorKind::InvalidUtf8 { offset: 0 }, error.kind());
+        assert_eq!("[2]", error.path().to_string());
+    }
+
+    #[test]
+    fn lazy_reads() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Point {
+            x: f32,
+            y: f32
+        }
+
+        // Fields that the struct doesn't have are never read, whatever they
+        // hold.
+        assert_eq!(
+            Point { x: 1.0, y: 2.0 },
+            read("{ x = 1, y = 2, callback = print, [print] = true }", Config::new()).unwrap()
+        );
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Entry {
+            id: u32,
+            #[serde(skip_deserializing)]
+            #[allow(dead_code)]
+            payload: ()
+        }
+        let entries: Vec<Entry> = read("{ { id = 1, payload = print } }", Config::new()).unwrap();
+        assert_eq!(vec![Entry { id: 1, payload: () }], entries);
+
+        let error = read::<Vec<Point>>("{ { x = 1, y = 2 }, { x = 1, y = 'a' } }", Config::new())
+            .unwrap_err();
+        assert_eq!("[2].y", error.path().to_string());
+    }
+
+    #[test]
+    fn read_all_fields() {
+        use SerdeLuaRead;
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(deny_unknown_fields)]
+        struct Strict {
+            x: f32,
+            #[serde(default)]
+            y: f32
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(tag = "type", deny_unknown_fields)]
+        enum Tagged {
+            Point { x: f32 }
+        }
+
+        let config = Config::new().read_all_fields(true);
+        assert!(read::<Strict>("{ x = 1, z = 2 }", Config::new()).is_ok());
+        assert_eq!(
+            "unknown field `z`, expected one of `x`, `y`",
+            read::<Strict>("{ x = 1, z = 2 }", config).unwrap_err().to_string()
+        );
+        assert_eq!(Strict { x: 1.0, y: 0.0 }, read("{ x = 1 }", config).unwrap());
+        same::<Strict>("{ x = 1, z = 2 }", config);
+        same::<Vec<Strict>>("{ { x = 1, y = 2 }, { x = 3 } }", config);
+        same::<Tagged>("{ type = 'Point', x = 1 }", config);
+        same::<Tagged>("{ type = 'Point', x = 1, y = 2 }", config);
+        same::<Scene>("{ name = 'n', shapes = {}, layers = {}, data = '' }", config);
+
+        // `SerdeLuaRead` looks fields up by name, as `from_lua_stack` does.
+        let mut lua = hlua::Lua::new();
+        assert!(lua.execute::<SerdeLuaRead<Strict>>("return { x = 1, z = 2 }").is_ok());
+        assert!(lua.execute::<SerdeLuaRead<Strict>>("return { y = 2 }").is_err());
+    }
+
+    #[test]
+    fn buffered_integers() {
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(tag = "type")]
+        enum Tagged {
+            Sized { width: u32, height: i8 }
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        #[serde(untagged)]
+        enum Untagged {
+            Count(u64),
+            Offset(i64),
+            Ratio(f64)
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Flattened {
+            name: String,
+            #[serde(flatten)]
+            sizes: BTreeMap<String, u8>
+        }
+
+        assert_eq!(
+            Tagged::Sized { width: 3, height: -2 },
+            read("{ type = 'Sized', width = 3, height = -2 }", Config::new()).unwrap()
+        );
+        same::<Vec<Untagged>>("{ 3, -3, 0.5, -0.0, 2^53, -2^63, 1e300 }", Config::new());
+        same::<Flattened>("{ name = 'n', small = 1, large = 255 }", Config::new());
+        same::<Flattened>("{ name = 'n', small = 1, too_large = 256 }", Config::new());
+    }
+
+    #[test]
+    fn coercion() {
+        use config::Coercion;
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Options {
+            port: u16,
+            ratio: f64,
+            name: String,
+            verbose: bool
+        }
+
+        let lenient = Config::new().coercion(Coercion::Lenient { truthiness: false });
+        let truthy = Config::new().coercion(Coercion::Lenient { truthiness: true });
+        for &config in &[Config::new(), lenient, truthy] {
+            same::<Options>("{ port = 80, ratio = 0.5, name = 'x', verbose = true }", config);
+            same::<Options>(
+                "{ port = ' 0x50 ', ratio = '5e-1', name = 1/3, verbose = true }",
+                config
+            );
+            same::<Options>("{ port = 80, ratio = '0x.8', name = 2^63, verbose = 0 }", config);
+            same::<Options>("{ port = '80.5', ratio = 1, name = 'x', verbose = true }", config);
+            same::<Options>("{ port = 80, ratio = 'nan', name = 'x', verbose = true }", config);
+            same::<BTreeMap<String, u8>>("{ [1] = '2', [0.5] = ' 3' }", config);
+            same::<Vec<bool>>("{ 0, '', {}, false, true }", config);
+        }
+        assert_eq!(
+            Options { port: 80, ratio: 0.5, name: "0.33333333333333".to_owned(), verbose: true },
+            read("{ port = ' 0x50 ', ratio = '5e-1', name = 1/3, verbose = 'no' }", truthy)
+                .unwrap()
+        );
+        // Converting a key must not change it in place, or `lua_next` would
+        // lose its way.
+        assert_eq!(
+            BTreeMap::from([("1".to_owned(), 1), ("2".to_owned(), 2), ("3".to_owned(), 3)]),
+            read::<BTreeMap<String, u8>>("{ 1, 2, 3 }", lenient).unwrap()
+        );
+    }
+
+    #[test]
+    fn limits() {
+        use config::Limits;
+        use error::Limit;
+        use Value;
+
+        let configs = [
+            Config::new().limits(Limits::new().max_depth(2)),
+            Config::new().limits(Limits::new().max_entries(2)),
+            Config::new().limits(Limits::new().max_string_bytes(4)),
+            Config::new().limits(Limits::new().max_nodes(6))
+        ];
+        for &config in &configs {
+            for value in &["{}", "{ 1, 2 }", "{ 1, 2, 3 }", "{ { {} } }", "'four'", "'fives'"] {
+                same::<Value>(value, config);
+            }
+        }
+
+        // Deeply nested tables fail cleanly instead of overflowing the stack.
+        let deep = "(function() \
+            local value = {} \
+            for _ = 1, 100000 do value = { value } end \
+            return value \
+        end)()";
+        let config = Config::new().limits(Limits::new().max_depth(64));
+        let error = read::<Value>(deep, config).unwrap_err();
+        assert_eq!(&ErrorKind::LimitExceeded { limit: Limit::Depth, max: 64 }, error.kind());
+        assert_eq!(64, error.path().segments().len());
+
+        // The limits also hold for values that are otherwise never read.
+        #[derive(Deserialize, Debug)]
+        struct Small {
+            #[allow(dead_code)]
+            name: String
+        }
+        let config = Config::new().limits(Limits::new().max_string_bytes(12));
+        assert!(read::<Small>("{ name = 'n', extra = 'long' }", config).is_err());
+        assert!(read::<Small>("{ name = 'n', extra = 'lo' }", config).is_ok());
+        assert!(read::<Option<Small>>("{ name = 'n', extra = 'long' }", config).is_err());
+
+        // The nils filling the holes of sparse sequences count too.
+        let limits = Limits::new().max_entries(10).max_nodes(10);
+        for &(policy, huge) in &[
+            (SparseArrays::Fill, "{ [2^40] = 1 }"),
+            (SparseArrays::Packed, "{ 1, n = 2^40 }")
+        ] {
+            let config = Config::new().sparse_arrays(policy).limits(limits);
+            for value in &[huge, "{ [8] = 1 }", "{ [9] = 1 }", "{ n = 9 }"] {
+                same::<Vec<Option<u8>>>(value, config);
+            }
+            let error = read::<Vec<Option<u8>>>(huge, config).unwrap_err();
+            assert_eq!(&ErrorKind::LimitExceeded { limit: Limit::Entries, max: 10 }, error.kind());
+        }
+        let config = Config::new().sparse_arrays(SparseArrays::Fill).limits(limits);
+        let error = read::<Vec<Option<u8>>>("{ [9] = 1 }", config).unwrap_err();
+        assert_eq!(&ErrorKind::LimitExceeded { limit: Limit::Nodes, max: 10 }, error.kind());
+    }
+
+    #[test]
+    fn cycles() {
+        use config::{Cycles, Limits};
+        use Value;
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Node {
+            name: String,
+            child: Option<Box<Node>>
+        }
+
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Name {
+            name: String
+        }
+
+        let error = |value: &str| -> (ErrorKind, String) {
+            let error = read::<Value>(value, Config::new()).unwrap_err();
+            (error.kind().clone(), error.path().to_string())
+        };
+        let cycle = |path: &str| (ErrorKind::CyclicTable, path.to_owned());
+        let script = |body: &str| format!("(function() {} end)()", body);
+
+        let own = script("local t = { name = 'n' }; t.child = t; return t");
+        let indirect = script("local a = {}; local b = { a = a }; a.b = b; return a");
+        let sequence = script("local t = { 1 }; t[2] = t; return t");
+        let key = script("local t = {}; t[t] = 1; return t");
+        let shared = script("local s = { 1 }; return { s, s, { s } }");
+
+        assert_eq!(cycle("child"), error(&own));
+        assert_eq!(cycle("b.a"), error(&indirect));
+        assert_eq!(cycle("[2]"), error(&sequence));
+        assert_eq!(cycle(""), error(&key));
+        assert_eq!(
+            "cyclic table at child",
+            read::<Node>(&own, Config::new()).unwrap_err().to_string()
+        );
+        // Parts that aren't read can't fail, and tables that are only
+        // shared aren't cycles.
+        assert!(read::<Name>(&own, Config::new()).is_ok());
+        same::<Vec<Value>>(&shared, Config::new());
+
+        let marker = Config::new().cycles(Cycles::Marker("<cycle>"));
+        assert_eq!(
+            Value::Table(vec![
+                (Value::from("a"), Value::from("<cycle>"))
+            ]),
+            read::<Value>(&indirect, marker).unwrap()["b"]
+        );
+        assert_eq!(Value::from("<cycle>"), read::<Value>(&sequence, marker).unwrap()[2]);
+        assert_eq!(Value::from(1.0), read::<Value>(&key, marker).unwrap()["<cycle>"]);
+        assert_eq!(Value::from("<cycle>"), read::<Value>(&own, marker).unwrap()["child"]);
+        assert!(read::<Node>(&own, marker).is_err());
+
+        // Checking the limits doesn't walk around the cycle forever.
+        let limited = marker.limits(Limits::new().max_string_bytes(8));
+        assert_eq!(Value::from("<cycle>"), read::<Value>(&sequence, limited).unwrap()[2]);
+        let limited = marker.limits(Limits::new().max_string_bytes(6));
+        assert!(read::<Value>(&sequence, limited).is_err());
+        let limited = Config::new().limits(Limits::new().max_string_bytes(8));
+        assert_eq!(
+            &ErrorKind::CyclicTable,
+            read::<Value>(&sequence, limited).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn shares_tables() {
+        use std::rc::Rc;
+        use std::sync::Arc;
+        use shared::Shared;
+
+        #[derive(Deserialize, Debug)]
+        struct Sprite {
+            #[serde(with = "::shared")]
+            texture: Rc<Vec<u32>>,
+            #[serde(with = "::shared")]
+            copy: Arc<Vec<u32>>
+        }
+
+        let sprites = "(function()
+            local texture = { 1, 2 }
+            return {
+                { texture = texture, copy = texture },
+                { texture = texture, copy = { 1, 2 } },
+                { texture = { 1, 2 }, copy = texture }
+            }
+        end)()";
+        let shared = Config::new().share_tables(true);
+        let read_sprites = |config| read::<Vec<Sprite>>(sprites, config).unwrap();
+
+        let found = read_sprites(shared);
+        assert!(Rc::ptr_eq(&found[0].texture, &found[1].texture));
+        assert!(!Rc::ptr_eq(&found[0].texture, &found[2].texture));
+        assert!(Arc::ptr_eq(&found[0].copy, &found[2].copy));
+        assert!(!Arc::ptr_eq(&found[0].copy, &found[1].copy));
+        assert_eq!(vec![1, 2], *found[2].texture);
+
+        let found = read_sprites(Config::new());
+        assert!(!Rc::ptr_eq(&found[0].texture, &found[1].texture));
+        assert_eq!(found[0].texture, found[1].texture);
+
+        // Values that aren't tables are never shared.
+        let numbers = read::<Vec<Shared<Rc<u32>>>>("{ 1, 1 }", shared).unwrap();
+        assert!(!Rc::ptr_eq(&numbers[0], &numbers[1]));
+    }
+
+    #[test]
+    fn sparse_round_trip() {
+        use ::push_to_lua_with_config;
+
+        let mut leading = vec![None; 40];
+        leading.push(Some(1));
+        let mut trailing = vec![Some(1)];
+        trailing.extend(vec![None; 40]);
+        for &(policy, value) in &[
+            (SparseArrays::Fill, &leading),
+            (SparseArrays::Packed, &leading),
+            (SparseArrays::Packed, &trailing)
+        ] {
+            let config = Config::new().sparse_arrays(policy);
+            let mut lua = hlua::Lua::new();
+            let mut guard = push_to_lua_with_config(value, &mut lua, config).unwrap();
+            let found = from_lua_stack_with_config::<_, Vec<Option<u32>>>(&mut guard, -1, config);
+            assert_eq!(*value, found.unwrap(), "{:?}", policy);
+        }
+    }
+}
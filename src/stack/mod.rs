@@ -0,0 +1,22 @@
+//! Conversion between rust values and values on the lua stack.
+//!
+//! Unlike `ser` and `de`, which go through an intermediate `AnyLuaValue`
+//! tree, the types in this module work on the lua stack directly, so that
+//! each table is only built once.
+
+use std::os::raw::c_int;
+
+use hlua::ffi;
+
+use error::ErrorKind;
+
+pub mod de;
+pub mod ser;
+
+/// Make room for `slots` more values on the lua stack.
+pub(crate) fn check_stack(lua: *mut ffi::lua_State, slots: c_int) -> Result<(), ErrorKind> {
+    if unsafe { ffi::lua_checkstack(lua, slots) } == 0 {
+        return Err(ErrorKind::StackOverflow);
+    }
+    Ok(())
+}
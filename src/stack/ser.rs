@@ -0,0 +1,868 @@
+//! Serialization from rust values directly onto the lua stack.
+
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+
+use hlua::{AnyLuaValue, AsMutLua};
+use hlua::ffi;
+use serde;
+use serde::Serialize;
+use serde::ser::Serializer;
+
+use config::{ByteEncoding, Config, EnumRepr, SparseArrays};
+use error::ErrorKind;
+use metatable;
+use ser::{LuaSerializeError, LuaSerializer, SerResult};
+use shared;
+use stack::check_stack;
+use value;
+
+/// A serializer that pushes its input data onto the lua stack, building
+/// tables in place rather than through an `AnyLuaValue`.
+///
+/// On success, exactly one value is left on top of the stack. On failure,
+/// partially built values may be left above it; `push_to_lua` and
+/// `SerdeLuaPush` take care of removing them.
+#[derive(Clone, Copy)]
+pub struct LuaStackSerializer<'a> {
+    lua: *mut ffi::lua_State,
+    config: Config,
+    // The stack index of the table mapping the pointees of shared pointers
+    // to the tables pushed for them, when tables are shared.
+    shared: Option<c_int>,
+    lifetime: PhantomData<&'a mut ()>
+}
+
+impl<'a> LuaStackSerializer<'a> {
+    /// Return a serializer that pushes input data onto the stack of `lua`.
+    pub fn new<'lua, L>(lua: &'a mut L) -> LuaStackSerializer<'a>
+        where L: AsMutLua<'lua>
+    {
+        LuaStackSerializer::with_config(lua, Config::new())
+    }
+
+    /// Return a serializer that pushes input data onto the stack of `lua`
+    /// using the provided configuration.
+    pub fn with_config<'lua, L>(lua: &'a mut L, config: Config) -> LuaStackSerializer<'a>
+        where L: AsMutLua<'lua>
+    {
+        LuaStackSerializer {
+            lua: lua.as_mut_lua().state_ptr(),
+            config,
+            shared: None,
+            lifetime: PhantomData
+        }
+    }
+
+    /// Return a serializer that pushes input data onto the stack of a lua
+    /// state that is borrowed elsewhere, such as by a C function.
+    pub(crate) fn from_state(lua: *mut ffi::lua_State, config: Config) -> LuaStackSerializer<'a> {
+        LuaStackSerializer {
+            lua,
+            config,
+            shared: None,
+            lifetime: PhantomData
+        }
+    }
+
+    /// Push the whole of a value, leaving the stack as it was if that fails.
+    ///
+    /// When tables are shared, the tables pushed for shared pointers are
+    /// kept in a table below the value while it is built.
+    pub(crate) fn push_root<T>(self, value: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        if !self.config.share_tables {
+            return self.push(value);
+        }
+        check_stack(self.lua, 1).map_err(LuaSerializeError)?;
+        let shared = unsafe {
+            ffi::lua_createtable(self.lua, 0, 0);
+            ffi::lua_gettop(self.lua)
+        };
+        let result = LuaStackSerializer { shared: Some(shared), ..self }.push(value);
+        unsafe {
+            match result {
+                Ok(()) => ffi::lua_remove(self.lua, shared),
+                Err(_) => ffi::lua_settop(self.lua, shared - 1)
+            }
+        }
+        result
+    }
+
+    /// Push a value, leaving the stack as it was if that fails.
+    pub(crate) fn push<T>(self, value: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        let top = unsafe { ffi::lua_gettop(self.lua) };
+        // Building a value takes at most three slots above the current top,
+        // such as a table, a key and a value; nested values check again.
+        check_stack(self.lua, 3).map_err(LuaSerializeError)?;
+        let lua = self.lua;
+        value.serialize(self).inspect_err(|_| unsafe { ffi::lua_settop(lua, top) })
+    }
+
+    /// Push the value of a shared pointer, or the table already pushed for
+    /// it.
+    fn push_shared<T>(self, shared: c_int, pointee: usize, value: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        let pointee = pointee as *const _;
+        unsafe {
+            ffi::lua_rawgetp(self.lua, shared, pointee);
+            if ffi::lua_type(self.lua, -1) != ffi::LUA_TNIL {
+                return Ok(());
+            }
+            ffi::lua_pop(self.lua, 1);
+        }
+        value.serialize(self)?;
+        unsafe {
+            if ffi::lua_type(self.lua, -1) == ffi::LUA_TTABLE {
+                ffi::lua_pushvalue(self.lua, -1);
+                ffi::lua_rawsetp(self.lua, shared, pointee);
+            }
+        }
+        Ok(())
+    }
+
+    /// Push a map key, rejecting keys that lua tables can't hold.
+    pub(crate) fn push_key<T>(self, key: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        // Keys are always externally tagged, so that unit variants used as
+        // keys remain plain strings.
+        let serializer = LuaStackSerializer {
+            config: self.config.enum_repr(EnumRepr::External),
+            ..self
+        };
+        serializer.push(key)?;
+        let invalid = unsafe {
+            match ffi::lua_type(self.lua, -1) {
+                ffi::LUA_TNIL => Some("nil"),
+                ffi::LUA_TNUMBER if {
+                    ffi::lua_tonumberx(self.lua, -1, ::std::ptr::null_mut()).is_nan()
+                } => Some("NaN"),
+                _ => None
+            }
+        };
+        match invalid {
+            Some(key) => {
+                unsafe { ffi::lua_pop(self.lua, 1) };
+                Err(LuaSerializeError(ErrorKind::InvalidKey(key.to_owned())))
+            },
+            None => Ok(())
+        }
+    }
+
+    fn push_nil(self) {
+        match self.config.null_sentinel {
+            Some(sentinel) => self.push_str(sentinel),
+            None => unsafe { ffi::lua_pushnil(self.lua) }
+        }
+    }
+
+    fn push_str(self, string: &str) {
+        unsafe {
+            ffi::lua_pushlstring(self.lua, string.as_ptr() as *const _, string.len() as _)
+        }
+    }
+
+    /// Push an `AnyLuaValue`, as produced when encoding bytes.
+    fn push_any(self, value: &AnyLuaValue) {
+        match *value {
+            AnyLuaValue::LuaString(ref string) => self.push_str(string),
+            AnyLuaValue::LuaAnyString(ref bytes) => unsafe {
+                ffi::lua_pushlstring(self.lua, bytes.0.as_ptr() as *const _, bytes.0.len() as _)
+            },
+            AnyLuaValue::LuaNumber(number) => unsafe { ffi::lua_pushnumber(self.lua, number) },
+            AnyLuaValue::LuaBoolean(boolean) => unsafe {
+                ffi::lua_pushboolean(self.lua, boolean as c_int)
+            },
+            AnyLuaValue::LuaArray(ref entries) => {
+                unsafe { ffi::lua_createtable(self.lua, preallocated(entries.len()), 0) };
+                for (key, value) in entries {
+                    self.push_any(key);
+                    self.push_any(value);
+                    unsafe { ffi::lua_rawset(self.lua, -3) };
+                }
+            },
+            AnyLuaValue::LuaNil | AnyLuaValue::LuaOther => unsafe { ffi::lua_pushnil(self.lua) }
+        }
+    }
+
+    /// Push the parts of a non-unit enum variant that come before its
+    /// payload, according to the configured enum representation.
+    fn begin_variant(self, variant: &'static str) {
+        unsafe {
+            match self.config.enum_repr {
+                EnumRepr::External => {
+                    ffi::lua_createtable(self.lua, 0, 1);
+                    self.push_str(variant);
+                },
+                EnumRepr::Internal { .. } => (),
+                EnumRepr::Adjacent { tag, content } => {
+                    ffi::lua_createtable(self.lua, 0, 2);
+                    self.push_str(tag);
+                    self.push_str(variant);
+                    ffi::lua_rawset(self.lua, -3);
+                    self.push_str(content);
+                },
+                EnumRepr::Array => {
+                    ffi::lua_createtable(self.lua, 2, 0);
+                    self.push_str(variant);
+                    ffi::lua_rawseti(self.lua, -2, 1);
+                }
+            }
+        }
+    }
+
+    /// Attach the payload on top of the stack to the parts pushed by
+    /// `begin_variant`.
+    fn end_variant(self, variant: &'static str) -> SerResult<()> {
+        unsafe {
+            match self.config.enum_repr {
+                EnumRepr::External | EnumRepr::Adjacent { .. } => {
+                    ffi::lua_rawset(self.lua, -3)
+                },
+                EnumRepr::Internal { tag } => {
+                    if ffi::lua_type(self.lua, -1) != ffi::LUA_TTABLE {
+                        return Err(LuaSerializeError(ErrorKind::InvalidType {
+                            expected: "a table for an internally tagged variant".to_owned(),
+                            got: type_name(ffi::lua_type(self.lua, -1)).to_owned()
+                        }));
+                    }
+                    self.push_str(tag);
+                    ffi::lua_rawget(self.lua, -2);
+                    let collides = ffi::lua_type(self.lua, -1) != ffi::LUA_TNIL;
+                    ffi::lua_pop(self.lua, 1);
+                    if collides {
+                        return Err(LuaSerializeError(ErrorKind::Custom(format!(
+                            "variant payload already contains the tag field '{}'",
+                            tag
+                        ))));
+                    }
+                    self.push_str(tag);
+                    self.push_str(variant);
+                    ffi::lua_rawset(self.lua, -3);
+                },
+                EnumRepr::Array => ffi::lua_rawseti(self.lua, -2, 2)
+            }
+        }
+        Ok(())
+    }
+
+    fn seq(
+        self,
+        len: usize,
+        name: Option<&'static str>,
+        variant: Option<&'static str>
+    ) -> LuaStackSerializeSeq<'a> {
+        if let Some(variant) = variant {
+            self.begin_variant(variant);
+        }
+        unsafe { ffi::lua_createtable(self.lua, preallocated(len), 0) };
+        LuaStackSerializeSeq { serializer: self, len: 0, has_nil: false, name, variant }
+    }
+
+    fn map(
+        self,
+        len: usize,
+        name: Option<&'static str>,
+        variant: Option<&'static str>
+    ) -> LuaStackSerializeMap<'a> {
+        if let Some(variant) = variant {
+            self.begin_variant(variant);
+        }
+        unsafe { ffi::lua_createtable(self.lua, 0, preallocated(len)) };
+        LuaStackSerializeMap { serializer: self, name, variant }
+    }
+
+    /// Finish the table on top of the stack, attaching the metatable
+    /// registered for the struct or variant it was built for.
+    fn end_table(
+        self,
+        name: Option<&'static str>,
+        variant: Option<&'static str>
+    ) -> SerResult<()> {
+        if let Some(name) = name {
+            metatable::attach(self.lua, name, variant)?;
+        }
+        match variant {
+            Some(variant) => self.end_variant(variant),
+            None => Ok(())
+        }
+    }
+}
+
+/// The number of slots to preallocate in a table for `len` entries, which is
+/// only a hint, so it is capped rather than checked.
+fn preallocated(len: usize) -> c_int {
+    len.min(c_int::MAX as usize) as c_int
+}
+
+/// Return the name of a lua type.
+pub(crate) fn type_name(lua_type: c_int) -> &'static str {
+    match lua_type {
+        ffi::LUA_TNIL => "nil",
+        ffi::LUA_TBOOLEAN => "boolean",
+        ffi::LUA_TNUMBER => "number",
+        ffi::LUA_TSTRING => "string",
+        ffi::LUA_TTABLE => "table",
+        _ => "other"
+    }
+}
+
+impl<'a> Serializer for LuaStackSerializer<'a> {
+    type Ok = ();
+    type Error = LuaSerializeError;
+    type SerializeSeq = LuaStackSerializeSeq<'a>;
+    type SerializeTuple = LuaStackSerializeSeq<'a>;
+    type SerializeTupleStruct = LuaStackSerializeSeq<'a>;
+    type SerializeTupleVariant = LuaStackSerializeSeq<'a>;
+    type SerializeMap = LuaStackSerializeMap<'a>;
+    type SerializeStruct = LuaStackSerializeMap<'a>;
+    type SerializeStructVariant = LuaStackSerializeMap<'a>;
+
+    fn serialize_bool(self, v: bool) -> SerResult<()> {
+        unsafe { ffi::lua_pushboolean(self.lua, v as c_int) };
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> SerResult<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i16(self, v: i16) -> SerResult<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i32(self, v: i32) -> SerResult<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_i64(self, v: i64) -> SerResult<()> {
+        if v as f64 as i128 != v as i128 {
+            Err(LuaSerializeError::lossy_number(v))
+        } else {
+            self.serialize_f64(v as f64)
+        }
+    }
+
+    fn serialize_u8(self, v: u8) -> SerResult<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u16(self, v: u16) -> SerResult<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u32(self, v: u32) -> SerResult<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u64(self, v: u64) -> SerResult<()> {
+        if v as f64 as i128 != v as i128 {
+            Err(LuaSerializeError::lossy_number(v))
+        } else {
+            self.serialize_f64(v as f64)
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> SerResult<()> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> SerResult<()> {
+        unsafe { ffi::lua_pushnumber(self.lua, v) };
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> SerResult<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> SerResult<()> {
+        if self.config.null_sentinel == Some(v) {
+            return Err(LuaSerializeError(ErrorKind::InvalidValue {
+                expected: "a string other than the null sentinel".to_owned(),
+                got: format!("string {:?}", v)
+            }));
+        }
+        self.push_str(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> SerResult<()> {
+        let encoded = LuaSerializer::with_config(self.config).serialize_bytes(v)?;
+        self.push_any(&encoded);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> SerResult<()> {
+        self.push_nil();
+        Ok(())
+    }
+
+    fn serialize_some<T>(self, value: &T) -> SerResult<()>
+        where T: ?Sized + serde::Serialize
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> SerResult<()> {
+        self.push_nil();
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> SerResult<()> {
+        self.push_nil();
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str
+    ) -> SerResult<()> {
+        unsafe {
+            match self.config.enum_repr {
+                EnumRepr::External => self.push_str(variant),
+                EnumRepr::Internal { tag } | EnumRepr::Adjacent { tag, .. } => {
+                    ffi::lua_createtable(self.lua, 0, 1);
+                    self.push_str(tag);
+                    self.push_str(variant);
+                    ffi::lua_rawset(self.lua, -3);
+                },
+                EnumRepr::Array => {
+                    ffi::lua_createtable(self.lua, 1, 0);
+                    self.push_str(variant);
+                    ffi::lua_rawseti(self.lua, -2, 1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        name: &'static str,
+        value: &T
+    ) -> SerResult<()>
+        where T: ?Sized + serde::Serialize
+    {
+        if name == shared::NAME {
+            if let (Some(shared), Some(pointee)) = (self.shared, shared::take_pointee()) {
+                return self.push_shared(shared, pointee, value);
+            }
+        }
+        if name == value::BYTES {
+            let config = self.config.byte_encoding(ByteEncoding::Raw);
+            return value.serialize(LuaStackSerializer { config, ..self });
+        }
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> SerResult<()>
+        where T: ?Sized + serde::Serialize
+    {
+        self.begin_variant(variant);
+        self.push(value)?;
+        self.end_variant(variant)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> SerResult<LuaStackSerializeSeq<'a>> {
+        Ok(self.seq(len.unwrap_or(0), None, None))
+    }
+
+    fn serialize_tuple(self, len: usize) -> SerResult<LuaStackSerializeSeq<'a>> {
+        Ok(self.seq(len, None, None))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize
+    ) -> SerResult<LuaStackSerializeSeq<'a>> {
+        Ok(self.seq(len, Some(name), None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize
+    ) -> SerResult<LuaStackSerializeSeq<'a>> {
+        Ok(self.seq(len, Some(name), Some(variant)))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> SerResult<LuaStackSerializeMap<'a>> {
+        Ok(self.map(len.unwrap_or(0), None, None))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize
+    ) -> SerResult<LuaStackSerializeMap<'a>> {
+        Ok(self.map(len, Some(name), None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize
+    ) -> SerResult<LuaStackSerializeMap<'a>> {
+        Ok(self.map(len, Some(name), Some(variant)))
+    }
+}
+
+/// Serializes sequences, tuples and tuple variants into the table on top of
+/// the stack.
+pub struct LuaStackSerializeSeq<'a> {
+    serializer: LuaStackSerializer<'a>,
+    len: c_int,
+    has_nil: bool,
+    name: Option<&'static str>,
+    variant: Option<&'static str>
+}
+
+impl<'a> serde::ser::SerializeSeq for LuaStackSerializeSeq<'a> {
+    type Ok = ();
+    type Error = LuaSerializeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        let index = match self.len.checked_add(1) {
+            Some(index) => index,
+            None => return Err(LuaSerializeError(ErrorKind::Custom(
+                "sequence is too long for a lua table".to_owned()
+            )))
+        };
+        let lua = self.serializer.lua;
+        self.serializer.push(value)?;
+        unsafe {
+            self.has_nil |= ffi::lua_type(lua, -1) == ffi::LUA_TNIL;
+            ffi::lua_rawseti(lua, -2, index);
+        }
+        self.len = index;
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<()> {
+        let lua = self.serializer.lua;
+        if self.serializer.config.sparse_arrays == SparseArrays::Packed && self.has_nil {
+            self.serializer.push_str("n");
+            unsafe {
+                ffi::lua_pushnumber(lua, self.len as f64);
+                ffi::lua_rawset(lua, -3);
+            }
+        }
+        self.serializer.end_table(self.name, self.variant)
+    }
+}
+
+impl<'a> serde::ser::SerializeTuple for LuaStackSerializeSeq<'a> {
+    type Ok = ();
+    type Error = LuaSerializeError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> SerResult<()> {
+        serde::ser::SerializeSeq::end(self)
+    }
+}
+
+impl<'a> serde::ser::SerializeTupleStruct for LuaStackSerializeSeq<'a> {
+    type Ok = ();
+    type Error = LuaSerializeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> SerResult<()> {
+        serde::ser::SerializeSeq::end(self)
+    }
+}
+
+impl<'a> serde::ser::SerializeTupleVariant for LuaStackSerializeSeq<'a> {
+    type Ok = ();
+    type Error = LuaSerializeError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> SerResult<()> {
+        serde::ser::SerializeSeq::end(self)
+    }
+}
+
+/// Serializes maps, structs and struct variants into the table on top of
+/// the stack.
+pub struct LuaStackSerializeMap<'a> {
+    serializer: LuaStackSerializer<'a>,
+    name: Option<&'static str>,
+    variant: Option<&'static str>
+}
+
+impl<'a> serde::ser::SerializeMap for LuaStackSerializeMap<'a> {
+    type Ok = ();
+    type Error = LuaSerializeError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        // The key stays on the stack, above the table, until its value is
+        // serialized.
+        self.serializer.push_key(key)
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        self.serializer.push(value)?;
+        unsafe { ffi::lua_rawset(self.serializer.lua, -3) };
+        Ok(())
+    }
+
+    fn serialize_entry<K, V>(
+        &mut self,
+        key: &K,
+        value: &V
+    ) -> SerResult<()>
+        where K: ?Sized + Serialize,
+              V: ?Sized + Serialize
+    {
+        self.serializer.push_key(key)?;
+        if let Err(error) = self.serializer.push(value) {
+            unsafe { ffi::lua_pop(self.serializer.lua, 1) };
+            return Err(error);
+        }
+        unsafe { ffi::lua_rawset(self.serializer.lua, -3) };
+        Ok(())
+    }
+
+    fn end(self) -> SerResult<()> {
+        self.serializer.end_table(self.name, self.variant)
+    }
+}
+
+impl<'a> serde::ser::SerializeStruct for LuaStackSerializeMap<'a> {
+    type Ok = ();
+    type Error = LuaSerializeError;
+
+    fn serialize_field<T>(
+        &mut self,
+        key: &'static str,
+        value: &T
+    ) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeMap::serialize_entry(self, key, value)
+    }
+
+    fn end(self) -> SerResult<()> {
+        serde::ser::SerializeMap::end(self)
+    }
+}
+
+impl<'a> serde::ser::SerializeStructVariant for LuaStackSerializeMap<'a> {
+    type Ok = ();
+    type Error = LuaSerializeError;
+
+    fn serialize_field<T>(
+        &mut self,
+        key: &'static str,
+        value: &T
+    ) -> SerResult<()>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeMap::serialize_entry(self, key, value)
+    }
+
+    fn end(self) -> SerResult<()> {
+        serde::ser::SerializeMap::end(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use hlua::{AsLua, ffi};
+    use serde::Serialize;
+
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    use ::{push_to_lua, push_to_lua_with_config, to_lua_with_config, Config};
+    use config::{ByteEncoding, EnumRepr, SparseArrays, NULL_SENTINEL};
+    use shared::Shared;
+
+    /// Return whether pushing a value directly produces the same lua value
+    /// as going through an `AnyLuaValue`.
+    fn same<S: Serialize>(value: &S, config: Config) -> bool {
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        lua.set("tree", to_lua_with_config(value, config).unwrap());
+        unsafe {
+            let state = lua.as_lua().state_ptr();
+            let guard = push_to_lua_with_config(value, &mut lua, config)
+                .map_err(|(error, _)| error)
+                .unwrap();
+            guard.forget();
+            ffi::lua_setglobal(state, b"stack\0".as_ptr() as *const _);
+        }
+        lua.execute::<bool>("
+            local function equal(a, b)
+                if type(a) ~= 'table' or type(b) ~= 'table' then
+                    return a == b
+                end
+                for key, value in pairs(a) do
+                    if not equal(value, b[key]) then return false end
+                end
+                for key in pairs(b) do
+                    if a[key] == nil then return false end
+                end
+                return true
+            end
+            return equal(tree, stack)
+        ").unwrap()
+    }
+
+    #[derive(Serialize)]
+    #[allow(dead_code)]
+    enum Enum {
+        Unit,
+        Newtype(BTreeMap<String, u32>),
+        Tuple(u32, Option<u32>),
+        Struct { first: String, second: Vec<Option<bool>> }
+    }
+
+    #[derive(Serialize)]
+    struct Frame {
+        id: u64,
+        name: String,
+        scale: f32,
+        visible: bool,
+        tags: Vec<String>,
+        parent: Option<u32>,
+        #[serde(with = "::serde_bytes")]
+        data: Vec<u8>,
+        shapes: Vec<Enum>
+    }
+
+    fn frame() -> Frame {
+        Frame {
+            id: 7,
+            name: "root".to_owned(),
+            scale: 0.5,
+            visible: true,
+            tags: vec!["a".to_owned(), "b".to_owned()],
+            parent: None,
+            data: vec![0, 1, 255],
+            shapes: vec![
+                Enum::Unit,
+                Enum::Newtype(vec![("x".to_owned(), 1)].into_iter().collect()),
+                Enum::Tuple(1, None),
+                Enum::Struct { first: "f".to_owned(), second: vec![None, Some(true)] }
+            ]
+        }
+    }
+
+    #[test]
+    fn matches_tree_serializer() {
+        let configs = [
+            Config::new(),
+            Config::new().enum_repr(EnumRepr::Internal { tag: "type" }),
+            Config::new().enum_repr(EnumRepr::Adjacent { tag: "t", content: "c" }),
+            Config::new().enum_repr(EnumRepr::Array),
+            Config::new().null_sentinel(NULL_SENTINEL),
+            Config::new().sparse_arrays(SparseArrays::Packed),
+            Config::new().byte_encoding(ByteEncoding::Raw),
+            Config::new().byte_encoding(ByteEncoding::Hex),
+            Config::new().byte_encoding(ByteEncoding::Array)
+        ];
+        for &config in &configs {
+            assert!(same(&frame(), config), "{:?}", config);
+            assert!(same(&vec![Some(1), None, None], config), "{:?}", config);
+            assert!(same(&"text", config), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn errors_leave_stack_unchanged() {
+        let mut lua = hlua::Lua::new();
+        let state = lua.as_lua().state_ptr();
+
+        let mut invalid = BTreeMap::new();
+        invalid.insert((), 1);
+        assert!(push_to_lua(&vec![(frame(), invalid)], &mut lua).is_err());
+        assert_eq!(0, unsafe { ffi::lua_gettop(state) });
+        assert!(push_to_lua(&vec![u64::MAX], &mut lua).is_err());
+        assert_eq!(0, unsafe { ffi::lua_gettop(state) });
+
+        let internal = Config::new().enum_repr(EnumRepr::Internal { tag: "type" });
+        let shapes = vec![Enum::Newtype(BTreeMap::new()), Enum::Tuple(1, None)];
+        assert!(push_to_lua_with_config(&shapes, &mut lua, internal).is_ok());
+        assert_eq!(0, unsafe { ffi::lua_gettop(state) });
+    }
+
+    #[test]
+    fn shares_tables() {
+        let texture = Rc::new(vec![1, 2]);
+        let other = Rc::new(vec![1, 2]);
+        let sprites = vec![
+            Shared(texture.clone()),
+            Shared(other),
+            Shared(texture)
+        ];
+        let check = |config: Config, code: &str| {
+            let mut lua = hlua::Lua::new();
+            let state = lua.as_lua().state_ptr();
+            unsafe {
+                let guard = push_to_lua_with_config(&sprites, &mut lua, config)
+                    .map_err(|(error, _)| error)
+                    .unwrap();
+                guard.forget();
+                assert_eq!(1, ffi::lua_gettop(state));
+                ffi::lua_setglobal(state, b"sprites\0".as_ptr() as *const _);
+            }
+            assert!(lua.execute::<bool>(code).unwrap(), "{:?}", config);
+        };
+        check(
+            Config::new().share_tables(true),
+            "return sprites[1] == sprites[3] and sprites[1] ~= sprites[2]"
+        );
+        check(
+            Config::new(),
+            "return sprites[1] ~= sprites[3] and sprites[1][2] == sprites[3][2]"
+        );
+
+        let mut lua = hlua::Lua::new();
+        let state = lua.as_lua().state_ptr();
+        let invalid = vec![Shared(Rc::new(vec![1])), Shared(Rc::new(vec![u64::MAX]))];
+        let config = Config::new().share_tables(true);
+        assert!(push_to_lua_with_config(&invalid, &mut lua, config).is_err());
+        assert_eq!(0, unsafe { ffi::lua_gettop(state) });
+    }
+}
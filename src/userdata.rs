@@ -0,0 +1,1167 @@
+//! Exposing rust values to lua through userdata, without copying them into
+//! tables.
+//!
+//! `SerdeLuaPush` builds the whole of a value as lua tables each time it is
+//! pushed, which is wasteful for large structures that scripts only look
+//! at a small part of. `SerdeLuaUserdata` instead moves the value into a
+//! lua userdata, and serializes the parts scripts ask for when they ask for
+//! them. Indexing, `#`, `pairs` and `ipairs` behave as they would on the
+//! tables that `to_lua` produces; fields that are tables themselves are
+//! exposed the same way.
+//!
+//! ```rust
+//! extern crate hlua;
+//! extern crate serde;
+//! #[macro_use] extern crate serde_derive;
+//! extern crate serde_hlua;
+//!
+//! use serde_hlua::SerdeLuaUserdata;
+//!
+//! #[derive(Serialize)]
+//! struct Tile {
+//!     kind: String,
+//!     height: u32
+//! }
+//!
+//! #[derive(Serialize)]
+//! struct Level {
+//!     name: String,
+//!     tiles: Vec<Tile>
+//! }
+//!
+//! fn main() {
+//!     let mut lua = hlua::Lua::new();
+//!     lua.openlibs();
+//!
+//!     let tiles = (0..1000).map(|height| Tile { kind: "grass".to_owned(), height });
+//!     let level = Level { name: "meadow".to_owned(), tiles: tiles.collect() };
+//!     lua.checked_set("level", SerdeLuaUserdata(level)).unwrap();
+//!
+//!     let height: u32 = lua.execute("
+//!         local total = 0
+//!         for _, tile in ipairs(level.tiles) do
+//!             if tile.height % 100 == 0 then total = total + tile.height end
+//!         end
+//!         return total + #level.tiles
+//!     ").unwrap();
+//!     assert_eq!(4500 + 1000, height);
+//! }
+//! ```
+//!
+//! Values are serialized with the default `Config`, and can't be modified
+//! from lua. Every lookup walks the value to the entry that is looked up,
+//! and only that entry is serialized. Elements of sequences are found by
+//! their position, while the keys of maps and structs are serialized and
+//! compared one at a time, so lookups in a map of `n` entries take time
+//! proportional to `n`, rather than constant time.
+
+use std::error;
+use std::fmt;
+use std::mem;
+use std::os::raw::c_int;
+use std::ptr;
+use std::rc::Rc;
+
+use hlua;
+use hlua::{AsMutLua, PushGuard};
+use hlua::ffi;
+use serde;
+use serde::Serialize;
+use serde::ser::{Impossible, Serializer};
+
+use config::Config;
+use ser::{LuaSerializeError, SerResult};
+use stack::check_stack;
+use stack::ser::LuaStackSerializer;
+use value;
+
+/// Pushes a value onto the lua stack as a userdata that serializes its
+/// fields when lua code looks them up.
+///
+/// Values that aren't tables, such as numbers and strings, are pushed as
+/// they are, like `SerdeLuaPush` would push them.
+pub struct SerdeLuaUserdata<T: Serialize + 'static>(pub T);
+
+impl<'lua, L, T> hlua::Push<L> for SerdeLuaUserdata<T>
+    where L: AsMutLua<'lua>,
+          T: Serialize + 'static
+{
+    type Err = LuaSerializeError;
+
+    fn push_to_lua(self, mut lua: L) -> Result<PushGuard<L>, (LuaSerializeError, L)> {
+        let state = lua.as_mut_lua().state_ptr();
+        let root = Rc::new(self.0);
+        let top = unsafe { ffi::lua_gettop(state) };
+        if let Err(kind) = check_stack(state, 3) {
+            return Err((LuaSerializeError(kind), lua));
+        }
+        let result = match root.serialize(Probe(state)) {
+            Ok(()) => Ok(()),
+            Err(Interrupt::Container) => {
+                unsafe { push_view(state, &root, Vec::new(), None) };
+                Ok(())
+            },
+            Err(Interrupt::Failed(error)) => Err(error),
+            Err(Interrupt::Done(_)) => unreachable!()
+        };
+        match result {
+            Ok(()) => Ok(unsafe { PushGuard::new(lua, 1) }),
+            Err(error) => {
+                unsafe { ffi::lua_settop(state, top) };
+                Err((error, lua))
+            }
+        }
+    }
+}
+
+impl<'lua, L, T> hlua::PushOne<L> for SerdeLuaUserdata<T>
+    where L: AsMutLua<'lua>,
+          T: Serialize + 'static
+{
+}
+
+/// The contents of a userdata: a table inside a rust value, found by
+/// following the positions of the entries leading to it.
+struct View<T> {
+    root: Rc<T>,
+    path: Vec<usize>
+}
+
+impl<T: Serialize + 'static> View<T> {
+    /// Perform an operation on the table this view refers to, whose
+    /// userdata is at index 1.
+    unsafe fn seek(&self, lua: *mut ffi::lua_State, op: Op) -> SerResult<c_int> {
+        let child = |position| {
+            let mut path = self.path.clone();
+            path.push(position);
+            push_view(lua, &self.root, path, Some(1));
+        };
+        let seek = Seek { lua, path: &self.path, op, child: &child };
+        match self.root.serialize(seek) {
+            Err(Interrupt::Done(results)) => Ok(results),
+            Err(Interrupt::Failed(error)) => Err(error),
+            Ok(()) | Err(Interrupt::Container) => Err(changed())
+        }
+    }
+}
+
+/// Push a userdata for the table at `path` in `root`, using the metatable
+/// of the userdata at `metatable`, or a new one.
+///
+/// The userdata holds an `Option<View<T>>`, which is emptied when it is
+/// collected. Each metamethod keeps the metatable as an upvalue, so that it
+/// can check that its first argument is one of these userdata.
+unsafe fn push_view<T>(
+    lua: *mut ffi::lua_State,
+    root: &Rc<T>,
+    path: Vec<usize>,
+    metatable: Option<c_int>
+)
+    where T: Serialize + 'static
+{
+    let size = mem::size_of::<Option<View<T>>>();
+    let data = ffi::lua_newuserdata(lua, size as _) as *mut Option<View<T>>;
+    ptr::write(data, Some(View { root: root.clone(), path }));
+    match metatable {
+        Some(index) => {
+            ffi::lua_getmetatable(lua, index);
+        },
+        None => {
+            ffi::lua_createtable(lua, 0, 7);
+            let methods: [(&[u8], ffi::lua_CFunction); 6] = [
+                (b"__index\0", index::<T>),
+                (b"__len\0", len::<T>),
+                (b"__pairs\0", pairs::<T>),
+                (b"__ipairs\0", ipairs),
+                (b"__newindex\0", newindex),
+                (b"__gc\0", gc::<T>)
+            ];
+            for &(name, method) in &methods {
+                ffi::lua_pushvalue(lua, -1);
+                ffi::lua_pushcclosure(lua, method, 1);
+                ffi::lua_setfield(lua, -2, name.as_ptr() as *const _);
+            }
+            // Hide the metamethods from `getmetatable`, though not from the
+            // debug library.
+            ffi::lua_pushboolean(lua, 0);
+            ffi::lua_setfield(lua, -2, b"__metatable\0".as_ptr() as *const _);
+        }
+    }
+    ffi::lua_setmetatable(lua, -2);
+}
+
+/// Return the result of a metamethod to lua, raising an error if it failed.
+fn finish(lua: *mut ffi::lua_State, result: SerResult<c_int>) -> c_int {
+    match result {
+        Ok(results) => results,
+        Err(error) => unsafe {
+            push_message(lua, error);
+            // Nothing that needs dropping is left in this frame.
+            ffi::lua_error(lua)
+        }
+    }
+}
+
+fn push_message(lua: *mut ffi::lua_State, error: LuaSerializeError) {
+    let message = error.to_string();
+    unsafe { ffi::lua_pushlstring(lua, message.as_ptr() as *const _, message.len() as _) };
+}
+
+/// Return the contents of the userdata at index 1, if it was made with the
+/// metatable that is the first upvalue of the running metamethod.
+///
+/// Scripts can reach the metamethods through the debug library, and call
+/// them with anything.
+unsafe fn view<'a, T>(lua: *mut ffi::lua_State) -> SerResult<&'a mut Option<View<T>>> {
+    let data = ffi::lua_touserdata(lua, 1) as *mut Option<View<T>>;
+    if data.is_null() || ffi::lua_getmetatable(lua, 1) == 0 {
+        return Err(not_view());
+    }
+    let same = ffi::lua_rawequal(lua, -1, ffi::lua_upvalueindex(1)) != 0;
+    ffi::lua_pop(lua, 1);
+    if same {
+        Ok(&mut *data)
+    } else {
+        Err(not_view())
+    }
+}
+
+/// Perform an operation on the view at index 1.
+unsafe fn seek<T: Serialize + 'static>(lua: *mut ffi::lua_State, op: Op) -> SerResult<c_int> {
+    match *view::<T>(lua)? {
+        Some(ref view) => view.seek(lua, op),
+        None => Err(serde::ser::Error::custom(
+            "attempt to use a rust value exposed as userdata after it was collected"
+        ))
+    }
+}
+
+extern "C" fn index<T: Serialize + 'static>(lua: *mut ffi::lua_State) -> c_int {
+    let result = unsafe { seek::<T>(lua, Op::Index(2)) };
+    finish(lua, result)
+}
+
+extern "C" fn len<T: Serialize + 'static>(lua: *mut ffi::lua_State) -> c_int {
+    let result = unsafe { seek::<T>(lua, Op::Len) };
+    finish(lua, result)
+}
+
+extern "C" fn pairs<T: Serialize + 'static>(lua: *mut ffi::lua_State) -> c_int {
+    // `pairs` iterates over a table of the entries of the view, in which
+    // the entries that are tables are views themselves.
+    let result = unsafe { seek::<T>(lua, Op::Pairs) };
+    let result = result.map(|_| unsafe {
+        ffi::lua_pushcfunction(lua, next);
+        ffi::lua_insert(lua, -2);
+        ffi::lua_pushnil(lua);
+        3
+    });
+    finish(lua, result)
+}
+
+extern "C" fn next(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        if !ffi::lua_istable(lua, 1) {
+            return finish(lua, Err(serde::ser::Error::custom("bad argument #1 (table expected)")));
+        }
+        ffi::lua_settop(lua, 2);
+        if ffi::lua_next(lua, 1) != 0 {
+            2
+        } else {
+            ffi::lua_pushnil(lua);
+            1
+        }
+    }
+}
+
+extern "C" fn ipairs(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        ffi::lua_pushcfunction(lua, ipairs_next);
+        ffi::lua_pushvalue(lua, 1);
+        ffi::lua_pushnumber(lua, 0.0);
+    }
+    3
+}
+
+extern "C" fn ipairs_next(lua: *mut ffi::lua_State) -> c_int {
+    unsafe {
+        let index = ffi::lua_tonumberx(lua, 2, ptr::null_mut()) + 1.0;
+        ffi::lua_pushnumber(lua, index);
+        ffi::lua_pushnumber(lua, index);
+        ffi::lua_gettable(lua, 1);
+        if ffi::lua_type(lua, -1) == ffi::LUA_TNIL {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+extern "C" fn newindex(lua: *mut ffi::lua_State) -> c_int {
+    let error = serde::ser::Error::custom("attempt to modify a rust value exposed as userdata");
+    finish(lua, Err(error))
+}
+
+extern "C" fn gc<T: Serialize + 'static>(lua: *mut ffi::lua_State) -> c_int {
+    let result = unsafe { view::<T>(lua) }.map(|view| {
+        mem::drop(view.take());
+        0
+    });
+    finish(lua, result)
+}
+
+/// The error reported when a metamethod is called on something other than
+/// the userdata it belongs to.
+fn not_view() -> LuaSerializeError {
+    serde::ser::Error::custom("bad argument #1 (expected a rust value exposed as userdata)")
+}
+
+/// The error reported when a value no longer has the shape it had when its
+/// userdata was created.
+fn changed() -> LuaSerializeError {
+    serde::ser::Error::custom("value exposed as userdata changed after it was pushed")
+}
+
+/// What to do with the table at the end of a path.
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    /// Push the value of the entry whose key is at the given stack index.
+    Index(c_int),
+    /// Push the length of the table.
+    Len,
+    /// Push a table of the entries of the table.
+    Pairs
+}
+
+/// Stops serializing a value as soon as the serializers in this module are
+/// done with it.
+#[derive(Debug)]
+enum Interrupt {
+    /// An operation is done, leaving the given number of results.
+    Done(c_int),
+    /// The value being serialized is a table.
+    Container,
+    Failed(LuaSerializeError)
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Interrupt::Failed(ref error) => error.fmt(formatter),
+            _ => formatter.write_str("serialization interrupted")
+        }
+    }
+}
+
+impl error::Error for Interrupt {}
+
+impl serde::ser::Error for Interrupt {
+    fn custom<T>(msg: T) -> Self
+        where T: fmt::Display
+    {
+        Interrupt::Failed(serde::ser::Error::custom(msg))
+    }
+}
+
+/// A serializer that follows a path of entry positions through a value,
+/// and performs an operation on the table at its end.
+#[derive(Clone, Copy)]
+struct Seek<'a> {
+    lua: *mut ffi::lua_State,
+    path: &'a [usize],
+    op: Op,
+    // Pushes a view of the entry at a position in the table at the end of
+    // the path.
+    child: &'a dyn Fn(usize)
+}
+
+impl<'a> Seek<'a> {
+    fn entries(self, sequence: bool) -> Entries<'a> {
+        if self.path.is_empty() && self.op == Op::Pairs {
+            unsafe { ffi::lua_createtable(self.lua, 0, 0) };
+        }
+        let wanted = match self.op {
+            Op::Index(key) if sequence && self.path.is_empty() => unsafe {
+                let index = ffi::lua_tonumberx(self.lua, key, ptr::null_mut());
+                let number = ffi::lua_type(self.lua, key) == ffi::LUA_TNUMBER;
+                if number && index >= 1.0 && index.fract() == 0.0 {
+                    Some(index as usize - 1)
+                } else {
+                    None
+                }
+            },
+            _ => None
+        };
+        Entries { seek: self, sequence, position: 0, wanted, integer_keys: Vec::new() }
+    }
+
+    /// Walk the single entry of a tuple or struct variant, whose payload is
+    /// a table.
+    fn variant(self, variant: &'static str, sequence: bool) -> Result<Entries<'a>, Interrupt> {
+        match self.path.split_first() {
+            Some((&0, path)) => Ok(Seek { path, ..self }.entries(sequence)),
+            Some(_) => Err(Interrupt::Failed(changed())),
+            None => {
+                let mut entries = self.entries(false);
+                entries.key_str(variant);
+                entries.position += 1;
+                entries.found(0, || {
+                    (self.child)(0);
+                    Ok(())
+                })?;
+                Err(entries.finish())
+            }
+        }
+    }
+}
+
+macro_rules! stale_scalars {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method(self, $(_: $ty),*) -> Result<(), Interrupt> {
+                Err(Interrupt::Failed(changed()))
+            }
+        )*
+    }
+}
+
+impl<'a> Serializer for Seek<'a> {
+    type Ok = ();
+    type Error = Interrupt;
+    type SerializeSeq = Entries<'a>;
+    type SerializeTuple = Entries<'a>;
+    type SerializeTupleStruct = Entries<'a>;
+    type SerializeTupleVariant = Entries<'a>;
+    type SerializeMap = Entries<'a>;
+    type SerializeStruct = Entries<'a>;
+    type SerializeStructVariant = Entries<'a>;
+
+    // Views only refer to tables, so finding anything else means the value
+    // changed.
+    stale_scalars! {
+        serialize_bool(v: bool);
+        serialize_i8(v: i8);
+        serialize_i16(v: i16);
+        serialize_i32(v: i32);
+        serialize_i64(v: i64);
+        serialize_u8(v: u8);
+        serialize_u16(v: u16);
+        serialize_u32(v: u32);
+        serialize_u64(v: u64);
+        serialize_f32(v: f32);
+        serialize_f64(v: f64);
+        serialize_char(v: char);
+        serialize_str(v: &str);
+        serialize_bytes(v: &[u8]);
+        serialize_none();
+        serialize_unit();
+        serialize_unit_struct(name: &'static str);
+        serialize_unit_variant(name: &'static str, index: u32, variant: &'static str);
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _name: &'static str,
+        value: &T
+    ) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T
+    ) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        let mut entries = self.entries(false);
+        serde::ser::SerializeStruct::serialize_field(&mut entries, variant, value)?;
+        Err(entries.finish())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Entries<'a>, Interrupt> {
+        Ok(self.entries(true))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Entries<'a>, Interrupt> {
+        Ok(self.entries(true))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Entries<'a>, Interrupt> {
+        Ok(self.entries(true))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize
+    ) -> Result<Entries<'a>, Interrupt> {
+        self.variant(variant, true)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Entries<'a>, Interrupt> {
+        Ok(self.entries(false))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Entries<'a>, Interrupt> {
+        Ok(self.entries(false))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize
+    ) -> Result<Entries<'a>, Interrupt> {
+        self.variant(variant, false)
+    }
+}
+
+/// Walks the entries of a table on the way to the end of a path, or
+/// performs an operation on them at its end.
+struct Entries<'a> {
+    seek: Seek<'a>,
+    sequence: bool,
+    position: usize,
+    // The position of the entry asked for when indexing a sequence, which
+    // is found without comparing keys.
+    wanted: Option<usize>,
+    // The positive integer keys of a map, from which its length is found.
+    integer_keys: Vec<usize>
+}
+
+impl<'a> Entries<'a> {
+    /// Whether the operation needs the key of each entry on the stack.
+    fn wants_keys(&self) -> bool {
+        self.seek.path.is_empty() && match self.seek.op {
+            Op::Len => false,
+            Op::Index(_) => !self.sequence,
+            Op::Pairs => true
+        }
+    }
+
+    fn key_str(&self, key: &str) {
+        if self.wants_keys() {
+            unsafe {
+                ffi::lua_pushlstring(self.seek.lua, key.as_ptr() as *const _, key.len() as _)
+            };
+        }
+    }
+
+    /// Handle the value of the next entry, whose key is on the stack if the
+    /// operation needs it.
+    fn value<T>(&mut self, value: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        let position = self.position;
+        self.position += 1;
+        match self.seek.path.split_first() {
+            Some((&target, path)) if target == position => {
+                value.serialize(Seek { path, ..self.seek })?;
+                Err(Interrupt::Failed(changed()))
+            },
+            Some(_) => Ok(()),
+            None => {
+                let Seek { lua, child, .. } = self.seek;
+                self.found(position, || match value.serialize(Probe(lua)) {
+                    Err(Interrupt::Container) => {
+                        child(position);
+                        Ok(())
+                    },
+                    result => result
+                })
+            }
+        }
+    }
+
+    /// Perform the operation on the entry at `position` at the end of the
+    /// path, whose key is on the stack if the operation needs it, given a
+    /// way to push its value.
+    fn found<F>(&mut self, position: usize, push: F) -> Result<(), Interrupt>
+        where F: FnOnce() -> Result<(), Interrupt>
+    {
+        let lua = self.seek.lua;
+        unsafe {
+            match self.seek.op {
+                Op::Len => Ok(()),
+                Op::Index(_) if self.sequence => {
+                    if self.wanted == Some(position) {
+                        push()?;
+                        Err(Interrupt::Done(1))
+                    } else {
+                        Ok(())
+                    }
+                },
+                Op::Index(key) => {
+                    let equal = ffi::lua_rawequal(lua, key, -1) != 0;
+                    ffi::lua_pop(lua, 1);
+                    if equal {
+                        push()?;
+                        Err(Interrupt::Done(1))
+                    } else {
+                        Ok(())
+                    }
+                },
+                Op::Pairs => {
+                    push()?;
+                    if ffi::lua_type(lua, -1) == ffi::LUA_TNIL {
+                        ffi::lua_pop(lua, 2);
+                    } else {
+                        ffi::lua_rawset(lua, -3);
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Finish walking the table, returning how the operation ended.
+    fn finish(self) -> Interrupt {
+        if !self.seek.path.is_empty() {
+            return Interrupt::Failed(changed());
+        }
+        let lua = self.seek.lua;
+        unsafe {
+            match self.seek.op {
+                Op::Len if self.sequence => ffi::lua_pushnumber(lua, self.position as f64),
+                Op::Len => {
+                    // Like lua, find a border: a key followed by a missing
+                    // one.
+                    let mut keys = self.integer_keys;
+                    keys.sort_unstable();
+                    let len = keys.iter().zip(1..).take_while(|&(&key, len)| key == len).count();
+                    ffi::lua_pushnumber(lua, len as f64);
+                },
+                Op::Index(_) => ffi::lua_pushnil(lua),
+                Op::Pairs => ()
+            }
+        }
+        Interrupt::Done(1)
+    }
+}
+
+impl<'a> serde::ser::SerializeSeq for Entries<'a> {
+    type Ok = ();
+    type Error = Interrupt;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        if self.wants_keys() {
+            unsafe { ffi::lua_pushnumber(self.seek.lua, (self.position + 1) as f64) };
+        }
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), Interrupt> {
+        Err(self.finish())
+    }
+}
+
+impl<'a> serde::ser::SerializeTuple for Entries<'a> {
+    type Ok = ();
+    type Error = Interrupt;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Interrupt> {
+        Err(self.finish())
+    }
+}
+
+impl<'a> serde::ser::SerializeTupleStruct for Entries<'a> {
+    type Ok = ();
+    type Error = Interrupt;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Interrupt> {
+        Err(self.finish())
+    }
+}
+
+impl<'a> serde::ser::SerializeTupleVariant for Entries<'a> {
+    type Ok = ();
+    type Error = Interrupt;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), Interrupt> {
+        Err(self.finish())
+    }
+}
+
+impl<'a> serde::ser::SerializeMap for Entries<'a> {
+    type Ok = ();
+    type Error = Interrupt;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        let lua = self.seek.lua;
+        let len = self.seek.path.is_empty() && self.seek.op == Op::Len;
+        if self.wants_keys() || len {
+            LuaStackSerializer::from_state(lua, Config::new())
+                .push_key(key)
+                .map_err(Interrupt::Failed)?;
+        }
+        if len {
+            unsafe {
+                if ffi::lua_type(lua, -1) == ffi::LUA_TNUMBER {
+                    let key = ffi::lua_tonumberx(lua, -1, ptr::null_mut());
+                    if key >= 1.0 && key.fract() == 0.0 {
+                        self.integer_keys.push(key as usize);
+                    }
+                }
+                ffi::lua_pop(lua, 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), Interrupt> {
+        Err(self.finish())
+    }
+}
+
+impl<'a> serde::ser::SerializeStruct for Entries<'a> {
+    type Ok = ();
+    type Error = Interrupt;
+
+    fn serialize_field<T>(
+        &mut self,
+        key: &'static str,
+        value: &T
+    ) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        self.key_str(key);
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), Interrupt> {
+        Err(self.finish())
+    }
+}
+
+impl<'a> serde::ser::SerializeStructVariant for Entries<'a> {
+    type Ok = ();
+    type Error = Interrupt;
+
+    fn serialize_field<T>(
+        &mut self,
+        key: &'static str,
+        value: &T
+    ) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        serde::ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<(), Interrupt> {
+        Err(self.finish())
+    }
+}
+
+/// A serializer that pushes values that aren't tables, and stops at those
+/// that are.
+struct Probe(*mut ffi::lua_State);
+
+impl Probe {
+    fn stack<'a>(self) -> LuaStackSerializer<'a> {
+        LuaStackSerializer::from_state(self.0, Config::new())
+    }
+}
+
+macro_rules! push_scalars {
+    ($($method:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            fn $method(self, $($arg: $ty),*) -> Result<(), Interrupt> {
+                self.stack().$method($($arg),*).map_err(Interrupt::Failed)
+            }
+        )*
+    }
+}
+
+impl Serializer for Probe {
+    type Ok = ();
+    type Error = Interrupt;
+    type SerializeSeq = Impossible<(), Interrupt>;
+    type SerializeTuple = Impossible<(), Interrupt>;
+    type SerializeTupleStruct = Impossible<(), Interrupt>;
+    type SerializeTupleVariant = Impossible<(), Interrupt>;
+    type SerializeMap = Impossible<(), Interrupt>;
+    type SerializeStruct = Impossible<(), Interrupt>;
+    type SerializeStructVariant = Impossible<(), Interrupt>;
+
+    push_scalars! {
+        serialize_bool(v: bool);
+        serialize_i8(v: i8);
+        serialize_i16(v: i16);
+        serialize_i32(v: i32);
+        serialize_i64(v: i64);
+        serialize_u8(v: u8);
+        serialize_u16(v: u16);
+        serialize_u32(v: u32);
+        serialize_u64(v: u64);
+        serialize_f32(v: f32);
+        serialize_f64(v: f64);
+        serialize_char(v: char);
+        serialize_str(v: &str);
+        serialize_bytes(v: &[u8]);
+        serialize_none();
+        serialize_unit();
+        serialize_unit_struct(name: &'static str);
+        serialize_unit_variant(name: &'static str, index: u32, variant: &'static str);
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        name: &'static str,
+        value: &T
+    ) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        if name == value::BYTES {
+            return self.stack().serialize_newtype_struct(name, value).map_err(Interrupt::Failed);
+        }
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T
+    ) -> Result<(), Interrupt>
+        where T: ?Sized + Serialize
+    {
+        Err(Interrupt::Container)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Interrupt> {
+        Err(Interrupt::Container)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Interrupt> {
+        Err(Interrupt::Container)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeTupleStruct, Interrupt> {
+        Err(Interrupt::Container)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeTupleVariant, Interrupt> {
+        Err(Interrupt::Container)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Interrupt> {
+        Err(Interrupt::Container)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeStruct, Interrupt> {
+        Err(Interrupt::Container)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize
+    ) -> Result<Self::SerializeStructVariant, Interrupt> {
+        Err(Interrupt::Container)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use serde::Serialize;
+
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+
+    use ::{to_lua, SerdeLuaPush};
+    use super::SerdeLuaUserdata;
+
+    #[derive(Serialize)]
+    #[allow(dead_code)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Line(f64, f64),
+        Rect { width: f64, height: Option<f64> }
+    }
+
+    #[derive(Serialize)]
+    struct Layer {
+        name: &'static str,
+        shapes: Vec<Shape>,
+        tags: BTreeMap<u32, &'static str>,
+        hidden: Option<bool>,
+        offset: (f64, f64)
+    }
+
+    fn layer() -> Layer {
+        let mut tags = BTreeMap::new();
+        tags.insert(1, "first");
+        tags.insert(7, "seventh");
+        Layer {
+            name: "ground",
+            shapes: vec![
+                Shape::Point,
+                Shape::Circle(2.0),
+                Shape::Line(1.0, 3.0),
+                Shape::Rect { width: 4.0, height: None },
+                Shape::Rect { width: 4.0, height: Some(5.0) }
+            ],
+            tags,
+            hidden: None,
+            offset: (0.5, -0.5)
+        }
+    }
+
+    /// Return whether a userdata holding a value behaves like the table
+    /// `to_lua` produces for it.
+    fn same<S: Serialize + 'static>(value: S) -> bool {
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        lua.set("tree", to_lua(&value).unwrap());
+        lua.checked_set("view", SerdeLuaUserdata(value)).unwrap();
+        lua.execute::<bool>("
+            local function equal(a, b)
+                if type(a) ~= 'table' then
+                    return a == b
+                end
+                if type(b) ~= 'userdata' or #a ~= #b then
+                    return false
+                end
+                for key, value in pairs(a) do
+                    if not equal(value, b[key]) then return false end
+                end
+                for key, value in pairs(b) do
+                    if not equal(a[key], value) then return false end
+                end
+                for index, value in ipairs(b) do
+                    if not equal(a[index], value) then return false end
+                end
+                return true
+            end
+            return equal(tree, view)
+        ").unwrap()
+    }
+
+    #[test]
+    fn matches_tables() {
+        assert!(same(layer()));
+        assert!(same(vec![layer(), layer()]));
+        assert!(same(Shape::Line(1.0, 2.0)));
+        assert!(same(Some(vec![Some(vec![1, 2]), Some(vec![])])));
+
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        lua.checked_set("number", SerdeLuaUserdata(5)).unwrap();
+        lua.checked_set("point", SerdeLuaUserdata(Shape::Point)).unwrap();
+        assert!(lua.execute::<bool>("
+            return type(number) == 'number' and point == 'Point'
+        ").unwrap());
+    }
+
+    #[test]
+    fn serializes_on_demand() {
+        #[derive(Serialize)]
+        struct Numbers {
+            small: u64,
+            large: u64
+        }
+
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        lua.checked_set("numbers", SerdeLuaUserdata(Numbers {
+            small: 1,
+            large: u64::MAX
+        })).unwrap();
+        lua.checked_set("layer", SerdeLuaUserdata(layer())).unwrap();
+        assert_eq!(1, lua.execute::<u32>("return numbers.small").unwrap());
+        assert!(lua.execute::<String>("
+            return select(2, pcall(function() return numbers.large end))
+        ").unwrap().contains("18446744073709551615"));
+        assert!(lua.execute::<String>("
+            return select(2, pcall(function() layer.name = 'sky' end))
+        ").unwrap().contains("attempt to modify"));
+        assert_eq!(
+            "ground 4 5 seventh false",
+            lua.execute::<String>("
+                return table.concat({
+                    layer.name,
+                    layer.shapes[5].Rect.width,
+                    #layer.shapes,
+                    layer.tags[7],
+                    tostring(getmetatable(layer))
+                }, ' ')
+            ").unwrap()
+        );
+        assert!(lua.execute::<bool>("
+            return layer.missing == nil and layer.shapes[6] == nil and layer[1] == nil
+        ").unwrap());
+    }
+
+    #[test]
+    fn indexes_sequences_by_position() {
+        thread_local!(static SERIALIZED: Cell<u32> = const { Cell::new(0) });
+
+        struct Counted(u32);
+
+        impl Serialize for Counted {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                where S: serde::Serializer
+            {
+                SERIALIZED.with(|serialized| serialized.set(serialized.get() + 1));
+                serializer.serialize_u32(self.0)
+            }
+        }
+
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        let counted: Vec<_> = (1..=1000).map(Counted).collect();
+        lua.checked_set("counted", SerdeLuaUserdata(counted)).unwrap();
+
+        // Only the element asked for is serialized.
+        assert_eq!(500, lua.execute::<u32>("return counted[500]").unwrap());
+        assert_eq!(1, SERIALIZED.with(Cell::get));
+        assert!(lua.execute::<bool>("
+            return counted[0] == nil and counted[1001] == nil and counted[1.5] == nil
+                and counted['1'] == nil and counted[-1] == nil
+        ").unwrap());
+        assert_eq!(1, SERIALIZED.with(Cell::get));
+        assert_eq!(500500, lua.execute::<u32>("
+            local sum = 0
+            for _, value in ipairs(counted) do sum = sum + value end
+            return sum
+        ").unwrap());
+        assert_eq!(1001, SERIALIZED.with(Cell::get));
+    }
+
+    #[test]
+    fn checks_metamethod_arguments() {
+        let mut lua = hlua::Lua::new();
+        lua.openlibs();
+        lua.checked_set("layer", SerdeLuaUserdata(layer())).unwrap();
+        lua.checked_set("other", SerdeLuaUserdata(vec![vec![1]])).unwrap();
+        let error = |lua: &mut hlua::Lua, code: &str| {
+            lua.execute::<String>(&format!("return select(2, pcall(function() {} end))", code))
+                .unwrap()
+        };
+        let not_view = "bad argument #1 (expected a rust value exposed as userdata)";
+
+        assert_eq!(not_view, error(&mut lua, "debug.getmetatable(layer).__index({}, 'name')"));
+        assert_eq!(not_view, error(&mut lua, "debug.getmetatable(layer).__len()"));
+        assert_eq!(not_view, error(&mut lua, "debug.getmetatable(layer).__pairs(io.stdout)"));
+        assert_eq!(not_view, error(&mut lua, "debug.getmetatable(layer).__gc(other)"));
+        assert_eq!(not_view, error(&mut lua, "debug.getmetatable(layer).__len(other[1])"));
+        assert_eq!(
+            "bad argument #1 (table expected)",
+            error(&mut lua, "pairs(layer)(5, nil)")
+        );
+        assert_eq!("ground", lua.execute::<String>("return layer.name").unwrap());
+
+        // Collecting a view by hand empties it, and only once.
+        assert_eq!(
+            "attempt to use a rust value exposed as userdata after it was collected",
+            error(&mut lua, "
+                local shapes = layer.shapes
+                debug.getmetatable(shapes).__gc(shapes)
+                debug.getmetatable(shapes).__gc(shapes)
+                return #shapes
+            ")
+        );
+        assert_eq!(5, lua.execute::<u32>("return #layer.shapes").unwrap());
+    }
+
+    #[test]
+    fn drops_values() {
+        thread_local!(static DROPPED: Cell<u32> = const { Cell::new(0) });
+
+        #[derive(Serialize)]
+        struct Tracked {
+            values: Vec<u32>
+        }
+
+        impl Drop for Tracked {
+            fn drop(&mut self) {
+                DROPPED.with(|dropped| dropped.set(dropped.get() + 1));
+            }
+        }
+
+        {
+            let mut lua = hlua::Lua::new();
+            lua.checked_set("tracked", SerdeLuaUserdata(Tracked { values: vec![1] })).unwrap();
+            lua.execute::<()>("values = tracked.values; tracked = nil").unwrap();
+            lua.checked_set("copy", SerdeLuaPush(Tracked { values: vec![2] })).unwrap();
+            assert_eq!(1, DROPPED.with(Cell::get));
+            assert_eq!(1, lua.execute::<u32>("return values[1]").unwrap());
+        }
+        assert_eq!(2, DROPPED.with(Cell::get));
+    }
+}
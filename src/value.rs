@@ -0,0 +1,452 @@
+//! An owned lua value that can be serialized and deserialized.
+
+use std::fmt;
+use std::ops::Index;
+
+use hlua::{AnyLuaString, AnyLuaValue};
+use serde;
+use serde::de::{Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+/// Any lua value that can be represented in rust.
+///
+/// Unlike `AnyLuaValue`, `Value` implements `Serialize` and `Deserialize`,
+/// so it can be used for struct fields that hold arbitrary lua data. It
+/// converts to and from `AnyLuaValue` without loss, except that
+/// `AnyLuaValue::LuaOther`, which holds no data, becomes `Value::Nil`.
+///
+/// ```rust
+/// extern crate hlua;
+/// extern crate serde;
+/// #[macro_use] extern crate serde_derive;
+/// extern crate serde_hlua;
+///
+/// use serde_hlua::Value;
+///
+/// #[derive(Deserialize)]
+/// struct Config {
+///     name: String,
+///     extra: Value
+/// }
+///
+/// fn main() {
+///     let mut lua = hlua::Lua::new();
+///     let config: Config = serde_hlua::from_lua(lua.execute::<hlua::AnyLuaValue>("
+///         return { name = 'test', extra = { size = 3, 'first', 'second' } }
+///     ").unwrap()).unwrap();
+///     assert_eq!(config.extra["size"].as_f64(), Some(3.0));
+///     assert_eq!(config.extra[2].as_str(), Some("second"));
+///     assert!(config.extra["missing"].is_nil());
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Value {
+    /// `nil`.
+    #[default]
+    Nil,
+    /// A boolean.
+    Boolean(bool),
+    /// A number.
+    Number(f64),
+    /// A string that is valid UTF-8.
+    String(String),
+    /// A string that isn't valid UTF-8.
+    Bytes(Vec<u8>),
+    /// A table, as its entries in order.
+    Table(Vec<(Value, Value)>)
+}
+
+/// Returned when indexing a value that doesn't have the requested entry.
+static NIL: Value = Value::Nil;
+
+// The name of the newtype struct that `Value::Bytes` serializes as. The
+// serializers in this crate push its contents as a raw lua string, whatever
+// the byte encoding, while other serializers see plain bytes.
+pub(crate) const BYTES: &str = "$serde_hlua::Bytes";
+
+/// Serializes its contents with `serialize_bytes`.
+struct RawBytes<'a>(&'a [u8]);
+
+impl<'a> Serialize for RawBytes<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+impl Value {
+    /// Whether the value is `nil`.
+    pub fn is_nil(&self) -> bool {
+        *self == Value::Nil
+    }
+
+    /// Return the boolean, if the value is one.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Boolean(boolean) => Some(boolean),
+            _ => None
+        }
+    }
+
+    /// Return the number, if the value is one.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Number(number) => Some(number),
+            _ => None
+        }
+    }
+
+    /// Return the number, if the value is one that is an integer that fits
+    /// in an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Number(number) if (
+                number as i64 as f64 == number && number < i64::MAX as f64
+            ) => Some(number as i64),
+            _ => None
+        }
+    }
+
+    /// Return the string, if the value is a UTF-8 string.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref string) => Some(string),
+            _ => None
+        }
+    }
+
+    /// Return the contents of the string, if the value is a string of any
+    /// kind.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::String(ref string) => Some(string.as_bytes()),
+            Value::Bytes(ref bytes) => Some(bytes),
+            _ => None
+        }
+    }
+
+    /// Return the entries of the table, if the value is one.
+    pub fn as_table(&self) -> Option<&[(Value, Value)]> {
+        match *self {
+            Value::Table(ref entries) => Some(entries),
+            _ => None
+        }
+    }
+
+    /// Return the entries of the table mutably, if the value is one.
+    pub fn as_table_mut(&mut self) -> Option<&mut Vec<(Value, Value)>> {
+        match *self {
+            Value::Table(ref mut entries) => Some(entries),
+            _ => None
+        }
+    }
+
+    /// Return the value of the table entry with the given key, if the value
+    /// is a table and has that entry.
+    pub fn get<K>(&self, key: K) -> Option<&Value>
+        where K: Into<Value>
+    {
+        let key = key.into();
+        self.as_table()?.iter().find(|entry| entry.0 == key).map(|entry| &entry.1)
+    }
+
+    /// Return the value of the table entry with the given key mutably, if
+    /// the value is a table and has that entry.
+    pub fn get_mut<K>(&mut self, key: K) -> Option<&mut Value>
+        where K: Into<Value>
+    {
+        let key = key.into();
+        self.as_table_mut()?.iter_mut().find(|entry| entry.0 == key).map(|entry| &mut entry.1)
+    }
+
+    /// Return the entries of the table if its keys are exactly 1 to N, in
+    /// order.
+    fn as_sequence(&self) -> Option<&[(Value, Value)]> {
+        let entries = self.as_table()?;
+        let ordered = entries.iter().enumerate().all(|(index, entry)| {
+            entry.0 == Value::Number((index + 1) as f64)
+        });
+        if ordered { Some(entries) } else { None }
+    }
+
+    /// Sort table entries by key and drop `nil` ones, so that tables built
+    /// in different ways compare equal when lua would store the same
+    /// entries. Neither lua nor this crate promise an order.
+    #[cfg(test)]
+    pub(crate) fn sorted(self) -> Value {
+        match self {
+            Value::Table(entries) => {
+                let mut entries: Vec<_> = entries.into_iter()
+                    .filter(|entry| !entry.1.is_nil())
+                    .map(|(key, value)| (key, value.sorted()))
+                    .collect();
+                entries.sort_by(|a, b| format!("{:?}", a.0).cmp(&format!("{:?}", b.0)));
+                Value::Table(entries)
+            },
+            other => other
+        }
+    }
+}
+
+/// Look up a string key, returning `nil` if there is no such entry.
+impl<'a> Index<&'a str> for Value {
+    type Output = Value;
+
+    fn index(&self, key: &'a str) -> &Value {
+        self.get(key).unwrap_or(&NIL)
+    }
+}
+
+/// Look up a numeric key, returning `nil` if there is no such entry. As in
+/// lua, sequences start at index 1.
+impl Index<usize> for Value {
+    type Output = Value;
+
+    fn index(&self, key: usize) -> &Value {
+        self.get(key as f64).unwrap_or(&NIL)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(boolean: bool) -> Value {
+        Value::Boolean(boolean)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Value {
+        Value::Number(number)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(string: &'a str) -> Value {
+        Value::String(string.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Value {
+        Value::String(string)
+    }
+}
+
+impl From<AnyLuaValue> for Value {
+    fn from(value: AnyLuaValue) -> Value {
+        match value {
+            AnyLuaValue::LuaString(string) => Value::String(string),
+            AnyLuaValue::LuaAnyString(bytes) => Value::Bytes(bytes.0),
+            AnyLuaValue::LuaNumber(number) => Value::Number(number),
+            AnyLuaValue::LuaBoolean(boolean) => Value::Boolean(boolean),
+            AnyLuaValue::LuaArray(entries) => Value::Table(
+                entries.into_iter().map(|(key, value)| (key.into(), value.into())).collect()
+            ),
+            AnyLuaValue::LuaNil | AnyLuaValue::LuaOther => Value::Nil
+        }
+    }
+}
+
+impl From<Value> for AnyLuaValue {
+    fn from(value: Value) -> AnyLuaValue {
+        match value {
+            Value::Nil => AnyLuaValue::LuaNil,
+            Value::Boolean(boolean) => AnyLuaValue::LuaBoolean(boolean),
+            Value::Number(number) => AnyLuaValue::LuaNumber(number),
+            Value::String(string) => AnyLuaValue::LuaString(string),
+            Value::Bytes(bytes) => AnyLuaValue::LuaAnyString(AnyLuaString(bytes)),
+            Value::Table(entries) => AnyLuaValue::LuaArray(
+                entries.into_iter().map(|(key, value)| (key.into(), value.into())).collect()
+            )
+        }
+    }
+}
+
+/// Tables with keys from 1 to N, in order, are serialized as sequences, and
+/// other tables as maps. The serializers in this crate keep `Value::Bytes`
+/// as a raw lua string rather than applying the byte encoding.
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        match *self {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Boolean(boolean) => serializer.serialize_bool(boolean),
+            Value::Number(number) => serializer.serialize_f64(number),
+            Value::String(ref string) => serializer.serialize_str(string),
+            Value::Bytes(ref bytes) => serializer.serialize_newtype_struct(BYTES, &RawBytes(bytes)),
+            Value::Table(ref entries) => match self.as_sequence() {
+                Some(sequence) => serializer.collect_seq(sequence.iter().map(|entry| &entry.1)),
+                None => serializer.collect_map(entries.iter().map(|entry| (&entry.0, &entry.1)))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Value, D::Error>
+        where D: Deserializer<'de>
+    {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any lua value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Value, D::Error>
+        where D: Deserializer<'de>
+    {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Value, D::Error>
+        where D: Deserializer<'de>
+    {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, boolean: bool) -> Result<Value, E> {
+        Ok(Value::Boolean(boolean))
+    }
+
+    fn visit_i64<E>(self, number: i64) -> Result<Value, E> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn visit_u64<E>(self, number: u64) -> Result<Value, E> {
+        Ok(Value::Number(number as f64))
+    }
+
+    fn visit_f64<E>(self, number: f64) -> Result<Value, E> {
+        Ok(Value::Number(number))
+    }
+
+    fn visit_str<E>(self, string: &str) -> Result<Value, E> {
+        Ok(Value::String(string.to_owned()))
+    }
+
+    fn visit_string<E>(self, string: String) -> Result<Value, E> {
+        Ok(Value::String(string))
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(bytes.to_owned()))
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(bytes))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+        where A: serde::de::SeqAccess<'de>
+    {
+        let mut entries = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(value) = seq.next_element()? {
+            let index = (entries.len() + 1) as f64;
+            entries.push((Value::Number(index), value));
+        }
+        Ok(Value::Table(entries))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Value, A::Error>
+        where A: serde::de::MapAccess<'de>
+    {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Value::Table(entries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua;
+    use hlua::AnyLuaValue;
+
+    use std::collections::BTreeMap;
+
+    use super::Value;
+    use config::{ByteEncoding, Config};
+    use ::{from_lua, from_value, to_lua, to_lua_with_config, to_value, SerdeLuaPush};
+
+    fn procure(value: &str) -> AnyLuaValue {
+        let mut lua = hlua::Lua::new();
+        lua.execute::<AnyLuaValue>(&format!("return {}", value)).unwrap()
+    }
+
+    #[test]
+    fn conversions() {
+        let any = procure(r"{ 1, 'two', { three = true }, [0.5] = '\255' }");
+        let value = Value::from(any.clone());
+        assert_eq!(any, AnyLuaValue::from(value.clone()));
+        assert_eq!(Some(1.0), value[1].as_f64());
+        assert_eq!(Some("two"), value[2].as_str());
+        assert_eq!(Some(true), value[3]["three"].as_bool());
+        assert_eq!(Some(&[255u8][..]), value.get(0.5).and_then(Value::as_bytes));
+        assert!(value[4].is_nil());
+        assert!(value["three"].is_nil());
+        assert!(Value::Number(1.0)["key"].is_nil());
+        assert_eq!(Some(-3), Value::Number(-3.0).as_i64());
+        assert_eq!(None, Value::Number(0.5).as_i64());
+    }
+
+    #[test]
+    fn serde() {
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Passthrough {
+            name: String,
+            extra: Value
+        }
+
+        let value: Passthrough = from_lua(procure(r"{
+            name = 'test',
+            extra = { 'a', 'b', nested = { [true] = 1.5 }, raw = '\255' }
+        }")).unwrap();
+        assert_eq!(Some(1.5), value.extra["nested"].get(true).and_then(Value::as_f64));
+        assert_eq!(Some(&[255u8][..]), value.extra["raw"].as_bytes());
+
+        let round_trip: Passthrough = from_lua(to_lua(&value).unwrap()).unwrap();
+        assert_eq!(value.name, round_trip.name);
+        assert_eq!(value.extra["nested"], round_trip.extra["nested"]);
+        assert_eq!(value.extra[2], round_trip.extra[2]);
+        assert_eq!(value.extra["raw"], round_trip.extra["raw"]);
+
+        // Bytes are kept as they are, whatever the byte encoding.
+        let bytes = Value::Bytes(vec![0, 255, 10]);
+        assert_eq!(bytes, to_value(&bytes).unwrap());
+        let config = Config::new().byte_encoding(ByteEncoding::Array);
+        assert_eq!(bytes, from_lua(to_lua_with_config(&bytes, config).unwrap()).unwrap());
+        let mut lua = hlua::Lua::new();
+        lua.checked_set("bytes", SerdeLuaPush(&bytes)).unwrap();
+        assert!(lua.execute::<bool>("return bytes == '\\0\\255\\n'").unwrap());
+
+        let mut map = BTreeMap::new();
+        map.insert("key".to_owned(), vec![1u32, 2]);
+        let value = to_value(&map).unwrap();
+        assert_eq!(Some(2.0), value["key"][2].as_f64());
+        assert_eq!(map, from_value::<BTreeMap<String, Vec<u32>>>(value).unwrap());
+
+        // Tables with keys 1 to N are sequences.
+        let sequence = Value::Table(vec![
+            (Value::Number(1.0), Value::from("a")),
+            (Value::Number(2.0), Value::from("b"))
+        ]);
+        assert_eq!(vec!["a", "b"], from_value::<Vec<String>>(to_value(&sequence).unwrap()).unwrap());
+    }
+}